@@ -0,0 +1,155 @@
+// These benchmarks measure how many messages the subscriptions server can publish while thousands of clients are subscribed
+// Clients keep subscribing and unsubscribing throughout, so publishing has to contend with them just like it would on a busy server
+// Every benchmark is run with channels split across shards (the default) and with every channel behind one lock, to show what sharding gains
+
+use async_graphql::{EmptyMutation, Object as GQLObject, Request, Subscription as GQLSubscription};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use diana::{
+    create_jwt, decode_time_str, errors::Result, get_jwt_secret, graphql_utils::get_stream_for_channel_from_ctx,
+    validate_and_decode_jwt, AuthBlockLevel, AuthState, AuthToken, DianaHandler, Options, Stream, StreamExt,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
+
+// The number of channels the subscribers are spread across
+const CHANNELS: usize = 100;
+// The number of tasks publishing at once, and how many messages each publishes in one iteration
+const PUBLISHERS: usize = 8;
+const MESSAGES_PER_PUBLISHER: usize = 50;
+// The number of tasks subscribing and immediately unsubscribing while messages are published
+const CHURNERS: usize = 8;
+
+#[derive(Clone)]
+struct Context {}
+
+#[derive(Clone)]
+struct Query {}
+#[GQLObject]
+impl Query {
+    async fn query(&self) -> bool {
+        true
+    }
+}
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    async fn messages(&self, raw_ctx: &async_graphql::Context<'_>, channel: String) -> Result<impl Stream<Item = String>> {
+        get_stream_for_channel_from_ctx(&channel, raw_ctx)
+    }
+}
+
+type Handler = DianaHandler<Context, Query, EmptyMutation, Subscription>;
+
+const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+
+// Gets a handler with channels split across the default number of shards, or all behind one lock
+fn get_handler(single_lock: bool) -> Handler {
+    let mut opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::AllowAll)
+        .jwt_secret(JWT_SECRET)
+        // Subscribers are drained as fast as they can be, but we don't want to measure them falling behind
+        .channel_buffer_size(MESSAGES_PER_PUBLISHER * PUBLISHERS)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .finish()
+        .unwrap();
+    if single_lock {
+        opts.pubsub_config.channel_shards = 1;
+    }
+    DianaHandler::new(opts).unwrap()
+}
+
+// Gets the authentication state the publisher has on the subscriptions server, which saves decoding a token for every message
+fn get_publishing_auth_state() -> AuthState {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let mut claims = HashMap::new();
+    claims.insert("role".to_string(), "graphql_server".to_string());
+    let exp = decode_time_str("1h").unwrap();
+    let jwt = create_jwt(claims, &secret, exp).unwrap();
+    AuthState::Authorised(AuthToken(validate_and_decode_jwt(&jwt, &secret).unwrap()))
+}
+
+fn subscription_request(subscriber: usize) -> Request {
+    Request::new(format!(
+        "subscription {{ messages(channel: \"channel.{}\") }}",
+        subscriber % CHANNELS
+    ))
+}
+
+// Subscribes the given number of clients, each of which is drained in its own task until it's aborted
+async fn subscribe_clients(handler: &Arc<Handler>, subscribers: usize) -> Vec<JoinHandle<()>> {
+    let mut tasks = Vec::new();
+    for subscriber in 0..subscribers {
+        let mut stream = handler
+            .schema_for_subscriptions
+            .execute_stream(subscription_request(subscriber));
+        // Polling the subscription once subscribes to the channel, and this always polls before timing out
+        let _ = tokio::time::timeout(Duration::from_secs(0), stream.next()).await;
+        tasks.push(tokio::spawn(async move { while stream.next().await.is_some() {} }));
+    }
+
+    tasks
+}
+
+// Publishes messages from several tasks at once while other clients subscribe and unsubscribe
+async fn publish_under_load(handler: Arc<Handler>, auth_state: AuthState) {
+    let mut tasks = Vec::new();
+    for publisher in 0..PUBLISHERS {
+        let handler = Arc::clone(&handler);
+        let auth_state = auth_state.clone();
+        tasks.push(tokio::spawn(async move {
+            for message in 0..MESSAGES_PER_PUBLISHER {
+                let req = Request::new(format!(
+                    "mutation {{ publish(channel: \"channel.{}\", data: \"message\") }}",
+                    (publisher * MESSAGES_PER_PUBLISHER + message) % CHANNELS
+                ))
+                .data(auth_state.clone());
+                let res = handler.schema_for_subscriptions.execute(req).await;
+                assert!(res.errors.is_empty(), "couldn't publish message: {:?}", res.errors);
+            }
+        }));
+    }
+    for churner in 0..CHURNERS {
+        let handler = Arc::clone(&handler);
+        tasks.push(tokio::spawn(async move {
+            for message in 0..MESSAGES_PER_PUBLISHER {
+                let mut stream = handler
+                    .schema_for_subscriptions
+                    .execute_stream(subscription_request(churner * MESSAGES_PER_PUBLISHER + message));
+                let _ = tokio::time::timeout(Duration::from_secs(0), stream.next()).await;
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+fn publish_with_many_subscribers(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let auth_state = get_publishing_auth_state();
+    let mut group = c.benchmark_group("publish_with_many_subscribers");
+    group.throughput(Throughput::Elements((PUBLISHERS * MESSAGES_PER_PUBLISHER) as u64));
+    // A single shard is the baseline, that's how channels were stored before they were sharded
+    for (name, single_lock) in [("single_lock", true), ("sharded", false)].iter() {
+        for subscribers in [1_000, 5_000].iter() {
+            let handler = Arc::new(get_handler(*single_lock));
+            let subscriber_tasks = rt.block_on(subscribe_clients(&handler, *subscribers));
+            group.bench_with_input(BenchmarkId::new(*name, subscribers), subscribers, |b, _| {
+                b.to_async(&rt)
+                    .iter(|| publish_under_load(Arc::clone(&handler), auth_state.clone()))
+            });
+            for task in subscriber_tasks {
+                task.abort();
+            }
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, publish_with_many_subscribers);
+criterion_main!(benches);
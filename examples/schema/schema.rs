@@ -8,9 +8,10 @@ use diana::{
         SimpleObject as GQLSimpleObject,
     },
     errors::GQLResult,
-    Stream, stream,
-    graphql_utils::get_stream_for_channel_from_ctx,
-    Publisher
+    errors::Result,
+    Stream,
+    graphql_utils::get_typed_stream_for_channel_from_ctx,
+    Channel, BufferedPublisher
 };
 use std::env;
 use serde::{Serialize, Deserialize};
@@ -20,6 +21,9 @@ pub struct User {
     username: String
 }
 
+// Both sides of the channel use this, so the name and the type of data published on it can't drift apart
+const NEW_BLAH: Channel<User> = Channel::new("new_blah");
+
 #[derive(Default, Clone)]
 pub struct Query {}
 #[GQLObject]
@@ -41,10 +45,10 @@ impl Mutation {
         let user = User {
             username: "This is a username".to_string()
         };
-        // Stringify and publish the data to the subscriptions server
-        let publisher = raw_ctx.data::<Publisher>()?;
-        let user_json = serde_json::to_string(&user)?;
-        publisher.publish("new_blah", user_json).await?;
+        // Publish the data to the subscriptions server (it'll be serialized for us)
+        // This will only actually be sent once the rest of the request has succeeded
+        let publisher = raw_ctx.data::<BufferedPublisher>()?;
+        publisher.publish_typed(&NEW_BLAH, &user)?;
         Ok(true)
     }
 }
@@ -55,19 +59,10 @@ impl Subscription {
     async fn new_blahs(
         &self,
         raw_ctx: &async_graphql::Context<'_>,
-    ) -> impl Stream<Item = GQLResult<User>> {
-        // Get a direct stream from the context on a certain channel
-        let stream_result = get_stream_for_channel_from_ctx("new_blah", raw_ctx);
-
-        // We can manipulate the stream using the stream macro from async-stream
-        stream! {
-            let stream = stream_result?;
-            for await message in stream {
-                // Serialise the data as a user
-                let new_user: User = serde_json::from_str(&message).map_err(|_err| "couldn't serialize given data correctly".to_string())?;
-                yield Ok(new_user);
-            }
-        }
+    ) -> Result<impl Stream<Item = GQLResult<User>>> {
+        // Get a stream from the context that deserializes everything on the channel into users for us
+        // If you need to manipulate the stream, you can use the stream macro from async-stream
+        get_typed_stream_for_channel_from_ctx(&NEW_BLAH, raw_ctx)
     }
 }
 
@@ -1,11 +1,11 @@
 // This example illustrates how to set up a subscriptions server for production (no serverless functions for subscriptions)
-// Note that this is literally identical to the `server.rs` example, just using `create_subscriptions_server` instead and a different port
+// Note that this is almost identical to the `server.rs` example, just using `create_subscriptions_server` instead, a different port, and listening for publishes on a Unix socket if there is one
 
 #![forbid(unsafe_code)]
 
 use diana_actix_web::{
     actix_web::{App, HttpServer},
-    create_subscriptions_server,
+    create_subscriptions_server, prepare_publish_socket,
 };
 
 // This 'dirty-imports' the code in `schema.in`
@@ -16,10 +16,13 @@ include!("../../../../examples/schema/schema.rs");
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let configurer = create_subscriptions_server(get_opts()).expect("Failed to set up configurer!");
+    let opts = get_opts();
+    let configurer = create_subscriptions_server(opts.clone()).expect("Failed to set up configurer!");
 
-    HttpServer::new(move || App::new().configure(configurer.clone()))
-        .bind("0.0.0.0:9002")?
-        .run()
-        .await
+    let mut server = HttpServer::new(move || App::new().configure(configurer.clone())).bind("0.0.0.0:9002")?;
+    // If the queries/mutations system publishes over a Unix socket, we need to listen there too
+    if let Some(socket) = prepare_publish_socket(&opts)? {
+        server = server.bind_uds(socket)?;
+    }
+    server.run().await
 }
@@ -28,6 +28,8 @@ where
 {
     // Create a new Diana handler (core logic primitive)
     let diana_handler = DianaHandler::new(opts.clone())?;
+    // Anything left in the publisher's outbox from a previous run should be delivered without waiting for a request
+    diana_handler.start_outbox()?;
 
     // Get the appropriate authentication middleware set up with the JWT secret
     // This will wrap the GraphQL endpoint itself
@@ -11,7 +11,7 @@ use diana::{errors::*, AuthBlockLevel, DianaHandler, Options};
 use std::any::Any;
 
 use crate::auth_middleware::AuthCheck;
-use crate::routes::{graphql_for_subscriptions, graphql_ws};
+use crate::routes::{graphql_for_subscriptions, graphql_sse, graphql_ws};
 
 /// Creates a new subscriptions server. This returns a closure that can be used with Actix Web's `.configure()` function to quickly configure
 /// a new or existing Actix Web server to use Diana. For examples, see the book. This function should be used to create production servers.
@@ -28,6 +28,10 @@ where
 {
     // Create a new Diana handler (core logic primitive)
     let diana_handler = DianaHandler::new(opts.clone())?;
+    // Relays, webhooks, and scheduled messages should all be running before the first request arrives
+    diana_handler.start_subscriptions_server();
+    // Any queries/mutations system built from the same options in this process will publish to us directly
+    diana_handler.serve_subscriptions_in_process();
 
     // Get the appropriate authentication middleware set up with the JWT secret
     // This will wrap the GraphQL endpoint itself
@@ -58,6 +62,14 @@ where
                     .guard(guard::Get())
                     .guard(guard::Header("upgrade", "websocket"))
                     .to(graphql_ws::<C, Q, M, S>),
+            )
+            // The GraphQL endpoint for subscriptions over Server-Sent Events, for clients that can't use WebSockets
+            .service(
+                web::resource(&graphql_endpoint)
+                    .guard(guard::Get())
+                    .guard(guard::Header("accept", "text/event-stream"))
+                    .wrap(auth_middleware.clone())
+                    .to(graphql_sse::<C, Q, M, S>),
             );
 
         // Define the closure for the GraphiQL endpoint
@@ -10,10 +10,15 @@ on that platform. For more information, see [the documentation for Diana](https:
 mod auth_middleware;
 mod create_graphql_server;
 mod create_subscriptions_server;
+#[cfg(unix)]
+mod publish_socket;
 mod routes;
+mod ws_connection;
 
 pub use crate::create_graphql_server::create_graphql_server;
 pub use crate::create_subscriptions_server::create_subscriptions_server;
+#[cfg(unix)]
+pub use crate::publish_socket::prepare_publish_socket;
 
 // Users shouldn't have to install Actix Web themselves for basic usage
 #[doc(no_inline)]
@@ -0,0 +1,45 @@
+use async_graphql::{ObjectType, SubscriptionType};
+use diana::Options;
+use std::any::Any;
+use std::fs;
+use std::io::Result;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+
+/// Gets the Unix socket that the queries/mutations system will publish to the subscriptions server on, if one has been set up with
+/// `.subscriptions_server_socket()` in the [`Options`](diana::Options). The subscriptions server should bind to this with Actix Web's
+/// `.bind_uds()` as well as binding wherever its clients connect. Anything left at that path from a previous run of the server is removed
+/// first, otherwise binding to it would fail.
+/// # Example
+/// ```rust,ignore
+/// let configurer = create_subscriptions_server(opts.clone()).expect("Failed to set up configurer!");
+/// let mut server = HttpServer::new(move || App::new().configure(configurer.clone())).bind("0.0.0.0:9002")?;
+/// if let Some(socket) = prepare_publish_socket(&opts)? {
+///     server = server.bind_uds(socket)?;
+/// }
+/// server.run().await
+/// ```
+pub fn prepare_publish_socket<C, Q, M, S>(opts: &Options<C, Q, M, S>) -> Result<Option<PathBuf>>
+where
+    C: Any + Send + Sync + Clone,
+    Q: Clone + ObjectType + 'static,
+    M: Clone + ObjectType + 'static,
+    S: Clone + SubscriptionType + 'static,
+{
+    let socket = match opts
+        .subscriptions_server_data
+        .as_ref()
+        .and_then(|subscriptions_server_data| subscriptions_server_data.socket.clone())
+    {
+        Some(socket) => socket,
+        None => return Ok(None),
+    };
+    // We only remove old sockets, anything else at that path is a mistake in the options that binding should report
+    if let Ok(metadata) = fs::symlink_metadata(&socket) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(&socket)?;
+        }
+    }
+
+    Ok(Some(socket))
+}
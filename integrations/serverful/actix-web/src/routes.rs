@@ -1,9 +1,13 @@
-use actix_web::{web, HttpRequest, HttpResponse, Result as ActixResult};
+use actix_web::{web, Error as ActixError, HttpRequest, HttpResponse, Result as ActixResult};
+use actix_web_actors::ws;
 use async_graphql::{ObjectType, SubscriptionType};
-use async_graphql_actix_web::WSSubscription; // Pre-built WebSocket logic
+use futures::StreamExt;
+use serde::Deserialize;
 use std::any::Any;
 
-use diana::{AuthVerdict, DianaHandler, DianaResponse};
+use diana::{AuthVerdict, DianaHandler, DianaResponse, DianaStreamResponse, WsProtocol};
+
+use crate::ws_connection::{WsConnection, WS_INCOMING_BUFFER_SIZE};
 
 // TODO reduce code duplication here
 
@@ -67,7 +71,8 @@ where
 }
 
 // The endpoint for GraphQL subscriptions
-// This doesn't use DianaHandler at all (except to extract the needed schema) because `async_graphql` provides practically pre-built integration for this
+// This speaks whichever of the graphql-transport-ws and legacy subscriptions-transport-ws protocols the client asks for, DianaHandler does all
+// the work and we just pass frames back and forth
 pub async fn graphql_ws<C, Q, M, S>(
     diana_handler: web::Data<DianaHandler<C, Q, M, S>>,
     http_req: HttpRequest,
@@ -79,6 +84,89 @@ where
     M: Clone + ObjectType + 'static,
     S: Clone + SubscriptionType + 'static,
 {
-    let schema = &diana_handler.schema_for_subscriptions;
-    WSSubscription::start(schema.clone(), &http_req, payload)
+    let protocols_header = http_req
+        .headers()
+        .get("SEC-WEBSOCKET-PROTOCOL")
+        .and_then(|protocols_header| protocols_header.to_str().ok());
+    let protocol = match WsProtocol::negotiate(protocols_header) {
+        Some(protocol) => protocol,
+        None => return Ok(HttpResponse::BadRequest().body("unsupported websocket subprotocol")),
+    };
+    // Browsers can't set headers on WebSockets, so the token can also come in the connection's initialization payload
+    let auth_header = http_req
+        .headers()
+        .get("AUTHORIZATION")
+        .and_then(|auth_header| auth_header.to_str().ok())
+        .map(|auth_header| auth_header.to_string());
+
+    let (incoming_tx, incoming_rx) = futures::channel::mpsc::channel(WS_INCOMING_BUFFER_SIZE);
+    let outgoing = diana_handler.run_ws_for_subscriptions(protocol, auth_header, incoming_rx);
+    ws::start_with_protocols(
+        WsConnection::new(incoming_tx, Box::pin(outgoing)),
+        &[protocol.name()],
+        &http_req,
+        payload,
+    )
+}
+
+// The parameters of a subscription run over Server-Sent Events, which come in the query string because browsers can only make GET requests
+// with `EventSource`
+#[derive(Deserialize)]
+pub struct SseParams {
+    query: String,
+    // This is a JSON object, encoded as a string
+    variables: Option<String>,
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+}
+
+// The endpoint for GraphQL subscriptions over Server-Sent Events, for clients that can't use WebSockets (e.g. behind proxies that kill them)
+// This goes through the same authentication middleware as the endpoint for queries and mutations
+pub async fn graphql_sse<C, Q, M, S>(
+    diana_handler: web::Data<DianaHandler<C, Q, M, S>>,
+    http_req: HttpRequest,
+    params: web::Query<SseParams>,
+) -> HttpResponse
+where
+    C: Any + Send + Sync + Clone,
+    Q: Clone + ObjectType + 'static,
+    M: Clone + ObjectType + 'static,
+    S: Clone + SubscriptionType + 'static,
+{
+    // Get the authorisation verdict from the request extensions if it exists (it would be set by the middleware)
+    let auth_verdict = http_req.extensions().get::<AuthVerdict>().cloned();
+    // Clients send the ID of the last event they received when they reconnect, so they can carry on from there
+    let last_event_id = http_req
+        .headers()
+        .get("LAST-EVENT-ID")
+        .and_then(|last_event_id| last_event_id.to_str().ok());
+
+    // Turn the parameters into the same body a POST request would have
+    let params = params.into_inner();
+    let variables = match params.variables.as_deref().map(serde_json::from_str::<serde_json::Value>) {
+        Some(Ok(variables)) => variables,
+        Some(Err(_)) => return HttpResponse::BadRequest().finish(),
+        None => serde_json::Value::Null,
+    };
+    let body = serde_json::json!({
+        "query": params.query,
+        "variables": variables,
+        "operationName": params.operation_name,
+    })
+    .to_string();
+
+    // Start the subscription, stating that authentication checks don't need to be performed again
+    let res = diana_handler.run_sse_for_subscriptions(body, Option::<String>::None, auth_verdict, last_event_id);
+
+    // Transform the DianaStreamResponse into a streaming HttpResponse
+    match res {
+        DianaStreamResponse::Success(events) => HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .header("Cache-Control", "no-cache")
+            // Stops proxies like Nginx from buffering the events
+            .header("X-Accel-Buffering", "no")
+            .streaming(events.map(|event| Ok::<_, ActixError>(web::Bytes::from(event)))),
+        DianaStreamResponse::Blocked => HttpResponse::Forbidden().finish(),
+        DianaStreamResponse::Error(_) => HttpResponse::InternalServerError().finish(),
+    }
 }
@@ -0,0 +1,91 @@
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web_actors::ws::{CloseCode, CloseReason, Message, ProtocolError, WebsocketContext};
+use futures::channel::mpsc::Sender;
+use std::pin::Pin;
+
+use diana::{DianaWsMessage, Stream};
+
+type Outgoing = Pin<Box<dyn Stream<Item = DianaWsMessage> + Send>>;
+
+// The number of text frames from a client that can be waiting for its session before the client is disconnected for sending too fast
+pub const WS_INCOMING_BUFFER_SIZE: usize = 64;
+
+// A subscriptions WebSocket connection, which just shuttles frames between the client and the session `DianaHandler` runs for it
+// Everything about the subscriptions protocols is handled by `DianaHandler`, this only deals with the WebSocket itself
+pub struct WsConnection {
+    // Where text frames from the client go, dropping this ends the session (and all its subscriptions)
+    incoming_tx: Sender<String>,
+    // What should be sent to the client, which is taken when the actor starts
+    outgoing: Option<Outgoing>,
+}
+impl WsConnection {
+    pub fn new(incoming_tx: Sender<String>, outgoing: Outgoing) -> Self {
+        Self {
+            incoming_tx,
+            outgoing: Some(outgoing),
+        }
+    }
+}
+impl WsConnection {
+    // Passes a text frame from the client on to its session
+    // Frames can't be dropped without breaking the protocol, so a client that's sending faster than they can be handled is disconnected
+    fn pass_on(&mut self, text: String, ctx: &mut WebsocketContext<Self>) {
+        match self.incoming_tx.try_send(text) {
+            Ok(()) => (),
+            Err(err) if err.is_full() => {
+                ctx.close(Some(CloseReason {
+                    code: CloseCode::Policy,
+                    description: Some("Too many messages".to_string()),
+                }));
+                ctx.stop();
+            }
+            // If the session has ended, it'll have told us to close the connection already
+            Err(_) => (),
+        }
+    }
+}
+impl Actor for WsConnection {
+    type Context = WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(outgoing) = self.outgoing.take() {
+            ctx.add_stream(outgoing);
+        }
+    }
+}
+// Frames from the client
+impl StreamHandler<Result<Message, ProtocolError>> for WsConnection {
+    fn handle(&mut self, msg: Result<Message, ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(Message::Text(text)) => self.pass_on(text, ctx),
+            // Some clients send text as binary frames
+            Ok(Message::Binary(bytes)) => {
+                if let Ok(text) = String::from_utf8(bytes.to_vec()) {
+                    self.pass_on(text, ctx);
+                }
+            }
+            Ok(Message::Ping(bytes)) => ctx.pong(&bytes),
+            Ok(Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(_) => (),
+            Err(_) => ctx.stop(),
+        }
+    }
+}
+// Messages from the session for the client
+impl StreamHandler<DianaWsMessage> for WsConnection {
+    fn handle(&mut self, msg: DianaWsMessage, ctx: &mut Self::Context) {
+        match msg {
+            DianaWsMessage::Text(text) => ctx.text(text),
+            DianaWsMessage::Close(code, description) => {
+                ctx.close(Some(CloseReason {
+                    code: code.into(),
+                    description: Some(description),
+                }));
+                ctx.stop();
+            }
+        }
+    }
+}
@@ -0,0 +1,53 @@
+// This module manages the runtime that Diana's own long-running tasks (e.g. relays to other systems) are run on
+// Integrations may be running on a different async runtime entirely (Actix Web still uses Tokio v0.2), so we can't just spawn onto whatever
+// happens to be driving the current request, we keep our own instead
+
+use once_cell::sync::Lazy;
+use std::future::Future;
+use std::panic;
+use tokio::runtime::{Builder, Runtime};
+
+// This is only created the first time something actually needs to run in the background, so systems that never need it never pay for it
+static BACKGROUND_RUNTIME: Lazy<Runtime> = Lazy::new(|| {
+    Builder::new_multi_thread()
+        .worker_threads(2) // These tasks are almost entirely I/O-bound
+        .thread_name("diana-background")
+        .enable_all()
+        .build()
+        .expect("couldn't create diana's background runtime")
+});
+
+// Spawns the given future on Diana's background runtime, which is independent of whatever runtime the caller is using
+pub fn spawn_background<F>(fut: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    BACKGROUND_RUNTIME.spawn(fut);
+}
+
+// Runs the given future on Diana's background runtime and waits for its result, which works from inside any runtime
+// This is for work that needs Tokio v1 (e.g. `reqwest` and timers) but is being done for an integration that might not be running on it
+pub async fn run_in_background<F>(fut: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match BACKGROUND_RUNTIME.spawn(fut).await {
+        Ok(output) => output,
+        // The background runtime is never shut down, so the task can only have failed by panicking, which we carry on into the caller
+        Err(err) => panic::resume_unwind(err.into_panic()),
+    }
+}
+
+// Runs the given blocking function (e.g. file I/O) on Diana's background runtime and waits for its result, which works from inside any
+// runtime without holding up whatever is driving the caller
+pub async fn run_blocking_in_background<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match BACKGROUND_RUNTIME.spawn_blocking(f).await {
+        Ok(output) => output,
+        Err(err) => panic::resume_unwind(err.into_panic()),
+    }
+}
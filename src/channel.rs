@@ -0,0 +1,93 @@
+// This module defines typed channels, which tie the name of a channel to the type of data published on it
+// They should be defined once and shared between the queries/mutations system and the subscriptions server so the two can't drift apart
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+use std::marker::PhantomData;
+use anyhow::Result;
+
+use crate::errors::DianaError;
+use crate::payload::Encoding;
+
+/// A channel on the subscriptions server that carries data of a particular type. Defining these as constants and using them on both sides
+/// means you can't publish one thing and try to subscribe to another, or misspell a channel name. Data is serialized to JSON (or
+/// MessagePack, if you create the channel with [`Channel::msgpack`]) for you when it's published with
+/// [`Publisher::publish_typed`](crate::Publisher::publish_typed), and deserialized when it arrives through
+/// [`get_typed_stream_for_channel_from_ctx`](crate::graphql_utils::get_typed_stream_for_channel_from_ctx).
+/// # Example
+/// ```
+/// use diana::Channel;
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct User {
+///     username: String,
+/// }
+///
+/// const NEW_USER: Channel<User> = Channel::new("new_user");
+/// ```
+pub struct Channel<T> {
+    name: &'static str,
+    encoding: Encoding,
+    // Channels don't actually hold any data, so they're `Send` and `Sync` whatever it is
+    _payload: PhantomData<fn() -> T>,
+}
+impl<T> Channel<T> {
+    /// Creates a new typed channel with the given name. This is a `const fn`, so channels can be defined as constants.
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            encoding: Encoding::Json,
+            _payload: PhantomData,
+        }
+    }
+    /// Creates a new typed channel with the given name that encodes its data as MessagePack rather than JSON. This is much faster and
+    /// smaller for large structured payloads. Like [`Channel::new`], this is a `const fn`.
+    pub const fn msgpack(name: &'static str) -> Self {
+        Self {
+            name,
+            encoding: Encoding::MessagePack,
+            _payload: PhantomData,
+        }
+    }
+    /// Gets the name of the channel, which is what it's called on the subscriptions server.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+    /// Gets the encoding data published on this channel uses.
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+}
+impl<T: Serialize> Channel<T> {
+    // Serializes data to be published on this channel
+    pub(crate) fn serialize(&self, payload: &T) -> Result<String> {
+        self.encoding.encode(payload).map_err(|err| {
+            DianaError::ChannelPayloadSerializationFailed(self.name.to_string(), err).into()
+        })
+    }
+}
+impl<T: DeserializeOwned> Channel<T> {
+    // Deserializes data that was published on this channel
+    pub(crate) fn deserialize(&self, data: &str) -> Result<T> {
+        self.encoding.decode(data).map_err(|err| {
+            DianaError::ChannelPayloadDeserializationFailed(self.name.to_string(), err).into()
+        })
+    }
+}
+
+// These are implemented manually because deriving them would require `T` to implement them too
+impl<T> Clone for Channel<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for Channel<T> {}
+impl<T> fmt::Debug for Channel<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Channel")
+            .field(&self.name)
+            .field(&self.encoding)
+            .finish()
+    }
+}
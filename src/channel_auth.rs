@@ -0,0 +1,85 @@
+// This module defines the rules for which clients can subscribe to which channels on the subscriptions server
+// Rules can refer to the claims in a client's token, so each user can be given their own channels (e.g. `user.{sub}`)
+
+use std::collections::HashMap;
+
+use crate::auth::auth_state::{AuthState, AuthToken};
+use crate::auth::jwt::Claims;
+use crate::channel_pattern::{is_channel_pattern, pattern_covers};
+
+// A single rule, which lets clients with the given claims subscribe to channels matching the given template
+#[derive(Debug, Clone)]
+struct ChannelAuthRule {
+    // A channel name or pattern, which can contain placeholders like `{sub}` that are filled in from the client's claims
+    channel: String,
+    // Claims the client's token must have for this rule to apply at all
+    required_claims: HashMap<String, String>,
+}
+impl ChannelAuthRule {
+    fn allows(&self, claims: &HashMap<String, String>, channel: &str) -> bool {
+        let has_required_claims = self
+            .required_claims
+            .iter()
+            .all(|(key, value)| claims.get(key) == Some(value));
+        if !has_required_claims {
+            return false;
+        }
+        match fill_placeholders(&self.channel, claims) {
+            // If the client asked for a pattern, everything it could match must be allowed
+            Some(allowed) => pattern_covers(&allowed, channel),
+            None => false,
+        }
+    }
+}
+
+/// The rules for which clients can subscribe to which channels. These are set up with `.allow_subscribing()` on
+/// [`OptionsBuilder`](crate::OptionsBuilder), and if there aren't any, every client can subscribe to every channel.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelAuthRules {
+    rules: Vec<ChannelAuthRule>,
+}
+impl ChannelAuthRules {
+    pub(crate) fn add(&mut self, channel: &str, required_claims: &[(&str, &str)]) {
+        self.rules.push(ChannelAuthRule {
+            channel: channel.to_string(),
+            required_claims: required_claims
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        });
+    }
+
+    /// Checks whether a client with the given authentication state can subscribe to the given channel (or channel pattern).
+    pub fn allows(&self, auth_state: &AuthState, channel: &str) -> bool {
+        if self.rules.is_empty() {
+            return true;
+        }
+        // Clients without a valid token can still use rules that don't need any claims
+        let no_claims = HashMap::new();
+        let claims = match auth_state {
+            AuthState::Authorised(AuthToken(Claims { claims, .. })) => claims,
+            _ => &no_claims,
+        };
+        self.rules.iter().any(|rule| rule.allows(claims, channel))
+    }
+}
+
+// Fills in the placeholders in the given channel template from the given claims
+// If a placeholder refers to a claim that isn't there, or to one that would turn into a wildcard, the template can't be used
+fn fill_placeholders(template: &str, claims: &HashMap<String, String>) -> Option<String> {
+    let mut filled = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}')?;
+        let value = claims.get(&rest[start + 1..end])?;
+        if is_channel_pattern(value) {
+            return None;
+        }
+        filled.push_str(&rest[..start]);
+        filled.push_str(value);
+        rest = &rest[end + 1..];
+    }
+    filled.push_str(rest);
+
+    Some(filled)
+}
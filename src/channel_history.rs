@@ -0,0 +1,358 @@
+// This module keeps the sequence-numbered history of each channel on the subscriptions server
+// That lets subscribers that have been offline (e.g. mobile clients that reconnect often) pick up where they left off
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use ring::digest;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+
+use crate::background::run_blocking_in_background;
+use crate::envelope::MessageEnvelope;
+use crate::errors::DianaError;
+use crate::payload::{decode_bytes, Encoding};
+
+/// A message that was published on a channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelMessage {
+    /// The channel the message was published on. This is mostly useful for subscriptions to channel patterns, which receive messages from
+    /// many channels.
+    #[serde(default)]
+    pub channel: String,
+    /// The position of this message in its channel. Sequence numbers start at 0 and go up by one with each message published on the channel,
+    /// so a subscriber can resume from the message after the last one it saw. These only survive restarts of the subscriptions server if
+    /// the history is persisted (see `.channel_history_dir()` on [`OptionsBuilder`](crate::OptionsBuilder)).
+    pub seq: u64,
+    /// When the message was published.
+    pub published_at: DateTime<Utc>,
+    /// The data that was published, which will be in whatever format it was serialized into.
+    pub data: String,
+    /// Metadata about the message from whoever published it, like its unique ID and the user that caused it. Messages from older versions
+    /// are given a new envelope when they're read.
+    #[serde(default)]
+    pub envelope: MessageEnvelope,
+}
+impl ChannelMessage {
+    /// Gets the raw bytes of the message's data, decoding them first if the data is binary (see
+    /// [`Publisher::publish_binary`](crate::Publisher::publish_binary)).
+    pub fn bytes(&self) -> Result<Vec<u8>> {
+        if !self.envelope.binary {
+            return Ok(self.data.as_bytes().to_vec());
+        }
+        decode_bytes(&self.data)
+            .map_err(|err| DianaError::ChannelPayloadDeserializationFailed(self.channel.clone(), err).into())
+    }
+    /// Deserializes the message's data into the given type. This uses MessagePack if that's the message's content type, and JSON
+    /// otherwise, so it'll work for anything published on a typed [`Channel`](crate::Channel).
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T> {
+        Encoding::from_content_type(self.envelope.content_type.as_deref())
+            .decode(&self.data)
+            .map_err(|err| DianaError::ChannelPayloadDeserializationFailed(self.channel.clone(), err).into())
+    }
+}
+
+/// Where a subscription to a channel should start from.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum StartFrom {
+    /// Only receive messages published after subscribing. This is the default.
+    #[default]
+    Now,
+    /// Replay the channel's history from the message with the given sequence number (inclusive), then carry on with new messages.
+    /// If some of those messages are no longer in the history, the subscriber is treated as having fallen behind by that many (see
+    /// [`LagPolicy`](crate::LagPolicy)).
+    Sequence(u64),
+    /// Replay every message in the channel's history that was published at or after the given time, then carry on with new messages.
+    Timestamp(DateTime<Utc>),
+}
+// Most filesystems won't allow longer filenames than this
+const MAX_FILENAME_LEN: usize = 255;
+const HISTORY_EXTENSION: &str = ".jsonl";
+// The number of writes that can be waiting for the history writer, past which histories are compacted instead of appended to
+const HISTORY_WRITE_QUEUE_SIZE: usize = 1024;
+
+// Something for the history writer to do
+enum HistoryWrite {
+    // Append a line to the given file
+    Append(PathBuf, String),
+    // Replace the given file with the given contents
+    Replace(PathBuf, String),
+    // Read the given file, sending back what's in it once everything queued before this has been written
+    Load(PathBuf, mpsc::Sender<Vec<ChannelMessage>>),
+    // Say when everything queued before this has been written
+    Flush(mpsc::Sender<()>),
+}
+
+// Where channel histories are persisted
+// All the file I/O for that happens in order on a thread of its own, so it never holds up publishing (which happens with channels locked)
+// or whatever async runtime is driving the subscriptions server
+pub struct HistoryStore {
+    dir: PathBuf,
+    writes: SyncSender<HistoryWrite>,
+}
+impl HistoryStore {
+    pub fn new(dir: &Path) -> Self {
+        let (writes, queue) = mpsc::sync_channel(HISTORY_WRITE_QUEUE_SIZE);
+        // If the writer can't be started, every write to it will fail, which just means nothing is persisted
+        let _ = thread::Builder::new()
+            .name("diana-history-writer".to_string())
+            .spawn(move || run_history_writer(queue));
+
+        Self {
+            dir: dir.to_path_buf(),
+            writes,
+        }
+    }
+
+    // Loads the persisted history of the given channel, waiting for anything still being written to it first
+    // Waiting for the writer blocks, so that's done on a thread that's allowed to, but this must still never be awaited while a shard is
+    // locked
+    pub async fn load(&self, channel: &str) -> Vec<ChannelMessage> {
+        let file = self.dir.join(get_history_filename(channel));
+        let writes = self.writes.clone();
+        let mut messages = run_blocking_in_background(move || {
+            let (sender, receiver) = mpsc::channel();
+            if writes.send(HistoryWrite::Load(file, sender)).is_err() {
+                return Vec::new();
+            }
+            receiver.recv().unwrap_or_default()
+        })
+        .await;
+        // Messages persisted by older versions won't have their channel recorded
+        for message in &mut messages {
+            message.channel = channel.to_string();
+        }
+
+        messages
+    }
+
+    // Queues the given write without waiting, returning whether or not there was room for it
+    fn queue(&self, write: HistoryWrite) -> bool {
+        match self.writes.try_send(write) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+// Everything queued is written before the store goes away, so a subscriptions server that's shut down cleanly doesn't lose anything
+impl Drop for HistoryStore {
+    fn drop(&mut self) {
+        let (sender, receiver) = mpsc::channel();
+        if self.writes.send(HistoryWrite::Flush(sender)).is_ok() {
+            let _ = receiver.recv();
+        }
+    }
+}
+
+// Does everything queued for the history writer in order, which stops once the store has been dropped
+// Failures are ignored, a channel should never stop working because its history couldn't be written
+fn run_history_writer(queue: Receiver<HistoryWrite>) {
+    for write in queue {
+        match write {
+            HistoryWrite::Append(file, line) => {
+                let _ = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(file)
+                    .and_then(|mut file| file.write_all(line.as_bytes()));
+            }
+            // The new contents are written alongside the old ones and then moved over them, so a crash part-way through can't lose both
+            HistoryWrite::Replace(file, contents) => {
+                let temp_file = file.with_extension("jsonl.tmp");
+                if fs::write(&temp_file, contents).is_ok() {
+                    let _ = fs::rename(&temp_file, &file);
+                }
+            }
+            HistoryWrite::Load(file, reply) => {
+                let _ = reply.send(read_history_file(&file));
+            }
+            HistoryWrite::Flush(reply) => {
+                let _ = reply.send(());
+            }
+        }
+    }
+}
+
+// Reads every message in the given history file
+// Anything that can't be read is treated as not being there, history is a best-effort mechanism
+fn read_history_file(file: &Path) -> Vec<ChannelMessage> {
+    let persisted = match File::open(file) {
+        Ok(persisted) => persisted,
+        Err(_) => return Vec::new(),
+    };
+    BufReader::new(persisted)
+        .lines()
+        .filter_map(|line| match line.map(|line| serde_json::from_str::<ChannelMessage>(&line)) {
+            Ok(Ok(message)) => Some(message),
+            _ => None,
+        })
+        .collect()
+}
+
+// The bounded history of a single channel, which is also the authority on its sequence numbers
+pub struct ChannelHistory {
+    channel: String,
+    messages: VecDeque<Arc<ChannelMessage>>,
+    capacity: usize,
+    next_seq: u64,
+    // Where this history is persisted and the file it's persisted to, if it is
+    store: Option<(Arc<HistoryStore>, PathBuf)>,
+    // The number of lines in that file, so we know when to compact it
+    persisted_lines: usize,
+    // Whether or not a write to that file has been dropped because the writer was too far behind, in which case it needs to be replaced
+    // with what we remember rather than appended to
+    needs_compaction: bool,
+}
+impl ChannelHistory {
+    // Creates the history for the given channel from what was loaded from the given store for it, if anything
+    // Sequence numbers carry on from the given one, or from after the last persisted message if that's later
+    pub fn new(
+        channel: &str,
+        capacity: usize,
+        store: Option<Arc<HistoryStore>>,
+        persisted: Vec<ChannelMessage>,
+        next_seq: u64,
+    ) -> Self {
+        let mut history = Self {
+            channel: channel.to_string(),
+            messages: VecDeque::with_capacity(capacity),
+            capacity,
+            next_seq,
+            store: store.map(|store| {
+                let file = store.dir.join(get_history_filename(channel));
+                (store, file)
+            }),
+            persisted_lines: persisted.len(),
+            needs_compaction: false,
+        };
+        for message in persisted {
+            history.remember(Arc::new(message));
+        }
+
+        history
+    }
+
+    // Gets the sequence number the next message will be given
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    // Records a new message with the next sequence number
+    pub fn record(&mut self, data: String, envelope: MessageEnvelope, published_at: DateTime<Utc>) -> Arc<ChannelMessage> {
+        let message = Arc::new(ChannelMessage {
+            channel: self.channel.clone(),
+            seq: self.next_seq,
+            published_at,
+            data,
+            envelope,
+        });
+        self.record_sequenced(Arc::clone(&message));
+        message
+    }
+
+    // Records a message that's already been given a sequence number elsewhere (e.g. by Redis)
+    pub fn record_sequenced(&mut self, message: Arc<ChannelMessage>) {
+        self.remember(Arc::clone(&message));
+        self.persist(&message);
+    }
+
+    fn remember(&mut self, message: Arc<ChannelMessage>) {
+        self.next_seq = self.next_seq.max(message.seq + 1);
+        if self.capacity == 0 {
+            return;
+        }
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
+
+    // Queues the given message to be appended to the persisted history, compacting it down to what we actually remember instead if it's
+    // grown too large
+    // This never waits for the writer, so it's fine to call with channels locked
+    fn persist(&mut self, message: &ChannelMessage) {
+        let (store, file) = match (&self.store, self.capacity) {
+            (Some(store), capacity) if capacity > 0 => store,
+            _ => return,
+        };
+        if self.needs_compaction || self.persisted_lines >= self.capacity * 2 {
+            let compacted = self
+                .messages
+                .iter()
+                .filter_map(|message| serde_json::to_string(&**message).ok())
+                .map(|line| line + "\n")
+                .collect::<String>();
+            // The message we were given is already in there
+            if store.queue(HistoryWrite::Replace(file.clone(), compacted)) {
+                self.persisted_lines = self.messages.len();
+                self.needs_compaction = false;
+            } else {
+                self.needs_compaction = true;
+            }
+            return;
+        }
+        let line = match serde_json::to_string(message) {
+            Ok(line) => line + "\n",
+            Err(_) => return,
+        };
+        if store.queue(HistoryWrite::Append(file.clone(), line)) {
+            self.persisted_lines += 1;
+        } else {
+            // Appending anything after this would leave a gap, so the whole file will be replaced next time
+            self.needs_compaction = true;
+        }
+    }
+
+    // Gets the messages to replay for a subscriber starting from the given point, along with the number of messages it asked for that
+    // are no longer in the history
+    pub fn replay(&self, start_from: &StartFrom) -> (Vec<Arc<ChannelMessage>>, u64) {
+        match start_from {
+            StartFrom::Now => (Vec::new(), 0),
+            StartFrom::Sequence(seq) => {
+                let oldest_seq = self
+                    .messages
+                    .front()
+                    .map(|message| message.seq)
+                    .unwrap_or(self.next_seq);
+                let missed = oldest_seq.saturating_sub(*seq);
+                let messages = self
+                    .messages
+                    .iter()
+                    .filter(|message| message.seq >= *seq)
+                    .cloned()
+                    .collect();
+                (messages, missed)
+            }
+            StartFrom::Timestamp(timestamp) => {
+                let messages = self
+                    .messages
+                    .iter()
+                    .filter(|message| message.published_at >= *timestamp)
+                    .cloned()
+                    .collect();
+                (messages, 0)
+            }
+        }
+    }
+}
+
+// Channel names can contain anything, so we hex-encode them to get something that's always a valid filename
+// That doubles their length though, so names too long for that get a fixed-length hash instead (hex-encoded names can't start with
+// `sha256-`, so the two can't clash)
+fn get_history_filename(channel: &str) -> String {
+    if channel.len() * 2 + HISTORY_EXTENSION.len() > MAX_FILENAME_LEN {
+        let hash = digest::digest(&digest::SHA256, channel.as_bytes());
+        return format!("sha256-{}{}", encode_hex(hash.as_ref()), HISTORY_EXTENSION);
+    }
+    format!("{}{}", encode_hex(channel.as_bytes()), HISTORY_EXTENSION)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
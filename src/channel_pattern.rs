@@ -0,0 +1,95 @@
+// This module defines the patterns subscribers can use to receive messages from many channels at once (e.g. `order.*`)
+// Channel names are split into segments by dots, and patterns can use wildcards that match within a segment or across them
+
+// The character that separates the segments of a channel name
+const SEGMENT_SEPARATOR: char = '.';
+
+// A single element of a pattern (a channel name is just a pattern with no wildcards)
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Token {
+    // A character that has to match exactly
+    Char(char),
+    // `?`, which matches a single character that isn't a segment separator
+    AnyChar,
+    // `*`, which matches any run of characters within a single segment
+    AnyInSegment,
+    // `**`, which matches any run of characters at all
+    Anything,
+}
+impl Token {
+    // Whether or not everything this token matches stays within a single segment
+    fn is_within_segment(&self) -> bool {
+        !matches!(self, Self::Char(SEGMENT_SEPARATOR) | Self::Anything)
+    }
+}
+
+// Checks whether the given channel name is actually a pattern
+pub fn is_channel_pattern(name: &str) -> bool {
+    name.contains(['*', '?'].as_ref())
+}
+
+// Checks whether the given channel matches the given pattern
+// `*` matches any run of characters within a single segment, `**` matches any run of characters at all, and `?` matches a single character
+// that isn't a segment separator
+// Everything else has to match exactly
+pub fn channel_matches_pattern(pattern: &str, channel: &str) -> bool {
+    let channel = channel.chars().map(Token::Char).collect::<Vec<_>>();
+    covers(&tokenize(pattern), &channel)
+}
+
+// Checks whether every channel the given subpattern could match would also be matched by the given pattern
+// This is conservative, so it may say no for some unusual subpatterns that are technically covered, but it will never wrongly say yes
+pub fn pattern_covers(pattern: &str, subpattern: &str) -> bool {
+    covers(&tokenize(pattern), &tokenize(subpattern))
+}
+
+fn tokenize(pattern: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                Token::Anything
+            }
+            '*' => Token::AnyInSegment,
+            '?' => Token::AnyChar,
+            c => Token::Char(c),
+        });
+    }
+
+    tokens
+}
+
+// Checks whether the given pattern covers everything the given target could match
+// This works out whether each suffix of the pattern covers each suffix of the target, from the ends backwards, so it takes time
+// proportional to the product of their lengths no matter how many wildcards there are (only the previous row of that table is kept)
+fn covers(pattern: &[Token], target: &[Token]) -> bool {
+    // An empty pattern only covers an empty target
+    let mut next_row = (0..=target.len()).map(|idx| idx == target.len()).collect::<Vec<_>>();
+    for token in pattern.iter().rev() {
+        let mut row = vec![false; target.len() + 1];
+        for idx in (0..=target.len()).rev() {
+            let target_token = target.get(idx);
+            row[idx] = match token {
+                // Wildcards can match nothing, or take the next target token and carry on
+                Token::Anything => next_row[idx] || (target_token.is_some() && row[idx + 1]),
+                Token::AnyInSegment => {
+                    next_row[idx] || (target_token.map(Token::is_within_segment).unwrap_or(false) && row[idx + 1])
+                }
+                Token::AnyChar => match target_token {
+                    Some(Token::AnyChar) => next_row[idx + 1],
+                    Some(Token::Char(c)) if *c != SEGMENT_SEPARATOR => next_row[idx + 1],
+                    _ => false,
+                },
+                Token::Char(expected) => match target_token {
+                    Some(Token::Char(c)) if c == expected => next_row[idx + 1],
+                    _ => false,
+                },
+            };
+        }
+        next_row = row;
+    }
+
+    next_row[0]
+}
@@ -1,16 +1,25 @@
 // This file contains the core logic primitives that actually run a given request
 // This is depended on by serverful and serverless systems
 
-use async_graphql::{EmptySubscription, ObjectType, Request, Schema, SubscriptionType};
+use async_graphql::{EmptySubscription, ObjectType, Request, Schema, ServerError, SubscriptionType};
 use std::any::Any;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_stream::Stream;
 use anyhow::{Result, bail};
+use uuid::Uuid;
 
+use crate::auth::auth_state::AuthState;
 use crate::auth::core::{get_auth_verdict, get_token_state_from_header, AuthVerdict};
 use crate::graphql::{
     get_schema_for_subscriptions, get_schema_without_subscriptions, PublishMutation,
     SubscriptionQuery,
 };
 use crate::options::Options;
+use crate::publisher::{BufferedPublisher, Publisher};
+use crate::pubsub::{PubSub, PubSubCounters, PubSubMetrics};
+use crate::sse::{get_sse_stream, SubscriptionCursor};
+use crate::websocket::{run_ws_session, DianaWsMessage, WsProtocol};
 
 /// The basic response from a given request.
 #[derive(Clone, Debug)]
@@ -27,6 +36,21 @@ pub enum DianaResponse {
     Error(String),
 }
 
+/// The response to a subscription run over Server-Sent Events.
+pub enum DianaStreamResponse {
+    /// The subscription was started and its events are attached. Each one is complete (including the blank line that ends it), so they
+    /// can be written to the client as they are.
+    /// Return a 200 with a `Content-Type` of `text/event-stream`, and make sure the response isn't buffered.
+    Success(Pin<Box<dyn Stream<Item = String> + Send>>),
+    /// The request was blocked (unauthorized).
+    /// Return a 403.
+    Blocked,
+    /// An error occurred on the server side and its body is encapsulated. Any GraphQL errors will be sent as events in the `Success`
+    /// variant's stream.
+    /// Return a 500.
+    Error(String),
+}
+
 // Represents the chice of the schema for/without subscriptions
 #[doc(hidden)]
 pub enum SysSchema {
@@ -53,6 +77,12 @@ where
     /// The schema created for the subscriptions server. This has the user's given subscription root and internally used query/mutation roots
     /// for communication with the query/mutation system. You should only need to touch this if you're building a custom integration.
     pub schema_for_subscriptions: Schema<SubscriptionQuery, PublishMutation, S>,
+    // The counters behind the metrics of the subscriptions server's PubSub
+    pubsub_counters: Arc<PubSubCounters>,
+    // The publisher to the subscriptions server, if we're using one, which every request gets a buffered version of
+    publisher: Option<Publisher>,
+    // The PubSub behind the subscriptions server, which publishers in this process can be given directly
+    pubsub: Arc<PubSub>,
 }
 impl<C, Q, M, S> DianaHandler<C, Q, M, S>
 where
@@ -64,22 +94,95 @@ where
     /// Creates a new instance of the handler with the given options.
     pub fn new(opts: Options<C, Q, M, S>) -> Result<Self> {
         // TODO only create a schema for subscriptions if they're actually being used (will require broader logic changes)
-        // Get the schema (this also creates a publisher to the subscriptions server and inserts context)
+        // Create a publisher to the subscriptions server if we're using one
+        // This is shared by every handler built from clones of these options, and its outbox isn't opened until it's started (see
+        // `.start_outbox()`), which is never done for the subscriptions server
+        let publisher = match opts.subscriptions_server_data.clone() {
+            Some(subscriptions_server_data) => Some(
+                opts.publisher
+                    .get_or_try_init(|| -> Result<_> {
+                        let publisher = match subscriptions_server_data.socket {
+                            Some(socket) => Publisher::over_socket(
+                                socket,
+                                subscriptions_server_data.endpoint,
+                                subscriptions_server_data.jwt_to_connect,
+                                opts.publisher_config.clone(),
+                            )?,
+                            None => Publisher::over_http(
+                                subscriptions_server_data.hostname,
+                                subscriptions_server_data.port,
+                                subscriptions_server_data.endpoint,
+                                subscriptions_server_data.jwt_to_connect,
+                                opts.publisher_config.clone(),
+                            )?,
+                        };
+                        Ok(publisher.with_local_subscriptions_server(opts.local_subscriptions_server.clone()))
+                    })?
+                    .clone(),
+            ),
+            None => None,
+        };
+        // Get the schema (this also inserts the publisher and context)
         // We deal with any errors directly with the serverless response enum
         let schema_without_subscriptions = get_schema_without_subscriptions(
             opts.schema.clone(),
-            opts.subscriptions_server_data.clone(),
+            publisher.clone(),
             opts.ctx.clone(),
         )?;
-        let schema_for_subscriptions =
-            get_schema_for_subscriptions(opts.schema.clone(), opts.ctx.clone());
+        let pubsub = Arc::new(PubSub::new(opts.pubsub_config.clone()));
+        let pubsub_counters = pubsub.counters();
+        let schema_for_subscriptions = get_schema_for_subscriptions(
+            opts.schema.clone(),
+            Arc::clone(&pubsub),
+            opts.channel_auth_rules.clone(),
+            opts.ctx.clone(),
+        );
 
         Ok(DianaHandler {
             opts,
             schema_without_subscriptions,
             schema_for_subscriptions,
+            pubsub_counters,
+            publisher,
+            pubsub,
         })
     }
+    /// Makes this handler's subscriptions server the one that every publisher built from the same [`Options`] (or a clone of them) sends
+    /// to, which they'll then do directly instead of over HTTP with a JWT. This is for running the queries/mutations system and the
+    /// subscriptions server in the same process (e.g. in development, or on a single machine), and the Actix Web integration's
+    /// `create_subscriptions_server()` does it for you, so you only need this if you're running a subscriptions server with your own
+    /// integration.
+    pub fn serve_subscriptions_in_process(&self) {
+        self.opts
+            .local_subscriptions_server
+            .serve(Arc::clone(&self.pubsub));
+    }
+    /// Starts everything the subscriptions server runs in the background: relays through Redis and from Postgres, webhooks, and scheduled
+    /// messages (including any saved from before a restart). The Actix Web integration's `create_subscriptions_server()` does this for you,
+    /// so you only need to call it if you're running a subscriptions server with your own integration, in which case you should call it as
+    /// soon as you've created the handler (otherwise they'll only start when the first request arrives). Don't call this for a
+    /// queries/mutations system. Calling it more than once does nothing.
+    pub fn start_subscriptions_server(&self) {
+        self.pubsub.start_relays(&self.pubsub);
+    }
+    /// Opens the publisher's outbox (if one was set up with `.publish_outbox_dir()` on [`OptionsBuilder`](crate::OptionsBuilder)) and
+    /// starts delivering anything left in it from a previous run. The Actix Web integration's `create_graphql_server()` does this for you,
+    /// and otherwise it's done when the first query or mutation is run, so you only need to call it to start delivering as soon as a
+    /// queries/mutations system with your own integration starts. Don't call this for a subscriptions server. Handlers built from clones
+    /// of the same [`Options`](crate::Options) share one outbox, so calling this more than once does nothing.
+    /// This will fail if another publisher (possibly in another process) is already using the outbox directory. It briefly blocks on the
+    /// disk, so it shouldn't be called from inside a request.
+    pub fn start_outbox(&self) -> Result<()> {
+        if let Some(publisher) = &self.publisher {
+            publisher.start_outbox_now()?;
+        }
+        Ok(())
+    }
+    /// Gets a snapshot of the metrics for the subscriptions server's channels, like how many messages have been dropped because subscribers
+    /// fell behind. This is only meaningful on the subscriptions server.
+    pub fn pubsub_metrics(&self) -> PubSubMetrics {
+        self.pubsub_counters.snapshot()
+    }
     /// Determines ahead of time whether or not a request is authenticated. This should be used in middleware if possible so we can avoid
     /// sending full payloads if the auth token isn't even valid.
     /// This just takes the HTTP `Authorization` header and returns an [`AuthVerdict`].
@@ -97,6 +200,23 @@ where
             get_token_state_from_header(auth_header_str, self.opts.jwt_secret.clone());
         get_auth_verdict(token_state, self.opts.authentication_block_state)
     }
+    /// Gets the authentication state for a new subscriptions connection (e.g. a WebSocket), which should be inserted into the connection's
+    /// data so that subscriptions can check which channels the client can use. The token is taken from the HTTP `Authorization` header if
+    /// there is one, and otherwise from the `Authorization` (or `authorization`) field of the connection's initialization payload, because
+    /// browsers can't set headers on WebSockets. Connections aren't blocked based on this, the rules set up with `.allow_subscribing()` on
+    /// [`OptionsBuilder`](crate::OptionsBuilder) decide what each client can subscribe to.
+    /// This will only fail if the JWT secret is invalid.
+    pub fn get_auth_state_for_subscriptions<A: Into<String> + std::fmt::Display>(
+        &self,
+        raw_auth_header: Option<A>,
+        connection_payload: &serde_json::Value,
+    ) -> Result<AuthState> {
+        get_auth_state_from_connection(
+            raw_auth_header.map(|x| x.to_string()),
+            connection_payload,
+            self.opts.jwt_secret.clone(),
+        )
+    }
     /// Runs a query or mutation (stateless) given the request body and the value of the HTTP `Authorization` header.
     /// This performs authorisation checks and runs the actual request. If you've already used `.is_authed()` to obtain an [`AuthVerdict`],
     /// this can be provided as the third argument to avoid running auth checks twice.
@@ -135,6 +255,68 @@ where
         )
         .await
     }
+    /// Runs a subscription over Server-Sent Events given the request body, the value of the HTTP `Authorization` header, and the value of the
+    /// HTTP `Last-Event-ID` header (which clients send when they reconnect). This performs the same authorisation checks as
+    /// [`.run_stateless_for_subscriptions()`](DianaHandler::run_stateless_for_subscriptions), and if you've already used `.is_authed()` to
+    /// obtain an [`AuthVerdict`], this can be provided as the third argument to avoid running them twice.
+    /// Each result is sent as a `next` event whose data is the GraphQL response, and a `complete` event is sent when the subscription ends.
+    /// Events carry IDs that let reconnecting clients carry on from where they were, as long as the messages they missed are still in the
+    /// channels' histories (see `.channel_history_size()` on [`OptionsBuilder`](crate::OptionsBuilder)).
+    /// This function is for the subscriptions system only.
+    pub fn run_sse_for_subscriptions<A: Into<String> + std::fmt::Display>(
+        &self,
+        body: String,
+        raw_auth_header: Option<A>,
+        given_auth_verdict: Option<AuthVerdict>,
+        last_event_id: Option<&str>,
+    ) -> DianaStreamResponse {
+        let verdict = match given_auth_verdict {
+            Some(verdict) => verdict,
+            None => self.is_authed(raw_auth_header),
+        };
+        match verdict {
+            AuthVerdict::Allow(auth_data) => {
+                let gql_req = match serde_json::from_str::<Request>(&body) {
+                    Ok(gql_req) => gql_req,
+                    Err(err) => return DianaStreamResponse::Error(err.to_string()),
+                };
+                // The channel helpers keep this up to date with where the client has got to in each channel
+                let cursor = Arc::new(SubscriptionCursor::new(last_event_id));
+                let gql_req = gql_req.data(auth_data).data(Arc::clone(&cursor));
+                let responses = self.schema_for_subscriptions.execute_stream(gql_req);
+
+                DianaStreamResponse::Success(Box::pin(get_sse_stream(responses, cursor)))
+            }
+            AuthVerdict::Block => DianaStreamResponse::Blocked,
+            AuthVerdict::Error(err) => DianaStreamResponse::Error(err),
+        }
+    }
+    /// Runs a subscriptions WebSocket given the subprotocol negotiated with the client (see [`WsProtocol::negotiate`]), the value of the
+    /// HTTP `Authorization` header, and a stream of the text frames the client sends. This returns a stream of what should be sent back to the
+    /// client, which ends after a [`DianaWsMessage::Close`] or once the client's frames stop. The integration should close the WebSocket
+    /// with the given code and reason when it gets a `Close`, and dropping the returned stream drops all the client's subscriptions.
+    /// Both the graphql-transport-ws and the legacy subscriptions-transport-ws protocols are handled here, including their keep-alives and
+    /// close codes. Clients are authenticated as in
+    /// [`.get_auth_state_for_subscriptions()`](DianaHandler::get_auth_state_for_subscriptions) when they initialise the connection.
+    /// This function is for the subscriptions system only.
+    pub fn run_ws_for_subscriptions<A: Into<String> + std::fmt::Display>(
+        &self,
+        protocol: WsProtocol,
+        raw_auth_header: Option<A>,
+        incoming: impl Stream<Item = String> + Send + Unpin + 'static,
+    ) -> impl Stream<Item = DianaWsMessage> + Send + 'static {
+        let auth_header = raw_auth_header.map(|x| x.to_string());
+        let jwt_secret = self.opts.jwt_secret.clone();
+        run_ws_session(
+            self.schema_for_subscriptions.clone(),
+            protocol,
+            Box::new(move |connection_payload| {
+                get_auth_state_from_connection(auth_header, connection_payload, jwt_secret)
+            }),
+            self.opts.pubsub_config.ws_keep_alive_interval,
+            incoming,
+        )
+    }
     // This is used internally to provide query/mutation running functionality to the systems for/without subscriptions
     // It is exposed to make testing easier, though users should not use it!
     #[doc(hidden)]
@@ -160,12 +342,38 @@ where
                     Ok(gql_req) => gql_req,
                     Err(err) => return DianaResponse::Error(err.to_string()),
                 };
-                // Insert the authentication data directly into that
-                gql_req = gql_req.data(auth_data);
+                // Insert the authentication data directly into that (the buffered publisher below needs it too)
+                gql_req = gql_req.data(auth_data.clone());
                 // Run the request with the correct schema
                 let res = match which_schema {
                     SysSchema::WithoutSubscriptions => {
-                        self.schema_without_subscriptions.execute(gql_req).await
+                        // Integrations don't have to start the outbox themselves, so anything left in it is delivered from here at the latest
+                        if let Some(publisher) = &self.publisher {
+                            if let Err(err) = publisher.start_outbox().await {
+                                return DianaResponse::Error(err.to_string());
+                            }
+                        }
+                        // Anything published through this is only sent if the request succeeds
+                        let request_id = Uuid::new_v4().to_string();
+                        let buffered_publisher = self
+                            .publisher
+                            .clone()
+                            .map(|publisher| BufferedPublisher::new(publisher, &auth_data, &request_id));
+                        if let Some(buffered_publisher) = &buffered_publisher {
+                            gql_req = gql_req.data(buffered_publisher.clone());
+                        }
+                        let mut res = self.schema_without_subscriptions.execute(gql_req).await;
+                        // If the request failed, the buffered publisher is just dropped with everything in it
+                        if let Some(buffered_publisher) = buffered_publisher {
+                            if res.errors.is_empty() {
+                                // The request has already done whatever it was going to do, so we can only tell the client that
+                                // publishing failed
+                                if let Err(err) = buffered_publisher.flush().await {
+                                    res.errors.push(ServerError::new(err.to_string(), None));
+                                }
+                            }
+                        }
+                        res
                     }
                     SysSchema::ForSubscriptions => {
                         self.schema_for_subscriptions.execute(gql_req).await
@@ -185,3 +393,19 @@ where
         }
     }
 }
+
+// Gets the authentication state for a subscriptions connection from the HTTP `Authorization` header, or from the connection's initialization
+// payload if there wasn't one
+fn get_auth_state_from_connection(
+    auth_header: Option<String>,
+    connection_payload: &serde_json::Value,
+    jwt_secret: String,
+) -> Result<AuthState> {
+    let auth_header = auth_header.or_else(|| {
+        ["Authorization", "authorization"]
+            .iter()
+            .find_map(|key| connection_payload.get(key)?.as_str())
+            .map(|auth_header| auth_header.to_string())
+    });
+    get_token_state_from_header(auth_header.as_deref(), jwt_secret)
+}
@@ -0,0 +1,90 @@
+// This module defines the envelope that travels with every message, from the publisher all the way through to subscribers
+// Messages themselves are just strings, so this is where everything about where a message came from lives
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::auth::auth_state::{AuthState, AuthToken};
+use crate::auth::jwt::Claims;
+use crate::payload::Encoding;
+
+/// Metadata about a published message, which is carried with it from the publisher to every subscriber. Subscriptions can read this from
+/// the `envelope` field of a [`ChannelMessage`](crate::ChannelMessage) (see
+/// [`get_event_stream_for_channel_from_ctx`](crate::graphql_utils::get_event_stream_for_channel_from_ctx)).
+/// Messages published with a [`BufferedPublisher`](crate::BufferedPublisher) get the claims and request ID of the request that published
+/// them automatically, and you can set all of this yourself with [`Publisher::publish_with_envelope`](crate::Publisher::publish_with_envelope).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageEnvelope {
+    /// A unique ID for the message. This stays the same if the message is delivered more than once (e.g. from an outbox after a restart),
+    /// so subscribers can use it to recognise messages they've already seen.
+    pub id: String,
+    /// When the message was published. This can be earlier than when the subscriptions server received it if the message was waiting in an
+    /// outbox.
+    pub sent_at: DateTime<Utc>,
+    /// The claims of the user whose request published the message, if they were authenticated.
+    pub origin: Option<HashMap<String, String>>,
+    /// The format of the message's data (e.g. `application/json`), if the publisher said. Typed channels set this for you.
+    pub content_type: Option<String>,
+    /// Whether or not the message's data is binary, in which case it's base64-encoded so it can be published as a string. You can get the
+    /// original bytes back with [`ChannelMessage::bytes`](crate::ChannelMessage::bytes).
+    #[serde(default)]
+    pub binary: bool,
+    /// The ID of the request that published the message, if it was published from one.
+    pub request_id: Option<String>,
+}
+impl Default for MessageEnvelope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl MessageEnvelope {
+    /// Creates a new envelope with a fresh ID, timestamped now.
+    pub fn new() -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            sent_at: Utc::now(),
+            origin: None,
+            content_type: None,
+            binary: false,
+            request_id: None,
+        }
+    }
+    /// Sets the origin of the message to the user with the given authentication state. If they weren't authenticated, there's no origin.
+    pub fn with_origin(mut self, auth_state: &AuthState) -> Self {
+        self.origin = match auth_state {
+            AuthState::Authorised(AuthToken(Claims { claims, .. })) => Some(claims.clone()),
+            _ => None,
+        };
+        self
+    }
+    /// Sets the format of the message's data.
+    pub fn with_content_type(mut self, content_type: &str) -> Self {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+    // Sets the content type of the message to that of the given encoding
+    pub(crate) fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.content_type = Some(encoding.content_type().to_string());
+        self.binary = encoding.is_binary();
+        self
+    }
+    /// Sets the ID of the request that published the message.
+    pub fn with_request_id(mut self, request_id: &str) -> Self {
+        self.request_id = Some(request_id.to_string());
+        self
+    }
+    /// Gets the `sub` claim of the user whose request published the message, which usually identifies them.
+    pub fn origin_sub(&self) -> Option<&str> {
+        self.origin.as_ref()?.get("sub").map(|sub| sub.as_str())
+    }
+    // Gets a copy of this envelope for another message, which needs its own ID and timestamp
+    pub(crate) fn renew(&self) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            sent_at: Utc::now(),
+            ..self.clone()
+        }
+    }
+}
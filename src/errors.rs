@@ -1,6 +1,7 @@
 #![allow(missing_docs)]
 
 use thiserror::Error;
+use std::time::Duration;
 pub use anyhow::{Result, bail};
 
 // TODO fix the integration errors
@@ -25,6 +26,47 @@ pub enum DianaError {
 	#[error("failed to publish data to the subscriptions server, this is most likely due to an authentication failure")]
     SubscriptionDataPublishFailed,
 	
+    /// A client tried to subscribe to a channel that the rules set up with `.allow_subscribing()` on
+    /// [`OptionsBuilder`](crate::OptionsBuilder) don't let it.
+	#[error("not authorised to subscribe to channel '{0}'")]
+    ChannelSubscriptionUnauthorised(String),
+	
+    /// Data couldn't be serialized to be published on a typed channel.
+	#[error("couldn't serialize data to publish on channel '{0}': {1}")]
+    ChannelPayloadSerializationFailed(String, String),
+	
+    /// Data received on a typed channel wasn't of the type the channel carries. This usually means something other than a
+    /// [`Channel`](crate::Channel) was used to publish it.
+	#[error("couldn't deserialize data received on channel '{0}': {1}")]
+    ChannelPayloadDeserializationFailed(String, String),
+	
+    /// Something tried to publish on a channel the subscriptions server uses itself (like
+    /// [`PRESENCE_CHANNEL`](crate::PRESENCE_CHANNEL)).
+	#[error("channel '{0}' is reserved for the subscriptions server's own use")]
+    ReservedChannel(String),
+	
+    /// Something tried to publish on a channel whose name contains `*` or `?`, which are only for subscribing to channel patterns.
+	#[error("channel '{0}' is named like a pattern, only subscriptions can use '*' and '?' in channel names")]
+    InvalidChannelName(String),
+	
+    /// A message was scheduled to be published at a time that couldn't be parsed (it should be in RFC 3339 format).
+	#[error("invalid time '{0}' for scheduled message: {1}")]
+    InvalidScheduleTime(String, String),
+	
+    /// A scheduled message couldn't be persisted (see `.schedule_dir()` on [`OptionsBuilder`](crate::OptionsBuilder)), so it won't be
+    /// published.
+	#[error("couldn't persist scheduled message: {0}")]
+    ScheduledMessageNotPersisted(String),
+	
+    /// A webhook was registered or unregistered, but the subscriptions server doesn't have anywhere to keep them (see `.webhook_registry()`
+    /// on [`OptionsBuilder`](crate::OptionsBuilder)).
+	#[error("webhooks aren't enabled on this subscriptions server")]
+    WebhooksNotConfigured,
+	
+    /// A webhook was registered with a URL messages can't be sent to.
+	#[error("invalid webhook url '{0}': {1}")]
+    InvalidWebhookUrl(String, String),
+	
     /// An invalid indicator string was used when trying to convert a timestring into a datetime.
 	#[error("invalid indicator '{0}' in timestring, must be one of: s, m, h, d, w, M, y")]
     InvalidDatetimeIntervalIndicator(String),
@@ -36,6 +78,21 @@ pub enum DianaError {
 	/// One or more required builder fields weren't set up.
 	#[error("some required builder fields haven't been instantiated")]
 	IncompleteBuilderFields,
+
+	/// A builder field was given a value that can't be used.
+	#[error("invalid value for option '{0}': {1}")]
+	InvalidOption(String, String),
+	
+    /// The subscriptions server already has as many channels as it's allowed (see `.max_channels()` on
+    /// [`OptionsBuilder`](crate::OptionsBuilder)), so a new one couldn't be created.
+	#[error("couldn't create a new channel, the subscriptions server already has the maximum of {0} channels")]
+    TooManyChannels(usize),
+	
+    /// A message couldn't be relayed to the other replicas of the subscriptions server (see `.redis_url()` on
+    /// [`OptionsBuilder`](crate::OptionsBuilder)), so it wasn't published. This is usually because the relay has lost its connection, and
+    /// publishing will work again once it's reconnected.
+	#[error("couldn't relay message to other subscriptions servers: {0}")]
+    RelayUnavailable(String),
 	
     /// The creation of an HTTP response for Lambda or its derivatives failed.
 	#[error("the builder for an http response (netlify_lambda_http) returned an error")]
@@ -72,6 +129,59 @@ pub enum DianaError {
     Unknown,
 }
 
+// The publisher has its own errors so that callers can tell what went wrong and decide what to do about it
+#[derive(Error, Debug)]
+pub enum PublishError {
+    /// The subscriptions server couldn't be reached (e.g. the connection was refused).
+	#[error("couldn't reach the subscriptions server")]
+    Transport(#[source] ::reqwest::Error),
+
+    /// The subscriptions server couldn't be reached over its Unix socket (e.g. nothing is listening on it).
+	#[error("couldn't reach the subscriptions server over its socket")]
+    Socket(#[source] ::std::io::Error),
+
+    /// The subscriptions server took longer to respond than the configured timeout.
+	#[error("the subscriptions server didn't respond in time")]
+    Timeout,
+
+    /// The subscriptions server responded with an unsuccessful HTTP status.
+	#[error("the subscriptions server responded with status {status}: {body}")]
+    Status {
+        /// The HTTP status code of the response.
+        status: u16,
+        /// The body of the response.
+        body: String,
+    },
+
+    /// The subscriptions server responded, but didn't acknowledge the published data. This is usually caused by an authentication failure.
+	#[error("the subscriptions server didn't acknowledge the published data, this is most likely due to an authentication failure: {body}")]
+    NotAcknowledged {
+        /// The body of the response.
+        body: String,
+    },
+
+    /// Too many publishes have failed in a row, so the publisher isn't trying the subscriptions server again until its cooldown is over.
+    /// Once it is, only one publish is let through until it's known whether the subscriptions server has recovered.
+	#[error("the subscriptions server is probably unavailable, not publishing for another {retry_after:?}")]
+    CircuitOpen {
+        /// How long until publishing will be tried again. This is zero if another publish is already checking whether the subscriptions
+        /// server has recovered.
+        retry_after: Duration,
+    },
+
+    /// The message couldn't be recorded in the outbox.
+	#[error("couldn't record the message in the outbox")]
+    Outbox(#[source] ::std::io::Error),
+
+    /// A message was scheduled for a time the subscriptions server can't handle, which happens if it's after the end of the year 9999.
+	#[error("can't schedule a message that far in the future")]
+    ScheduleTooFar,
+
+    /// Data couldn't be serialized to be published on a typed channel.
+	#[error("couldn't serialize data to publish on channel '{0}': {1}")]
+    Serialization(String, String),
+}
+
 /// A wrapper around [`async_graphql::Result<T>`](async_graphql::Result).
 /// You should use this as the return type for any of your own schemas that might return errors.
 /// # Example
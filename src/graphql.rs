@@ -1,11 +1,24 @@
-use async_graphql::{EmptySubscription, Object as GQLObject, ObjectType, Schema, SubscriptionType};
+use async_graphql::{
+    EmptySubscription, InputObject as GQLInputObject, Json, Object as GQLObject, ObjectType, Schema, SimpleObject as GQLSimpleObject,
+    SubscriptionType,
+};
 use anyhow::{Result, bail};
+use chrono::{DateTime, Utc};
 use std::any::Any;
-use std::sync::Mutex;
+use uuid::Uuid;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::graphql_utils::{get_auth_data_from_ctx, get_pubsub_from_ctx};
+use crate::channel_auth::ChannelAuthRules;
+use crate::envelope::MessageEnvelope;
+use crate::graphql_utils::{check_channel_allowed_from_ctx, get_auth_data_from_ctx, get_pubsub_from_ctx};
 use crate::is_authed;
-use crate::pubsub::{PubSub, Publisher};
+use crate::presence::{can_see_presence_change, PRESENCE_CHANNEL};
+use crate::publisher::Publisher;
+use crate::pubsub::{ChannelMessage, PubSub, StartFrom};
+use crate::sse::SubscriptionCursor;
+use crate::webhooks::Webhook;
 
 use crate::errors::DianaError;
 
@@ -18,6 +31,66 @@ impl SubscriptionQuery {
     async fn _query(&self) -> String {
         "This is a meaningless endpoint needed only for initialisation.".to_string()
     }
+    // This gets the messages on a channel (or channel pattern) since the given cursor, for clients that poll rather than subscribing (e.g.
+    // because the only part of the system they can reach is serverless), waiting up to `wait_ms` milliseconds for some if there aren't any
+    // Without `wait_ms`, this returns straight away
+    // Clients without a cursor start from now, so they should poll once without waiting to get one
+    async fn poll(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+        channel: String,
+        cursor: Option<String>,
+        wait_ms: Option<u64>,
+    ) -> Result<PollResponse> {
+        // Publishers poll on behalf of clients they've checked themselves, everyone else has to be allowed to subscribe to the channel
+        // They can also only see presence changes on the channels they could subscribe to themselves
+        let auth_state = get_auth_data_from_ctx(raw_ctx)?;
+        let mut presence_rules = None;
+        if !is_authed!(
+            auth_state,
+            {
+                "role" => "graphql_server"
+            }
+        ) {
+            check_channel_allowed_from_ctx(&channel, raw_ctx)?;
+            if channel == PRESENCE_CHANNEL.name() {
+                presence_rules = raw_ctx.data_opt::<ChannelAuthRules>().cloned();
+            }
+        }
+        let auth_state = auth_state.clone();
+        let pubsub = get_pubsub_from_ctx(raw_ctx)?;
+        // Anything published from now on will be picked up by the next poll if this one doesn't get it
+        let polled_at = Utc::now();
+        let cursor = Arc::new(SubscriptionCursor::new(cursor.as_deref()));
+        let start_from = cursor
+            .start_from(&channel)
+            .unwrap_or(StartFrom::Timestamp(polled_at));
+        let accepts_cursor = Arc::clone(&cursor);
+        let polled_channel = channel.clone();
+        let (messages, missed) = pubsub
+            .poll(&channel, &start_from, Duration::from_millis(wait_ms.unwrap_or(0)), move |message| {
+                let visible = match &presence_rules {
+                    Some(rules) => can_see_presence_change(message, rules, &auth_state),
+                    None => true,
+                };
+                visible && accepts_cursor.advance(&polled_channel, message)
+            })
+            .await?;
+
+        Ok(PollResponse {
+            messages: Json(messages.iter().map(|message| (**message).clone()).collect()),
+            missed,
+            cursor: cursor.id_from(polled_at),
+        })
+    }
+}
+
+// The result of polling a channel
+#[derive(GQLSimpleObject)]
+pub struct PollResponse {
+    messages: Json<Vec<ChannelMessage>>,
+    missed: u64,
+    cursor: String,
 }
 
 // This mutation type is utilised by the subscriptions server to allow the publishing of data
@@ -30,32 +103,163 @@ impl PublishMutation {
     // That may seem to subvert some of the purpose of GraphQL, but this resolver is to be INTERNALLY ONLY!
     // That provides a system-level data integrity guarantee, as only full mutations will call this, and through a PubSub abstraction
     // There should be very little reason for users to implement it themselves, but this type could easily be extended with custom logic
+    // This returns the number of subscribers the message was sent to, so the publisher can tell if anyone was listening
     async fn publish(
         &self,
         raw_ctx: &async_graphql::Context<'_>,
         channel: String,
         data: String,
-    ) -> Result<bool> {
+        // Anything that doesn't come from a Diana publisher won't have an envelope, so it gets a new one
+        envelope: Option<Json<MessageEnvelope>>,
+    ) -> Result<usize> {
+        if is_authed!(
+            get_auth_data_from_ctx(raw_ctx)?,
+            {
+                "role" => "graphql_server"
+            }
+        ) {
+            let pubsub = get_pubsub_from_ctx(raw_ctx)?;
+            let envelope = envelope.map(|envelope| envelope.0).unwrap_or_default();
+            let subscribers = pubsub.publish(&channel, data, envelope).await?;
+            Ok(subscribers)
+        } else {
+            bail!(DianaError::Unauthorised)
+        }
+    }
+    // This publishes many messages in one go, which saves a round trip for each one when the publisher has a lot to send
+    // They're published in order, all together or not at all, so nothing from elsewhere is published in between them
+    // This returns the number of subscribers each message was sent to, in the same order
+    async fn publish_many(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+        messages: Vec<PublishInput>,
+    ) -> Result<Vec<usize>> {
         if is_authed!(
             get_auth_data_from_ctx(raw_ctx)?,
             {
                 "role" => "graphql_server"
             }
         ) {
-            let mut pubsub = get_pubsub_from_ctx(raw_ctx)?;
-            pubsub.publish(&channel, data);
-            Ok(true)
+            let pubsub = get_pubsub_from_ctx(raw_ctx)?;
+            let subscribers = pubsub.publish_many(
+                messages
+                    .into_iter()
+                    .map(|message| {
+                        let envelope = message.envelope.map(|envelope| envelope.0).unwrap_or_default();
+                        (message.channel, message.data, envelope)
+                    })
+                    .collect(),
+            )
+            .await?;
+            Ok(subscribers)
         } else {
             bail!(DianaError::Unauthorised)
         }
     }
+    // This schedules a message to be published later, returning its ID so it can be cancelled
+    // The time should be in RFC 3339 format, and anything in the past will be published straight away
+    // Publishers can choose the ID themselves, in which case scheduling the same ID again replaces the earlier message
+    async fn schedule_publish(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+        id: Option<String>,
+        channel: String,
+        data: String,
+        at: String,
+        envelope: Option<Json<MessageEnvelope>>,
+    ) -> Result<String> {
+        if is_authed!(
+            get_auth_data_from_ctx(raw_ctx)?,
+            {
+                "role" => "graphql_server"
+            }
+        ) {
+            let at = DateTime::parse_from_rfc3339(&at)
+                .map_err(|err| DianaError::InvalidScheduleTime(at.clone(), err.to_string()))?
+                .with_timezone(&Utc);
+            let id = id.unwrap_or_else(|| Uuid::new_v4().to_string());
+            let pubsub = get_pubsub_from_ctx(raw_ctx)?;
+            let envelope = envelope.map(|envelope| envelope.0).unwrap_or_default();
+            pubsub.schedule(id.clone(), &channel, data, envelope, at).await?;
+            Ok(id)
+        } else {
+            bail!(DianaError::Unauthorised)
+        }
+    }
+    // This cancels a scheduled message, returning whether or not there was one with the given ID that hadn't been published yet
+    async fn cancel_scheduled(&self, raw_ctx: &async_graphql::Context<'_>, id: String) -> Result<bool> {
+        if is_authed!(
+            get_auth_data_from_ctx(raw_ctx)?,
+            {
+                "role" => "graphql_server"
+            }
+        ) {
+            let pubsub = get_pubsub_from_ctx(raw_ctx)?;
+            let cancelled = pubsub.cancel_scheduled(&id).await?;
+            Ok(cancelled)
+        } else {
+            bail!(DianaError::Unauthorised)
+        }
+    }
+    // This registers a webhook that every message on the given channel (or channel pattern) will be POSTed to, returning its ID
+    // Publishers can choose the ID themselves, in which case registering the same ID again replaces the earlier webhook
+    async fn register_webhook(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+        id: Option<String>,
+        url: String,
+        channel: String,
+        secret: String,
+    ) -> Result<String> {
+        if is_authed!(
+            get_auth_data_from_ctx(raw_ctx)?,
+            {
+                "role" => "graphql_server"
+            }
+        ) {
+            let mut webhook = Webhook::new(&url, &channel, &secret);
+            if let Some(id) = id {
+                webhook.id = id;
+            }
+            let id = webhook.id.clone();
+            let pubsub = get_pubsub_from_ctx(raw_ctx)?;
+            pubsub.register_webhook(webhook).await?;
+            Ok(id)
+        } else {
+            bail!(DianaError::Unauthorised)
+        }
+    }
+    // This unregisters a webhook, returning whether or not there was one with the given ID
+    async fn unregister_webhook(&self, raw_ctx: &async_graphql::Context<'_>, id: String) -> Result<bool> {
+        if is_authed!(
+            get_auth_data_from_ctx(raw_ctx)?,
+            {
+                "role" => "graphql_server"
+            }
+        ) {
+            let pubsub = get_pubsub_from_ctx(raw_ctx)?;
+            let unregistered = pubsub.unregister_webhook(&id).await?;
+            Ok(unregistered)
+        } else {
+            bail!(DianaError::Unauthorised)
+        }
+    }
+}
+
+// A single message to be published as part of a batch
+#[derive(GQLInputObject)]
+pub struct PublishInput {
+    channel: String,
+    data: String,
+    envelope: Option<Json<MessageEnvelope>>,
 }
 
 // Information about the subscriptions server for the rest of the system
 #[derive(Clone)]
 pub struct SubscriptionsServerInformation {
-    pub hostname: String,
+    pub hostname: String, // This and the port aren't used if there's a socket
     pub port: String, // It'll be mixed in to create a URL, may as well start as a string
+    pub socket: Option<PathBuf>, // A Unix socket to reach the subscriptions server on instead of over TCP
     pub endpoint: String,
     pub jwt_to_connect: String, // This should be signed with the secret the subscriptions server knows
 }
@@ -75,7 +279,7 @@ where
 
 pub fn get_schema_without_subscriptions<C, Q, M, S>(
     user_schema: UserSchema<Q, M, S>,
-    subscription_server_info: Option<SubscriptionsServerInformation>,
+    publisher: Option<Publisher>,
     user_ctx: C,
 ) -> Result<Schema<Q, M, EmptySubscription>>
 where
@@ -94,15 +298,9 @@ where
     .data(user_ctx);
 
     // Conditionally extend that schema with a publisher if we're using a subscriptions server
-    let schema = match subscription_server_info {
-        Some(subscription_server_info) => schema
-            .data(Publisher::new(
-                subscription_server_info.hostname,
-                subscription_server_info.port,
-                subscription_server_info.endpoint,
-                subscription_server_info.jwt_to_connect,
-            )?)
-            .finish(),
+    // A buffered publisher is also inserted into each request by the handler
+    let schema = match publisher {
+        Some(publisher) => schema.data(publisher).finish(),
         None => schema.finish(),
     };
 
@@ -110,6 +308,8 @@ where
 }
 pub fn get_schema_for_subscriptions<C, Q, M, S>(
     user_schema: UserSchema<Q, M, S>,
+    pubsub: Arc<PubSub>,
+    channel_auth_rules: ChannelAuthRules,
     user_ctx: C,
 ) -> Schema<SubscriptionQuery, PublishMutation, S>
 where
@@ -127,7 +327,8 @@ where
     )
     // We add some custom user-defined context (e.g. a database connection pool)
     .data(user_ctx)
-    // We add a mutable PubSub instance for managing subscriptions internally
-    .data(Mutex::new(PubSub::default())) // We add a PubSub instance to internally manage state in the serverful subscriptions system
+    .data(pubsub) // We add a PubSub instance to internally manage state in the serverful subscriptions system
+    // We add the rules for who can subscribe to what, which are checked whenever a subscription gets a stream for a channel
+    .data(channel_auth_rules)
     .finish()
 }
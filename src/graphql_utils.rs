@@ -1,10 +1,15 @@
 // Utility functions for GraphQL resolvers
-use std::sync::{Mutex, MutexGuard};
-use tokio_stream::Stream;
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
+use tokio_stream::{Stream, StreamExt};
 use anyhow::{Result, bail};
 
 use crate::auth::auth_state::AuthState;
-use crate::pubsub::PubSub;
+use crate::channel::Channel;
+use crate::channel_auth::ChannelAuthRules;
+use crate::presence::{can_see_presence_change, ChannelPresence, PRESENCE_CHANNEL};
+use crate::pubsub::{ChannelEvent, ChannelMessage, MessageFilter, PubSub, StartFrom, SubscribeOptions};
+use crate::sse::SubscriptionCursor;
 
 use crate::errors::DianaError;
 
@@ -130,6 +135,10 @@ macro_rules! is_authed(
 /// **This must only be used in subscriptions! It will not work anywhere else!**
 /// This returns a pre-created stream which you should manipulate if necessary.
 /// All data sent via the publisher from the queries/mutations system will land here **in string format**. Serialization is up to you.
+/// The channel can also be a pattern, in which case you'll get messages from every matching channel. Channel names are split into segments
+/// by dots, and in a pattern `*` matches anything within one segment, `**` matches anything at all, and `?` matches any single character
+/// other than a dot. For example, `order.*` matches `order.123`, but not `order.123.shipped` (which `order.**` would). If you need to know
+/// which channel each message was published on, use [`get_event_stream_for_channel_from_ctx`].
 /// # Example
 /// ```
 /// use diana::{
@@ -175,10 +184,331 @@ pub fn get_stream_for_channel_from_ctx(
     channel: &str,
     raw_ctx: &async_graphql::Context<'_>,
 ) -> Result<impl Stream<Item = String>> {
-    // Get the PubSub mutably
-    let mut pubsub = get_pubsub_from_ctx(raw_ctx)?;
-    // Return a stream on the given channel
-    Ok(pubsub.subscribe(channel))
+    let event_stream = get_event_stream_for_channel_from_ctx(channel, raw_ctx)?;
+    Ok(get_data_stream(event_stream))
+}
+
+/// Gets a subscription stream to data published on a particular channel that passes the given filter from the context of a GraphQL
+/// resolver. This is the same as [`get_stream_for_channel_from_ctx`], except that the filter is given each message and the authentication
+/// state of the subscriber, and anything it returns `false` for is dropped on the server, before it's even copied into the subscriber's
+/// stream. This is much cheaper than filtering messages yourself when most of those on a channel aren't relevant to each subscriber.
+/// **This must only be used in subscriptions! It will not work anywhere else!**
+/// # Example
+/// ```
+/// use diana::{
+///     graphql_utils::get_filtered_stream_for_channel_from_ctx,
+///     errors::Result,
+///     async_graphql::Subscription as GQLSubscription,
+/// };
+/// use tokio_stream::Stream;
+///
+/// #[derive(Default, Clone)]
+/// pub struct Subscription;
+/// #[GQLSubscription]
+/// impl Subscription {
+///     async fn order_updates(
+///         &self,
+///         raw_ctx: &async_graphql::Context<'_>,
+///         order_id: String,
+///     ) -> Result<impl Stream<Item = String>> {
+///         // Only send the client updates about the order it's interested in
+///         get_filtered_stream_for_channel_from_ctx(
+///             "order_updates",
+///             move |message, _auth_state| {
+///                 serde_json::from_str::<serde_json::Value>(&message.data)
+///                     .map(|update| update["orderId"] == order_id.as_str())
+///                     .unwrap_or(false)
+///             },
+///             raw_ctx,
+///         )
+///     }
+/// }
+/// # fn main() {}
+/// ```
+pub fn get_filtered_stream_for_channel_from_ctx<F>(
+    channel: &str,
+    filter: F,
+    raw_ctx: &async_graphql::Context<'_>,
+) -> Result<impl Stream<Item = String>>
+where
+    F: Fn(&ChannelMessage, &AuthState) -> bool + Send + Sync + 'static,
+{
+    let opts = SubscribeOptions {
+        filter: Some(MessageFilter::new(filter)),
+        ..Default::default()
+    };
+    let event_stream = get_event_stream_for_channel_from_ctx_with_options(channel, opts, raw_ctx)?;
+    Ok(get_data_stream(event_stream))
+}
+
+// Turns a stream of events into a stream of the data in each message
+// Lag notices are dropped here, you'll need the event stream if you want those
+fn get_data_stream(event_stream: impl Stream<Item = ChannelEvent>) -> impl Stream<Item = String> {
+    event_stream.filter_map(|event| match event {
+        ChannelEvent::Message(message) => Some(message.data),
+        ChannelEvent::Lagged(_) => None,
+    })
+}
+
+/// Gets a subscription stream to data published on a particular typed channel from the context of a GraphQL resolver. This is the same as
+/// [`get_stream_for_channel_from_ctx`], except that everything received is deserialized into the channel's type for you. If something
+/// arrives that can't be deserialized (which can only happen if it wasn't published with the same [`Channel`]), the stream will yield an
+/// error for it. Note that `async_graphql` ends a subscription after its first error, so you should filter these out yourself if you'd
+/// rather skip them.
+/// **This must only be used in subscriptions! It will not work anywhere else!**
+/// # Example
+/// ```
+/// use diana::{
+///     graphql_utils::get_typed_stream_for_channel_from_ctx,
+///     errors::{GQLResult, Result},
+///     async_graphql::{Subscription as GQLSubscription, SimpleObject as GQLSimpleObject},
+///     Channel,
+/// };
+/// use tokio_stream::Stream;
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Serialize, Deserialize, GQLSimpleObject)]
+/// struct User {
+///     username: String
+/// }
+///
+/// const NEW_USER: Channel<User> = Channel::new("new_user");
+///
+/// #[derive(Default, Clone)]
+/// pub struct Subscription;
+/// #[GQLSubscription]
+/// impl Subscription {
+///     async fn new_users(
+///         &self,
+///         raw_ctx: &async_graphql::Context<'_>,
+///     ) -> Result<impl Stream<Item = GQLResult<User>>> {
+///         get_typed_stream_for_channel_from_ctx(&NEW_USER, raw_ctx)
+///     }
+/// }
+/// # fn main() {}
+/// ```
+pub fn get_typed_stream_for_channel_from_ctx<T: DeserializeOwned>(
+    channel: &Channel<T>,
+    raw_ctx: &async_graphql::Context<'_>,
+) -> Result<impl Stream<Item = async_graphql::Result<T>>> {
+    let channel = *channel;
+    let stream = get_stream_for_channel_from_ctx(channel.name(), raw_ctx)?;
+    Ok(stream.map(move |data| {
+        channel
+            .deserialize(&data)
+            .map_err(|err| async_graphql::Error::new(err.to_string()))
+    }))
+}
+
+/// Gets a stream of everything that happens on a particular channel from the context of a GraphQL resolver. This is the same as
+/// [`get_stream_for_channel_from_ctx`], except that it yields [`ChannelEvent`]s, which carry the sequence number, publishing time, and
+/// channel of each message, and which let you see when messages have been dropped because the subscriber fell behind (if the
+/// [`LagPolicy`](crate::LagPolicy) is `Notify`). If you want to start from an earlier point in the channel's history, use
+/// [`get_event_stream_for_channel_from_ctx_with_options`].
+/// **This must only be used in subscriptions! It will not work anywhere else!**
+/// # Example
+/// ```
+/// use diana::{
+///     stream,
+///     graphql_utils::get_event_stream_for_channel_from_ctx,
+///     errors::GQLResult,
+///     async_graphql::Subscription as GQLSubscription,
+///     ChannelEvent,
+/// };
+/// use tokio_stream::{Stream, StreamExt};
+///
+/// #[derive(Default, Clone)]
+/// pub struct Subscription;
+/// #[GQLSubscription]
+/// impl Subscription {
+///     async fn new_users(
+///         &self,
+///         raw_ctx: &async_graphql::Context<'_>,
+///     ) -> impl Stream<Item = GQLResult<String>> {
+///         let stream_result = get_event_stream_for_channel_from_ctx("new_user", raw_ctx);
+///
+///         stream! {
+///             let stream = stream_result?;
+///             for await event in stream {
+///                 match event {
+///                     ChannelEvent::Message(message) => yield Ok(message.data),
+///                     // Tell the client it should refetch everything
+///                     ChannelEvent::Lagged(missed) => yield Err(format!("missed {} messages", missed).into()),
+///                 }
+///             }
+///         }
+///     }
+/// }
+/// # fn main() {}
+/// ```
+pub fn get_event_stream_for_channel_from_ctx(
+    channel: &str,
+    raw_ctx: &async_graphql::Context<'_>,
+) -> Result<impl Stream<Item = ChannelEvent>> {
+    get_event_stream_for_channel_from_ctx_with_options(channel, SubscribeOptions::default(), raw_ctx)
+}
+
+/// Gets a stream of everything that happens on a particular channel from the context of a GraphQL resolver, subscribing with the given
+/// [`SubscribeOptions`]. This is the same as [`get_event_stream_for_channel_from_ctx`], except that you can start from an earlier point
+/// in the channel's history, which is useful for clients that are reconnecting, and filter messages on the server. Like
+/// [`get_stream_for_channel_from_ctx`], this accepts channel patterns, and each message carries the name of the channel it was actually
+/// published on. Clients subscribed over Server-Sent Events automatically carry on from where they were when they reconnect, unless you've
+/// set [`SubscribeOptions::start_from`] yourself.
+/// **This must only be used in subscriptions! It will not work anywhere else!**
+/// # Example
+/// ```
+/// use diana::{
+///     stream,
+///     graphql_utils::get_event_stream_for_channel_from_ctx_with_options,
+///     errors::GQLResult,
+///     async_graphql::Subscription as GQLSubscription,
+///     ChannelEvent, StartFrom, SubscribeOptions,
+/// };
+/// use tokio_stream::{Stream, StreamExt};
+///
+/// #[derive(Default, Clone)]
+/// pub struct Subscription;
+/// #[GQLSubscription]
+/// impl Subscription {
+///     async fn new_users(
+///         &self,
+///         raw_ctx: &async_graphql::Context<'_>,
+///         // The client can tell us the sequence number of the last message it saw if it's reconnecting
+///         last_seen: Option<u64>,
+///     ) -> impl Stream<Item = GQLResult<String>> {
+///         let opts = SubscribeOptions {
+///             start_from: match last_seen {
+///                 Some(seq) => StartFrom::Sequence(seq + 1),
+///                 None => StartFrom::Now,
+///             },
+///             ..Default::default()
+///         };
+///         let stream_result = get_event_stream_for_channel_from_ctx_with_options("new_user", opts, raw_ctx);
+///
+///         stream! {
+///             let stream = stream_result?;
+///             for await event in stream {
+///                 match event {
+///                     ChannelEvent::Message(message) => yield Ok(message.data),
+///                     // Tell the client it should refetch everything
+///                     ChannelEvent::Lagged(missed) => yield Err(format!("missed {} messages", missed).into()),
+///                 }
+///             }
+///         }
+///     }
+/// }
+/// # fn main() {}
+/// ```
+pub fn get_event_stream_for_channel_from_ctx_with_options(
+    channel: &str,
+    opts: SubscribeOptions,
+    raw_ctx: &async_graphql::Context<'_>,
+) -> Result<impl Stream<Item = ChannelEvent>> {
+    let auth_state = check_channel_allowed_from_ctx(channel, raw_ctx)?;
+    let pubsub = get_pubsub_from_ctx(raw_ctx)?;
+    // Clients subscribed over SSE carry on from where they were when they reconnect, unless the resolver has chosen where to start itself
+    // The presence channel has no history to carry on from
+    let cursor = raw_ctx
+        .data_opt::<Arc<SubscriptionCursor>>()
+        .filter(|_| channel != PRESENCE_CHANNEL.name())
+        .cloned();
+    let mut opts = opts;
+    if let (Some(cursor), StartFrom::Now) = (&cursor, &opts.start_from) {
+        if let Some(start_from) = cursor.start_from(channel) {
+            opts.start_from = start_from;
+        }
+    }
+    // Clients can only see presence changes on the channels they could subscribe to themselves
+    let presence_rules = raw_ctx
+        .data_opt::<ChannelAuthRules>()
+        .filter(|_| channel == PRESENCE_CHANNEL.name())
+        .cloned();
+    // Return a stream on the given channel, any filter will need to know who the subscriber is
+    let event_stream = pubsub.subscribe(channel, opts, auth_state.clone())?;
+    let auth_state = auth_state.clone();
+    let channel = channel.to_string();
+    Ok(event_stream.filter(move |event| match event {
+        ChannelEvent::Message(message) => {
+            let visible = match &presence_rules {
+                Some(rules) => can_see_presence_change(message, rules, &auth_state),
+                None => true,
+            };
+            match &cursor {
+                Some(cursor) if visible => cursor.advance(&channel, message),
+                _ => visible,
+            }
+        }
+        ChannelEvent::Lagged(_) => true,
+    }))
+}
+
+/// Gets everyone currently subscribed to a particular channel from the context of a GraphQL resolver, which is useful for showing things like
+/// "5 people are viewing this document". Subscribers to a channel pattern are listed under the pattern itself, not each channel it matches.
+/// Clients can only see who is subscribed to the channels they could subscribe to themselves (see `.allow_subscribing()` on
+/// [`OptionsBuilder`](crate::OptionsBuilder)). If you want to know when this changes, subscribe to [`PRESENCE_CHANNEL`](crate::PRESENCE_CHANNEL).
+/// **This must only be used on the subscriptions server! It will not work anywhere else!**
+/// # Example
+/// ```
+/// use diana::{
+///     stream,
+///     graphql_utils::{get_presence_for_channel_from_ctx, get_typed_stream_for_channel_from_ctx},
+///     errors::GQLResult,
+///     async_graphql::Subscription as GQLSubscription,
+///     PRESENCE_CHANNEL,
+/// };
+/// use tokio_stream::{Stream, StreamExt};
+///
+/// #[derive(Default, Clone)]
+/// pub struct Subscription;
+/// #[GQLSubscription]
+/// impl Subscription {
+///     // Yields the number of people viewing a document now and whenever that changes
+///     async fn viewers(
+///         &self,
+///         raw_ctx: &async_graphql::Context<'_>,
+///         document_id: String,
+///     ) -> impl Stream<Item = GQLResult<usize>> {
+///         let channel = format!("document.{}", document_id);
+///         let changes_result = get_typed_stream_for_channel_from_ctx(&PRESENCE_CHANNEL, raw_ctx);
+///         let presence_result = get_presence_for_channel_from_ctx(&channel, raw_ctx);
+///
+///         stream! {
+///             let changes = changes_result?;
+///             yield Ok(presence_result?.count());
+///             for await event in changes {
+///                 let event = event?;
+///                 if event.channel == channel {
+///                     yield Ok(event.count);
+///                 }
+///             }
+///         }
+///     }
+/// }
+/// # fn main() {}
+/// ```
+pub fn get_presence_for_channel_from_ctx(
+    channel: &str,
+    raw_ctx: &async_graphql::Context<'_>,
+) -> Result<ChannelPresence> {
+    check_channel_allowed_from_ctx(channel, raw_ctx)?;
+    let pubsub = get_pubsub_from_ctx(raw_ctx)?;
+    Ok(pubsub.presence(channel))
+}
+
+// Checks the client whose request this is can use the given channel, returning its authentication state if it can
+pub(crate) fn check_channel_allowed_from_ctx<'a>(
+    channel: &str,
+    raw_ctx: &'a async_graphql::Context<'_>,
+) -> Result<&'a AuthState> {
+    // Connections that weren't given any authentication data are treated as not having a token
+    let auth_state = raw_ctx.data::<AuthState>().unwrap_or(&AuthState::NoToken);
+    if let Ok(channel_auth_rules) = raw_ctx.data::<ChannelAuthRules>() {
+        if !channel_auth_rules.allows(auth_state, channel) {
+            bail!(DianaError::ChannelSubscriptionUnauthorised(channel.to_string()));
+        }
+    }
+
+    Ok(auth_state)
 }
 
 /// Gets authentication data from the context of a GraphQL resolver.
@@ -197,14 +527,13 @@ pub fn get_auth_data_from_ctx<'a>(
 #[doc(hidden)]
 pub fn get_pubsub_from_ctx<'a>(
     raw_ctx: &'a async_graphql::Context<'_>,
-) -> Result<MutexGuard<'a, PubSub>> {
-    // We store the PubSub instance as a Mutex because we need it sent/synced between threads as a mutable
-    let pubsub_mutex = raw_ctx
-        .data::<Mutex<PubSub>>()
+) -> Result<&'a Arc<PubSub>> {
+    // The PubSub manages its own locking, so it can be shared between every connection without one being able to block the others
+    // It's behind an Arc so that relays to other systems can deliver messages to it too
+    let pubsub = raw_ctx
+        .data::<Arc<PubSub>>()
         .map_err(|_err| DianaError::GraphQLContextNotFound("pubsub".to_string()))?;
-    let pubsub = pubsub_mutex
-        .lock()
-        .map_err(|_err| DianaError::MutexPoisoned("pubsub".to_string()))?;
+    pubsub.start_relays(pubsub);
 
     Ok(pubsub)
 }
@@ -0,0 +1,50 @@
+// This module lets the queries/mutations system publish straight to a subscriptions server running in the same process, with no HTTP or JWT
+// Both systems are built from clones of the same `Options`, which share the slot the subscriptions server puts itself in
+
+use async_graphql::{EmptySubscription, Request, Schema, Variables};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::auth::auth_state::{AuthState, AuthToken};
+use crate::auth::jwt::Claims;
+use crate::graphql::{PublishMutation, SubscriptionQuery};
+use crate::pubsub::PubSub;
+
+// The parts of the subscriptions server's schema that publishers use, run against its PubSub
+type LocalSchema = Schema<SubscriptionQuery, PublishMutation, EmptySubscription>;
+
+// The subscriptions server running in this process, if there is one, clones share it
+#[derive(Clone, Default)]
+pub struct LocalSubscriptionsServer {
+    schema: Arc<RwLock<Option<LocalSchema>>>,
+}
+impl LocalSubscriptionsServer {
+    // Makes the given PubSub the one that publishers sharing this will send to
+    pub fn serve(&self, pubsub: Arc<PubSub>) {
+        // Anything in the same process is trusted just as much as something with the token to connect, so it gets the same role
+        let mut claims = HashMap::new();
+        claims.insert("role".to_string(), "graphql_server".to_string());
+        let auth_state = AuthState::Authorised(AuthToken(Claims { exp: u64::MAX, claims }));
+        let schema = Schema::build(SubscriptionQuery, PublishMutation, EmptySubscription)
+            .data(pubsub)
+            .data(auth_state)
+            .finish();
+        *self.schema.write().unwrap_or_else(|err| err.into_inner()) = Some(schema);
+    }
+
+    // Checks whether or not there's a subscriptions server in this process to publish to
+    pub fn is_serving(&self) -> bool {
+        self.schema.read().unwrap_or_else(|err| err.into_inner()).is_some()
+    }
+
+    // Runs the given query or mutation on the subscriptions server in this process, returning the serialized response just as it would come
+    // back over HTTP, or `None` if there isn't a subscriptions server here
+    pub async fn execute(&self, query: &str, variables: &serde_json::Value) -> Option<String> {
+        // The lock can't be held across the request, but the schema is cheap to clone
+        let schema = self.schema.read().unwrap_or_else(|err| err.into_inner()).clone()?;
+        let req = Request::new(query).variables(Variables::from_json(variables.clone()));
+        let res = schema.execute(req).await;
+        // We know more than the compiler here, responses will always serialize
+        Some(serde_json::to_string(&res).unwrap())
+    }
+}
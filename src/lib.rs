@@ -20,7 +20,13 @@ detailed explanations and tutorials can be found in the [book](https://arctic-he
 */
 
 mod auth;
+mod background;
+mod channel;
+mod channel_auth;
+mod channel_history;
+mod channel_pattern;
 mod diana_handler;
+mod envelope;
 /// The module for errors and results. This uses [error_chain] behind the scenes.
 /// You'll also find [`GQLResult`](crate::errors::GQLResult) and [`GQLError`](crate::errors::Error) in here, which may be useful in working
 /// with your own resolvers.
@@ -28,8 +34,21 @@ pub mod errors;
 mod graphql;
 /// The module for utility functions for schema development.
 pub mod graphql_utils;
+mod in_process;
 mod options;
+mod outbox;
+mod presence;
+mod payload;
+#[cfg(feature = "postgres")]
+mod postgres_listener;
+mod publisher;
 mod pubsub;
+#[cfg(feature = "redis")]
+mod redis_relay;
+mod scheduler;
+mod sse;
+mod webhooks;
+mod websocket;
 
 #[macro_use]
 extern crate anyhow;
@@ -37,12 +56,28 @@ extern crate anyhow;
 // Public exports accessible from the root (everything the user will need)
 pub use crate::auth::auth_state::{AuthState, AuthToken};
 pub use crate::auth::core::{AuthBlockLevel, AuthVerdict};
+pub use crate::channel::Channel;
+pub use crate::channel_auth::ChannelAuthRules;
 pub use crate::auth::jwt::{
     create_jwt, decode_time_str, get_jwt_secret, validate_and_decode_jwt, Claims, JWTSecret,
 };
-pub use crate::diana_handler::{DianaHandler, DianaResponse, SysSchema};
+pub use crate::diana_handler::{DianaHandler, DianaResponse, DianaStreamResponse, SysSchema};
+pub use crate::envelope::MessageEnvelope;
 pub use crate::options::{Options, OptionsBuilder};
-pub use crate::pubsub::Publisher;
+pub use crate::outbox::RejectedMessage;
+pub use crate::payload::Encoding;
+pub use crate::presence::{
+    ChannelPresence, ChannelSubscriber, PresenceChange, PresenceEvent, PRESENCE_CHANNEL,
+};
+pub use crate::publisher::{BufferedPublisher, PollResult, Publisher, PublisherConfig};
+pub use crate::pubsub::{
+    ChannelEvent, ChannelMessage, LagPolicy, MessageFilter, PubSubMetrics, StartFrom, SubscribeOptions,
+};
+pub use crate::webhooks::{
+    verify_webhook_signature, FileWebhookRegistry, MemoryWebhookRegistry, Webhook, WebhookRegistry, WEBHOOK_ID_HEADER,
+    WEBHOOK_SIGNATURE_HEADER, WEBHOOK_TIMESTAMP_HEADER, WEBHOOK_TIMESTAMP_TOLERANCE,
+};
+pub use crate::websocket::{DianaWsMessage, WsProtocol, WS_PROTOCOLS};
 
 // Users shouldn't have to install `async_graphql` themselves for basic usage
 #[doc(no_inline)]
@@ -0,0 +1,230 @@
+// This module defines the durable outbox that the publisher can record messages in rather than sending them straight away
+// Messages are appended to a file, and a separate file records how far through that delivery has got, so nothing is lost if the
+// subscriptions server is down or the process restarts
+// Everything here blocks on the disk, so it must only ever be used from a thread that's allowed to block
+// Only one outbox can be open in a directory at once (even across processes), two workers delivering from the same files would deliver
+// messages more than once, and could empty the outbox just after the other recorded something in it
+
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+use crate::envelope::MessageEnvelope;
+
+const MESSAGES_FILENAME: &str = "outbox.jsonl";
+const OFFSET_FILENAME: &str = "outbox.offset";
+const REJECTED_FILENAME: &str = "outbox.rejected.jsonl";
+const LOCK_FILENAME: &str = "outbox.lock";
+
+// A message waiting to be published
+// Messages are recorded in batches, each of which is delivered in one request
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub channel: String,
+    pub data: String,
+    // Entries recorded by older versions won't have one, so they're given a new one when they're read
+    #[serde(default)]
+    pub envelope: MessageEnvelope,
+}
+
+// A line in the outbox, which is a batch of entries, or a single one if it was recorded by an older version
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OutboxLine {
+    Batch(Vec<OutboxEntry>),
+    Single(OutboxEntry),
+}
+impl From<OutboxLine> for Vec<OutboxEntry> {
+    fn from(line: OutboxLine) -> Self {
+        match line {
+            OutboxLine::Batch(batch) => batch,
+            OutboxLine::Single(entry) => vec![entry],
+        }
+    }
+}
+
+/// A message that was recorded in the publisher's outbox, but that the subscriptions server then rejected outright (e.g. because the
+/// publisher's JWT is invalid), so it was never delivered. You can get these with
+/// [`Publisher::take_rejected`](crate::Publisher::take_rejected).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RejectedMessage {
+    /// The channel the message was published on.
+    pub channel: String,
+    /// The data that was published.
+    pub data: String,
+    /// The message's envelope.
+    pub envelope: MessageEnvelope,
+    /// Why the subscriptions server rejected it.
+    pub reason: String,
+}
+
+pub struct Outbox {
+    messages_path: PathBuf,
+    offset_path: PathBuf,
+    rejected_path: PathBuf,
+    // This holds an exclusive lock on the directory for as long as the outbox is open, which the OS releases if the process dies
+    _lock_file: File,
+    // Everything that touches the files goes through this, so the worker never sees a half-written message
+    lock: Mutex<()>,
+    // This is notified whenever a message is recorded, so the worker doesn't have to poll
+    pub recorded: Notify,
+}
+impl Outbox {
+    // Opens the outbox in the given directory, creating it if it doesn't exist
+    // Anything left over from a previous run will be delivered
+    // This fails if another outbox is already open in the same directory, in this process or any other
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let lock_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(dir.join(LOCK_FILENAME))?;
+        lock_file.try_lock_exclusive().map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "the outbox in '{}' is already being used by another publisher: {}",
+                    dir.display(),
+                    err
+                ),
+            )
+        })?;
+        let outbox = Self {
+            messages_path: dir.join(MESSAGES_FILENAME),
+            offset_path: dir.join(OFFSET_FILENAME),
+            rejected_path: dir.join(REJECTED_FILENAME),
+            _lock_file: lock_file,
+            lock: Mutex::new(()),
+            recorded: Notify::new(),
+        };
+        // If we crashed in the middle of recording a message, it was never acknowledged, so we can safely drop it
+        let messages = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&outbox.messages_path)?;
+        let contents = fs::read(&outbox.messages_path)?;
+        let complete_len = contents
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map(|idx| idx + 1)
+            .unwrap_or(0);
+        if complete_len != contents.len() {
+            messages.set_len(complete_len as u64)?;
+        }
+
+        Ok(outbox)
+    }
+
+    // Durably records a batch of messages, only returning once it's actually on disk
+    pub fn record(&self, batch: &[OutboxEntry]) -> io::Result<()> {
+        // We know more than the compiler here, this will always serialize
+        let line = serde_json::to_string(batch).unwrap() + "\n";
+        {
+            let _guard = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+            let mut messages = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.messages_path)?;
+            messages.write_all(line.as_bytes())?;
+            messages.sync_data()?;
+        }
+        self.recorded.notify_one();
+
+        Ok(())
+    }
+
+    // Gets the oldest batch that hasn't been delivered yet, along with the offset to mark as delivered once it has been
+    // Batches that can't be parsed are returned as `None` with their offset, so they can be skipped
+    pub fn next(&self) -> io::Result<Option<(Option<Vec<OutboxEntry>>, u64)>> {
+        let _guard = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        let offset = self.read_offset()?;
+        let mut messages = BufReader::new(File::open(&self.messages_path)?);
+        messages.seek(SeekFrom::Start(offset))?;
+        let mut line = String::new();
+        let read = messages.read_line(&mut line)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        let batch = serde_json::from_str::<OutboxLine>(&line).ok().map(Vec::from);
+
+        Ok(Some((batch, offset + read as u64)))
+    }
+
+    // Marks everything before the given offset as delivered
+    // Once everything has been delivered, the outbox is emptied so it doesn't grow forever
+    pub fn mark_delivered(&self, offset: u64) -> io::Result<()> {
+        let _guard = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        let messages = OpenOptions::new().write(true).open(&self.messages_path)?;
+        if messages.metadata()?.len() == offset {
+            // The offset is reset first, so that crashing in between means redelivering messages rather than skipping new ones
+            self.write_offset(0)?;
+            messages.set_len(0)?;
+            messages.sync_data()
+        } else {
+            self.write_offset(offset)
+        }
+    }
+
+    // Durably records a batch the subscriptions server rejected outright, so the publisher can hand it back rather than it being lost
+    pub fn reject(&self, batch: Vec<OutboxEntry>, reason: &str) -> io::Result<()> {
+        let lines = batch
+            .into_iter()
+            .map(|OutboxEntry { channel, data, envelope }| RejectedMessage {
+                channel,
+                data,
+                envelope,
+                reason: reason.to_string(),
+            })
+            // We know more than the compiler here, this will always serialize
+            .map(|message| serde_json::to_string(&message).unwrap() + "\n")
+            .collect::<String>();
+        let _guard = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        let mut rejected = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.rejected_path)?;
+        rejected.write_all(lines.as_bytes())?;
+        rejected.sync_data()
+    }
+
+    // Takes every rejected message out of the outbox, oldest first
+    // Anything that can't be parsed was only half-written, and so was never rejected as far as anyone knows
+    pub fn take_rejected(&self) -> io::Result<Vec<RejectedMessage>> {
+        let _guard = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        let contents = match fs::read_to_string(&self.rejected_path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        fs::remove_file(&self.rejected_path)?;
+
+        Ok(contents
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    fn read_offset(&self) -> io::Result<u64> {
+        match fs::read_to_string(&self.offset_path) {
+            // A corrupted offset would mean redelivering everything, which is better than losing anything
+            Ok(offset) => Ok(offset.trim().parse().unwrap_or(0)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+
+    // The offset is written to a temporary file and then moved into place, so it's never half-written
+    fn write_offset(&self, offset: u64) -> io::Result<()> {
+        let tmp_path = self.offset_path.with_extension("offset.tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(offset.to_string().as_bytes())?;
+        tmp.sync_data()?;
+        fs::rename(tmp_path, &self.offset_path)
+    }
+}
@@ -0,0 +1,62 @@
+// This module defines how data is encoded for publishing, which matters because messages always travel as strings
+// Binary encodings (like MessagePack) are base64-encoded so they can pass through GraphQL and the subscriptions server unchanged
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// The ways data published on a typed [`Channel`](crate::Channel) can be encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// JSON, which is readable by anything but slow and large for big structured payloads. This is what [`Channel::new`](crate::Channel::new)
+    /// uses.
+    Json,
+    /// MessagePack, a compact binary format that's much faster to encode and decode for large payloads.
+    MessagePack,
+}
+impl Encoding {
+    /// Gets the content type that messages in this encoding are published with (see
+    /// [`MessageEnvelope`](crate::MessageEnvelope)).
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::MessagePack => "application/msgpack",
+        }
+    }
+    /// Whether or not this encoding is binary, in which case it's base64-encoded to be published.
+    pub fn is_binary(&self) -> bool {
+        matches!(self, Self::MessagePack)
+    }
+    // Works out the encoding of a message from its content type, anything we don't recognise is assumed to be JSON
+    pub(crate) fn from_content_type(content_type: Option<&str>) -> Self {
+        match content_type {
+            Some(content_type) if content_type == Self::MessagePack.content_type() => Self::MessagePack,
+            _ => Self::Json,
+        }
+    }
+
+    // Encodes the given payload into a string that can be published
+    pub(crate) fn encode<T: Serialize>(&self, payload: &T) -> Result<String, String> {
+        match self {
+            Self::Json => serde_json::to_string(payload).map_err(|err| err.to_string()),
+            Self::MessagePack => rmp_serde::to_vec_named(payload)
+                .map(|bytes| encode_bytes(&bytes))
+                .map_err(|err| err.to_string()),
+        }
+    }
+    // Decodes a published string back into a payload
+    pub(crate) fn decode<T: DeserializeOwned>(&self, data: &str) -> Result<T, String> {
+        match self {
+            Self::Json => serde_json::from_str(data).map_err(|err| err.to_string()),
+            Self::MessagePack => rmp_serde::from_slice(&decode_bytes(data)?).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+// Encodes binary data so it can be published as a string
+pub(crate) fn encode_bytes(bytes: &[u8]) -> String {
+    base64::encode(bytes)
+}
+
+// Decodes binary data that was published as a string
+pub(crate) fn decode_bytes(data: &str) -> Result<Vec<u8>, String> {
+    base64::decode(data).map_err(|err| err.to_string())
+}
@@ -0,0 +1,94 @@
+// This module forwards Postgres notifications into Diana channels
+// That means anything that can run `NOTIFY` (migrations, batch jobs, triggers, etc.) can feed subscriptions without going through Diana
+
+use chrono::Utc;
+use futures::{stream, StreamExt};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio_postgres::{AsyncMessage, NoTls};
+
+use crate::background::spawn_background;
+use crate::envelope::MessageEnvelope;
+use crate::pubsub::PubSub;
+
+// How long to wait before trying to reconnect to Postgres after the connection fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// Starts listening on the given Postgres channels, which are mapped to the Diana channels their notifications will be delivered on
+pub fn start_postgres_listener(
+    postgres_url: String,
+    channels: HashMap<String, String>,
+    pubsub: &Arc<PubSub>,
+) {
+    spawn_background(deliver_from_postgres(
+        postgres_url,
+        channels,
+        Arc::clone(pubsub),
+    ));
+}
+
+// Listens to Postgres and delivers notifications to local subscribers, reconnecting as necessary
+async fn deliver_from_postgres(
+    postgres_url: String,
+    channels: HashMap<String, String>,
+    pubsub: Arc<PubSub>,
+) {
+    loop {
+        // This will only return if the connection fails in some way
+        let _ = listen_to_postgres(&postgres_url, &channels, &pubsub).await;
+        // If the PubSub has been dropped everywhere else, nobody's listening anymore
+        if Arc::strong_count(&pubsub) == 1 {
+            return;
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn listen_to_postgres(
+    postgres_url: &str,
+    channels: &HashMap<String, String>,
+    pubsub: &Arc<PubSub>,
+) -> Result<(), tokio_postgres::Error> {
+    let (client, mut connection) = tokio_postgres::connect(postgres_url, NoTls).await?;
+
+    // Notifications arrive through the connection, which also has to be driven for the client to work at all
+    // When the connection ends, this drops the sender, which ends the loop below
+    let (notification_tx, mut notification_rx) = unbounded_channel();
+    spawn_background(async move {
+        let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(Ok(message)) = messages.next().await {
+            if let AsyncMessage::Notification(notification) = message {
+                if notification_tx.send(notification).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    // Channel names are identifiers, so they have to be quoted in case they contain anything unusual
+    let listen_query = channels
+        .keys()
+        .map(|channel| format!("LISTEN \"{}\";", channel.replace('"', "\"\"")))
+        .collect::<String>();
+    client.batch_execute(&listen_query).await?;
+
+    while let Some(notification) = notification_rx.recv().await {
+        let diana_channel = match channels.get(notification.channel()) {
+            Some(diana_channel) => diana_channel,
+            None => continue,
+        };
+        // This isn't allowed with Redis (see `.finish()` in `options.rs`), so there's nothing to relay the message to
+        // If there's no room for the channel, the notification has nowhere to go, so it's dropped
+        let _ = pubsub.deliver(
+            diana_channel,
+            notification.payload().to_string(),
+            MessageEnvelope::new(),
+            Utc::now(),
+        )
+        .await;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,201 @@
+// This module keeps track of who is subscribed to which channels on the subscriptions server
+// Subscribers are added when they subscribe and removed when their stream is dropped, and every change is announced on a system channel
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{channel as create_channel, Receiver, Sender};
+use uuid::Uuid;
+
+use crate::auth::auth_state::{AuthState, AuthToken};
+use crate::auth::jwt::Claims;
+use crate::channel::Channel;
+use crate::channel_auth::ChannelAuthRules;
+use crate::channel_history::ChannelMessage;
+use crate::envelope::MessageEnvelope;
+
+/// The system channel that every change in who is subscribed to what is announced on. You can subscribe to this like any other typed channel
+/// (e.g. with [`get_typed_stream_for_channel_from_ctx`](crate::graphql_utils::get_typed_stream_for_channel_from_ctx)), but nothing can be
+/// published on it. Subscribers to this channel aren't tracked themselves, and they only get changes on the channels they could subscribe to
+/// themselves (see `.allow_subscribing()` on [`OptionsBuilder`](crate::OptionsBuilder)).
+pub const PRESENCE_CHANNEL: Channel<PresenceEvent> = Channel::new("diana:presence");
+
+/// A single subscriber to a channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelSubscriber {
+    /// A unique ID for this subscription. The same client will have a different one for each subscription it makes.
+    pub id: String,
+    /// The `sub` claim of the subscriber's token, if they're authenticated and have one. This usually identifies the user.
+    pub sub: Option<String>,
+    /// When the subscriber subscribed.
+    pub subscribed_at: DateTime<Utc>,
+}
+
+/// Everyone currently subscribed to a channel. You can get this from
+/// [`get_presence_for_channel_from_ctx`](crate::graphql_utils::get_presence_for_channel_from_ctx).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelPresence {
+    /// The channel (or channel pattern, for those subscribed to a pattern).
+    pub channel: String,
+    /// The subscribers, in the order they subscribed.
+    pub subscribers: Vec<ChannelSubscriber>,
+}
+impl ChannelPresence {
+    /// Gets the number of subscribers.
+    pub fn count(&self) -> usize {
+        self.subscribers.len()
+    }
+}
+
+/// Whether a subscriber joined or left a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceChange {
+    /// The subscriber has just subscribed.
+    Joined,
+    /// The subscriber's subscription has ended.
+    Left,
+}
+
+/// A change in who is subscribed to a channel, which is published on [`PRESENCE_CHANNEL`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresenceEvent {
+    /// The channel (or channel pattern) the change happened on.
+    pub channel: String,
+    /// Whether the subscriber joined or left.
+    pub change: PresenceChange,
+    /// The subscriber that joined or left.
+    pub subscriber: ChannelSubscriber,
+    /// The number of subscribers to the channel after the change.
+    pub count: usize,
+}
+
+// The tracker for every subscriber on the subscriptions server, this is shared with every subscriber's stream so they can leave when dropped
+pub struct Presence {
+    subscribers: Mutex<HashMap<String, Vec<ChannelSubscriber>>>,
+    // The broadcaster for the presence channel, which doesn't live with the other channels because it can't be published on
+    sender: Sender<Arc<ChannelMessage>>,
+    next_seq: AtomicU64,
+}
+impl Presence {
+    pub fn new(buffer_size: usize) -> Self {
+        Self {
+            subscribers: Mutex::new(HashMap::new()),
+            sender: create_channel(buffer_size).0,
+            next_seq: AtomicU64::new(0),
+        }
+    }
+
+    // Subscribes to the presence channel
+    pub fn subscribe(&self) -> Receiver<Arc<ChannelMessage>> {
+        self.sender.subscribe()
+    }
+
+    // Records a new subscriber with the given authentication state on the given channel
+    // They'll be removed when the returned guard is dropped
+    pub fn join(self: &Arc<Self>, channel: &str, auth_state: &AuthState) -> PresenceGuard {
+        let subscriber = ChannelSubscriber {
+            id: Uuid::new_v4().to_string(),
+            sub: match auth_state {
+                AuthState::Authorised(AuthToken(Claims { claims, .. })) => claims.get("sub").cloned(),
+                _ => None,
+            },
+            subscribed_at: Utc::now(),
+        };
+        let count = {
+            let mut subscribers = self.lock();
+            let channel_subscribers = subscribers.entry(channel.to_string()).or_default();
+            channel_subscribers.push(subscriber.clone());
+            channel_subscribers.len()
+        };
+        let guard = PresenceGuard {
+            presence: Arc::clone(self),
+            channel: channel.to_string(),
+            id: subscriber.id.clone(),
+        };
+        self.announce(PresenceEvent {
+            channel: channel.to_string(),
+            change: PresenceChange::Joined,
+            subscriber,
+            count,
+        });
+
+        guard
+    }
+
+    fn leave(&self, channel: &str, id: &str) {
+        let (subscriber, count) = {
+            let mut subscribers = self.lock();
+            let channel_subscribers = match subscribers.get_mut(channel) {
+                Some(channel_subscribers) => channel_subscribers,
+                None => return,
+            };
+            let subscriber = match channel_subscribers.iter().position(|subscriber| subscriber.id == id) {
+                Some(idx) => channel_subscribers.remove(idx),
+                None => return,
+            };
+            let count = channel_subscribers.len();
+            if count == 0 {
+                subscribers.remove(channel);
+            }
+            (subscriber, count)
+        };
+        self.announce(PresenceEvent {
+            channel: channel.to_string(),
+            change: PresenceChange::Left,
+            subscriber,
+            count,
+        });
+    }
+
+    // Gets everyone currently subscribed to the given channel
+    pub fn get(&self, channel: &str) -> ChannelPresence {
+        ChannelPresence {
+            channel: channel.to_string(),
+            subscribers: self.lock().get(channel).cloned().unwrap_or_default(),
+        }
+    }
+
+    // Publishes the given event on the presence channel
+    fn announce(&self, event: PresenceEvent) {
+        // We know more than the compiler here, this will always serialize
+        let data = PRESENCE_CHANNEL.serialize(&event).unwrap();
+        let message = ChannelMessage {
+            channel: PRESENCE_CHANNEL.name().to_string(),
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+            published_at: Utc::now(),
+            data,
+            envelope: MessageEnvelope::new().with_encoding(PRESENCE_CHANNEL.encoding()),
+        };
+        // This will fail only if there are no receivers, but we don't care if that's the case
+        let _ = self.sender.send(Arc::new(message));
+    }
+
+    // A poisoned tracker just means a panic happened while updating some lists, they're still usable
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Vec<ChannelSubscriber>>> {
+        self.subscribers.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+// Checks whether a client with the given authentication state can see the given message from the presence channel, which it can only do if
+// it could subscribe to the channel the change happened on
+pub fn can_see_presence_change(message: &ChannelMessage, rules: &ChannelAuthRules, auth_state: &AuthState) -> bool {
+    match PRESENCE_CHANNEL.deserialize(&message.data) {
+        Ok(event) => rules.allows(auth_state, &event.channel),
+        Err(_) => false,
+    }
+}
+
+// A subscriber's place in the presence tracker, which they give up when this is dropped (along with their stream)
+pub struct PresenceGuard {
+    presence: Arc<Presence>,
+    channel: String,
+    id: String,
+}
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        self.presence.leave(&self.channel, &self.id);
+    }
+}
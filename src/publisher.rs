@@ -0,0 +1,1158 @@
+// This module defines the publisher, which sends data from the queries/mutations system to the subscriptions server
+// It has to cope with the subscriptions server being slow or unavailable without stalling every mutation that publishes something
+
+use chrono::{DateTime, Datelike, Utc};
+use once_cell::sync::OnceCell;
+use reqwest::{Client, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use uuid::Uuid;
+
+use crate::background::{run_blocking_in_background, run_in_background, spawn_background};
+use crate::auth::auth_state::AuthState;
+use crate::channel::Channel as TypedChannel;
+use crate::channel_history::ChannelMessage;
+use crate::envelope::MessageEnvelope;
+use crate::errors::PublishError;
+use crate::in_process::LocalSubscriptionsServer;
+use crate::outbox::{Outbox, OutboxEntry, RejectedMessage};
+use crate::payload::encode_bytes;
+
+// How often the outbox worker checks if the publisher it's working for still exists when there's nothing to deliver
+const OUTBOX_IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// How much of the publisher's timeout a poll leaves for the subscriptions server to respond once it's done waiting
+const POLL_RESPONSE_MARGIN: Duration = Duration::from_secs(1);
+// The last year messages can be scheduled in, because the subscriptions server only accepts four-digit years
+const MAX_SCHEDULE_YEAR: i32 = 9999;
+
+/// The messages published on a channel since a client last polled it, from [`Publisher::poll`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct PollResult {
+    /// The messages published since the cursor the client gave, oldest first.
+    pub messages: Vec<ChannelMessage>,
+    /// The number of messages published since the cursor that are no longer in the channel's history. If this isn't 0, the client has
+    /// missed something and should refetch whatever it was keeping up to date.
+    pub missed: u64,
+    /// Where the client has got to, which should be given to its next poll.
+    pub cursor: String,
+}
+
+#[derive(Serialize)]
+struct GQLQueryBody<T: Serialize> {
+    query: String,
+    variables: T,
+}
+
+// Every mutation on the subscriptions server is the only field in its response
+#[derive(Deserialize)]
+struct GQLResponse<T> {
+    data: HashMap<String, T>,
+}
+// The number of subscribers each message was sent to, which depends on which mutation we used
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GQLPublishResult {
+    Single(usize),
+    Many(Vec<usize>),
+}
+
+/// Configuration for how the [`Publisher`] copes with a slow or unavailable subscriptions server. You'll usually set this up through
+/// the methods on [`OptionsBuilder`](crate::OptionsBuilder) rather than directly.
+#[derive(Debug, Clone)]
+pub struct PublisherConfig {
+    /// How long to wait for the subscriptions server to respond to each attempt at publishing. This defaults to 10 seconds.
+    pub timeout: Duration,
+    /// How many times to retry publishing after the first attempt fails. Only failures that might be temporary (the subscriptions server
+    /// being unreachable, timing out, or responding with a 5xx or 429 status) are retried. This defaults to 2.
+    pub retries: u32,
+    /// How long to wait before the first retry. This is doubled for each retry after that. This defaults to 100 milliseconds.
+    pub retry_backoff: Duration,
+    /// The longest to ever wait between retries, however many there have been. This defaults to 5 seconds.
+    pub max_retry_backoff: Duration,
+    /// How many publishes in a row have to fail (after retrying) before the circuit breaker opens. While it's open, publishing fails
+    /// immediately with [`PublishError::CircuitOpen`] rather than waiting on a subscriptions server that's probably down. Setting this to 0
+    /// disables the circuit breaker. This defaults to 5.
+    pub circuit_breaker_threshold: u32,
+    /// How long the circuit breaker stays open before a single publish is let through to check if the subscriptions server has recovered.
+    /// Any other publishes while that one is being sent still fail with [`PublishError::CircuitOpen`], and if it fails too, the circuit
+    /// breaker opens again for another cooldown. This defaults to 30 seconds.
+    pub circuit_breaker_cooldown: Duration,
+    /// A directory for a durable outbox that messages will be recorded in rather than being sent straight away. If this is set, publishing
+    /// returns as soon as the message is safely on disk, and a background worker delivers everything in the outbox in order, retrying for
+    /// as long as the subscriptions server is unavailable. Messages will be delivered at least once, even if the process restarts. Only
+    /// one publisher can use an outbox directory at once (even across processes), so each instance of the queries/mutations system needs
+    /// its own. By default, there's no outbox.
+    pub outbox_dir: Option<PathBuf>,
+}
+impl Default for PublisherConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            retries: 2,
+            retry_backoff: Duration::from_millis(100),
+            max_retry_backoff: Duration::from_secs(5),
+            circuit_breaker_threshold: 5,
+            circuit_breaker_cooldown: Duration::from_secs(30),
+            outbox_dir: None,
+        }
+    }
+}
+
+// How the publisher reaches the subscriptions server
+#[derive(Clone)]
+enum Transport {
+    // Over TCP, with `reqwest`
+    Http { client: Client, address: String },
+    // Over a Unix socket, which `reqwest` can't do, so this keeps its own timeout
+    Socket {
+        path: PathBuf,
+        endpoint: String,
+        timeout: Duration,
+    },
+}
+
+// The state of the circuit breaker, which is shared between every publish
+#[derive(Default)]
+struct CircuitBreaker {
+    consecutive_failures: u32,
+    // If this is set, the circuit is open until then
+    open_until: Option<Instant>,
+    // Whether a publish has been let through after the cooldown to check if the subscriptions server has recovered (i.e. the circuit is
+    // half-open), in which case nothing else is until we know how it went
+    probing: bool,
+}
+
+// A publish that's been let through a half-open circuit breaker, which lets another through if it's dropped before its outcome is recorded
+// (e.g. because the future publishing it was cancelled), otherwise the circuit would never close again
+struct CircuitProbe {
+    circuit_breaker: Arc<Mutex<CircuitBreaker>>,
+}
+impl Drop for CircuitProbe {
+    fn drop(&mut self) {
+        self.circuit_breaker
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .probing = false;
+    }
+}
+
+/// The system that publishes data from the queries/mutations system to the subscriptions server.
+/// These communications are secured by a JWT specified in [`Options`](crate::Options), unless the subscriptions server is running in the
+/// same process and was built from the same [`Options`](crate::Options), in which case messages are published to it directly.
+/// This is automatically created from the [`Options`](crate::Options) and passed to all resolvers. You should never need to manually create it.
+/// Messages published with this are sent straight away, even if the mutation publishing them goes on to fail. You'll usually want
+/// [`BufferedPublisher`] instead.
+// Clones share their circuit breaker and outbox
+#[derive(Clone)]
+pub struct Publisher {
+    transport: Transport,
+    token: String,
+    config: PublisherConfig,
+    circuit_breaker: Arc<Mutex<CircuitBreaker>>,
+    // This is only opened (which starts delivering from it) once it's needed, see `.open_outbox()`
+    outbox: Arc<OnceCell<Arc<Outbox>>>,
+    // The subscriptions server in this process, which is published to directly instead if it's there
+    local: LocalSubscriptionsServer,
+}
+impl Publisher {
+    /// Creates a new publisher. This is done for you when you create the queries/mutations system, so you should never need to call this.
+    pub fn new(hostname: String, port: String, endpoint: String, token: String) -> Result<Self> {
+        Self::with_config(hostname, port, endpoint, token, PublisherConfig::default())
+    }
+
+    /// Creates a new publisher with the given configuration for timeouts, retries, and circuit breaking. This is done for you when you
+    /// create the queries/mutations system, so you should never need to call this.
+    pub fn with_config(
+        hostname: String,
+        port: String,
+        endpoint: String,
+        token: String,
+        config: PublisherConfig,
+    ) -> Result<Self> {
+        let publisher = Self::over_http(hostname, port, endpoint, token, config)?;
+        // If there's an outbox, we start delivering from it straight away, there may be messages left over from a previous run
+        publisher.start_outbox_now()?;
+        Ok(publisher)
+    }
+
+    /// Creates a new publisher that reaches the subscriptions server over the Unix socket at the given path rather than over TCP. This is
+    /// done for you when you create the queries/mutations system with a socket for the subscriptions server, so you should never need
+    /// to call this.
+    pub fn with_socket(socket: PathBuf, endpoint: String, token: String, config: PublisherConfig) -> Result<Self> {
+        let publisher = Self::over_socket(socket, endpoint, token, config)?;
+        publisher.start_outbox_now()?;
+        Ok(publisher)
+    }
+
+    // These create publishers without opening their outboxes, the handler only does that for the queries/mutations system (see
+    // `DianaHandler::start_outbox`), the subscriptions server must never deliver from the same outbox
+    pub(crate) fn over_http(
+        hostname: String,
+        port: String,
+        endpoint: String,
+        token: String,
+        config: PublisherConfig,
+    ) -> Result<Self> {
+        let address = format!(
+            "{hostname}:{port}{endpoint}", // The endpoint should start with '/'
+            hostname = hostname,
+            port = port,
+            endpoint = endpoint
+        );
+
+        let client = Client::builder().timeout(config.timeout).build()?;
+        Ok(Self::with_transport(Transport::Http { client, address }, token, config))
+    }
+    pub(crate) fn over_socket(socket: PathBuf, endpoint: String, token: String, config: PublisherConfig) -> Result<Self> {
+        // Anything wrong with the endpoint should come up now, not on every publish
+        endpoint.parse::<hyper::Uri>()?;
+        let transport = Transport::Socket {
+            path: socket,
+            endpoint,
+            timeout: config.timeout,
+        };
+        Ok(Self::with_transport(transport, token, config))
+    }
+
+    // Sets up everything that doesn't depend on how the subscriptions server is reached
+    fn with_transport(transport: Transport, token: String, config: PublisherConfig) -> Self {
+        Self {
+            transport,
+            token,
+            config,
+            circuit_breaker: Arc::new(Mutex::new(CircuitBreaker::default())),
+            outbox: Arc::new(OnceCell::new()),
+            local: LocalSubscriptionsServer::default(),
+        }
+    }
+
+    // Opens the outbox and starts delivering from it, if there is one and that hasn't been done yet
+    // This blocks on the disk, and fails if another publisher is already using the outbox
+    pub(crate) fn start_outbox_now(&self) -> io::Result<()> {
+        match &self.config.outbox_dir {
+            Some(outbox_dir) => self.open_outbox(outbox_dir).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    // Does the same as `.start_outbox_now()` without holding up whatever runtime is driving the caller
+    pub(crate) async fn start_outbox(&self) -> Result<(), PublishError> {
+        if self.config.outbox_dir.is_none() || self.outbox.get().is_some() {
+            return Ok(());
+        }
+        let publisher = self.clone();
+        run_blocking_in_background(move || publisher.start_outbox_now())
+            .await
+            .map_err(PublishError::Outbox)
+    }
+
+    // Gets the outbox in the given directory, opening it and starting to deliver from it the first time
+    // Clones share the outbox, so there's only ever one worker delivering from it
+    fn open_outbox(&self, outbox_dir: &Path) -> io::Result<Arc<Outbox>> {
+        let outbox = self.outbox.get_or_try_init(|| -> io::Result<_> {
+            let outbox = Arc::new(Outbox::open(outbox_dir)?);
+            // There may be messages left over from a previous run, so we start delivering straight away
+            spawn_background(deliver_from_outbox(
+                Arc::clone(&outbox),
+                self.transport.clone(),
+                self.token.clone(),
+                self.config.clone(),
+            ));
+            Ok(outbox)
+        })?;
+        Ok(Arc::clone(outbox))
+    }
+
+    // Publishes to the given subscriptions server instead whenever it's running in this process
+    pub(crate) fn with_local_subscriptions_server(mut self, local: LocalSubscriptionsServer) -> Self {
+        self.local = local;
+        self
+    }
+
+    /// Sends the given data to the subscriptions server on the given channel. In-depth information about this process is available in the book.
+    /// You should use [serde] to serialize anything sent here as a string (this won't be done for you). It should then be deserialized in the
+    /// appropriate subscription (which will listen for messages from here indirectly).
+    /// Failures that might be temporary are retried with exponential backoff, and if the subscriptions server seems to be down, this will
+    /// fail immediately rather than waiting on it (see [`PublisherConfig`]). Otherwise, this will return a [`PublishError`] saying what
+    /// went wrong. If you've set up an outbox, this will instead return as soon as the message has been recorded in it.
+    /// If the message was sent, this returns the number of subscribers on the subscriptions server it was sent to, so you can fall back to
+    /// something else (like a push notification or an email) if nobody was listening. If it was recorded in an outbox, nobody has received it
+    /// yet, so this returns `None`.
+    /// Up to 0.2.9, this returned Diana's general `Result<()>` instead, so code that names that type will need updating, though `?` works
+    /// just as it did.
+    /// # Example
+    /// ```
+    /// use diana::{
+    ///     async_graphql::{Object as GQLObject, InputObject as GQLInputObject, SimpleObject as GQLSimpleObject},
+    ///     errors::GQLResult,
+    ///     Publisher,
+    /// };
+    /// use serde::Serialize;
+    ///
+    /// #[derive(Serialize, GQLSimpleObject)]
+    /// struct User {
+    ///     username: String,
+    /// }
+    /// #[derive(Serialize, GQLInputObject)]
+    /// struct UserInput {
+    ///     username: String,
+    /// }
+    ///
+    /// #[derive(Default, Clone)]
+    /// pub struct Mutation {}
+    /// #[GQLObject]
+    /// impl Mutation {
+    ///     async fn add_user(
+    ///         &self,
+    ///         ctx: &async_graphql::Context<'_>,
+    ///         new_user: UserInput,
+    ///     ) -> GQLResult<User> {
+    ///         // Your code to add the new user
+    ///
+    ///         // Notify the subscriptions server that a new user has been added
+    ///         let publisher = ctx.data::<Publisher>()?;
+    ///         let user_json = serde_json::to_string(&new_user).unwrap(); // GraphQL has already checked for ill-formation
+    ///         publisher.publish("new_user", user_json.to_string()).await?;
+    ///
+    ///         Ok(User {
+    ///             username: new_user.username
+    ///         }) // In reality, you'd probably return the user that's just been created
+    ///     }
+    /// }
+    ///
+    /// # fn main() {}
+    /// ```
+    pub async fn publish(&self, channel: &str, data: String) -> Result<Option<usize>, PublishError> {
+        self.publish_with_envelope(channel, data, MessageEnvelope::new()).await
+    }
+
+    /// Sends the given data to the subscriptions server on the given channel with the given envelope, which subscribers will receive along
+    /// with it. This works just like [`.publish()`](Publisher::publish), which gives each message a new envelope with no origin, content type,
+    /// or request ID.
+    /// # Example
+    /// ```
+    /// use diana::{
+    ///     async_graphql::{Object as GQLObject},
+    ///     errors::GQLResult,
+    ///     AuthState, MessageEnvelope, Publisher,
+    /// };
+    ///
+    /// #[derive(Default, Clone)]
+    /// pub struct Mutation {}
+    /// #[GQLObject]
+    /// impl Mutation {
+    ///     async fn add_user(
+    ///         &self,
+    ///         ctx: &async_graphql::Context<'_>,
+    ///         username: String,
+    ///     ) -> GQLResult<bool> {
+    ///         // Your code to add the new user
+    ///
+    ///         let publisher = ctx.data::<Publisher>()?;
+    ///         let envelope = MessageEnvelope::new()
+    ///             .with_origin(ctx.data::<AuthState>()?)
+    ///             .with_content_type("text/plain");
+    ///         publisher.publish_with_envelope("new_user", username, envelope).await?;
+    ///
+    ///         Ok(true)
+    ///     }
+    /// }
+    ///
+    /// # fn main() {}
+    /// ```
+    pub async fn publish_with_envelope(
+        &self,
+        channel: &str,
+        data: String,
+        envelope: MessageEnvelope,
+    ) -> Result<Option<usize>, PublishError> {
+        let subscribers = self
+            .send(vec![OutboxEntry {
+                channel: channel.to_string(),
+                data,
+                envelope,
+            }])
+            .await?;
+        Ok(subscribers.and_then(|subscribers| subscribers.first().copied()))
+    }
+
+    /// Sends all the given channel/data pairs to the subscriptions server in a single request. They'll be published in order, all together or
+    /// not at all, so subscribers to each channel will receive them in the order they were given, with nothing from elsewhere published in
+    /// between them. Nothing is published if there isn't room for every channel involved (see `.max_channels()` on [`OptionsBuilder`](crate::OptionsBuilder)). This is much faster than calling
+    /// [`.publish()`](Publisher::publish) for each message when there are a lot of them, and otherwise works in exactly the same way.
+    /// # Example
+    /// ```
+    /// use diana::{
+    ///     async_graphql::{Object as GQLObject},
+    ///     errors::GQLResult,
+    ///     Publisher,
+    /// };
+    ///
+    /// #[derive(Default, Clone)]
+    /// pub struct Mutation {}
+    /// #[GQLObject]
+    /// impl Mutation {
+    ///     async fn import_users(
+    ///         &self,
+    ///         ctx: &async_graphql::Context<'_>,
+    ///         usernames: Vec<String>,
+    ///     ) -> GQLResult<bool> {
+    ///         // Your code to import the users
+    ///
+    ///         let publisher = ctx.data::<Publisher>()?;
+    ///         let messages = usernames
+    ///             .into_iter()
+    ///             .map(|username| ("new_user".to_string(), serde_json::json!({ "username": username }).to_string()))
+    ///             .collect();
+    ///         publisher.publish_many(messages).await?;
+    ///
+    ///         Ok(true)
+    ///     }
+    /// }
+    ///
+    /// # fn main() {}
+    /// ```
+    pub async fn publish_many(&self, messages: Vec<(String, String)>) -> Result<Option<Vec<usize>>, PublishError> {
+        if messages.is_empty() {
+            return Ok(Some(Vec::new()));
+        }
+        let batch = messages
+            .into_iter()
+            .map(|(channel, data)| OutboxEntry {
+                channel,
+                data,
+                envelope: MessageEnvelope::new(),
+            })
+            .collect();
+        self.send(batch).await
+    }
+
+    // Sends the given batch of messages in one request, or records it in the outbox if there is one
+    // This returns the number of subscribers each message was sent to, unless it went into the outbox
+    async fn send(&self, batch: Vec<OutboxEntry>) -> Result<Option<Vec<usize>>, PublishError> {
+        // A subscriptions server in the same process can't be unreachable, so there's no need for the outbox
+        if let (Some(outbox_dir), false) = (&self.config.outbox_dir, self.local.is_serving()) {
+            // Recording waits for the disk, which mustn't hold up whatever runtime is driving the caller
+            let publisher = self.clone();
+            let outbox_dir = outbox_dir.clone();
+            return run_blocking_in_background(move || publisher.open_outbox(&outbox_dir)?.record(&batch))
+                .await
+                .map(|_| None)
+                .map_err(PublishError::Outbox);
+        }
+        let res = self.request::<GQLPublishResult>(get_publish_body(batch)).await?;
+        Ok(Some(res.into()))
+    }
+
+    /// Takes every message that was recorded in the outbox but then rejected outright by the subscriptions server (e.g. because of an
+    /// authentication failure), along with why. Retrying those would block the outbox forever, so they're set aside for you to log or
+    /// publish again instead, and once taken they're gone from the outbox. Without an outbox, this always returns nothing, because
+    /// `.publish()` returns those errors itself.
+    pub async fn take_rejected(&self) -> Result<Vec<RejectedMessage>, PublishError> {
+        let outbox_dir = match &self.config.outbox_dir {
+            Some(outbox_dir) => outbox_dir.clone(),
+            None => return Ok(Vec::new()),
+        };
+        let publisher = self.clone();
+        run_blocking_in_background(move || publisher.open_outbox(&outbox_dir)?.take_rejected())
+            .await
+            .map_err(PublishError::Outbox)
+    }
+
+    /// Schedules the given data to be published on the given channel at the given time, returning an ID that the message can be cancelled
+    /// with (see [`.cancel_scheduled()`](Publisher::cancel_scheduled)). The subscriptions server holds on to the message until then, so this
+    /// is good for things like "auction ends at T" events. If the time has already passed, the message will be published straight away,
+    /// and if it's after the end of the year 9999, this will fail with [`PublishError::ScheduleTooFar`].
+    /// Scheduled messages are only kept in memory unless the subscriptions server has been given somewhere to persist them (see
+    /// `.schedule_dir()` on [`OptionsBuilder`](crate::OptionsBuilder)). They're always sent to the subscriptions server straight away, even
+    /// if you've set up an outbox, but they're retried and circuit-broken just like anything else.
+    /// # Example
+    /// ```
+    /// use diana::{
+    ///     async_graphql::{Object as GQLObject},
+    ///     errors::GQLResult,
+    ///     Publisher,
+    /// };
+    /// use chrono::{DateTime, Utc};
+    ///
+    /// #[derive(Default, Clone)]
+    /// pub struct Mutation {}
+    /// #[GQLObject]
+    /// impl Mutation {
+    ///     async fn start_auction(
+    ///         &self,
+    ///         ctx: &async_graphql::Context<'_>,
+    ///         auction_id: String,
+    ///         ends_at: String,
+    ///     ) -> GQLResult<String> {
+    ///         let ends_at = DateTime::parse_from_rfc3339(&ends_at)?.with_timezone(&Utc);
+    ///         // Your code to start the auction
+    ///
+    ///         let publisher = ctx.data::<Publisher>()?;
+    ///         // The ID can be used to cancel this if the auction is called off
+    ///         let id = publisher.publish_at(&format!("auction.{}", auction_id), "ended".to_string(), ends_at).await?;
+    ///
+    ///         Ok(id)
+    ///     }
+    /// }
+    ///
+    /// # fn main() {}
+    /// ```
+    pub async fn publish_at(&self, channel: &str, data: String, at: DateTime<Utc>) -> Result<String, PublishError> {
+        // Times are sent in RFC 3339 format, which only has room for four-digit years
+        if at.year() > MAX_SCHEDULE_YEAR {
+            return Err(PublishError::ScheduleTooFar);
+        }
+        let body = GQLQueryBody {
+            query: "
+                mutation SchedulePublishData($id: String, $channel: String!, $data: String!, $at: String!, $envelope: JSON) {
+                    schedulePublish(
+                        id: $id,
+                        channel: $channel,
+                        data: $data,
+                        at: $at,
+                        envelope: $envelope
+                    )
+                }
+            "
+            .to_string(),
+            // The ID is chosen here, so if the first attempt is scheduled but the response is lost, retrying won't schedule it twice
+            variables: serde_json::json!({
+                "id": Uuid::new_v4().to_string(),
+                "channel": channel,
+                "data": data,
+                "at": at.to_rfc3339(),
+                "envelope": MessageEnvelope::new(),
+            }),
+        };
+        self.request(body).await
+    }
+
+    /// Schedules the given data to be published on the given channel once the given amount of time has passed (e.g. for a "reminder in 10
+    /// minutes" event), returning an ID that the message can be cancelled with. This works just like
+    /// [`.publish_at()`](Publisher::publish_at) otherwise.
+    pub async fn publish_after(&self, channel: &str, data: String, delay: Duration) -> Result<String, PublishError> {
+        let at = chrono::Duration::from_std(delay)
+            .ok()
+            .and_then(|delay| Utc::now().checked_add_signed(delay))
+            .ok_or(PublishError::ScheduleTooFar)?;
+        self.publish_at(channel, data, at).await
+    }
+
+    /// Cancels a message scheduled with [`.publish_at()`](Publisher::publish_at) or [`.publish_after()`](Publisher::publish_after), given
+    /// the ID that returned. This returns whether or not there was a message to cancel, so you'll get `false` if it's already been
+    /// published.
+    pub async fn cancel_scheduled(&self, id: &str) -> Result<bool, PublishError> {
+        let body = GQLQueryBody {
+            query: "
+                mutation CancelScheduled($id: String!) {
+                    cancelScheduled(id: $id)
+                }
+            "
+            .to_string(),
+            variables: serde_json::json!({ "id": id }),
+        };
+        self.request(body).await
+    }
+
+    /// Registers a webhook on the subscriptions server, which every message on the given channel (or channel pattern, like `order.*`) will
+    /// be POSTed to from now on. Each request is signed with the given secret, which the receiver should check with
+    /// [`verify_webhook_signature`](crate::verify_webhook_signature). This returns the webhook's ID, which it can be unregistered with.
+    /// The subscriptions server must have webhooks enabled (see `.webhook_registry()` on [`OptionsBuilder`](crate::OptionsBuilder)).
+    pub async fn register_webhook(&self, url: &str, channel: &str, secret: &str) -> Result<String, PublishError> {
+        let body = GQLQueryBody {
+            query: "
+                mutation RegisterWebhook($id: String, $url: String!, $channel: String!, $secret: String!) {
+                    registerWebhook(id: $id, url: $url, channel: $channel, secret: $secret)
+                }
+            "
+            .to_string(),
+            // The ID is chosen here, so if the first attempt is registered but the response is lost, retrying won't register it twice
+            variables: serde_json::json!({
+                "id": Uuid::new_v4().to_string(),
+                "url": url,
+                "channel": channel,
+                "secret": secret,
+            }),
+        };
+        self.request(body).await
+    }
+
+    /// Unregisters a webhook registered with [`.register_webhook()`](Publisher::register_webhook), given the ID that returned. This
+    /// returns whether or not there was a webhook to unregister.
+    pub async fn unregister_webhook(&self, id: &str) -> Result<bool, PublishError> {
+        let body = GQLQueryBody {
+            query: "
+                mutation UnregisterWebhook($id: String!) {
+                    unregisterWebhook(id: $id)
+                }
+            "
+            .to_string(),
+            variables: serde_json::json!({ "id": id }),
+        };
+        self.request(body).await
+    }
+
+    /// Gets the messages published on the given channel (or channel pattern) since the given cursor, for clients that can only reach the
+    /// serverless part of your system and so can't hold a subscription open. If nothing has been published yet, this waits up to the given
+    /// time for something to be, though never for longer than the publisher's timeout allows (see [`PublisherConfig`]) or the subscriptions
+    /// server's maximum (see `.max_poll_wait()` on [`OptionsBuilder`](crate::OptionsBuilder)).
+    /// Each result has a new cursor that should be given to the next poll. A client without one should poll without waiting first (this
+    /// is how it registers its interest), and then carry on from the cursor that gives it. Messages are only kept for polling clients if the
+    /// subscriptions server keeps channel histories (see `.channel_history_size()` on [`OptionsBuilder`](crate::OptionsBuilder)), and if
+    /// any the client would have got are no longer there, [`PollResult::missed`] will say how many.
+    /// The publisher polls with its own token, so you should check the client can see the channel yourself.
+    pub async fn poll(&self, channel: &str, cursor: Option<&str>, wait: Duration) -> Result<PollResult, PublishError> {
+        // The subscriptions server has to be able to respond before we give up on it
+        let wait = wait.min(self.config.timeout.saturating_sub(POLL_RESPONSE_MARGIN));
+        let body = GQLQueryBody {
+            query: "
+                query Poll($channel: String!, $cursor: String, $waitMs: Int) {
+                    poll(channel: $channel, cursor: $cursor, waitMs: $waitMs) {
+                        messages
+                        missed
+                        cursor
+                    }
+                }
+            "
+            .to_string(),
+            variables: serde_json::json!({
+                "channel": channel,
+                "cursor": cursor,
+                "waitMs": wait.as_millis() as u64,
+            }),
+        };
+        self.request(body).await
+    }
+
+    // Sends the given query body to the subscriptions server, retrying temporary failures and respecting the circuit breaker
+    // This returns the result of the mutation in the query
+    async fn request<T: DeserializeOwned + Send + 'static>(
+        &self,
+        body: GQLQueryBody<serde_json::Value>,
+    ) -> Result<T, PublishError> {
+        // There's nothing to retry or circuit-break if the subscriptions server is in this process
+        if let Some(res) = self.local.execute(&body.query, &body.variables).await {
+            return parse_response(res);
+        }
+        // If this is the publish that checks whether the subscriptions server has recovered, this has to be held until it's done
+        let _probe = self
+            .check_circuit()
+            .map_err(|retry_after| PublishError::CircuitOpen { retry_after })?;
+
+        // The integrations may not be running on a runtime that `reqwest` works on, so this all happens on our own
+        let transport = self.transport.clone();
+        let token = self.token.clone();
+        let config = self.config.clone();
+        let res = run_in_background(async move {
+            let mut backoff = config.retry_backoff;
+            let mut retries_left = config.retries;
+            loop {
+                match send_request(&transport, &token, &body).await {
+                    Err(err) if err.is_temporary() && retries_left > 0 => {
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(config.max_retry_backoff);
+                        retries_left -= 1;
+                    }
+                    res => break res,
+                }
+            }
+        })
+        .await;
+
+        match &res {
+            Ok(_) => self.record_outcome(true),
+            Err(err) if err.is_temporary() => self.record_outcome(false),
+            // Anything else (e.g. a bad token or a rejected payload) says nothing about whether the subscriptions server is available, so
+            // one caller's mistakes can't stop everyone else publishing
+            Err(_) => (),
+        }
+        res
+    }
+
+    /// Serializes the given data and sends it to the subscriptions server on the given typed channel. This works just like
+    /// [`.publish()`](Publisher::publish), except that the type of the data is checked against the channel at compile-time, so the subscriptions
+    /// on the other end will always be able to deserialize it.
+    /// # Example
+    /// ```
+    /// use diana::{
+    ///     async_graphql::{Object as GQLObject},
+    ///     errors::GQLResult,
+    ///     Channel, Publisher,
+    /// };
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct User {
+    ///     username: String,
+    /// }
+    ///
+    /// const NEW_USER: Channel<User> = Channel::new("new_user");
+    ///
+    /// #[derive(Default, Clone)]
+    /// pub struct Mutation {}
+    /// #[GQLObject]
+    /// impl Mutation {
+    ///     async fn add_user(
+    ///         &self,
+    ///         ctx: &async_graphql::Context<'_>,
+    ///         username: String,
+    ///     ) -> GQLResult<bool> {
+    ///         // Your code to add the new user
+    ///
+    ///         let publisher = ctx.data::<Publisher>()?;
+    ///         publisher.publish_typed(&NEW_USER, &User { username }).await?;
+    ///
+    ///         Ok(true)
+    ///     }
+    /// }
+    ///
+    /// # fn main() {}
+    /// ```
+    pub async fn publish_typed<T: Serialize>(
+        &self,
+        channel: &TypedChannel<T>,
+        payload: &T,
+    ) -> Result<Option<usize>, PublishError> {
+        let data = serialize_for_channel(channel, payload)?;
+        let envelope = MessageEnvelope::new().with_encoding(channel.encoding());
+        self.publish_with_envelope(channel.name(), data, envelope).await
+    }
+
+    /// Sends the given binary data to the subscriptions server on the given channel, marked with the given content type (e.g.
+    /// `application/cbor`). The data is base64-encoded to be sent, and subscribers can get the original bytes back with
+    /// [`ChannelMessage::bytes`](crate::ChannelMessage::bytes). If you're publishing structured data, a typed channel created with
+    /// [`Channel::msgpack`](crate::Channel::msgpack) will do all this for you. This works just like [`.publish()`](Publisher::publish)
+    /// otherwise.
+    pub async fn publish_binary(
+        &self,
+        channel: &str,
+        data: &[u8],
+        content_type: &str,
+    ) -> Result<Option<usize>, PublishError> {
+        let mut envelope = MessageEnvelope::new().with_content_type(content_type);
+        envelope.binary = true;
+        self.publish_with_envelope(channel, encode_bytes(data), envelope)
+            .await
+    }
+
+    // Checks if the circuit breaker is open, returning how long it'll stay open for if it is
+    // Once the cooldown has passed, exactly one publish is let through to check if the subscriptions server has recovered, which is given a
+    // probe to hold until it's done, and its failure will reopen the circuit straight away
+    fn check_circuit(&self) -> Result<Option<CircuitProbe>, Duration> {
+        // A poisoned circuit breaker just means a panic happened while updating some counters, they're still usable
+        let mut circuit_breaker = self
+            .circuit_breaker
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        let open_until = match circuit_breaker.open_until {
+            Some(open_until) => open_until,
+            None => return Ok(None),
+        };
+        if let Some(retry_after) = open_until.checked_duration_since(Instant::now()) {
+            return Err(retry_after);
+        }
+        // Another publish is already checking, we'll know if it's worth trying again as soon as it's done
+        if circuit_breaker.probing {
+            return Err(Duration::ZERO);
+        }
+        circuit_breaker.probing = true;
+
+        Ok(Some(CircuitProbe {
+            circuit_breaker: Arc::clone(&self.circuit_breaker),
+        }))
+    }
+
+    fn record_outcome(&self, succeeded: bool) {
+        let mut circuit_breaker = self
+            .circuit_breaker
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if succeeded {
+            *circuit_breaker = CircuitBreaker::default();
+            return;
+        }
+        circuit_breaker.consecutive_failures += 1;
+        let threshold = self.config.circuit_breaker_threshold;
+        if threshold > 0 && circuit_breaker.consecutive_failures >= threshold {
+            circuit_breaker.open_until = Some(Instant::now() + self.config.circuit_breaker_cooldown);
+            circuit_breaker.probing = false;
+        }
+    }
+}
+
+/// A publisher that holds on to everything published with it until the request it's for has finished, only sending it to the subscriptions
+/// server if the response had no errors. That way, subscribers never see events for changes that didn't actually happen (e.g. a "user
+/// created" event from a mutation that went on to fail). A new one of these is passed to the resolvers for every request.
+/// If you need to send something straight away, you can use `.publish_now()`.
+/// Everything published with this is sent with the claims of the user that made the request and the request's ID in its
+/// [`MessageEnvelope`].
+/// # Example
+/// ```
+/// use diana::{
+///     async_graphql::{Object as GQLObject},
+///     errors::GQLResult,
+///     BufferedPublisher,
+/// };
+///
+/// #[derive(Default, Clone)]
+/// pub struct Mutation {}
+/// #[GQLObject]
+/// impl Mutation {
+///     async fn add_user(
+///         &self,
+///         ctx: &async_graphql::Context<'_>,
+///         username: String,
+///     ) -> GQLResult<bool> {
+///         let publisher = ctx.data::<BufferedPublisher>()?;
+///         // This will only be sent if the rest of the request succeeds
+///         publisher.publish("new_user", username.clone());
+///
+///         // Your code to add the new user
+///
+///         Ok(true)
+///     }
+/// }
+///
+/// # fn main() {}
+/// ```
+// Clones share their buffer, so the handler can keep one to flush after the request has been run
+#[derive(Clone)]
+pub struct BufferedPublisher {
+    publisher: Publisher,
+    buffer: Arc<Mutex<Vec<OutboxEntry>>>,
+    // The envelope every message gets a copy of, which records where the request came from
+    envelope: MessageEnvelope,
+}
+impl BufferedPublisher {
+    pub(crate) fn new(publisher: Publisher, auth_state: &AuthState, request_id: &str) -> Self {
+        Self {
+            publisher,
+            buffer: Arc::new(Mutex::new(Vec::new())),
+            envelope: MessageEnvelope::new()
+                .with_origin(auth_state)
+                .with_request_id(request_id),
+        }
+    }
+
+    /// Gets a new envelope for a message published in this request, which will have the request's ID and the claims of the user that made
+    /// it. Everything published with this publisher already gets one of these, but it can be useful for publishing in other ways.
+    pub fn envelope(&self) -> MessageEnvelope {
+        self.envelope.renew()
+    }
+
+    /// Gets the ID of the request this publisher is for.
+    pub fn request_id(&self) -> &str {
+        // This is always set when the publisher is created
+        self.envelope.request_id.as_deref().unwrap_or_default()
+    }
+
+    /// Buffers the given data to be sent to the subscriptions server on the given channel once the request has finished successfully. If it
+    /// doesn't, the data will be discarded. Otherwise, this works just like [`Publisher::publish`].
+    pub fn publish(&self, channel: &str, data: String) {
+        self.buffer_entry(channel, data, self.envelope());
+    }
+
+    /// Serializes the given data and buffers it to be sent to the subscriptions server on the given typed channel once the request has
+    /// finished successfully. This works just like [`Publisher::publish_typed`] otherwise.
+    pub fn publish_typed<T: Serialize>(&self, channel: &TypedChannel<T>, payload: &T) -> Result<(), PublishError> {
+        let data = serialize_for_channel(channel, payload)?;
+        let envelope = self.envelope().with_encoding(channel.encoding());
+        self.buffer_entry(channel.name(), data, envelope);
+        Ok(())
+    }
+
+    /// Buffers the given binary data to be sent to the subscriptions server on the given channel once the request has finished
+    /// successfully. This works just like [`Publisher::publish_binary`] otherwise.
+    pub fn publish_binary(&self, channel: &str, data: &[u8], content_type: &str) {
+        let mut envelope = self.envelope().with_content_type(content_type);
+        envelope.binary = true;
+        self.buffer_entry(channel, encode_bytes(data), envelope);
+    }
+
+    /// Sends the given data to the subscriptions server on the given channel straight away, whether or not the request goes on to succeed.
+    /// This is the same as using [`Publisher::publish`], including returning the number of subscribers the data was sent to.
+    pub async fn publish_now(&self, channel: &str, data: String) -> Result<Option<usize>, PublishError> {
+        self.publisher
+            .publish_with_envelope(channel, data, self.envelope())
+            .await
+    }
+
+    fn buffer_entry(&self, channel: &str, data: String, envelope: MessageEnvelope) {
+        self.buffer
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(OutboxEntry {
+                channel: channel.to_string(),
+                data,
+                envelope,
+            });
+    }
+
+    // Sends everything that's been buffered in a single request, which the subscriptions server publishes in order, all together or not at
+    // all
+    pub(crate) async fn flush(&self) -> Result<(), PublishError> {
+        let buffered = std::mem::take(&mut *self.buffer.lock().unwrap_or_else(|err| err.into_inner()));
+        if buffered.is_empty() {
+            return Ok(());
+        }
+        self.publisher.send(buffered).await.map(|_| ())
+    }
+}
+
+// Delivers everything recorded in the given outbox in order, for as long as the publisher it belongs to exists
+// Temporary failures are retried until they succeed, but messages the subscriptions server rejects outright are set aside for the caller
+// to take (see `Publisher::take_rejected`), they'd otherwise block the outbox forever
+async fn deliver_from_outbox(
+    outbox: Arc<Outbox>,
+    transport: Transport,
+    token: String,
+    config: PublisherConfig,
+) {
+    loop {
+        // If the publisher has been dropped, nothing more will be recorded, and anything left will be delivered by the next one
+        if Arc::strong_count(&outbox) == 1 {
+            return;
+        }
+        let next_outbox = Arc::clone(&outbox);
+        let (batch, next_offset) = match run_blocking_in_background(move || next_outbox.next()).await {
+            Ok(Some(next)) => next,
+            Ok(None) => {
+                let _ = tokio::time::timeout(OUTBOX_IDLE_CHECK_INTERVAL, outbox.recorded.notified()).await;
+                continue;
+            }
+            Err(_) => {
+                tokio::time::sleep(config.max_retry_backoff).await;
+                continue;
+            }
+        };
+
+        // Anything that couldn't be parsed is skipped straight away
+        if let Some(batch) = batch {
+            let body = get_publish_body(batch.clone());
+            let mut backoff = config.retry_backoff;
+            let rejection = loop {
+                match send_request::<GQLPublishResult>(&transport, &token, &body).await {
+                    Err(err) if err.is_temporary() => {
+                        if Arc::strong_count(&outbox) == 1 {
+                            return;
+                        }
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(config.max_retry_backoff);
+                    }
+                    Err(err) => break Some(err.to_string()),
+                    Ok(_) => break None,
+                }
+            };
+            if let Some(reason) = rejection {
+                let rejecting_outbox = Arc::clone(&outbox);
+                // If this fails, the batch is left where it is and will be tried again, which is better than losing it
+                if run_blocking_in_background(move || rejecting_outbox.reject(batch, &reason))
+                    .await
+                    .is_err()
+                {
+                    tokio::time::sleep(config.max_retry_backoff).await;
+                    continue;
+                }
+            }
+        }
+
+        // If this fails, the message will be delivered again, which is better than losing it
+        let delivered_outbox = Arc::clone(&outbox);
+        if run_blocking_in_background(move || delivered_outbox.mark_delivered(next_offset))
+            .await
+            .is_err()
+        {
+            tokio::time::sleep(config.max_retry_backoff).await;
+        }
+    }
+}
+
+// Creates the query body to publish the given batch of messages
+// A single message uses the simpler `publish` mutation, anything more uses `publishMany` so it all goes in one request
+fn get_publish_body(mut batch: Vec<OutboxEntry>) -> GQLQueryBody<serde_json::Value> {
+    if batch.len() == 1 {
+        let OutboxEntry { channel, data, envelope } = batch.remove(0);
+        GQLQueryBody {
+            query: "
+                mutation PublishData($channel: String!, $data: String!, $envelope: JSON) {
+                    publish(
+                        channel: $channel,
+                        data: $data,
+                        envelope: $envelope
+                    )
+                }
+            "
+            .to_string(),
+            variables: serde_json::json!({ "channel": channel, "data": data, "envelope": envelope }),
+        }
+    } else {
+        GQLQueryBody {
+            query: "
+                mutation PublishManyData($messages: [PublishInput!]!) {
+                    publishMany(
+                        messages: $messages
+                    )
+                }
+            "
+            .to_string(),
+            variables: serde_json::json!({ "messages": batch }),
+        }
+    }
+}
+
+impl From<GQLPublishResult> for Vec<usize> {
+    fn from(res: GQLPublishResult) -> Self {
+        match res {
+            GQLPublishResult::Single(subscribers) => vec![subscribers],
+            GQLPublishResult::Many(subscribers) => subscribers,
+        }
+    }
+}
+
+// Makes a single attempt at sending a query to the subscriptions server
+// If it works, this returns the result of the mutation in the query
+async fn send_request<T: DeserializeOwned>(
+    transport: &Transport,
+    token: &str,
+    body: &GQLQueryBody<serde_json::Value>,
+) -> Result<T, PublishError> {
+    let (status, body) = match transport {
+        Transport::Http { client, address } => send_over_http(client, address, token, body).await?,
+        Transport::Socket {
+            path,
+            endpoint,
+            timeout,
+        } => tokio::time::timeout(*timeout, send_over_socket(path, endpoint, token, body))
+            .await
+            .map_err(|_| PublishError::Timeout)??,
+    };
+    // Handle if the request wasn't successful on an HTTP level
+    if !status.is_success() {
+        return Err(PublishError::Status {
+            status: status.as_u16(),
+            body,
+        });
+    }
+
+    parse_response(body)
+}
+
+// Sends a query to the subscriptions server over TCP, returning the status and body of the response
+async fn send_over_http(
+    client: &Client,
+    address: &str,
+    token: &str,
+    body: &GQLQueryBody<serde_json::Value>,
+) -> Result<(StatusCode, String), PublishError> {
+    let res = client
+        .post(address)
+        .json(body)
+        .header("Authorization", "Bearer ".to_string() + token)
+        .send()
+        .await
+        .map_err(PublishError::from_reqwest)?;
+
+    let status = res.status();
+    let body = res.text().await.map_err(PublishError::from_reqwest)?;
+    Ok((status, body))
+}
+
+// Sends a query to the subscriptions server over a Unix socket, returning the status and body of the response
+// Each request gets its own connection, which is closed once the response has been read
+#[cfg(unix)]
+async fn send_over_socket(
+    path: &Path,
+    endpoint: &str,
+    token: &str,
+    body: &GQLQueryBody<serde_json::Value>,
+) -> Result<(StatusCode, String), PublishError> {
+    use hyper::{body::to_bytes, client::conn::handshake, header, Body, Request};
+
+    let stream = tokio::net::UnixStream::connect(path)
+        .await
+        .map_err(PublishError::Socket)?;
+    let (mut sender, connection) = handshake(stream).await.map_err(PublishError::from_hyper)?;
+    // The connection has to be driven separately, it'll finish once we're done with it
+    tokio::spawn(connection);
+
+    // We know more than the compiler here, JSON values will always serialize
+    let body = serde_json::to_vec(body).unwrap();
+    let req = Request::post(endpoint)
+        .header(header::HOST, "localhost")
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::AUTHORIZATION, "Bearer ".to_string() + token)
+        .body(Body::from(body))
+        .map_err(|err| PublishError::Socket(io::Error::new(io::ErrorKind::InvalidInput, err)))?;
+    let res = sender.send_request(req).await.map_err(PublishError::from_hyper)?;
+
+    // `reqwest` re-exports the same `http` types `hyper` uses
+    let status = res.status();
+    let body = to_bytes(res.into_body()).await.map_err(PublishError::from_hyper)?;
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
+#[cfg(not(unix))]
+async fn send_over_socket(
+    _path: &Path,
+    _endpoint: &str,
+    _token: &str,
+    _body: &GQLQueryBody<serde_json::Value>,
+) -> Result<(StatusCode, String), PublishError> {
+    Err(PublishError::Socket(io::Error::other("unix sockets aren't supported on this platform")))
+}
+
+// Gets the result of the mutation (or query) in a response from the subscriptions server
+// Serializes data to be published on the given typed channel
+fn serialize_for_channel<T: Serialize>(channel: &TypedChannel<T>, payload: &T) -> Result<String, PublishError> {
+    channel
+        .encoding()
+        .encode(payload)
+        .map_err(|err| PublishError::Serialization(channel.name().to_string(), err))
+}
+
+fn parse_response<T: DeserializeOwned>(body: String) -> Result<T, PublishError> {
+    // Confirm nothing's gone wrong on a GraphQL level (e.g. an authentication error, which would give us `null`)
+    // Whichever mutation (or query) we used, it's the only field in the response
+    match serde_json::from_str::<GQLResponse<T>>(&body) {
+        Ok(GQLResponse { data }) if data.len() == 1 => match data.into_iter().next() {
+            Some((_, res)) => Ok(res),
+            None => Err(PublishError::NotAcknowledged { body }),
+        },
+        _ => Err(PublishError::NotAcknowledged { body }),
+    }
+}
+
+impl PublishError {
+    fn from_reqwest(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            Self::Timeout
+        } else {
+            Self::Transport(err)
+        }
+    }
+
+    #[cfg(unix)]
+    fn from_hyper(err: hyper::Error) -> Self {
+        Self::Socket(io::Error::other(err))
+    }
+
+    // Whether or not the error might go away if we try again
+    fn is_temporary(&self) -> bool {
+        match self {
+            Self::Transport(_) | Self::Socket(_) | Self::Timeout => true,
+            Self::Status { status, .. } => {
+                *status >= 500 || *status == StatusCode::TOO_MANY_REQUESTS.as_u16()
+            }
+            Self::NotAcknowledged { .. }
+            | Self::CircuitOpen { .. }
+            | Self::Outbox(_)
+            | Self::ScheduleTooFar
+            | Self::Serialization(..) => false,
+        }
+    }
+}
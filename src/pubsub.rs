@@ -1,214 +1,1072 @@
 // This module defines a simple publish-subscribe structure, though one designed to run across the web
-// The publishing and subscribing are done on different servers/functions
+// The publishing and subscribing are done on different servers/functions, the publishing side is in `publisher.rs`
 
 use async_stream::stream;
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use once_cell::sync::OnceCell;
+use std::collections::hash_map::{Entry, RandomState};
 use std::collections::HashMap;
-use tokio::sync::broadcast::{channel as create_channel, Sender};
+use std::fmt;
+use std::hash::BuildHasher;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{
+    channel as create_channel,
+    error::{RecvError, TryRecvError},
+    Receiver, Sender,
+};
+use tokio::sync::mpsc::Sender as QueueSender;
 use tokio_stream::Stream;
 use anyhow::{Result, bail};
 
+use crate::auth::auth_state::AuthState;
+use crate::background::{run_blocking_in_background, run_in_background, spawn_background};
+pub use crate::channel_history::{ChannelMessage, StartFrom};
+use crate::channel_history::{ChannelHistory, HistoryStore};
+use crate::channel_pattern::{channel_matches_pattern, is_channel_pattern};
+use crate::envelope::MessageEnvelope;
 use crate::errors::DianaError;
+#[cfg(feature = "postgres")]
+use crate::postgres_listener::start_postgres_listener;
+use crate::presence::{ChannelPresence, Presence, PRESENCE_CHANNEL};
+#[cfg(feature = "redis")]
+use crate::redis_relay::{start_redis_relay, RedisRelay};
+use crate::scheduler::{run_scheduler, ScheduledMessage, Scheduler};
+use crate::webhooks::{queue_webhook_event, start_webhooks, Webhook, WebhookConfig, WebhookEvent};
+use crate::websocket::DEFAULT_WS_KEEP_ALIVE_INTERVAL;
 
-const MESSAGES_TO_BE_RETAINED: usize = 5;
+// The number of messages a channel will buffer for each subscriber if the user hasn't said otherwise
+pub const DEFAULT_CHANNEL_BUFFER_SIZE: usize = 5;
+// The number of shards channels are split across if the user hasn't said otherwise, each of which has its own lock
+// Publishing and subscribing only lock the shard their channel is in, so they don't block each other on different channels
+pub const DEFAULT_CHANNEL_SHARDS: usize = 32;
+// The longest a poll will wait for new messages if the user hasn't said otherwise
+pub const DEFAULT_MAX_POLL_WAIT: Duration = Duration::from_secs(20);
 
-#[derive(Serialize)]
-struct GQLQueryBody<T: Serialize> {
-    query: String,
-    variables: T,
+// Everything from here down operates solely on the subscriptions server, and is stateful!
+// Do NOT import these mechanisms in the serverless system!
+
+/// What a subscriber should do if it falls so far behind a channel that messages are dropped before it can receive them.
+/// This happens when more messages are published than the channel buffers (see `.channel_buffer_size()` on
+/// [`OptionsBuilder`](crate::OptionsBuilder)) before a slow client can take them. Dropped messages are always counted in
+/// [`PubSubMetrics`], whatever the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LagPolicy {
+    /// Carry on from the oldest message still buffered, the dropped messages are never seen. This is the default.
+    #[default]
+    Skip,
+    /// End the subscription, so the client knows it has missed messages and can resubscribe.
+    Disconnect,
+    /// Carry on from the oldest message still buffered, but first yield a [`ChannelEvent::Lagged`] with the number of messages missed.
+    /// You'll only see these notices if you use [`get_event_stream_for_channel_from_ctx`](crate::graphql_utils::get_event_stream_for_channel_from_ctx).
+    Notify,
+}
+/// Options for subscribing to a channel. The defaults will give you every new message from the moment you subscribe.
+#[derive(Debug, Clone, Default)]
+pub struct SubscribeOptions {
+    /// Where the subscription should start from. If this is earlier than now, messages will be replayed from the channel's history (see
+    /// `.channel_history_size()` on [`OptionsBuilder`](crate::OptionsBuilder)). If you're subscribing to a channel pattern, this applies to
+    /// each matching channel separately, and their histories are merged in the order the messages were published.
+    pub start_from: StartFrom,
+    /// A filter for the messages the subscriber should receive. Anything it rejects is dropped on the server before it's even copied into
+    /// the subscriber's stream. By default, every message is received.
+    pub filter: Option<MessageFilter>,
 }
 
-#[derive(Deserialize)]
-struct GQLPublishResponse {
-    data: PublishResponse,
+/// A predicate that decides whether a subscriber should receive a message. It's given the message and the authentication state of the
+/// subscriber, and anything else it needs (like the subscription's arguments) can be captured.
+/// # Example
+/// ```
+/// use diana::MessageFilter;
+///
+/// let order_id = "123".to_string();
+/// let filter = MessageFilter::new(move |message, _auth_state| message.data.contains(&order_id));
+/// ```
+#[derive(Clone)]
+pub struct MessageFilter(Arc<FilterFn>);
+type FilterFn = dyn Fn(&ChannelMessage, &AuthState) -> bool + Send + Sync;
+impl MessageFilter {
+    /// Creates a new filter from the given predicate, which should return `true` for messages the subscriber should receive.
+    pub fn new<F: Fn(&ChannelMessage, &AuthState) -> bool + Send + Sync + 'static>(filter: F) -> Self {
+        Self(Arc::new(filter))
+    }
+    /// Checks whether the subscriber with the given authentication state should receive the given message.
+    pub fn matches(&self, message: &ChannelMessage, auth_state: &AuthState) -> bool {
+        (self.0)(message, auth_state)
+    }
 }
-#[derive(Deserialize)]
-struct PublishResponse {
-    publish: bool,
+impl fmt::Debug for MessageFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MessageFilter").finish()
+    }
 }
 
-/// The system that publishes data from the queries/mutations system to the subscriptions server.
-/// These communications are secured by a JWT specified in [`Options`](crate::Options).
-/// This is automatically created from the [`Options`](crate::Options) and passed to all resolvers. You should never need to manually create it.
-pub struct Publisher {
-    client: Client,
-    address: String,
-    token: String,
+/// Something that happened on a channel, as seen by one subscriber.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelEvent {
+    /// A message was published on the channel, it's attached with its sequence number.
+    Message(ChannelMessage),
+    /// The subscriber fell behind and the attached number of messages were dropped before it could receive them. This is only ever yielded
+    /// under [`LagPolicy::Notify`].
+    Lagged(u64),
 }
-impl Publisher {
-    /// Creates a new publisher. This is done for you when you create the queries/mutations system, so you should never need to call this.
-    pub fn new(hostname: String, port: String, endpoint: String, token: String) -> Result<Self> {
-        let address = format!(
-            "{hostname}:{port}{endpoint}", // The endpoint should start with '/'
-            hostname = hostname,
-            port = port,
-            endpoint = endpoint
-        );
 
-        let client = Client::new();
+/// A snapshot of the metrics for the subscriptions server's channels since it started. You can get this from
+/// [`DianaHandler::pubsub_metrics`](crate::DianaHandler::pubsub_metrics).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PubSubMetrics {
+    /// The number of messages that have been delivered to channels on this server (not to each subscriber).
+    pub messages_published: u64,
+    /// The number of messages subscribers have missed because they fell too far behind.
+    pub messages_dropped: u64,
+    /// The number of subscriptions that have been ended because they fell too far behind (see [`LagPolicy::Disconnect`]).
+    pub subscribers_disconnected: u64,
+    /// The number of channels that have been removed because they were idle (see `.channel_idle_timeout()` on
+    /// [`OptionsBuilder`](crate::OptionsBuilder)).
+    pub channels_removed: u64,
+    /// The number of messages that couldn't be sent to a webhook, even after retrying (see `.webhook_registry()` on
+    /// [`OptionsBuilder`](crate::OptionsBuilder)).
+    pub webhook_deliveries_failed: u64,
+    /// The number of messages that were never sent to a webhook because it (or the subscriptions server's queue for all webhooks) had too
+    /// many waiting already (see `.webhook_queue_size()` on [`OptionsBuilder`](crate::OptionsBuilder)).
+    pub webhook_messages_dropped: u64,
+}
+// The live counters behind `PubSubMetrics`, these are shared with every subscriber's stream
+#[derive(Default)]
+pub struct PubSubCounters {
+    messages_published: AtomicU64,
+    messages_dropped: AtomicU64,
+    subscribers_disconnected: AtomicU64,
+    channels_removed: AtomicU64,
+    webhook_deliveries_failed: AtomicU64,
+    webhook_messages_dropped: AtomicU64,
+}
+impl PubSubCounters {
+    pub fn snapshot(&self) -> PubSubMetrics {
+        PubSubMetrics {
+            messages_published: self.messages_published.load(Ordering::Relaxed),
+            messages_dropped: self.messages_dropped.load(Ordering::Relaxed),
+            subscribers_disconnected: self.subscribers_disconnected.load(Ordering::Relaxed),
+            channels_removed: self.channels_removed.load(Ordering::Relaxed),
+            webhook_deliveries_failed: self.webhook_deliveries_failed.load(Ordering::Relaxed),
+            webhook_messages_dropped: self.webhook_messages_dropped.load(Ordering::Relaxed),
+        }
+    }
 
-        Ok(Self {
-            client,
-            address,
-            token,
-        })
+    pub fn record_failed_webhook_delivery(&self) {
+        self.webhook_deliveries_failed.fetch_add(1, Ordering::Relaxed);
     }
 
-    /// Sends the given data to the subscriptions server on the given channel. In-depth information about this process is available in the book.
-    /// You should use [serde] to serialize anything sent here as a string (this won't be done for you). It should then be deserialized in the
-    /// appropriate subscription (which will listen for messages from here indirectly).
-    /// This function will return an error if the subscriptions server was unavailable or didn't correctly acknowledge the request.
-    /// # Example
-    /// ```
-    /// use diana::{
-    ///     async_graphql::{Object as GQLObject, InputObject as GQLInputObject, SimpleObject as GQLSimpleObject},
-    ///     errors::GQLResult,
-    ///     Publisher,
-    /// };
-    /// use serde::Serialize;
-    ///
-    /// #[derive(Serialize, GQLSimpleObject)]
-    /// struct User {
-    ///     username: String,
-    /// }
-    /// #[derive(Serialize, GQLInputObject)]
-    /// struct UserInput {
-    ///     username: String,
-    /// }
-    ///
-    /// #[derive(Default, Clone)]
-    /// pub struct Mutation {}
-    /// #[GQLObject]
-    /// impl Mutation {
-    ///     async fn add_user(
-    ///         &self,
-    ///         ctx: &async_graphql::Context<'_>,
-    ///         new_user: UserInput,
-    ///     ) -> GQLResult<User> {
-    ///         // Your code to add the new user
-    ///
-    ///         // Notify the subscriptions server that a new user has been added
-    ///         let publisher = ctx.data::<Publisher>()?;
-    ///         let user_json = serde_json::to_string(&new_user).unwrap(); // GraphQL has already checked for ill-formation
-    ///         publisher.publish("new_user", user_json.to_string()).await?;
-    ///
-    ///         Ok(User {
-    ///             username: new_user.username
-    ///         }) // In reality, you'd probably return the user that's just been created
-    ///     }
-    /// }
-    ///
-    /// # fn main() {}
-    /// ```
-    pub async fn publish(&self, channel: &str, data: String) -> Result<()> {
-        let client = &self.client;
-
-        // Create the query body with a HashMap of variables
-        let mut variables = HashMap::new();
-        variables.insert("channel", channel.to_string());
-        variables.insert("data", data);
-
-        let body = GQLQueryBody {
-            query: "
-                mutation PublishData($channel: String!, $data: String!) {
-                    publish(
-                        channel: $channel,
-                        data: $data
-                    )
-                }
-            "
-            .to_string(),
-            variables,
-        };
+    pub fn record_dropped_webhook_message(&self) {
+        self.webhook_messages_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
 
-        let res = client
-            .post(&self.address)
-            .json(&body)
-            .header("Authorization", "Bearer ".to_string() + &self.token)
-            .send()
-            .await
-            .map_err(|_| DianaError::SubscriptionDataPublishFailed)?;
+// A receiver for new messages, along with the messages to replay from the history and the number of those that are no longer there
+type Replay = (Receiver<Arc<ChannelMessage>>, Vec<Arc<ChannelMessage>>, u64);
 
-        // Handle if the request wasn't successful on an HTTP level
-        if res.status().to_string() != "200 OK" {
-            bail!(DianaError::SubscriptionDataPublishFailed)
-        }
+// What a new subscriber will receive from, which can't be set up until the channel's persisted history has been loaded if it has to be
+// created (along with where the subscriber should start from then)
+enum PendingReceiver {
+    Ready(Replay),
+    Loading(Arc<PubSub>, String, StartFrom),
+}
 
-        // Get the body out (data still stringified though, that's handled by resolvers)
-        let body: GQLPublishResponse = serde_json::from_str(
-            &res.text()
-                .await
-                .map_err(|_| DianaError::SubscriptionDataPublishFailed)?,
-        )
-        .map_err(|_| DianaError::SubscriptionDataPublishFailed)?;
+// Configuration for the subscriptions server's internal PubSub, this is derived from the user's `Options`
+#[derive(Clone)]
+pub struct PubSubConfig {
+    // The number of messages each channel will buffer for each subscriber
+    pub channel_buffer_size: usize,
+    // Buffer sizes for particular channels, which override the above
+    pub channel_buffer_sizes: HashMap<String, usize>,
+    // What subscribers should do if they fall behind
+    pub lag_policy: LagPolicy,
+    // The number of messages each channel keeps in its history for subscribers that want to start from an earlier point
+    pub channel_history_size: usize,
+    // The directory to persist channel histories to, if they should be
+    pub channel_history_dir: Option<PathBuf>,
+    // How long a channel with no subscribers can go without being used before it's removed, if they should be
+    pub channel_idle_timeout: Option<Duration>,
+    // The maximum number of channels that can exist at once, if there is one
+    pub max_channels: Option<usize>,
+    // The number of shards channels are split across, each with its own lock (1 puts every channel behind the same lock)
+    pub channel_shards: usize,
+    // The directory to persist scheduled messages to, if they should be
+    pub schedule_dir: Option<PathBuf>,
+    // Where webhooks are registered and how messages are sent to them
+    pub webhooks: WebhookConfig,
+    // The longest a poll will wait for new messages before returning with none
+    pub max_poll_wait: Duration,
+    // How often clients on WebSockets are checked to still be there
+    pub ws_keep_alive_interval: Duration,
+    // The Redis server to relay all messages through, which lets multiple replicas of the subscriptions server share channels
+    #[cfg(feature = "redis")]
+    pub redis_url: Option<String>,
+    // The Postgres database to listen for notifications from
+    #[cfg(feature = "postgres")]
+    pub postgres_url: Option<String>,
+    // The Postgres channels to listen on, mapped to the Diana channels their notifications should be delivered on
+    #[cfg(feature = "postgres")]
+    pub postgres_channels: HashMap<String, String>,
+}
+impl PubSubConfig {
+    // Gets the number of messages the given channel (or channel pattern) should buffer for each subscriber
+    fn buffer_size_for(&self, channel: &str) -> usize {
+        self.channel_buffer_sizes
+            .get(channel)
+            .copied()
+            .unwrap_or(self.channel_buffer_size)
+    }
+}
+impl Default for PubSubConfig {
+    fn default() -> Self {
+        Self {
+            channel_buffer_size: DEFAULT_CHANNEL_BUFFER_SIZE,
+            channel_buffer_sizes: HashMap::new(),
+            lag_policy: LagPolicy::default(),
+            channel_history_size: 0,
+            channel_history_dir: None,
+            channel_idle_timeout: None,
+            max_channels: None,
+            channel_shards: DEFAULT_CHANNEL_SHARDS,
+            schedule_dir: None,
+            webhooks: WebhookConfig::default(),
+            max_poll_wait: DEFAULT_MAX_POLL_WAIT,
+            ws_keep_alive_interval: DEFAULT_WS_KEEP_ALIVE_INTERVAL,
+            #[cfg(feature = "redis")]
+            redis_url: None,
+            #[cfg(feature = "postgres")]
+            postgres_url: None,
+            #[cfg(feature = "postgres")]
+            postgres_channels: HashMap::new(),
+        }
+    }
+}
 
-        // Confirm nothing's gone wrong on a GraphQL level (basically only if we got `false` instead of `true`)
-        match body.data.publish {
-            true => Ok(()),
-            _ => bail!(DianaError::SubscriptionDataPublishFailed),
+// A single channel on the subscriptions server
+struct Channel {
+    sender: Sender<Arc<ChannelMessage>>,
+    history: ChannelHistory,
+    // When the channel was last published or subscribed to, which tells us if it's idle
+    last_active: Instant,
+}
+impl Channel {
+    fn new(
+        name: &str,
+        config: &PubSubConfig,
+        history_store: Option<Arc<HistoryStore>>,
+        persisted: Vec<ChannelMessage>,
+        next_seq: u64,
+    ) -> Self {
+        let (sender, _receiver) = create_channel(config.buffer_size_for(name));
+        Self {
+            sender,
+            history: ChannelHistory::new(name, config.channel_history_size, history_store, persisted, next_seq),
+            last_active: Instant::now(),
         }
     }
 }
 
-// Everything from here down operates solely on the subscriptions server, and is stateful!
-// Do NOT import these mechanisms in the serverless system!
+// The relays to other systems, which are started along with the subscriptions server
+struct Relays {
+    // The queue of events for the webhook dispatcher, if webhooks are enabled
+    webhooks: Option<QueueSender<WebhookEvent>>,
+    // The relay messages are published through if we're using Redis, which gives them their sequence numbers
+    #[cfg(feature = "redis")]
+    redis: Option<RedisRelay>,
+}
 
 // This is a traditional PubSub implementation using Tokio's broadcast system
 // This doesn't need to be made available because it's entirely internal
+// Everything here takes `&self` so it can be shared between every connection on the subscriptions server without one big lock, channels are
+// split into shards that are locked separately
 pub struct PubSub {
-    // A hash map of channels to their Tokio broadcasters
-    channels: HashMap<String, Sender<String>>,
+    // Hash maps of channel names to their Tokio broadcasters and histories, a channel lives in the shard its name hashes to
+    channels: Vec<Mutex<HashMap<String, Channel>>>,
+    hasher: RandomState,
+    // The number of channels across every shard (and patterns, which take up room just like channels), which is kept separately so we
+    // don't have to lock them all to check the maximum
+    channel_count: AtomicUsize,
+    // The sequence numbers that removed channels had got up to, so they carry on from there if they're recreated rather than reusing ones
+    // clients have already seen
+    // If a channel's shard needs to be locked too, it must be locked first
+    removed_seqs: Mutex<HashMap<String, u64>>,
+    // A hash map of channel patterns to the Tokio broadcasters for their subscribers, these receive messages from every matching channel
+    // If a channel's shard needs to be locked too, it must be locked first
+    patterns: RwLock<HashMap<String, Sender<Arc<ChannelMessage>>>>,
+    config: PubSubConfig,
+    // Where channel histories are persisted, if they are
+    history_store: Option<Arc<HistoryStore>>,
+    counters: Arc<PubSubCounters>,
+    // Who is subscribed to what, this is shared with every subscriber's stream so they can leave when they're dropped
+    presence: Arc<Presence>,
+    // The messages waiting to be published later, this is shared with the task that publishes them
+    scheduler: Arc<Scheduler>,
+    // When the PubSub was created, and how long after that we last looked for idle channels to remove (in milliseconds)
+    created_at: Instant,
+    last_sweep: AtomicU64,
+    relays: OnceCell<Relays>,
 }
 impl Default for PubSub {
     fn default() -> Self {
-        Self {
-            channels: HashMap::new(),
-        }
+        Self::new(PubSubConfig::default())
     }
 }
 impl PubSub {
-    // Gets a channel or creates a new one if needed
-    fn get_channel(&mut self, channel: &str) -> Sender<String> {
-        let channel_sender = self.channels.get(channel);
-        let channel_sender = match channel_sender {
-            Some(sub) => sub,
+    pub fn new(config: PubSubConfig) -> Self {
+        Self {
+            // There has to be at least one shard to put channels in
+            channels: (0..config.channel_shards.max(1)).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+            channel_count: AtomicUsize::new(0),
+            removed_seqs: Mutex::new(HashMap::new()),
+            patterns: RwLock::new(HashMap::new()),
+            presence: Arc::new(Presence::new(config.buffer_size_for(PRESENCE_CHANNEL.name()))),
+            scheduler: Arc::new(Scheduler::new(config.schedule_dir.as_deref())),
+            // There's nothing to persist if channels don't keep any history
+            history_store: match (&config.channel_history_dir, config.channel_history_size) {
+                (Some(dir), size) if size > 0 => Some(Arc::new(HistoryStore::new(dir))),
+                _ => None,
+            },
+            config,
+            counters: Arc::new(PubSubCounters::default()),
+            created_at: Instant::now(),
+            last_sweep: AtomicU64::new(0),
+            relays: OnceCell::new(),
+        }
+    }
+
+    // Starts any relays to other systems that have been configured, along with the scheduler, this is a no-op if they've already been started
+    // This isn't done on creation because the serverless system builds (but never uses) a PubSub too, so the subscriptions server starts
+    // them when it's created, and they're started on first use as well in case an integration doesn't do that
+    // The relays need a handle to the PubSub itself so they can deliver incoming messages
+    pub fn start_relays(&self, handle: &Arc<PubSub>) {
+        // Anything else using the PubSub waits here until the relays have started, so nothing can be published around them
+        self.relays.get_or_init(|| {
+            // The scheduler only holds a weak handle, so it stops once nothing else is using the PubSub
+            spawn_background(run_scheduler(Arc::downgrade(handle), Arc::clone(&self.scheduler)));
+            #[cfg(feature = "postgres")]
+            if let Some(postgres_url) = &self.config.postgres_url {
+                start_postgres_listener(
+                    postgres_url.to_string(),
+                    self.config.postgres_channels.clone(),
+                    handle,
+                );
+            }
+            Relays {
+                webhooks: self.config.webhooks.registry.as_ref().map(|registry| {
+                    start_webhooks(Arc::clone(registry), self.config.webhooks.clone(), Arc::clone(&self.counters))
+                }),
+                #[cfg(feature = "redis")]
+                redis: self
+                    .config
+                    .redis_url
+                    .as_ref()
+                    .map(|redis_url| start_redis_relay(redis_url.to_string(), handle)),
+            }
+        });
+    }
+
+    // Gets the live counters behind this PubSub's metrics
+    pub fn counters(&self) -> Arc<PubSubCounters> {
+        Arc::clone(&self.counters)
+    }
+
+    // Gets everyone currently subscribed to the given channel (or channel pattern)
+    pub fn presence(&self, channel: &str) -> ChannelPresence {
+        self.presence.get(channel)
+    }
+
+    // Locks the shard the given channel lives in
+    // A poisoned shard just means a panic happened while using one of its channels, they're still usable
+    fn lock_shard(&self, channel: &str) -> MutexGuard<'_, HashMap<String, Channel>> {
+        self.channels[self.shard_index(channel)]
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn shard_index(&self, channel: &str) -> usize {
+        self.hasher.hash_one(channel) as usize % self.channels.len()
+    }
+
+    // Checks whether or not the given channel would have to be created with a history loaded from disk before it could be used
+    // Patterns and the presence channel don't have histories of their own
+    fn needs_history_loaded(&self, channel: &str) -> bool {
+        self.history_store.is_some()
+            && channel != PRESENCE_CHANNEL.name()
+            && !is_channel_pattern(channel)
+            && !self.lock_shard(channel).contains_key(channel)
+    }
+
+    // Loads the persisted history of the given channel if it's about to be created, for `.with_channel()` to create it with
+    // Loading a persisted history means waiting for the history writer, so that's done before the channel's shard is locked
+    async fn load_history(&self, channel: &str) -> Vec<ChannelMessage> {
+        match &self.history_store {
+            Some(store) if self.needs_history_loaded(channel) => store.load(channel).await,
+            _ => Vec::new(),
+        }
+    }
+
+    // Runs the given function on a channel, creating it first if needed (with the given persisted history, see `.load_history()`)
+    // This will fail if the channel doesn't exist and there's no room for it, or if it's reserved for the subscriptions server itself
+    fn with_channel<T>(
+        &self,
+        channel: &str,
+        persisted: Vec<ChannelMessage>,
+        f: impl FnOnce(&mut Channel) -> T,
+    ) -> Result<T> {
+        check_publishable(channel)?;
+        // Idle channels are removed as we go, which saves having a separate task for it
+        if let Some(idle_timeout) = self.config.channel_idle_timeout {
+            let since_sweep = (self.created_at.elapsed().as_millis() as u64).saturating_sub(self.last_sweep.load(Ordering::Relaxed));
+            if since_sweep >= idle_timeout.as_millis() as u64 {
+                self.remove_idle_channels();
+            }
+        }
+        self.make_room_for(&[channel])?;
+
+        let mut shard = self.lock_shard(channel);
+        let channel = match shard.entry(channel.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                // Other channels could have been created since we made room, so this is where the channel's place is actually taken
+                self.claim_channel_slot()?;
+                let next_seq = self
+                    .removed_seqs
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner())
+                    .remove(channel)
+                    .unwrap_or(0);
+                entry.insert(Channel::new(channel, &self.config, self.history_store.clone(), persisted, next_seq))
+            }
+        };
+        channel.last_active = Instant::now();
+
+        Ok(f(channel))
+    }
+
+    // Counts a new channel, failing if that would go over the maximum number of channels
+    fn claim_channel_slot(&self) -> Result<()> {
+        let max_channels = match self.config.max_channels {
+            Some(max_channels) => max_channels,
             None => {
-                let (channel_sender, _receiver) = create_channel(MESSAGES_TO_BE_RETAINED);
-                self.channels.insert(channel.to_string(), channel_sender); // We put a clone into the HashMap because broadcast can be multi-producer
-                self.channels.get(channel).unwrap() // We just added it, we know more than the compiler
+                self.channel_count.fetch_add(1, Ordering::SeqCst);
+                return Ok(());
             }
         };
+        let res = self
+            .channel_count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                if count < max_channels {
+                    Some(count + 1)
+                } else {
+                    None
+                }
+            });
+        if res.is_err() {
+            bail!(DianaError::TooManyChannels(max_channels));
+        }
 
-        channel_sender.clone()
+        Ok(())
     }
 
-    pub fn subscribe(&mut self, channel: &str) -> impl Stream<Item = String> {
-        let channel_sender = self.get_channel(channel);
-        let mut receiver = channel_sender.subscribe();
+    // Makes sure any of the given channels (or channel patterns) that don't exist yet could be created without going over the maximum
+    // number of channels
+    // If they couldn't, idle channels are removed to make room, and this fails if that doesn't free up enough
+    fn make_room_for(&self, channels: &[&str]) -> Result<()> {
+        let max_channels = match self.config.max_channels {
+            Some(max_channels) => max_channels,
+            None => return Ok(()),
+        };
+        let mut new_channels = channels
+            .iter()
+            .filter(|channel| {
+                if is_channel_pattern(channel) {
+                    !self.patterns.read().unwrap_or_else(|err| err.into_inner()).contains_key(**channel)
+                } else {
+                    !self.lock_shard(channel).contains_key(**channel)
+                }
+            })
+            .collect::<Vec<_>>();
+        new_channels.sort();
+        new_channels.dedup();
+        if self.channel_count.load(Ordering::SeqCst) + new_channels.len() > max_channels {
+            self.remove_idle_channels();
+        }
+        if self.channel_count.load(Ordering::SeqCst) + new_channels.len() > max_channels {
+            bail!(DianaError::TooManyChannels(max_channels));
+        }
+
+        Ok(())
+    }
 
-        stream! {
+    // Removes every channel that has no subscribers and hasn't been used for the idle timeout, along with any patterns nobody is subscribed
+    // to any more
+    // Their histories go with them, unless they're persisted, in which case they'll be loaded again if the channel is recreated
+    // This locks every shard in turn, so it must never be called while one is already locked
+    fn remove_idle_channels(&self) {
+        self.last_sweep
+            .store(self.created_at.elapsed().as_millis() as u64, Ordering::Relaxed);
+        self.remove_abandoned_patterns();
+        let idle_timeout = match self.config.channel_idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return,
+        };
+        for shard in &self.channels {
+            let mut shard = shard.lock().unwrap_or_else(|err| err.into_inner());
+            let idle_channels = shard
+                .iter()
+                .filter(|(_, channel)| {
+                    channel.sender.receiver_count() == 0 && channel.last_active.elapsed() >= idle_timeout
+                })
+                .map(|(name, _)| name.to_string())
+                .collect::<Vec<_>>();
+            for name in &idle_channels {
+                if let Some(channel) = shard.remove(name) {
+                    self.remember_removed_seq(name, &channel);
+                }
+            }
+            self.channel_count.fetch_sub(idle_channels.len(), Ordering::SeqCst);
+            self.counters
+                .channels_removed
+                .fetch_add(idle_channels.len() as u64, Ordering::Relaxed);
+        }
+    }
+
+    // Keeps the sequence number the given channel had got up to, for when it's recreated
+    // This is called with the channel's shard locked, so nothing can recreate it in the meantime
+    fn remember_removed_seq(&self, name: &str, channel: &Channel) {
+        let next_seq = channel.history.next_seq();
+        if next_seq > 0 {
+            self.removed_seqs
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .insert(name.to_string(), next_seq);
+        }
+    }
+
+    // Removes every pattern nobody is subscribed to any more, there's no way to know when their last subscriber leaves, so this is done
+    // whenever we come across them
+    fn remove_abandoned_patterns(&self) {
+        let mut patterns = self.patterns.write().unwrap_or_else(|err| err.into_inner());
+        let patterns_before = patterns.len();
+        patterns.retain(|_, sender| sender.receiver_count() > 0);
+        self.channel_count
+            .fetch_sub(patterns_before - patterns.len(), Ordering::SeqCst);
+    }
+
+    // Subscribes to every channel matching the given pattern, including ones that haven't been published on yet
+    // This returns the receiver for new messages, along with the messages to replay and the number missed, just like a channel's history
+    // Patterns take up room just like channels, so this will fail if there's no room for a new one
+    fn subscribe_to_pattern(
+        &self,
+        pattern: &str,
+        start_from: &StartFrom,
+    ) -> Result<Replay> {
+        self.make_room_for(&[pattern])?;
+        // The patterns are unlocked again before we touch any shards, which is the opposite order to delivery
+        let receiver = match self
+            .patterns
+            .write()
+            .unwrap_or_else(|err| err.into_inner())
+            .entry(pattern.to_string())
+        {
+            Entry::Occupied(entry) => entry.get().subscribe(),
+            Entry::Vacant(entry) => {
+                // Other channels could have been created since we made room, so this is where the pattern's place is actually taken
+                self.claim_channel_slot()?;
+                entry
+                    .insert(create_channel(self.config.buffer_size_for(pattern)).0)
+                    .subscribe()
+            }
+        };
+
+        let mut replay = Vec::new();
+        let mut missed = 0;
+        for shard in &self.channels {
+            let shard = shard.lock().unwrap_or_else(|err| err.into_inner());
+            for (name, channel) in shard.iter() {
+                if channel_matches_pattern(pattern, name) {
+                    let (channel_replay, channel_missed) = channel.history.replay(start_from);
+                    replay.extend(channel_replay);
+                    missed += channel_missed;
+                }
+            }
+        }
+        // This is a stable sort, so messages published at the same time on one channel stay in order
+        replay.sort_by_key(|message| message.published_at);
+
+        Ok((receiver, replay, missed))
+    }
+
+    // Gets a receiver for new messages on the given channel (or every channel matching it if it's a pattern), along with the messages to
+    // replay from the given point and the number of those that are no longer in the history
+    // We subscribe before looking at the history so nothing can be published in between, callers have to skip anything they get from both
+    // A channel that has to be created is given the persisted history from `.load_history()`
+    fn receive_from(
+        &self,
+        channel: &str,
+        start_from: &StartFrom,
+        persisted: Vec<ChannelMessage>,
+    ) -> Result<Replay> {
+        if channel == PRESENCE_CHANNEL.name() {
+            // The presence channel has no history
+            Ok((self.presence.subscribe(), Vec::new(), 0))
+        } else if is_channel_pattern(channel) {
+            self.subscribe_to_pattern(channel, start_from)
+        } else {
+            self.with_channel(channel, persisted, |channel| {
+                let receiver = channel.sender.subscribe();
+                let (replay, missed) = channel.history.replay(start_from);
+                (receiver, replay, missed)
+            })
+        }
+    }
+
+    // Subscribes to the given channel, or to every channel matching it if it's a pattern
+    // The subscriber's authentication state is needed for any filter it's given
+    // If the channel has to be created with a persisted history, that can't be waited for here, so the subscriber only starts receiving
+    // from it once the stream is first polled (and anything published before then is replayed from the history)
+    pub fn subscribe(
+        self: &Arc<Self>,
+        channel: &str,
+        opts: SubscribeOptions,
+        auth_state: AuthState,
+    ) -> Result<impl Stream<Item = ChannelEvent>> {
+        let lag_policy = self.config.lag_policy;
+        let counters = self.counters();
+        let pending = if self.needs_history_loaded(channel) {
+            // We can still say straight away if there's no room for the channel
+            check_publishable(channel)?;
+            self.make_room_for(&[channel])?;
+            let start_from = match opts.start_from {
+                StartFrom::Now => StartFrom::Timestamp(Utc::now()),
+                start_from => start_from,
+            };
+            PendingReceiver::Loading(Arc::clone(self), channel.to_string(), start_from)
+        } else {
+            PendingReceiver::Ready(self.receive_from(channel, &opts.start_from, Vec::new())?)
+        };
+        // Subscribers to the presence channel aren't tracked, otherwise watching presence would change it
+        let presence_guard = if channel == PRESENCE_CHANNEL.name() {
+            None
+        } else {
+            Some(self.presence.join(channel, &auth_state))
+        };
+        let filter = opts.filter;
+        let accepts = move |message: &ChannelMessage| match &filter {
+            Some(filter) => filter.matches(message, &auth_state),
+            None => true,
+        };
+
+        Ok(stream! {
+            // The subscriber leaves the channel when this is dropped along with the stream
+            let _presence_guard = presence_guard;
+            let (mut receiver, replay, missed) = match pending {
+                PendingReceiver::Ready(received) => received,
+                PendingReceiver::Loading(pubsub, channel, start_from) => {
+                    let persisted = pubsub.load_history(&channel).await;
+                    match pubsub.receive_from(&channel, &start_from, persisted) {
+                        Ok(received) => received,
+                        // Other channels have taken the room there was for this one since we checked
+                        Err(_) => return,
+                    }
+                }
+            };
+            // Sequence numbers are only ordered within a channel, so we keep track of the last one replayed from each
+            let mut last_replayed_seqs = HashMap::new();
+            for message in &replay {
+                let last_seq = last_replayed_seqs.entry(message.channel.clone()).or_insert(message.seq);
+                *last_seq = (*last_seq).max(message.seq);
+            }
+            // Messages that were asked for but are no longer in the history are treated just like ones dropped by lagging
+            if missed > 0 {
+                counters.messages_dropped.fetch_add(missed, Ordering::Relaxed);
+                match lag_policy {
+                    LagPolicy::Skip => (),
+                    LagPolicy::Disconnect => {
+                        counters.subscribers_disconnected.fetch_add(1, Ordering::Relaxed);
+                        return;
+                    },
+                    LagPolicy::Notify => yield ChannelEvent::Lagged(missed),
+                }
+            }
+            for message in replay {
+                if accepts(&message) {
+                    yield ChannelEvent::Message((*message).clone());
+                }
+            }
             loop {
                 let message = receiver.recv().await;
                 match message {
-                    Ok(message) => yield message,
-                    _ => continue
+                    Ok(message) => {
+                        // Skip anything we've already replayed from the history
+                        if let Some(last_seq) = last_replayed_seqs.get(&message.channel).copied() {
+                            if message.seq <= last_seq {
+                                continue;
+                            }
+                            last_replayed_seqs.remove(&message.channel);
+                        }
+                        // The filter sees the shared message, so only what the subscriber actually wants is cloned
+                        if accepts(&message) {
+                            yield ChannelEvent::Message((*message).clone())
+                        }
+                    },
+                    // The subscriber has fallen behind, the next `.recv()` will carry on from the oldest message still buffered
+                    Err(RecvError::Lagged(missed)) => {
+                        counters.messages_dropped.fetch_add(missed, Ordering::Relaxed);
+                        match lag_policy {
+                            LagPolicy::Skip => continue,
+                            LagPolicy::Disconnect => {
+                                counters.subscribers_disconnected.fetch_add(1, Ordering::Relaxed);
+                                break;
+                            },
+                            LagPolicy::Notify => yield ChannelEvent::Lagged(missed),
+                        }
+                    },
+                    // The channel has been closed, so nothing more will ever arrive
+                    Err(RecvError::Closed) => break,
                 }
             }
+        })
+    }
+
+    // Gets the messages the given function accepts on the given channel (or channel pattern) from the given point, along with the number
+    // asked for that are no longer in the history (or were dropped while waiting)
+    // If there aren't any yet, this waits for up to the given time (capped by the configured maximum) for some to be published
+    // This is for clients that poll instead of holding a subscription open, so they don't count towards presence
+    pub async fn poll(
+        &self,
+        channel: &str,
+        start_from: &StartFrom,
+        wait: Duration,
+        accepts: impl Fn(&ChannelMessage) -> bool + Send + 'static,
+    ) -> Result<(Vec<Arc<ChannelMessage>>, u64)> {
+        let wait = wait.min(self.config.max_poll_wait);
+        let persisted = self.load_history(channel).await;
+        let (mut receiver, replay, missed) = self.receive_from(channel, start_from, persisted)?;
+        let replay = replay
+            .into_iter()
+            .filter(|message| accepts(message))
+            .collect::<Vec<_>>();
+        if !replay.is_empty() || missed > 0 || wait == Duration::ZERO {
+            return Ok((replay, missed));
         }
+
+        // Waiting needs a timer, and the integration might not be on a runtime with one we can use
+        let counters = self.counters();
+        let res = run_in_background(async move {
+            let deadline = tokio::time::Instant::now() + wait;
+            let mut messages = Vec::new();
+            let mut missed = 0;
+            // We wait for the first message, then take whatever else has already arrived with it
+            while messages.is_empty() {
+                match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                    Ok(Ok(message)) if accepts(&message) => messages.push(message),
+                    Ok(Ok(_)) => continue,
+                    Ok(Err(RecvError::Lagged(lagged))) => missed += lagged,
+                    // Either we've run out of time or the channel has been closed
+                    Ok(Err(RecvError::Closed)) | Err(_) => break,
+                }
+            }
+            loop {
+                match receiver.try_recv() {
+                    Ok(message) if accepts(&message) => messages.push(message),
+                    Ok(_) => continue,
+                    Err(TryRecvError::Lagged(lagged)) => missed += lagged,
+                    Err(_) => break,
+                }
+            }
+            (messages, missed)
+        })
+        .await;
+        counters.messages_dropped.fetch_add(res.1, Ordering::Relaxed);
+
+        Ok(res)
     }
 
-    // Creates a new sender for a given channel name if one doesn't exist and then sends a message using it
-    pub fn publish(&mut self, channel: &str, data: String) {
-        let channel_sender = self.get_channel(channel);
-        // This will fail only if there are now receivers, but we don't care if that's the case
-        let _ = channel_sender.send(data);
+    // Publishes a message on the given channel, returning the number of subscribers it was sent to
+    // If we're relaying through Redis, the message will come back to us (and every other replica) from there to be delivered, so we can only
+    // say how many subscribers this replica has for it right now
+    pub async fn publish(&self, channel: &str, data: String, envelope: MessageEnvelope) -> Result<usize> {
+        check_publishable(channel)?;
+        // We check there's room for the channel here too, otherwise a message relayed through Redis would fail where we couldn't report it
+        self.make_room_for(&[channel])?;
+        let published_at = Utc::now();
+        #[cfg(feature = "redis")]
+        if let Some(redis) = self.relays.get().and_then(|relays| relays.redis.as_ref()) {
+            let message = ChannelMessage {
+                channel: channel.to_string(),
+                seq: 0,
+                published_at,
+                data,
+                envelope,
+            };
+            // If Redis is unavailable, this fails rather than delivering locally, which would only reach some subscribers
+            redis.queue(vec![message])?;
+            return Ok(self.count_subscribers(channel));
+        }
+
+        self.deliver(channel, data, envelope, published_at).await
+    }
+
+    // Publishes all the given messages in order, either all together or not at all
+    // Nothing else can be published on the channels involved in between them, and subscribers to each channel will receive them in order
+    // This returns the number of subscribers each message was sent to
+    pub async fn publish_many(&self, messages: Vec<(String, String, MessageEnvelope)>) -> Result<Vec<usize>> {
+        let mut channels = messages
+            .iter()
+            .map(|(channel, _, _)| channel.as_str())
+            .collect::<Vec<_>>();
+        for channel in &channels {
+            check_publishable(channel)?;
+        }
+        self.make_room_for(&channels)?;
+        let published_at = Utc::now();
+        // Redis publishes the whole batch in one go
+        #[cfg(feature = "redis")]
+        if let Some(redis) = self.relays.get().and_then(|relays| relays.redis.as_ref()) {
+            let batch = messages
+                .iter()
+                .map(|(channel, data, envelope)| ChannelMessage {
+                    channel: channel.to_string(),
+                    seq: 0,
+                    published_at,
+                    data: data.to_string(),
+                    envelope: envelope.clone(),
+                })
+                .collect();
+            redis.queue(batch)?;
+            return Ok(channels
+                .iter()
+                .map(|channel| self.count_subscribers(channel))
+                .collect());
+        }
+
+        channels.sort_unstable();
+        channels.dedup();
+        loop {
+            // Creating a channel is the only thing that can fail, so they're all created before anything is published
+            for channel in &channels {
+                let persisted = self.load_history(channel).await;
+                self.with_channel(channel, persisted, |_| ())?;
+            }
+            // Every shard involved stays locked while the messages are published, so nothing else can be published in between them
+            // They're locked in order, so two batches can never each be waiting for a shard the other has
+            let mut shard_indices = channels.iter().map(|channel| self.shard_index(channel)).collect::<Vec<_>>();
+            shard_indices.sort_unstable();
+            shard_indices.dedup();
+            let mut shards = shard_indices
+                .into_iter()
+                .map(|idx| (idx, self.channels[idx].lock().unwrap_or_else(|err| err.into_inner())))
+                .collect::<HashMap<_, _>>();
+            // A channel can only have been removed since we created it if the idle timeout is very short, we just create it again
+            if channels
+                .iter()
+                .any(|channel| !shards[&self.shard_index(channel)].contains_key(*channel))
+            {
+                continue;
+            }
+
+            let mut subscribers = Vec::with_capacity(messages.len());
+            for (name, data, envelope) in messages {
+                // We know more than the compiler here, we've just checked every channel is there
+                let channel = shards
+                    .get_mut(&self.shard_index(&name))
+                    .and_then(|shard| shard.get_mut(&name))
+                    .unwrap();
+                channel.last_active = Instant::now();
+                let message = channel.history.record(data, envelope, published_at);
+                subscribers.push(self.send(channel, &message));
+            }
+            self.counters
+                .messages_published
+                .fetch_add(subscribers.len() as u64, Ordering::Relaxed);
+
+            return Ok(subscribers);
+        }
+    }
+
+    // Schedules a message to be published on the given channel at the given time, replacing any other with the same ID
+    // This only succeeds once the message has been persisted, if scheduled messages are being persisted, which is done on a thread that's
+    // allowed to block
+    pub async fn schedule(
+        &self,
+        id: String,
+        channel: &str,
+        data: String,
+        envelope: MessageEnvelope,
+        at: DateTime<Utc>,
+    ) -> Result<()> {
+        check_publishable(channel)?;
+        let message = ScheduledMessage {
+            id,
+            channel: channel.to_string(),
+            data,
+            envelope,
+            at,
+        };
+        let scheduler = Arc::clone(&self.scheduler);
+        run_blocking_in_background(move || scheduler.schedule(message))
+            .await
+            .map_err(|err| DianaError::ScheduledMessageNotPersisted(err.to_string()))?;
+
+        Ok(())
+    }
+
+    // Cancels the scheduled message with the given ID, returning whether or not there was one to cancel
+    pub async fn cancel_scheduled(&self, id: &str) -> Result<bool> {
+        let scheduler = Arc::clone(&self.scheduler);
+        let id = id.to_string();
+        let cancelled = run_blocking_in_background(move || scheduler.cancel(&id))
+            .await
+            .map_err(|err| DianaError::ScheduledMessageNotPersisted(err.to_string()))?;
+        Ok(cancelled)
+    }
+
+    // Registers the given webhook, so it's sent every message on its channel from now on
+    // The registry could be doing anything (e.g. writing a file), so it's used on a thread that's allowed to block
+    pub async fn register_webhook(&self, webhook: Webhook) -> Result<()> {
+        let registry = match &self.config.webhooks.registry {
+            Some(registry) => Arc::clone(registry),
+            None => bail!(DianaError::WebhooksNotConfigured),
+        };
+        if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+            bail!(DianaError::InvalidWebhookUrl(
+                webhook.url,
+                "only HTTP(S) URLs are supported".to_string()
+            ));
+        }
+        run_blocking_in_background(move || registry.register(webhook)).await?;
+        self.notify_webhooks_changed();
+
+        Ok(())
+    }
+
+    // Unregisters the webhook with the given ID, returning whether or not there was one
+    pub async fn unregister_webhook(&self, id: &str) -> Result<bool> {
+        let registry = match &self.config.webhooks.registry {
+            Some(registry) => Arc::clone(registry),
+            None => bail!(DianaError::WebhooksNotConfigured),
+        };
+        let id = id.to_string();
+        let unregistered = run_blocking_in_background(move || registry.unregister(&id)).await?;
+        self.notify_webhooks_changed();
+
+        Ok(unregistered)
+    }
+
+    // Makes the webhook dispatcher reload the registry before it sends anything else
+    fn notify_webhooks_changed(&self) {
+        if let Some(webhooks) = self.relays.get().and_then(|relays| relays.webhooks.as_ref()) {
+            queue_webhook_event(webhooks, WebhookEvent::RegistryChanged, &self.counters);
+        }
+    }
+
+    // Gets the number of subscribers a message published on the given channel right now would be sent to, including through patterns
+    #[cfg_attr(not(feature = "redis"), allow(dead_code))]
+    fn count_subscribers(&self, channel: &str) -> usize {
+        let channel_subscribers = self
+            .lock_shard(channel)
+            .get(channel)
+            .map(|channel| channel.sender.receiver_count())
+            .unwrap_or(0);
+        let pattern_subscribers = self
+            .patterns
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+            .filter(|(pattern, _)| channel_matches_pattern(pattern, channel))
+            .map(|(_, sender)| sender.receiver_count())
+            .sum::<usize>();
+
+        channel_subscribers + pattern_subscribers
+    }
+
+    // Creates a new sender for a given channel name if one doesn't exist and then sends a message using it to local subscribers
+    // The message will be given the next sequence number on the channel, and this returns the number of subscribers it was sent to
+    pub async fn deliver(
+        &self,
+        channel: &str,
+        data: String,
+        envelope: MessageEnvelope,
+        published_at: DateTime<Utc>,
+    ) -> Result<usize> {
+        let persisted = self.load_history(channel).await;
+        let subscribers = self.with_channel(channel, persisted, |channel| {
+            let message = channel.history.record(data, envelope, published_at);
+            self.send(channel, &message)
+        })?;
+        self.counters.messages_published.fetch_add(1, Ordering::Relaxed);
+
+        Ok(subscribers)
+    }
+
+    // The same as `.deliver()`, but for a message that's already been given a sequence number (e.g. by Redis)
+    #[cfg_attr(not(feature = "redis"), allow(dead_code))]
+    pub async fn deliver_sequenced(&self, channel: &str, mut message: ChannelMessage) -> Result<usize> {
+        // Messages from older versions won't say which channel they're on
+        message.channel = channel.to_string();
+        let message = Arc::new(message);
+        let persisted = self.load_history(channel).await;
+        let subscribers = self.with_channel(channel, persisted, |channel| {
+            channel.history.record_sequenced(Arc::clone(&message));
+            self.send(channel, &message)
+        })?;
+        self.counters.messages_published.fetch_add(1, Ordering::Relaxed);
+
+        Ok(subscribers)
+    }
+
+    // Sends the given message to the subscribers of its channel and every pattern that matches it, returning how many there were
+    // This is called with the channel's shard still locked, so that pattern subscribers receive each channel's messages in order too
+    fn send(&self, channel: &Channel, message: &Arc<ChannelMessage>) -> usize {
+        // This will fail only if there are no receivers, which means nobody received it
+        let channel_subscribers = channel.sender.send(Arc::clone(message)).unwrap_or(0);
+
+        let mut pattern_subscribers = 0;
+        let mut abandoned_patterns = false;
+        for (pattern, sender) in self.patterns.read().unwrap_or_else(|err| err.into_inner()).iter() {
+            if sender.receiver_count() == 0 {
+                abandoned_patterns = true;
+            } else if channel_matches_pattern(pattern, &message.channel) {
+                pattern_subscribers += sender.send(Arc::clone(message)).unwrap_or(0);
+            }
+        }
+        if abandoned_patterns {
+            self.remove_abandoned_patterns();
+        }
+        // Webhooks don't count as subscribers, they're sent messages in the background
+        if let Some(webhooks) = self.relays.get().and_then(|relays| relays.webhooks.as_ref()) {
+            queue_webhook_event(webhooks, WebhookEvent::Message(Arc::clone(message)), &self.counters);
+        }
+
+        channel_subscribers + pattern_subscribers
     }
 
     // Drops the handle to a sender for the given channel
     // All receiver calls after this point will result in a closed channel error
     // This doesn't need to be explicitly called normally
-    pub fn close_channel(&mut self, channel: &str) {
-        self.channels.remove(channel);
+    pub fn close_channel(&self, channel: &str) {
+        let mut shard = self.lock_shard(channel);
+        if let Some(closed) = shard.remove(channel) {
+            self.remember_removed_seq(channel, &closed);
+            self.channel_count.fetch_sub(1, Ordering::SeqCst);
+        }
     }
 }
+
+// Makes sure nothing is ever published on the channels the subscriptions server uses itself, or on a channel named like a pattern (which
+// would match patterns it was never meant to)
+fn check_publishable(channel: &str) -> Result<()> {
+    if channel == PRESENCE_CHANNEL.name() {
+        bail!(DianaError::ReservedChannel(channel.to_string()));
+    }
+    if is_channel_pattern(channel) {
+        bail!(DianaError::InvalidChannelName(channel.to_string()));
+    }
+
+    Ok(())
+}
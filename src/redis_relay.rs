@@ -0,0 +1,199 @@
+// This module relays channel messages through Redis so that every replica of the subscriptions server receives every message
+// Without this, a message would only reach the clients connected to whichever replica happened to receive the publish request
+
+use anyhow::{bail, Result};
+use chrono::Utc;
+use redis::{aio::MultiplexedConnection, Client, RedisResult};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use tokio_stream::StreamExt;
+
+use crate::background::spawn_background;
+use crate::envelope::MessageEnvelope;
+use crate::errors::DianaError;
+use crate::pubsub::{ChannelMessage, PubSub};
+
+// All Diana channels are namespaced in Redis with this so they don't collide with anything else using the same server
+const REDIS_CHANNEL_PREFIX: &str = "diana:";
+// The sequence numbers for each channel are kept in Redis under keys with this prefix, so every replica agrees on them
+const REDIS_SEQUENCE_PREFIX: &str = "diana-seq:";
+// Publishes a batch of messages, each of which is an argument (with everything but its sequence number) and a pair of keys (where its
+// channel's sequence number is kept and the channel itself), taking the next sequence number on each message's channel for it
+// Sequence numbers start at 0, but `INCR` starts at 1
+const SEND_SCRIPT: &str = r#"
+for i = 1, #ARGV do
+    local seq = redis.call('INCR', KEYS[i * 2 - 1]) - 1
+    redis.call('PUBLISH', KEYS[i * 2], '{"seq":' .. string.format('%d', seq) .. ',' .. string.sub(ARGV[i], 2))
+end
+"#;
+// How long to wait before trying to reconnect to Redis after the connection fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+// The number of batches of messages that can be waiting to be sent to Redis before publishing fails
+const REDIS_QUEUE_SIZE: usize = 1024;
+
+// A running relay through Redis, which messages to be published are queued on
+pub struct RedisRelay {
+    outbound: Sender<Vec<ChannelMessage>>,
+    // Whether or not we're connected to Redis to publish messages and to receive them, each of these is cleared when its connection fails
+    // and set again when it's back
+    // These start out set so messages published while the relay is first connecting are queued rather than refused
+    publishing: Arc<AtomicBool>,
+    listening: Arc<AtomicBool>,
+}
+impl RedisRelay {
+    // Queues the given batch of messages to be published through Redis, from where they'll be delivered to every replica (including this
+    // one), either all together or not at all
+    // This fails if we've lost our connection to Redis (in either direction) or too many messages are already waiting, because the messages
+    // wouldn't be delivered anywhere for a while, and this replica can't deliver them itself without their sequence numbers clashing with
+    // those Redis gives out
+    pub fn queue(&self, batch: Vec<ChannelMessage>) -> Result<()> {
+        self.check_available()?;
+        match self.outbound.try_send(batch) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(_)) => bail!(DianaError::RelayUnavailable(
+                "too many messages are waiting to be sent to redis".to_string()
+            )),
+            // The relay only stops when the PubSub is dropped, so this shouldn't happen
+            Err(TrySendError::Closed(_)) => bail!(DianaError::RelayUnavailable("the relay to redis has stopped".to_string())),
+        }
+    }
+
+    // Checks that messages can be relayed through Redis right now
+    pub fn check_available(&self) -> Result<()> {
+        if !self.publishing.load(Ordering::Relaxed) || !self.listening.load(Ordering::Relaxed) {
+            bail!(DianaError::RelayUnavailable("not connected to redis".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+// Starts relaying messages through the Redis server at the given URL
+// Messages queued on the returned relay will arrive back at the given PubSub through Redis
+// Their sequence numbers are ignored, they'll be given new ones here
+pub fn start_redis_relay(redis_url: String, pubsub: &Arc<PubSub>) -> RedisRelay {
+    let (outbound_tx, outbound_rx) = channel(REDIS_QUEUE_SIZE);
+    let publishing = Arc::new(AtomicBool::new(true));
+    let listening = Arc::new(AtomicBool::new(true));
+
+    spawn_background(publish_to_redis(redis_url.clone(), outbound_rx, Arc::clone(&publishing)));
+    spawn_background(deliver_from_redis(redis_url, Arc::clone(pubsub), Arc::clone(&listening)));
+
+    RedisRelay {
+        outbound: outbound_tx,
+        publishing,
+        listening,
+    }
+}
+
+// Publishes every batch of messages sent into the given queue to Redis, reconnecting as necessary
+// Batches are sent in order, and a batch that fails to send will be retried after reconnecting
+async fn publish_to_redis(redis_url: String, mut outbound_rx: Receiver<Vec<ChannelMessage>>, connected: Arc<AtomicBool>) {
+    let mut pending: Option<Vec<ChannelMessage>> = None;
+    loop {
+        let mut conn = match get_publishing_connection(&redis_url).await {
+            Ok(conn) => conn,
+            Err(_) => {
+                connected.store(false, Ordering::Relaxed);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        connected.store(true, Ordering::Relaxed);
+        loop {
+            let batch = match pending.take() {
+                Some(batch) => batch,
+                None => match outbound_rx.recv().await {
+                    Some(batch) => batch,
+                    // The PubSub has been dropped, so there's nothing left to do
+                    None => return,
+                },
+            };
+            let res = send_to_redis(&mut conn, &batch).await;
+            if res.is_err() {
+                // Hold on to the batch and reconnect
+                connected.store(false, Ordering::Relaxed);
+                pending = Some(batch);
+                break;
+            }
+        }
+    }
+}
+
+// Sends a batch of messages to Redis, each with the next sequence number for its channel
+// Taking the sequence numbers and publishing happen in one script, which Redis runs atomically, so messages are always published in the
+// order of their sequence numbers, even with many replicas publishing at once (otherwise subscribers resuming from a later message
+// could miss an earlier one that was published after it), and nothing else can be published in the middle of a batch
+// If sending fails after the sequence numbers have been taken, those numbers will just be skipped (subscribers won't mind)
+async fn send_to_redis(conn: &mut MultiplexedConnection, batch: &[ChannelMessage]) -> RedisResult<()> {
+    let mut cmd = redis::cmd("EVAL");
+    cmd.arg(SEND_SCRIPT).arg(batch.len() * 2);
+    for message in batch {
+        cmd.arg(REDIS_SEQUENCE_PREFIX.to_string() + &message.channel)
+            .arg(REDIS_CHANNEL_PREFIX.to_string() + &message.channel);
+    }
+    for message in batch {
+        // The script puts the sequence number into the message itself, so we give it everything else
+        // We know more than the compiler here, this will always serialize (and to an object with other fields in it)
+        let mut fields = serde_json::to_value(message).unwrap();
+        if let Some(fields) = fields.as_object_mut() {
+            fields.remove("seq");
+        }
+        cmd.arg(fields.to_string());
+    }
+    cmd.query_async(conn).await
+}
+
+async fn get_publishing_connection(redis_url: &str) -> RedisResult<MultiplexedConnection> {
+    let client = Client::open(redis_url)?;
+    client.get_multiplexed_tokio_connection().await
+}
+
+// Listens to every Diana channel on Redis and delivers the messages to local subscribers, reconnecting as necessary
+async fn deliver_from_redis(redis_url: String, pubsub: Arc<PubSub>, connected: Arc<AtomicBool>) {
+    loop {
+        // This will only return if the connection fails in some way
+        let _ = listen_to_redis(&redis_url, &pubsub, &connected).await;
+        connected.store(false, Ordering::Relaxed);
+        // If the PubSub has been dropped everywhere else, nobody's listening anymore
+        if Arc::strong_count(&pubsub) == 1 {
+            return;
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn listen_to_redis(redis_url: &str, pubsub: &Arc<PubSub>, connected: &AtomicBool) -> RedisResult<()> {
+    let client = Client::open(redis_url)?;
+    let mut redis_pubsub = client.get_async_connection().await?.into_pubsub();
+    redis_pubsub
+        .psubscribe(REDIS_CHANNEL_PREFIX.to_string() + "*")
+        .await?;
+    connected.store(true, Ordering::Relaxed);
+
+    let mut messages = redis_pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let channel = match message
+            .get_channel_name()
+            .strip_prefix(REDIS_CHANNEL_PREFIX)
+        {
+            Some(channel) => channel.to_string(),
+            None => continue,
+        };
+        let payload: String = match message.get_payload() {
+            Ok(payload) => payload,
+            Err(_) => continue, // Anything that isn't a string can't have come from Diana
+        };
+        // We deliver directly here rather than publishing, otherwise we'd send the message straight back to Redis!
+        // If there's no room for the channel, the message has nowhere to go, so it's dropped
+        let _ = match serde_json::from_str::<ChannelMessage>(&payload) {
+            Ok(message) => pubsub.deliver_sequenced(&channel, message).await,
+            // Something other than Diana has published this, so it won't have a sequence number yet
+            Err(_) => pubsub.deliver(&channel, payload, MessageEnvelope::new(), Utc::now()).await,
+        };
+    }
+
+    Ok(())
+}
@@ -0,0 +1,181 @@
+// This module holds messages that have been scheduled to be published later on the subscriptions server
+// They can be persisted to a file so they survive restarts, and a background task publishes each one when it's due
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::background::run_blocking_in_background;
+use crate::envelope::MessageEnvelope;
+use crate::pubsub::PubSub;
+
+const SCHEDULE_FILENAME: &str = "scheduled.json";
+// The longest the scheduler will wait before checking if the PubSub it works for still exists
+const SCHEDULER_IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// A message waiting to be published at a particular time
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ScheduledMessage {
+    pub id: String,
+    pub channel: String,
+    pub data: String,
+    #[serde(default)]
+    pub envelope: MessageEnvelope,
+    pub at: DateTime<Utc>,
+}
+
+pub struct Scheduler {
+    // Scheduled messages by their IDs
+    messages: Mutex<HashMap<String, ScheduledMessage>>,
+    // The file scheduled messages are persisted to, if they are
+    file: Option<PathBuf>,
+    // This is notified whenever a message is scheduled, so the worker can wake up if it's due before anything else
+    scheduled: Notify,
+}
+impl Scheduler {
+    // Creates the scheduler, loading anything persisted in the given directory
+    pub fn new(dir: Option<&Path>) -> Self {
+        let file = dir.map(|dir| dir.join(SCHEDULE_FILENAME));
+        // A file that can't be read is treated as not being there, it'll be replaced the next time something is scheduled
+        let messages = file
+            .as_ref()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .and_then(|persisted| serde_json::from_str::<Vec<ScheduledMessage>>(&persisted).ok())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|message| (message.id.clone(), message))
+            .collect();
+
+        Self {
+            messages: Mutex::new(messages),
+            file,
+            scheduled: Notify::new(),
+        }
+    }
+
+    // Schedules the given message, replacing any other with the same ID, and only returns once it's persisted
+    pub fn schedule(&self, message: ScheduledMessage) -> io::Result<()> {
+        {
+            let mut messages = self.lock();
+            let replaced = messages.insert(message.id.clone(), message.clone());
+            if let Err(err) = self.persist(&messages) {
+                // The caller will be told this failed, so it mustn't be published
+                match replaced {
+                    Some(replaced) => messages.insert(message.id, replaced),
+                    None => messages.remove(&message.id),
+                };
+                return Err(err);
+            }
+        }
+        self.scheduled.notify_one();
+
+        Ok(())
+    }
+
+    // Cancels the scheduled message with the given ID, returning whether or not there was one
+    pub fn cancel(&self, id: &str) -> io::Result<bool> {
+        let mut messages = self.lock();
+        let cancelled = match messages.remove(id) {
+            Some(cancelled) => cancelled,
+            None => return Ok(false),
+        };
+        if let Err(err) = self.persist(&messages) {
+            messages.insert(id.to_string(), cancelled);
+            return Err(err);
+        }
+
+        Ok(true)
+    }
+
+    // Gets every message that's due to be published, in the order they were scheduled for, along with when the next one after them is due
+    fn due(&self) -> (Vec<ScheduledMessage>, Option<DateTime<Utc>>) {
+        let now = Utc::now();
+        let messages = self.lock();
+        let mut due = messages
+            .values()
+            .filter(|message| message.at <= now)
+            .cloned()
+            .collect::<Vec<_>>();
+        due.sort_by_key(|message| message.at);
+        let next_at = messages
+            .values()
+            .map(|message| message.at)
+            .filter(|at| *at > now)
+            .min();
+
+        (due, next_at)
+    }
+
+    // Removes the given messages once they've been published, unless they've been rescheduled in the meantime
+    // If this can't be persisted, they'll be published again after a restart, which is better than losing them
+    fn remove(&self, published: &[ScheduledMessage]) {
+        let mut messages = self.lock();
+        for message in published {
+            if messages.get(&message.id).map(|scheduled| scheduled.at) == Some(message.at) {
+                messages.remove(&message.id);
+            }
+        }
+        let _ = self.persist(&messages);
+    }
+
+    // The messages are written to a temporary file and then moved into place, so the file is never half-written
+    fn persist(&self, messages: &HashMap<String, ScheduledMessage>) -> io::Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // We know more than the compiler here, this will always serialize
+        let contents = serde_json::to_string(&messages.values().collect::<Vec<_>>()).unwrap();
+        let tmp_path = file.with_extension("json.tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(contents.as_bytes())?;
+        tmp.sync_data()?;
+        fs::rename(tmp_path, file)
+    }
+
+    // A poisoned scheduler just means a panic happened while updating the messages, they're still usable
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, ScheduledMessage>> {
+        self.messages.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+// Publishes scheduled messages on the given PubSub as they fall due, for as long as it exists
+pub async fn run_scheduler(pubsub: Weak<PubSub>, scheduler: Arc<Scheduler>) {
+    loop {
+        let (due, next_at) = scheduler.due();
+        if !due.is_empty() {
+            let pubsub = match pubsub.upgrade() {
+                Some(pubsub) => pubsub,
+                None => return,
+            };
+            // Anything that can't be published now never will be (e.g. because there's no room for its channel), so it's dropped either way
+            for message in &due {
+                let _ = pubsub
+                    .publish(&message.channel, message.data.clone(), message.envelope.clone())
+                    .await;
+            }
+            // Persisting what's left means writing a file, so that's done on a thread that's allowed to block
+            let published_scheduler = Arc::clone(&scheduler);
+            run_blocking_in_background(move || published_scheduler.remove(&due)).await;
+        }
+        if pubsub.strong_count() == 0 {
+            return;
+        }
+
+        // If the next message fell due while we were publishing, we don't wait at all
+        let wait = next_at
+            .map(|next_at| (next_at - Utc::now()).to_std().unwrap_or(Duration::ZERO))
+            .unwrap_or(SCHEDULER_IDLE_CHECK_INTERVAL)
+            .min(SCHEDULER_IDLE_CHECK_INTERVAL);
+        let _ = tokio::time::timeout(wait, scheduler.scheduled.notified()).await;
+    }
+}
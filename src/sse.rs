@@ -0,0 +1,172 @@
+// This module runs subscriptions over Server-Sent Events, for clients that can't use WebSockets (e.g. because a proxy kills the upgrade)
+// Each event carries an ID that records how far through its channels the client has got, which it sends back as `Last-Event-ID` when it
+// reconnects so the subscription can carry on from the channels' histories
+
+use async_graphql::Response;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::mpsc::channel;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::background::spawn_background;
+use crate::channel_history::{ChannelMessage, StartFrom};
+use crate::channel_pattern::is_channel_pattern;
+
+// How long a stream can go without sending anything before a comment is sent to stop proxies from closing it
+const SSE_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+// The number of events that can be waiting to be written to a client before the subscription stops producing more
+const SSE_EVENT_BUFFER_SIZE: usize = 16;
+
+// Where an SSE or polling client has got to, which is what its event IDs (or poll cursors) encode
+// Subscriptions to channel patterns could see any number of channels, so they're tracked by time instead of by channel to keep this small
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct CursorPosition {
+    // The sequence number of the last message the client has been sent from each channel it's subscribed to directly
+    seqs: HashMap<String, u64>,
+    // When the latest message the client has been sent was published
+    at: Option<DateTime<Utc>>,
+    // The sequence numbers of the messages the client has been sent through patterns that were published at exactly that time, which will
+    // be replayed again when the patterns carry on from it
+    #[serde(default)]
+    seqs_at: HashMap<String, u64>,
+}
+impl CursorPosition {
+    // Checks whether the given message has already been sent to the client
+    fn has_sent(&self, message: &ChannelMessage) -> bool {
+        let sent_on_channel = |seqs: &HashMap<String, u64>| {
+            matches!(seqs.get(&message.channel), Some(last_seq) if message.seq <= *last_seq)
+        };
+        match self.at {
+            Some(at) if message.published_at == at => sent_on_channel(&self.seqs) || sent_on_channel(&self.seqs_at),
+            _ => sent_on_channel(&self.seqs),
+        }
+    }
+}
+
+// How far through each channel an SSE or polling client has got, this is inserted into the data of its subscription so the channel helpers can update it
+pub struct SubscriptionCursor {
+    // Where the client got to before it reconnected, which never changes
+    resume_from: CursorPosition,
+    // Where the client has got to now
+    position: Mutex<CursorPosition>,
+}
+impl SubscriptionCursor {
+    // Creates a cursor that carries on from the given event ID, anything that can't be parsed is treated as a fresh start
+    pub fn new(last_event_id: Option<&str>) -> Self {
+        let resume_from = last_event_id
+            .and_then(|last_event_id| base64::decode_config(last_event_id.trim(), base64::URL_SAFE_NO_PAD).ok())
+            .and_then(|decoded| serde_json::from_slice::<CursorPosition>(&decoded).ok())
+            .unwrap_or_default();
+        Self {
+            position: Mutex::new(resume_from.clone()),
+            resume_from,
+        }
+    }
+
+    // Gets where a subscription to the given channel (or channel pattern) should start from to carry on where the client left off, if it's
+    // been anywhere before
+    // Patterns start from when the last message the client saw was published, because it might have missed messages on channels it's never
+    // seen before, and anything it's already seen is skipped by `.advance()`, channels the client hasn't seen anything from are the same
+    pub fn start_from(&self, channel: &str) -> Option<StartFrom> {
+        let last_seq = if is_channel_pattern(channel) {
+            None
+        } else {
+            self.resume_from.seqs.get(channel)
+        };
+        match last_seq {
+            Some(last_seq) => Some(StartFrom::Sequence(last_seq + 1)),
+            None => self.resume_from.at.map(StartFrom::Timestamp),
+        }
+    }
+
+    // Records that the given message from a subscription to the given channel (or channel pattern) is about to be sent to the client,
+    // returning `false` if it's been sent it before
+    pub fn advance(&self, channel: &str, message: &ChannelMessage) -> bool {
+        if self.resume_from.has_sent(message) {
+            return false;
+        }
+        let mut position = self.lock();
+        // Messages on different channels can arrive slightly out of order, but the time we carry on from should never go backwards
+        match position.at {
+            Some(at) if message.published_at <= at => (),
+            _ => {
+                position.at = Some(message.published_at);
+                position.seqs_at.clear();
+            }
+        }
+        if !is_channel_pattern(channel) {
+            position.seqs.insert(message.channel.clone(), message.seq);
+        } else if position.at == Some(message.published_at) {
+            position.seqs_at.insert(message.channel.clone(), message.seq);
+        }
+        true
+    }
+
+    // Gets the ID for the next event, if the client has been sent any messages yet
+    fn event_id(&self) -> Option<String> {
+        let position = self.lock();
+        position.at.map(|_| encode_position(&position))
+    }
+
+    // Gets an ID for where the client has got to, which a client that hasn't been sent any messages yet gets from the given time
+    // Polling clients need this to carry on from the right place even if nothing has been published since they started
+    pub fn id_from(&self, at: DateTime<Utc>) -> String {
+        let mut position = self.lock().clone();
+        position.at.get_or_insert(at);
+        encode_position(&position)
+    }
+
+    // A poisoned cursor just means a panic happened while updating it, the position is still usable
+    fn lock(&self) -> MutexGuard<'_, CursorPosition> {
+        self.position.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+fn encode_position(position: &CursorPosition) -> String {
+    // We know more than the compiler here, this will always serialize
+    let position = serde_json::to_vec(position).unwrap();
+    base64::encode_config(position, base64::URL_SAFE_NO_PAD)
+}
+
+// Turns a stream of subscription responses into SSE events, each of which is complete and ready to be written to the client
+// Every response is a `next` event, and a `complete` event is sent when the subscription ends so the client knows not to reconnect
+// The subscription is run on Diana's background runtime because it needs timers for the keep-alives, and the integration might not be on
+// Tokio v1, the events are passed back through a channel that works on any runtime
+pub fn get_sse_stream(
+    mut responses: impl Stream<Item = Response> + Send + Unpin + 'static,
+    cursor: Arc<SubscriptionCursor>,
+) -> impl Stream<Item = String> + Send + 'static {
+    let (events_tx, events_rx) = channel(SSE_EVENT_BUFFER_SIZE);
+    spawn_background(async move {
+        loop {
+            let event = tokio::select! {
+                res = tokio::time::timeout(SSE_KEEP_ALIVE_INTERVAL, responses.next()) => match res {
+                    Ok(Some(res)) => {
+                        // We know more than the compiler here, responses will always serialize
+                        let data = serde_json::to_string(&res).unwrap();
+                        // The cursor has been advanced past whatever produced this response by now
+                        let id = cursor
+                            .event_id()
+                            .map(|id| format!("id: {}\n", id))
+                            .unwrap_or_default();
+                        format!("{}event: next\ndata: {}\n\n", id, data)
+                    }
+                    Ok(None) => break,
+                    Err(_) => ": keep-alive\n\n".to_string(),
+                },
+                // The client has gone, so the subscription is dropped straight away rather than when the next event is sent
+                _ = events_tx.closed() => return,
+            };
+            if events_tx.send(event).await.is_err() {
+                return;
+            }
+        }
+        let _ = events_tx.send("event: complete\ndata:\n\n".to_string()).await;
+    });
+
+    ReceiverStream::new(events_rx)
+}
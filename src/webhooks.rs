@@ -0,0 +1,388 @@
+// This module POSTs messages from the subscriptions server's channels to webhooks that other services have registered
+// Every request is signed with the webhook's secret and retried with backoff, and each webhook has its own queue so a slow one doesn't hold up
+// the others
+
+use reqwest::{Client, StatusCode};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use uuid::Uuid;
+use anyhow::Result;
+
+use crate::background::{run_blocking_in_background, spawn_background};
+use crate::channel_history::ChannelMessage;
+use crate::channel_pattern::channel_matches_pattern;
+use crate::pubsub::PubSubCounters;
+
+/// The header every webhook request carries its signature in. This is `sha256=` followed by the hex-encoded HMAC-SHA256 of the request's
+/// timestamp (see [`WEBHOOK_TIMESTAMP_HEADER`]), a `.`, and then the request body, keyed with the webhook's secret. You can check it with
+/// [`verify_webhook_signature`].
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "X-Diana-Signature";
+/// The header every webhook request carries the time it was sent in, as seconds since the Unix epoch. This is covered by the signature, so
+/// an old request can't be replayed with a new timestamp.
+pub const WEBHOOK_TIMESTAMP_HEADER: &str = "X-Diana-Timestamp";
+/// How far a webhook request's timestamp can be from the receiver's clock before [`verify_webhook_signature`] rejects it.
+pub const WEBHOOK_TIMESTAMP_TOLERANCE: Duration = Duration::from_secs(300);
+/// The header every webhook request carries the ID of the webhook it's for in.
+pub const WEBHOOK_ID_HEADER: &str = "X-Diana-Webhook-Id";
+const SIGNATURE_PREFIX: &str = "sha256=";
+// How often the list of webhooks is reloaded from the registry, in case it's been changed by something else (e.g. another replica)
+const REGISTRY_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+// The longest to ever wait between retries, however many there have been
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+// The number of messages that can be waiting to be sent to each webhook by default
+pub const DEFAULT_WEBHOOK_QUEUE_SIZE: usize = 1024;
+
+/// A URL that messages on a channel are POSTed to by the subscriptions server. Each request's body is the JSON of a
+/// [`ChannelMessage`](crate::ChannelMessage), signed with the webhook's secret (see [`WEBHOOK_SIGNATURE_HEADER`]).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Webhook {
+    /// A unique ID for the webhook, which it can be unregistered with.
+    pub id: String,
+    /// The URL to POST messages to.
+    pub url: String,
+    /// The channel whose messages should be sent. This can be a pattern (e.g. `order.*`).
+    pub channel: String,
+    /// The secret requests are signed with, which the receiver should check them against.
+    pub secret: String,
+}
+impl Webhook {
+    /// Creates a new webhook with a fresh ID.
+    pub fn new(url: &str, channel: &str, secret: &str) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            url: url.to_string(),
+            channel: channel.to_string(),
+            secret: secret.to_string(),
+        }
+    }
+}
+
+/// Somewhere webhooks are stored. Diana comes with [`MemoryWebhookRegistry`] and [`FileWebhookRegistry`], but you can implement this
+/// yourself to keep them anywhere else (e.g. in your database, so every replica of the subscriptions server shares them). The subscriptions
+/// server lists the webhooks every few seconds and whenever one is registered through it, so listing should be quick.
+pub trait WebhookRegistry: Send + Sync {
+    /// Gets every registered webhook.
+    fn list(&self) -> Result<Vec<Webhook>>;
+    /// Registers the given webhook, replacing any other with the same ID.
+    fn register(&self, webhook: Webhook) -> Result<()>;
+    /// Unregisters the webhook with the given ID, returning whether or not there was one.
+    fn unregister(&self, id: &str) -> Result<bool>;
+}
+
+/// A registry that only keeps webhooks in memory, so they're lost when the subscriptions server restarts. Webhooks you register yourself
+/// before creating the server are a good fit for this.
+#[derive(Default)]
+pub struct MemoryWebhookRegistry {
+    webhooks: Mutex<Vec<Webhook>>,
+}
+impl MemoryWebhookRegistry {
+    /// Creates a new registry with the given webhooks already registered.
+    pub fn new(webhooks: Vec<Webhook>) -> Self {
+        Self {
+            webhooks: Mutex::new(webhooks),
+        }
+    }
+}
+impl WebhookRegistry for MemoryWebhookRegistry {
+    fn list(&self) -> Result<Vec<Webhook>> {
+        Ok(self.webhooks.lock().unwrap_or_else(|err| err.into_inner()).clone())
+    }
+    fn register(&self, webhook: Webhook) -> Result<()> {
+        let mut webhooks = self.webhooks.lock().unwrap_or_else(|err| err.into_inner());
+        webhooks.retain(|existing| existing.id != webhook.id);
+        webhooks.push(webhook);
+        Ok(())
+    }
+    fn unregister(&self, id: &str) -> Result<bool> {
+        let mut webhooks = self.webhooks.lock().unwrap_or_else(|err| err.into_inner());
+        let before = webhooks.len();
+        webhooks.retain(|webhook| webhook.id != id);
+        Ok(webhooks.len() != before)
+    }
+}
+
+/// A registry that keeps webhooks in a JSON file, so they survive restarts of the subscriptions server.
+pub struct FileWebhookRegistry {
+    path: PathBuf,
+    // Everything that touches the file goes through this, so changes can't overwrite each other
+    lock: Mutex<()>,
+}
+impl FileWebhookRegistry {
+    /// Creates a registry that keeps webhooks in the file at the given path, which will be created when the first one is registered.
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn read(&self) -> Result<Vec<Webhook>> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => Ok(serde_json::from_str(&contents)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // The webhooks are written to a temporary file and then moved into place, so the file is never half-written
+    fn write(&self, webhooks: &[Webhook]) -> Result<()> {
+        if let Some(dir) = self.path.parent().filter(|dir| dir != &Path::new("")) {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(serde_json::to_string(webhooks)?.as_bytes())?;
+        tmp.sync_data()?;
+        fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+impl WebhookRegistry for FileWebhookRegistry {
+    fn list(&self) -> Result<Vec<Webhook>> {
+        let _guard = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        self.read()
+    }
+    fn register(&self, webhook: Webhook) -> Result<()> {
+        let _guard = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        let mut webhooks = self.read()?;
+        webhooks.retain(|existing| existing.id != webhook.id);
+        webhooks.push(webhook);
+        self.write(&webhooks)
+    }
+    fn unregister(&self, id: &str) -> Result<bool> {
+        let _guard = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        let mut webhooks = self.read()?;
+        let before = webhooks.len();
+        webhooks.retain(|webhook| webhook.id != id);
+        if webhooks.len() == before {
+            return Ok(false);
+        }
+        self.write(&webhooks)?;
+        Ok(true)
+    }
+}
+
+/// Checks that the given signature (from the [`WEBHOOK_SIGNATURE_HEADER`] of a webhook request) is right for the given timestamp (from
+/// the [`WEBHOOK_TIMESTAMP_HEADER`]), request body, and webhook secret, and that the timestamp is within
+/// [`WEBHOOK_TIMESTAMP_TOLERANCE`] of now. Receivers should reject any request where this fails.
+pub fn verify_webhook_signature(secret: &str, timestamp: &str, body: &[u8], signature: &str) -> bool {
+    let signature = match signature.strip_prefix(SIGNATURE_PREFIX).and_then(decode_hex) {
+        Some(signature) => signature,
+        None => return false,
+    };
+    let sent_at = match timestamp.parse::<u64>() {
+        Ok(sent_at) => sent_at,
+        Err(_) => return false,
+    };
+    if get_unix_time().abs_diff(sent_at) > WEBHOOK_TIMESTAMP_TOLERANCE.as_secs() {
+        return false;
+    }
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    hmac::verify(&key, &get_signed_payload(timestamp, body), &signature).is_ok()
+}
+
+fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, &get_signed_payload(timestamp, body));
+    let hex = tag.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    SIGNATURE_PREFIX.to_string() + &hex
+}
+
+// The timestamp is signed along with the body so neither can be changed without the other
+fn get_signed_payload(timestamp: &str, body: &[u8]) -> Vec<u8> {
+    let mut payload = format!("{}.", timestamp).into_bytes();
+    payload.extend_from_slice(body);
+    payload
+}
+
+// A clock before the Unix epoch is treated as being at it, which will just make every signature look stale
+fn get_unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or(0)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|byte| match byte {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(byte).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+// Configuration for webhooks on the subscriptions server, this is derived from the user's `Options`
+#[derive(Clone)]
+pub struct WebhookConfig {
+    // Where webhooks are registered, if they're enabled
+    pub registry: Option<Arc<dyn WebhookRegistry>>,
+    // How long to wait for each attempt at sending a message to a webhook
+    pub timeout: Duration,
+    // How many times to retry sending a message after the first attempt fails
+    pub retries: u32,
+    // How long to wait before the first retry, this is doubled for each retry after that
+    pub retry_backoff: Duration,
+    // The number of messages that can be waiting to be sent to each webhook (and to the dispatcher) before new ones are dropped
+    pub queue_size: usize,
+}
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            registry: None,
+            timeout: Duration::from_secs(10),
+            retries: 3,
+            retry_backoff: Duration::from_millis(500),
+            queue_size: DEFAULT_WEBHOOK_QUEUE_SIZE,
+        }
+    }
+}
+
+// Something the webhook dispatcher needs to know about
+pub enum WebhookEvent {
+    // A message has been delivered on a channel
+    Message(Arc<ChannelMessage>),
+    // A webhook has been registered or unregistered on this server
+    RegistryChanged,
+}
+
+// Starts sending messages to the webhooks in the given registry
+// This returns a queue that every message delivered on the subscriptions server should be sent into, without waiting for room in it
+// (see `queue_webhook_event`)
+pub fn start_webhooks(
+    registry: Arc<dyn WebhookRegistry>,
+    config: WebhookConfig,
+    counters: Arc<PubSubCounters>,
+) -> Sender<WebhookEvent> {
+    // There has to be room for at least one event
+    let (events_tx, events_rx) = channel(config.queue_size.max(1));
+    spawn_background(dispatch_to_webhooks(events_rx, registry, config, counters));
+
+    events_tx
+}
+
+// Queues the given event for the webhook dispatcher without waiting
+// If the dispatcher is too far behind, messages are dropped and counted just like they are for a lagging subscriber, and a change to the
+// registry will be picked up when it next refreshes anyway
+pub fn queue_webhook_event(events_tx: &Sender<WebhookEvent>, event: WebhookEvent, counters: &PubSubCounters) {
+    if let Err(TrySendError::Full(WebhookEvent::Message(_))) = events_tx.try_send(event) {
+        counters.record_dropped_webhook_message();
+    }
+}
+
+// Passes every message on to the queues of the webhooks for its channel, for as long as the PubSub exists
+async fn dispatch_to_webhooks(
+    mut events_rx: Receiver<WebhookEvent>,
+    registry: Arc<dyn WebhookRegistry>,
+    config: WebhookConfig,
+    counters: Arc<PubSubCounters>,
+) {
+    let client = match Client::builder().timeout(config.timeout).build() {
+        Ok(client) => client,
+        Err(_) => return,
+    };
+    // Each webhook's queue is kept with the version of the webhook its worker was started with, so it can be restarted if that changes
+    let mut queues: HashMap<String, (Webhook, Sender<Arc<ChannelMessage>>)> = HashMap::new();
+    let mut webhooks = Vec::new();
+    let mut last_refresh = None;
+    // This only ends when the PubSub has been dropped
+    while let Some(event) = events_rx.recv().await {
+        let message = match event {
+            WebhookEvent::Message(message) => Some(message),
+            WebhookEvent::RegistryChanged => {
+                last_refresh = None;
+                None
+            }
+        };
+        if last_refresh
+            .filter(|last_refresh: &Instant| last_refresh.elapsed() < REGISTRY_REFRESH_INTERVAL)
+            .is_none()
+        {
+            // The registry could be doing anything (e.g. reading a file), so it's listed on a thread that's allowed to block
+            // If it can't be read, we carry on with the webhooks we already know about
+            let listing_registry = Arc::clone(&registry);
+            if let Ok(registered) = run_blocking_in_background(move || listing_registry.list()).await {
+                webhooks = registered;
+                // Dropping a webhook's queue stops its worker once it's finished with what's already in there
+                queues.retain(|_, (webhook, _)| webhooks.contains(webhook));
+            }
+            last_refresh = Some(Instant::now());
+        }
+
+        let message = match message {
+            Some(message) => message,
+            None => continue,
+        };
+        for webhook in &webhooks {
+            if !channel_matches_pattern(&webhook.channel, &message.channel) {
+                continue;
+            }
+            let (_, queue) = queues.entry(webhook.id.clone()).or_insert_with(|| {
+                let (queue_tx, queue_rx) = channel(config.queue_size.max(1));
+                spawn_background(deliver_to_webhook(
+                    webhook.clone(),
+                    queue_rx,
+                    client.clone(),
+                    config.clone(),
+                    Arc::clone(&counters),
+                ));
+                (webhook.clone(), queue_tx)
+            });
+            // A webhook that's too far behind has messages dropped, rather than holding up the others
+            if let Err(TrySendError::Full(_)) = queue.try_send(Arc::clone(&message)) {
+                counters.record_dropped_webhook_message();
+            }
+        }
+    }
+}
+
+// Sends every message in the given queue to the given webhook in order
+// Failures that might be temporary are retried with backoff, and a message is given up on if it still hasn't been accepted after that
+async fn deliver_to_webhook(
+    webhook: Webhook,
+    mut queue_rx: Receiver<Arc<ChannelMessage>>,
+    client: Client,
+    config: WebhookConfig,
+    counters: Arc<PubSubCounters>,
+) {
+    while let Some(message) = queue_rx.recv().await {
+        // We know more than the compiler here, this will always serialize
+        let body = serde_json::to_vec(&*message).unwrap();
+        let mut backoff = config.retry_backoff;
+        let mut retries_left = config.retries;
+        loop {
+            // Each attempt is signed afresh, so retries aren't rejected for being too old
+            let timestamp = get_unix_time().to_string();
+            let signature = sign(&webhook.secret, &timestamp, &body);
+            let res = client
+                .post(&webhook.url)
+                .header("Content-Type", "application/json")
+                .header(WEBHOOK_TIMESTAMP_HEADER, &timestamp)
+                .header(WEBHOOK_SIGNATURE_HEADER, &signature)
+                .header(WEBHOOK_ID_HEADER, &webhook.id)
+                .body(body.clone())
+                .send()
+                .await;
+            let temporary = match res {
+                Ok(res) if res.status().is_success() => break,
+                Ok(res) => res.status().is_server_error() || res.status() == StatusCode::TOO_MANY_REQUESTS,
+                Err(_) => true,
+            };
+            if !temporary || retries_left == 0 {
+                counters.record_failed_webhook_delivery();
+                break;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+            retries_left -= 1;
+        }
+    }
+}
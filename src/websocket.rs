@@ -0,0 +1,328 @@
+// This module runs subscriptions over WebSockets, speaking both the graphql-transport-ws protocol (used by `graphql-ws` and newer Apollo
+// clients) and the legacy subscriptions-transport-ws protocol, whichever the client asks for in `Sec-WebSocket-Protocol`
+// The integrations only need to pass text frames in and write out what comes back, everything about the protocols is handled here
+
+use async_graphql::{Data, ObjectType, Request, Response, Schema, SubscriptionType, Value};
+use serde::{Deserialize, Serialize};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt, StreamMap};
+
+use crate::auth::auth_state::AuthState;
+use crate::background::spawn_background;
+
+/// The WebSocket subprotocols the subscriptions server speaks, in order of preference. If you're writing your own integration, you should
+/// offer these when accepting a WebSocket, and use [`WsProtocol::negotiate`] to work out which one the client will get.
+pub const WS_PROTOCOLS: [&str; 2] = ["graphql-transport-ws", "graphql-ws"];
+// How long a client has to send `connection_init` after connecting before it's disconnected (graphql-transport-ws only)
+const WS_CONNECTION_INIT_TIMEOUT: Duration = Duration::from_secs(3);
+// How often the server checks that a connection is still alive by default
+pub const DEFAULT_WS_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+// The number of messages that can be waiting to be written to a client before its subscriptions stop producing more
+const WS_MESSAGE_BUFFER_SIZE: usize = 16;
+
+// Close codes from the graphql-transport-ws protocol
+const CLOSE_INVALID_MESSAGE: u16 = 4400;
+const CLOSE_UNAUTHORIZED: u16 = 4401;
+const CLOSE_FORBIDDEN: u16 = 4403;
+const CLOSE_INIT_TIMEOUT: u16 = 4408;
+const CLOSE_SUBSCRIBER_EXISTS: u16 = 4409;
+const CLOSE_TOO_MANY_INITS: u16 = 4429;
+// The legacy protocol has no close codes of its own, so we use the standard ones
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+// Neither protocol has a close code for a client that's stopped answering pings, so we use the standard one for going away
+const CLOSE_KEEP_ALIVE_TIMEOUT: u16 = 1001;
+
+/// A WebSocket subprotocol for subscriptions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WsProtocol {
+    /// The [graphql-transport-ws protocol](https://github.com/enisdenjo/graphql-ws/blob/master/PROTOCOL.md), which is used by `graphql-ws`
+    /// and newer versions of Apollo. Confusingly, its subprotocol name is `graphql-transport-ws`.
+    GraphQLTransportWS,
+    /// The legacy [subscriptions-transport-ws protocol](https://github.com/apollographql/subscriptions-transport-ws/blob/master/PROTOCOL.md).
+    /// Its subprotocol name is `graphql-ws`.
+    SubscriptionsTransportWS,
+}
+impl WsProtocol {
+    /// Works out which protocol to speak from the value of the client's `Sec-WebSocket-Protocol` header, which lists the protocols it
+    /// supports in order of preference. Clients that don't say are assumed to speak the legacy protocol, which is what older clients expect,
+    /// but this returns `None` if the client only offers protocols the subscriptions server doesn't speak.
+    pub fn negotiate(protocols_header: Option<&str>) -> Option<Self> {
+        let protocols_header = match protocols_header {
+            Some(protocols_header) => protocols_header,
+            None => return Some(Self::SubscriptionsTransportWS),
+        };
+        protocols_header
+            .split(',')
+            .find_map(|protocol| match protocol.trim() {
+                "graphql-transport-ws" => Some(Self::GraphQLTransportWS),
+                "graphql-ws" => Some(Self::SubscriptionsTransportWS),
+                _ => None,
+            })
+    }
+    /// Gets the subprotocol name for this protocol, which should be sent back in the `Sec-WebSocket-Protocol` header.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::GraphQLTransportWS => "graphql-transport-ws",
+            Self::SubscriptionsTransportWS => "graphql-ws",
+        }
+    }
+}
+
+/// A message to be sent to a client over a subscriptions WebSocket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DianaWsMessage {
+    /// A text frame.
+    Text(String),
+    /// The connection should be closed with the given code and reason. Nothing else will be sent after this.
+    Close(u16, String),
+}
+
+// A message from a client, this covers both protocols because they only overlap where they mean the same thing
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    ConnectionInit {
+        #[serde(default)]
+        payload: Option<serde_json::Value>,
+    },
+    // This is `start` in the legacy protocol
+    #[serde(alias = "start")]
+    Subscribe { id: String, payload: Request },
+    // This is `stop` in the legacy protocol
+    #[serde(alias = "stop")]
+    Complete { id: String },
+    // This only exists in the legacy protocol, the new one just closes the WebSocket
+    ConnectionTerminate,
+    // Pings and pongs can have payloads, but we don't do anything with them
+    Ping,
+    Pong,
+}
+
+// How the client has authenticated itself, if it has yet
+type Authenticate = Box<dyn FnOnce(&serde_json::Value) -> anyhow::Result<AuthState> + Send>;
+
+// Runs a subscriptions WebSocket session with the given protocol on Diana's background runtime, returning a stream of what should be sent
+// to the client
+// The integration might not be on Tokio v1, but this needs timers, so everything goes through channels that work on any runtime
+pub fn run_ws_session<Q, M, S>(
+    schema: Schema<Q, M, S>,
+    protocol: WsProtocol,
+    authenticate: Authenticate,
+    keep_alive_interval: Duration,
+    incoming: impl Stream<Item = String> + Send + Unpin + 'static,
+) -> impl Stream<Item = DianaWsMessage> + Send + 'static
+where
+    Q: ObjectType + 'static,
+    M: ObjectType + 'static,
+    S: SubscriptionType + 'static,
+{
+    let (outgoing_tx, outgoing_rx) = channel(WS_MESSAGE_BUFFER_SIZE);
+    let session = WsSession {
+        schema,
+        protocol,
+        authenticate: Some(authenticate),
+        session_data: None,
+        subscriptions: StreamMap::new(),
+        outgoing_tx,
+        keep_alive_interval,
+        awaiting_pong: false,
+    };
+    spawn_background(session.run(incoming));
+
+    ReceiverStream::new(outgoing_rx)
+}
+
+// Each subscription's responses, followed by `None` when it ends so the client can be told
+type SubscriptionStream = Pin<Box<dyn Stream<Item = Option<Response>> + Send>>;
+
+struct WsSession<Q, M, S> {
+    schema: Schema<Q, M, S>,
+    protocol: WsProtocol,
+    // This is taken when the client sends `connection_init`, so it can only do that once
+    authenticate: Option<Authenticate>,
+    // The data every subscription is run with, which is only there once the connection has been acknowledged
+    session_data: Option<Arc<Data>>,
+    subscriptions: StreamMap<String, SubscriptionStream>,
+    outgoing_tx: Sender<DianaWsMessage>,
+    keep_alive_interval: Duration,
+    // Whether or not we've pinged the client and haven't heard anything back since (graphql-transport-ws only)
+    awaiting_pong: bool,
+}
+impl<Q, M, S> WsSession<Q, M, S>
+where
+    Q: ObjectType + 'static,
+    M: ObjectType + 'static,
+    S: SubscriptionType + 'static,
+{
+    async fn run(mut self, mut incoming: impl Stream<Item = String> + Send + Unpin) {
+        let init_deadline = Instant::now() + WS_CONNECTION_INIT_TIMEOUT;
+        // This ticks regardless of what else is being sent, so a client that's gone quiet is noticed even while it's being sent messages
+        let mut keep_alive = tokio::time::interval_at(Instant::now() + self.keep_alive_interval, self.keep_alive_interval);
+        loop {
+            let outgoing = tokio::select! {
+                message = incoming.next() => match message {
+                    Some(message) => {
+                        // Anything from the client shows it's still there, not just a pong
+                        self.awaiting_pong = false;
+                        self.handle(&message)
+                    },
+                    // The client has gone, so every subscription is dropped along with the session
+                    None => return,
+                },
+                Some((id, res)) = self.subscriptions.next(), if !self.subscriptions.is_empty() => {
+                    vec![match res {
+                        // A subscription that failed without producing anything is over, so the client isn't told it's complete as well
+                        Some(res) if res.is_err() && res.data == Value::Null => {
+                            self.subscriptions.remove(&id);
+                            self.error_message(&id, res)
+                        }
+                        Some(res) => self.next_message(&id, res),
+                        None => server_message("complete", Some(&id), None::<()>),
+                    }]
+                },
+                // The new protocol requires clients to initialise the connection quickly, the legacy one has no such limit
+                _ = tokio::time::sleep_until(init_deadline), if self.authenticate.is_some() && self.protocol == WsProtocol::GraphQLTransportWS => {
+                    vec![DianaWsMessage::Close(CLOSE_INIT_TIMEOUT, "Connection initialisation timeout".to_string())]
+                },
+                _ = keep_alive.tick() => vec![self.keep_alive_message()],
+                // Whoever was writing to the client has stopped, so there's no point carrying on
+                _ = self.outgoing_tx.closed() => return,
+            };
+            for message in outgoing {
+                let closing = matches!(message, DianaWsMessage::Close(_, _));
+                if self.outgoing_tx.send(message).await.is_err() || closing {
+                    return;
+                }
+            }
+        }
+    }
+
+    // Handles a message from the client, returning whatever should be sent back
+    fn handle(&mut self, message: &str) -> Vec<DianaWsMessage> {
+        let message = match serde_json::from_str::<ClientMessage>(message) {
+            Ok(message) => message,
+            Err(err) => {
+                return vec![self.close(CLOSE_INVALID_MESSAGE, format!("Invalid message received: {}", err))];
+            }
+        };
+        match message {
+            ClientMessage::ConnectionInit { payload } => {
+                let authenticate = match self.authenticate.take() {
+                    Some(authenticate) => authenticate,
+                    None => return vec![self.connection_error(CLOSE_TOO_MANY_INITS, "Too many initialisation requests")],
+                };
+                match authenticate(&payload.unwrap_or_default()) {
+                    Ok(auth_state) => {
+                        let mut data = Data::default();
+                        data.insert(auth_state);
+                        self.session_data = Some(Arc::new(data));
+                        vec![server_message("connection_ack", None, None::<()>)]
+                    }
+                    Err(_) => vec![self.connection_error(CLOSE_FORBIDDEN, "Forbidden")],
+                }
+            }
+            ClientMessage::Subscribe { id, payload } => {
+                let session_data = match &self.session_data {
+                    Some(session_data) => Arc::clone(session_data),
+                    None => return vec![self.close(CLOSE_UNAUTHORIZED, "Unauthorized".to_string())],
+                };
+                // The legacy protocol lets clients replace subscriptions, the new one treats that as a mistake
+                if self.subscriptions.contains_key(&id) && self.protocol == WsProtocol::GraphQLTransportWS {
+                    return vec![self.close(CLOSE_SUBSCRIBER_EXISTS, format!("Subscriber for {} already exists", id))];
+                }
+                let responses = self
+                    .schema
+                    .execute_stream_with_session_data(payload, session_data)
+                    .map(Some)
+                    .chain(tokio_stream::once(None));
+                self.subscriptions.insert(id, Box::pin(responses));
+                Vec::new()
+            }
+            ClientMessage::Complete { id } => {
+                let removed = self.subscriptions.remove(&id).is_some();
+                // In the new protocol, the client already knows the subscription is over because it ended it
+                if removed && self.protocol == WsProtocol::SubscriptionsTransportWS {
+                    vec![server_message("complete", Some(&id), None::<()>)]
+                } else {
+                    Vec::new()
+                }
+            }
+            ClientMessage::ConnectionTerminate => vec![DianaWsMessage::Close(1000, String::new())],
+            ClientMessage::Ping => vec![server_message("pong", None, None::<()>)],
+            ClientMessage::Pong => Vec::new(),
+        }
+    }
+
+    // Creates a message with a result for the subscription with the given ID
+    fn next_message(&self, id: &str, res: Response) -> DianaWsMessage {
+        let message_type = match self.protocol {
+            WsProtocol::GraphQLTransportWS => "next",
+            WsProtocol::SubscriptionsTransportWS => "data",
+        };
+        server_message(message_type, Some(id), Some(res))
+    }
+
+    // Creates a message with the errors from a subscription that failed, which ends it
+    fn error_message(&self, id: &str, res: Response) -> DianaWsMessage {
+        server_message("error", Some(id), Some(res.errors))
+    }
+
+    // Creates a message that lets the client know the connection is still alive, which the new protocol expects a `pong` back for
+    // If the client hasn't answered the last one by the time we'd send the next, it's assumed to have gone and the connection is closed
+    fn keep_alive_message(&mut self) -> DianaWsMessage {
+        match self.protocol {
+            WsProtocol::GraphQLTransportWS if self.awaiting_pong => {
+                DianaWsMessage::Close(CLOSE_KEEP_ALIVE_TIMEOUT, "Keep-alive timeout".to_string())
+            }
+            WsProtocol::GraphQLTransportWS => {
+                self.awaiting_pong = true;
+                server_message("ping", None, None::<()>)
+            }
+            WsProtocol::SubscriptionsTransportWS => server_message("ka", None, None::<()>),
+        }
+    }
+
+    // Tells the client initialising the connection failed, which closes it in the new protocol and sends an error in the legacy one
+    fn connection_error(&self, code: u16, reason: &str) -> DianaWsMessage {
+        match self.protocol {
+            WsProtocol::GraphQLTransportWS => DianaWsMessage::Close(code, reason.to_string()),
+            WsProtocol::SubscriptionsTransportWS => {
+                server_message("connection_error", None, Some(serde_json::json!({ "message": reason })))
+            }
+        }
+    }
+
+    // Closes the connection, the legacy protocol only gets the standard code for a protocol error
+    fn close(&self, code: u16, reason: String) -> DianaWsMessage {
+        match self.protocol {
+            WsProtocol::GraphQLTransportWS => DianaWsMessage::Close(code, reason),
+            WsProtocol::SubscriptionsTransportWS => DianaWsMessage::Close(CLOSE_PROTOCOL_ERROR, reason),
+        }
+    }
+}
+
+// A message to a client, the fields are always in this order (which some clients depend on)
+#[derive(Serialize)]
+struct ServerMessage<'a, P> {
+    #[serde(rename = "type")]
+    message_type: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<P>,
+}
+
+fn server_message<P: Serialize>(message_type: &str, id: Option<&str>, payload: Option<P>) -> DianaWsMessage {
+    let message = ServerMessage {
+        message_type,
+        id,
+        payload,
+    };
+    // We know more than the compiler here, messages will always serialize
+    DianaWsMessage::Text(serde_json::to_string(&message).unwrap())
+}
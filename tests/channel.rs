@@ -0,0 +1,185 @@
+// These tests check that typed channels deserialize what's published on them in either encoding, and surface anything that can't be
+
+mod common;
+
+use async_graphql::{EmptyMutation, Request, SimpleObject as GQLSimpleObject, Subscription as GQLSubscription};
+use chrono::Utc;
+use common::{get_publishing_auth_header, Context, Query, JWT_SECRET};
+use diana::{
+    errors::{GQLResult, Result},
+    graphql_utils::get_typed_stream_for_channel_from_ctx,
+    AuthBlockLevel, Channel, ChannelMessage, DianaHandler, DianaResponse, Encoding, MessageEnvelope, Options, Stream,
+    StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, GQLSimpleObject)]
+struct User {
+    username: String,
+}
+
+const NEW_USER: Channel<User> = Channel::new("new_user");
+const BINARY_USER: Channel<User> = Channel::msgpack("binary_user");
+
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    async fn new_users(&self, raw_ctx: &async_graphql::Context<'_>) -> Result<impl Stream<Item = GQLResult<User>>> {
+        get_typed_stream_for_channel_from_ctx(&NEW_USER, raw_ctx)
+    }
+    async fn binary_users(&self, raw_ctx: &async_graphql::Context<'_>) -> Result<impl Stream<Item = GQLResult<User>>> {
+        get_typed_stream_for_channel_from_ctx(&BINARY_USER, raw_ctx)
+    }
+}
+
+fn get_handler() -> DianaHandler<Context, Query, EmptyMutation, Subscription> {
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+// Publishes raw data on the channel through the subscriptions server's inbuilt mutation, like the publisher would
+async fn publish(diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>, data: &str) {
+    let body = serde_json::json!({
+        "query": "mutation($channel: String!, $data: String!) { publish(channel: $channel, data: $data) }",
+        "variables": { "channel": NEW_USER.name(), "data": data }
+    })
+    .to_string();
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_publishing_auth_header(), None)
+        .await;
+    if !matches!(res.clone(), DianaResponse::Success(val) if val.starts_with("{\"data\":{\"publish\":")) {
+        panic!("Couldn't publish message, got {:?}", res)
+    }
+}
+
+#[tokio::test]
+async fn deserializes_and_surfaces_errors_on_typed_channel() {
+    let diana_handler = get_handler();
+    let mut subscription = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { newUsers { username } }"));
+    // Polling the subscription once subscribes to the channel
+    let _ = tokio::time::timeout(Duration::from_millis(10), subscription.next()).await;
+
+    publish(&diana_handler, "{\"username\": \"test\"}").await;
+    publish(&diana_handler, "not a user").await;
+
+    let res = subscription.next().await.unwrap();
+    assert_eq!(
+        serde_json::to_string(&res).unwrap(),
+        "{\"data\":{\"newUsers\":{\"username\":\"test\"}}}"
+    );
+    let res = subscription.next().await.unwrap();
+    assert!(res.errors[0]
+        .message
+        .starts_with("couldn't deserialize data received on channel 'new_user'"));
+    // GraphQL subscriptions end after their first error, so even valid data won't come through now
+    publish(&diana_handler, "{\"username\": \"other\"}").await;
+    let res = tokio::time::timeout(Duration::from_secs(1), subscription.next())
+        .await
+        .expect("subscription didn't end");
+    assert!(res.is_none());
+}
+#[tokio::test]
+async fn publishes_batches_in_order() {
+    let diana_handler = get_handler();
+    let mut subscription = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { newUsers { username } }"));
+    let _ = tokio::time::timeout(Duration::from_millis(10), subscription.next()).await;
+
+    let body = serde_json::json!({
+        "query": "mutation($messages: [PublishInput!]!) { publishMany(messages: $messages) }",
+        "variables": { "messages": [
+            { "channel": NEW_USER.name(), "data": "{\"username\": \"first\"}" },
+            { "channel": NEW_USER.name(), "data": "{\"username\": \"second\"}" },
+        ] }
+    })
+    .to_string();
+    // Batches need the same authentication as single messages
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body.clone(), Option::<String>::None, None)
+        .await;
+    assert!(matches!(res, DianaResponse::Blocked));
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_publishing_auth_header(), None)
+        .await;
+    assert!(matches!(res, DianaResponse::Success(val) if val == "{\"data\":{\"publishMany\":[1,1]}}"));
+
+    for username in &["first", "second"] {
+        let res = subscription.next().await.unwrap();
+        assert_eq!(
+            serde_json::to_string(&res).unwrap(),
+            format!("{{\"data\":{{\"newUsers\":{{\"username\":\"{}\"}}}}}}", username)
+        );
+    }
+}
+#[tokio::test]
+async fn decodes_msgpack_on_typed_channel() {
+    let diana_handler = get_handler();
+    let mut subscription = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { binaryUsers { username } }"));
+    let _ = tokio::time::timeout(Duration::from_millis(10), subscription.next()).await;
+
+    // This is what the publisher sends for the channel
+    let user = rmp_serde::to_vec_named(&User {
+        username: "test".to_string(),
+    })
+    .unwrap();
+    let body = serde_json::json!({
+        "query": "mutation($channel: String!, $data: String!, $envelope: JSON) { publish(channel: $channel, data: $data, envelope: $envelope) }",
+        "variables": {
+            "channel": BINARY_USER.name(),
+            "data": base64::encode(&user),
+            "envelope": MessageEnvelope { binary: true, ..MessageEnvelope::new().with_content_type("application/msgpack") },
+        }
+    })
+    .to_string();
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_publishing_auth_header(), None)
+        .await;
+    assert!(matches!(res, DianaResponse::Success(val) if val.starts_with("{\"data\":{\"publish\":")));
+
+    let res = subscription.next().await.unwrap();
+    assert_eq!(
+        serde_json::to_string(&res).unwrap(),
+        "{\"data\":{\"binaryUsers\":{\"username\":\"test\"}}}"
+    );
+}
+#[test]
+fn decodes_messages_by_content_type() {
+    let user = User {
+        username: "test".to_string(),
+    };
+    let bytes = rmp_serde::to_vec_named(&user).unwrap();
+    let message = ChannelMessage {
+        channel: BINARY_USER.name().to_string(),
+        seq: 0,
+        published_at: Utc::now(),
+        data: base64::encode(&bytes),
+        envelope: MessageEnvelope {
+            binary: true,
+            ..MessageEnvelope::new().with_content_type(Encoding::MessagePack.content_type())
+        },
+    };
+    assert_eq!(message.bytes().unwrap(), bytes);
+    assert_eq!(message.decode::<User>().unwrap(), user);
+
+    // Anything else is treated as JSON
+    let message = ChannelMessage {
+        data: "{\"username\": \"test\"}".to_string(),
+        envelope: MessageEnvelope::new(),
+        ..message
+    };
+    assert_eq!(message.bytes().unwrap(), message.data.as_bytes());
+    assert_eq!(message.decode::<User>().unwrap(), user);
+}
@@ -0,0 +1,101 @@
+// These tests check that clients can only subscribe to the channels the rules in the options allow them to
+
+mod common;
+
+use async_graphql::{EmptyMutation, Request, Subscription as GQLSubscription};
+use common::{get_auth_header_with_claims, Context, Query, JWT_SECRET};
+use diana::{
+    graphql_utils::get_stream_for_channel_from_ctx, AuthBlockLevel, AuthState, DianaHandler, Options, Stream, StreamExt,
+};
+use std::time::Duration;
+
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    async fn messages(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+        channel: String,
+    ) -> async_graphql::Result<impl Stream<Item = String>> {
+        Ok(get_stream_for_channel_from_ctx(&channel, raw_ctx)?)
+    }
+}
+
+fn get_handler() -> DianaHandler<Context, Query, EmptyMutation, Subscription> {
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::AllowMissing)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .allow_subscribing("user.{sub}", &[])
+        .allow_subscribing("admin.**", &[("role", "admin")])
+        .allow_subscribing("public.*", &[])
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+// Gets the authentication state for a WebSocket connection that sent the given claims in its initialization payload
+fn get_auth_state(
+    diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>,
+    claims: &[(&str, &str)],
+) -> AuthState {
+    let payload = serde_json::json!({ "Authorization": get_auth_header_with_claims(claims) });
+    diana_handler
+        .get_auth_state_for_subscriptions(Option::<String>::None, &payload)
+        .unwrap()
+}
+
+// Subscribes to the given channel, returning whether or not the subscription was allowed
+async fn can_subscribe(
+    diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>,
+    channel: &str,
+    auth_state: Option<AuthState>,
+) -> bool {
+    let mut req = Request::new(format!("subscription {{ messages(channel: \"{}\") }}", channel));
+    if let Some(auth_state) = auth_state {
+        req = req.data(auth_state);
+    }
+    let mut subscription = diana_handler.schema_for_subscriptions.execute_stream(req);
+    // An allowed subscription won't yield anything until something's published, a disallowed one yields its error straight away
+    match tokio::time::timeout(Duration::from_millis(50), subscription.next()).await {
+        Err(_) => true,
+        Ok(Some(res)) => {
+            assert_eq!(
+                res.errors[0].message,
+                format!("not authorised to subscribe to channel '{}'", channel)
+            );
+            false
+        }
+        Ok(None) => panic!("subscription ended without an error"),
+    }
+}
+
+#[tokio::test]
+async fn fills_claims_into_channel_rules() {
+    let diana_handler = get_handler();
+    let auth_state = get_auth_state(&diana_handler, &[("sub", "alice")]);
+    assert!(can_subscribe(&diana_handler, "user.alice", Some(auth_state.clone())).await);
+    assert!(!can_subscribe(&diana_handler, "user.bob", Some(auth_state.clone())).await);
+    // Patterns could reach other users' channels
+    assert!(!can_subscribe(&diana_handler, "user.*", Some(auth_state)).await);
+}
+#[tokio::test]
+async fn requires_claims_for_channel_rules() {
+    let diana_handler = get_handler();
+    let admin = get_auth_state(&diana_handler, &[("sub", "alice"), ("role", "admin")]);
+    let user = get_auth_state(&diana_handler, &[("sub", "bob"), ("role", "user")]);
+    assert!(can_subscribe(&diana_handler, "admin.audit.logins", Some(admin.clone())).await);
+    assert!(can_subscribe(&diana_handler, "admin.*", Some(admin)).await);
+    assert!(!can_subscribe(&diana_handler, "admin.audit.logins", Some(user)).await);
+}
+#[tokio::test]
+async fn applies_rules_without_claims_to_clients_without_tokens() {
+    let diana_handler = get_handler();
+    assert!(can_subscribe(&diana_handler, "public.news", None).await);
+    assert!(can_subscribe(&diana_handler, "public.?ews", Some(AuthState::NoToken)).await);
+    // This pattern reaches further than the rule allows
+    assert!(!can_subscribe(&diana_handler, "public.**", None).await);
+    assert!(!can_subscribe(&diana_handler, "user.alice", None).await);
+}
@@ -0,0 +1,208 @@
+// These tests check that channels on the subscriptions server keep a history that reconnecting subscribers can resume from
+
+mod common;
+
+use async_graphql::{EmptyMutation, Request, Response, Subscription as GQLSubscription};
+use chrono::{DateTime, Utc};
+use common::{get_publishing_auth_header, Context, Query, JWT_SECRET};
+use diana::{
+    graphql_utils::get_event_stream_for_channel_from_ctx_with_options, AuthBlockLevel, ChannelEvent, DianaHandler,
+    DianaResponse, LagPolicy, Options, StartFrom, Stream, StreamExt, SubscribeOptions,
+};
+use std::time::Duration;
+
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    // Messages are yielded with their sequence numbers so we can check them
+    async fn events(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+        channel: Option<String>,
+        from_seq: Option<u64>,
+        from_timestamp: Option<String>,
+    ) -> async_graphql::Result<impl Stream<Item = String>> {
+        let start_from = match (from_seq, from_timestamp) {
+            (Some(seq), _) => StartFrom::Sequence(seq),
+            (None, Some(timestamp)) => StartFrom::Timestamp(timestamp.parse::<DateTime<Utc>>()?),
+            (None, None) => StartFrom::Now,
+        };
+        let opts = SubscribeOptions {
+            start_from,
+            ..Default::default()
+        };
+        let channel = channel.unwrap_or_else(|| "test_channel".to_string());
+        let stream = get_event_stream_for_channel_from_ctx_with_options(&channel, opts, raw_ctx)?;
+        Ok(stream.map(|event| match event {
+            ChannelEvent::Message(message) => format!("{}: {}", message.seq, message.data),
+            ChannelEvent::Lagged(missed) => format!("lagged {}", missed),
+        }))
+    }
+}
+
+fn get_handler(history_dir: Option<&str>) -> DianaHandler<Context, Query, EmptyMutation, Subscription> {
+    let mut opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .channel_history_size(2)
+        .lag_policy(LagPolicy::Notify);
+    if let Some(history_dir) = history_dir {
+        opts = opts.channel_history_dir(history_dir);
+    }
+    DianaHandler::new(opts.finish().unwrap()).unwrap()
+}
+
+async fn publish(diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>, data: &str) {
+    publish_on(diana_handler, "test_channel", data).await
+}
+
+async fn publish_on(
+    diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>,
+    channel: &str,
+    data: &str,
+) {
+    let body = format!(
+        "{{\"query\": \"mutation {{ publish(channel: \\\"{}\\\", data: \\\"{}\\\") }}\"}}",
+        channel, data
+    );
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_publishing_auth_header(), None)
+        .await;
+    if !matches!(res.clone(), DianaResponse::Success(val) if val.starts_with("{\"data\":{\"publish\":")) {
+        panic!("Couldn't publish message, got {:?}", res)
+    }
+}
+
+async fn next_event(subscription: &mut (impl Stream<Item = Response> + Unpin)) -> Option<String> {
+    let res = tokio::time::timeout(Duration::from_secs(1), subscription.next())
+        .await
+        .expect("subscription didn't yield or end")?;
+    Some(serde_json::to_string(&res).unwrap())
+}
+
+fn event(data: &str) -> Option<String> {
+    Some(format!("{{\"data\":{{\"events\":\"{}\"}}}}", data))
+}
+
+#[tokio::test]
+async fn replays_history_from_sequence_number() {
+    let diana_handler = get_handler(None);
+    for data in &["first", "second", "third"] {
+        publish(&diana_handler, data).await;
+    }
+    let mut subscription = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { events(fromSeq: 1) }"));
+    assert_eq!(next_event(&mut subscription).await, event("1: second"));
+    assert_eq!(next_event(&mut subscription).await, event("2: third"));
+    // New messages should carry on straight after the replayed ones
+    publish(&diana_handler, "fourth").await;
+    assert_eq!(next_event(&mut subscription).await, event("3: fourth"));
+}
+#[tokio::test]
+async fn notifies_of_messages_no_longer_in_history() {
+    let diana_handler = get_handler(None);
+    for data in &["first", "second", "third"] {
+        publish(&diana_handler, data).await;
+    }
+    let mut subscription = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { events(fromSeq: 0) }"));
+    // Only two messages are kept, so the first one is gone
+    assert_eq!(next_event(&mut subscription).await, event("lagged 1"));
+    assert_eq!(next_event(&mut subscription).await, event("1: second"));
+    assert_eq!(next_event(&mut subscription).await, event("2: third"));
+}
+#[tokio::test]
+async fn replays_history_from_timestamp() {
+    let diana_handler = get_handler(None);
+    publish(&diana_handler, "first").await;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let timestamp = Utc::now().to_rfc3339();
+    publish(&diana_handler, "second").await;
+    let mut subscription = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new(format!(
+            "subscription {{ events(fromTimestamp: \"{}\") }}",
+            timestamp
+        )));
+    assert_eq!(next_event(&mut subscription).await, event("1: second"));
+}
+#[tokio::test]
+async fn persists_history_across_restarts() {
+    let history_dir = std::env::temp_dir().join(format!("diana-history-test-{}", std::process::id()));
+    std::fs::create_dir_all(&history_dir).unwrap();
+    let history_dir = history_dir.to_str().unwrap();
+    {
+        let diana_handler = get_handler(Some(history_dir));
+        for data in &["first", "second", "third"] {
+            publish(&diana_handler, data).await;
+        }
+    }
+    // A new handler is effectively a restarted subscriptions server
+    let diana_handler = get_handler(Some(history_dir));
+    let mut subscription = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { events(fromSeq: 2) }"));
+    assert_eq!(next_event(&mut subscription).await, event("2: third"));
+    // Sequence numbers should carry on from where they were
+    publish(&diana_handler, "fourth").await;
+    assert_eq!(next_event(&mut subscription).await, event("3: fourth"));
+
+    std::fs::remove_dir_all(history_dir).unwrap();
+}
+#[tokio::test]
+async fn compacts_persisted_history() {
+    let history_dir = std::env::temp_dir().join(format!("diana-history-compaction-test-{}", std::process::id()));
+    std::fs::create_dir_all(&history_dir).unwrap();
+    {
+        let diana_handler = get_handler(Some(history_dir.to_str().unwrap()));
+        for data in &["first", "second", "third", "fourth", "fifth", "sixth"] {
+            publish(&diana_handler, data).await;
+        }
+    }
+    // Only what's remembered should be left in the file, and nothing should have been left behind from replacing it
+    let files = std::fs::read_dir(&history_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    assert_eq!(files.len(), 1);
+    assert!(std::fs::read_to_string(&files[0]).unwrap().lines().count() <= 4);
+    let diana_handler = get_handler(Some(history_dir.to_str().unwrap()));
+    let mut subscription = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { events(fromSeq: 4) }"));
+    assert_eq!(next_event(&mut subscription).await, event("4: fifth"));
+    assert_eq!(next_event(&mut subscription).await, event("5: sixth"));
+
+    std::fs::remove_dir_all(history_dir).unwrap();
+}
+#[tokio::test]
+async fn persists_history_of_channels_with_long_names() {
+    let history_dir = std::env::temp_dir().join(format!("diana-history-long-name-test-{}", std::process::id()));
+    std::fs::create_dir_all(&history_dir).unwrap();
+    let history_dir = history_dir.to_str().unwrap();
+    // Hex-encoding this would give a filename too long for most filesystems
+    let channel = "a".repeat(200);
+    {
+        let diana_handler = get_handler(Some(history_dir));
+        for data in &["first", "second"] {
+            publish_on(&diana_handler, &channel, data).await;
+        }
+    }
+    let diana_handler = get_handler(Some(history_dir));
+    let mut subscription = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new(format!(
+            "subscription {{ events(channel: \"{}\", fromSeq: 1) }}",
+            channel
+        )));
+    assert_eq!(next_event(&mut subscription).await, event("1: second"));
+    publish_on(&diana_handler, &channel, "third").await;
+    assert_eq!(next_event(&mut subscription).await, event("2: third"));
+
+    std::fs::remove_dir_all(history_dir).unwrap();
+}
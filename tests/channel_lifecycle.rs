@@ -0,0 +1,180 @@
+// These tests check that idle channels on the subscriptions server are removed, and that the number of channels can be limited
+
+mod common;
+
+use async_graphql::{EmptyMutation, Request, Subscription as GQLSubscription};
+use common::{get_publishing_auth_header, Context, Query, JWT_SECRET};
+use diana::{
+    graphql_utils::get_stream_for_channel_from_ctx, AuthBlockLevel, DianaHandler, DianaResponse, Options, Stream,
+    StreamExt,
+};
+use std::time::Duration;
+
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    async fn messages(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+        channel: String,
+    ) -> async_graphql::Result<impl Stream<Item = String>> {
+        Ok(get_stream_for_channel_from_ctx(&channel, raw_ctx)?)
+    }
+}
+
+const IDLE_TIMEOUT: Duration = Duration::from_millis(50);
+
+fn get_handler() -> DianaHandler<Context, Query, EmptyMutation, Subscription> {
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .channel_idle_timeout(IDLE_TIMEOUT)
+        .channel_history_size(5)
+        .max_channels(2)
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+// Publishes on the given channel, returning the raw response
+async fn publish(diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>, channel: &str) -> String {
+    let body = serde_json::json!({
+        "query": "mutation($channel: String!) { publish(channel: $channel, data: \"test\") }",
+        "variables": { "channel": channel }
+    })
+    .to_string();
+    match diana_handler
+        .run_stateless_for_subscriptions(body, get_publishing_auth_header(), None)
+        .await
+    {
+        DianaResponse::Success(res) => res,
+        res => panic!("Couldn't run publish mutation, got {:?}", res),
+    }
+}
+
+// Polls the given channel from the given cursor, returning the sequence numbers of the messages and the new cursor
+async fn poll(
+    diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>,
+    channel: &str,
+    cursor: Option<&str>,
+) -> (Vec<u64>, String) {
+    let body = serde_json::json!({
+        "query": "query($channel: String!, $cursor: String) { poll(channel: $channel, cursor: $cursor, waitMs: 0) { messages cursor } }",
+        "variables": { "channel": channel, "cursor": cursor }
+    })
+    .to_string();
+    let res = match diana_handler
+        .run_stateless_for_subscriptions(body, get_publishing_auth_header(), None)
+        .await
+    {
+        DianaResponse::Success(res) => serde_json::from_str::<serde_json::Value>(&res).unwrap(),
+        res => panic!("Couldn't poll, got {:?}", res),
+    };
+    let seqs = res["data"]["poll"]["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["seq"].as_u64().unwrap())
+        .collect();
+    (seqs, res["data"]["poll"]["cursor"].as_str().unwrap().to_string())
+}
+
+// The response to a successful publish that reached the given number of subscribers
+fn published(subscribers: usize) -> String {
+    format!("{{\"data\":{{\"publish\":{}}}}}", subscribers)
+}
+
+#[tokio::test]
+async fn limits_channels_until_idle_ones_are_removed() {
+    let diana_handler = get_handler();
+    assert_eq!(publish(&diana_handler, "first").await, published(0));
+    assert_eq!(publish(&diana_handler, "second").await, published(0));
+    // The other channels have only just been used, so there's no room yet
+    let res = publish(&diana_handler, "third").await;
+    assert!(res.contains("maximum of 2 channels"));
+
+    tokio::time::sleep(IDLE_TIMEOUT * 2).await;
+    assert_eq!(publish(&diana_handler, "third").await, published(0));
+    assert_eq!(diana_handler.pubsub_metrics().channels_removed, 2);
+}
+#[tokio::test]
+async fn keeps_idle_channels_with_subscribers() {
+    let diana_handler = get_handler();
+    let mut subscription = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { messages(channel: \"subscribed\") }"));
+    // Polling the subscription once subscribes to the channel
+    let _ = tokio::time::timeout(Duration::from_millis(10), subscription.next()).await;
+    assert_eq!(publish(&diana_handler, "unsubscribed").await, published(0));
+
+    tokio::time::sleep(IDLE_TIMEOUT * 2).await;
+    // Only the channel without subscribers can be removed to make room
+    assert_eq!(publish(&diana_handler, "new").await, published(0));
+    assert_eq!(diana_handler.pubsub_metrics().channels_removed, 1);
+    assert_eq!(publish(&diana_handler, "subscribed").await, published(1));
+    let res = subscription.next().await.unwrap();
+    assert_eq!(
+        serde_json::to_string(&res).unwrap(),
+        "{\"data\":{\"messages\":\"test\"}}"
+    );
+}
+#[tokio::test]
+async fn counts_patterns_towards_max_channels() {
+    let diana_handler = get_handler();
+    let mut subscription = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { messages(channel: \"order.*\") }"));
+    let _ = tokio::time::timeout(Duration::from_millis(10), subscription.next()).await;
+
+    assert_eq!(publish(&diana_handler, "first").await, published(0));
+    // The pattern has taken the other place
+    let res = publish(&diana_handler, "second").await;
+    assert!(res.contains("maximum of 2 channels"));
+}
+#[tokio::test]
+async fn carries_on_sequence_numbers_for_recreated_channels() {
+    let diana_handler = get_handler();
+    let (_, cursor) = poll(&diana_handler, "first", None).await;
+    publish(&diana_handler, "first").await;
+    publish(&diana_handler, "first").await;
+    let (seqs, cursor) = poll(&diana_handler, "first", Some(&cursor)).await;
+    assert_eq!(seqs, vec![0, 1]);
+
+    // Making room for these removes the first channel
+    tokio::time::sleep(IDLE_TIMEOUT * 2).await;
+    publish(&diana_handler, "second").await;
+    publish(&diana_handler, "third").await;
+    tokio::time::sleep(IDLE_TIMEOUT * 2).await;
+    // The recreated channel shouldn't give out sequence numbers the client has already seen
+    publish(&diana_handler, "first").await;
+    let (seqs, _) = poll(&diana_handler, "first", Some(&cursor)).await;
+    assert_eq!(seqs, vec![2]);
+}
+#[tokio::test]
+async fn publishes_nothing_from_a_batch_without_room_for_it() {
+    let diana_handler = get_handler();
+    let (_, cursor) = poll(&diana_handler, "first", None).await;
+    let body = serde_json::json!({
+        "query": "mutation($messages: [PublishInput!]!) { publishMany(messages: $messages) }",
+        "variables": { "messages": [
+            { "channel": "first", "data": "test" },
+            { "channel": "second", "data": "test" },
+            { "channel": "third", "data": "test" }
+        ] }
+    })
+    .to_string();
+    let res = match diana_handler
+        .run_stateless_for_subscriptions(body, get_publishing_auth_header(), None)
+        .await
+    {
+        DianaResponse::Success(res) => res,
+        res => panic!("Couldn't run publishMany mutation, got {:?}", res),
+    };
+    assert!(res.contains("maximum of 2 channels"));
+    // The message for the channel that already existed shouldn't have been published either
+    let (seqs, _) = poll(&diana_handler, "first", Some(&cursor)).await;
+    assert!(seqs.is_empty());
+}
@@ -0,0 +1,179 @@
+// These tests check that subscribers can receive messages from every channel matching a pattern, along with the channel they came from
+
+mod common;
+
+use async_graphql::{EmptyMutation, Request, Response, Subscription as GQLSubscription};
+use common::{get_publishing_auth_header, Context, Query, JWT_SECRET};
+use diana::{
+    graphql_utils::get_event_stream_for_channel_from_ctx_with_options, AuthBlockLevel, ChannelEvent, DianaHandler,
+    DianaResponse, Options, StartFrom, Stream, StreamExt, SubscribeOptions,
+};
+use std::time::Duration;
+
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    // Messages are yielded with the channel they were published on so we can check them
+    async fn events(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+        pattern: String,
+        from_start: bool,
+    ) -> async_graphql::Result<impl Stream<Item = String>> {
+        let opts = SubscribeOptions {
+            start_from: if from_start {
+                StartFrom::Sequence(0)
+            } else {
+                StartFrom::Now
+            },
+            ..Default::default()
+        };
+        let stream = get_event_stream_for_channel_from_ctx_with_options(&pattern, opts, raw_ctx)?;
+        Ok(stream.map(|event| match event {
+            ChannelEvent::Message(message) => format!("{}: {}", message.channel, message.data),
+            ChannelEvent::Lagged(missed) => format!("lagged {}", missed),
+        }))
+    }
+}
+
+fn get_handler() -> DianaHandler<Context, Query, EmptyMutation, Subscription> {
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .channel_history_size(5)
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+async fn publish(diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>, channel: &str, data: &str) {
+    let body = serde_json::json!({
+        "query": "mutation($channel: String!, $data: String!) { publish(channel: $channel, data: $data) }",
+        "variables": { "channel": channel, "data": data }
+    })
+    .to_string();
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_publishing_auth_header(), None)
+        .await;
+    if !matches!(res.clone(), DianaResponse::Success(val) if val.starts_with("{\"data\":{\"publish\":")) {
+        panic!("Couldn't publish message, got {:?}", res)
+    }
+}
+
+fn subscribe(
+    diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>,
+    pattern: &str,
+    from_start: bool,
+) -> impl Stream<Item = Response> {
+    let query = format!(
+        "subscription {{ events(pattern: \"{}\", fromStart: {}) }}",
+        pattern, from_start
+    );
+    diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new(query))
+}
+
+async fn next_event(subscription: &mut (impl Stream<Item = Response> + Unpin)) -> Option<String> {
+    let res = tokio::time::timeout(Duration::from_secs(1), subscription.next())
+        .await
+        .expect("subscription didn't yield or end")?;
+    Some(serde_json::to_string(&res).unwrap())
+}
+
+#[tokio::test]
+async fn receives_messages_from_matching_channels() {
+    let diana_handler = get_handler();
+    let single_segment = subscribe(&diana_handler, "order.*", false);
+    let any_segments = subscribe(&diana_handler, "order.**", false);
+    tokio::pin!(single_segment);
+    tokio::pin!(any_segments);
+    // Polling the subscriptions once subscribes to the channels
+    let _ = tokio::time::timeout(Duration::from_millis(10), single_segment.next()).await;
+    let _ = tokio::time::timeout(Duration::from_millis(10), any_segments.next()).await;
+
+    publish(&diana_handler, "order.1", "created").await;
+    publish(&diana_handler, "user.1", "created").await;
+    publish(&diana_handler, "order.1.shipped", "shipped").await;
+    publish(&diana_handler, "order.2", "created").await;
+
+    assert_eq!(
+        next_event(&mut single_segment).await,
+        Some("{\"data\":{\"events\":\"order.1: created\"}}".to_string())
+    );
+    assert_eq!(
+        next_event(&mut single_segment).await,
+        Some("{\"data\":{\"events\":\"order.2: created\"}}".to_string())
+    );
+    for expected in &["order.1: created", "order.1.shipped: shipped", "order.2: created"] {
+        assert_eq!(
+            next_event(&mut any_segments).await,
+            Some(format!("{{\"data\":{{\"events\":\"{}\"}}}}", expected))
+        );
+    }
+}
+#[tokio::test]
+async fn replays_history_of_matching_channels_in_order() {
+    let diana_handler = get_handler();
+    publish(&diana_handler, "order.1", "first").await;
+    publish(&diana_handler, "order.2", "second").await;
+    publish(&diana_handler, "user.1", "ignored").await;
+    publish(&diana_handler, "order.1", "third").await;
+
+    let subscription = subscribe(&diana_handler, "order.?", true);
+    tokio::pin!(subscription);
+    for expected in &["order.1: first", "order.2: second", "order.1: third"] {
+        assert_eq!(
+            next_event(&mut subscription).await,
+            Some(format!("{{\"data\":{{\"events\":\"{}\"}}}}", expected))
+        );
+    }
+    // New messages carry on after the replay, without repeating anything
+    publish(&diana_handler, "order.2", "fourth").await;
+    assert_eq!(
+        next_event(&mut subscription).await,
+        Some("{\"data\":{\"events\":\"order.2: fourth\"}}".to_string())
+    );
+}
+#[tokio::test]
+async fn matches_patterns_with_many_wildcards_quickly() {
+    let diana_handler = get_handler();
+    // Backtracking through every way of splitting the channel between these wildcards would take far too long
+    let subscription = subscribe(&diana_handler, "**a**a**a**a**a**a**a**a**a**a**b", false);
+    tokio::pin!(subscription);
+    let _ = tokio::time::timeout(Duration::from_millis(10), subscription.next()).await;
+
+    publish(&diana_handler, &"a".repeat(200), "missed").await;
+    publish(&diana_handler, &format!("{}b", "a".repeat(200)), "matched").await;
+
+    let expected = format!("{{\"data\":{{\"events\":\"{}b: matched\"}}}}", "a".repeat(200));
+    assert_eq!(next_event(&mut subscription).await, Some(expected));
+}
+#[tokio::test]
+async fn rejects_publishing_on_channels_named_like_patterns() {
+    let diana_handler = get_handler();
+    let mutations = [
+        "mutation($channel: String!) { publish(channel: $channel, data: \"test\") }",
+        "mutation($channel: String!) { publishMany(messages: [{ channel: $channel, data: \"test\" }]) }",
+        "mutation($channel: String!) { schedulePublish(channel: $channel, data: \"test\", at: \"2000-01-01T00:00:00Z\") }",
+    ];
+    for mutation in &mutations {
+        for channel in &["news.*", "news.?"] {
+            let body = serde_json::json!({
+                "query": mutation,
+                "variables": { "channel": channel }
+            })
+            .to_string();
+            let res = diana_handler
+                .run_stateless_for_subscriptions(body, get_publishing_auth_header(), None)
+                .await;
+            match res {
+                DianaResponse::Success(val) => assert!(val.contains("is named like a pattern"), "{}", val),
+                _ => panic!("Couldn't run publish mutation, got {:?}", res),
+            }
+        }
+    }
+}
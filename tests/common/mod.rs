@@ -0,0 +1,51 @@
+// Fixtures shared by the tests that run a subscriptions server, each test binary includes this with `mod common;`
+// Not every test binary uses all of these
+#![allow(dead_code)]
+
+use async_graphql::Object as GQLObject;
+use diana::{create_jwt, decode_time_str, get_jwt_secret, validate_and_decode_jwt, AuthState, AuthToken};
+use std::collections::HashMap;
+
+pub const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+
+#[derive(Clone)]
+pub struct Context {}
+
+#[derive(Clone)]
+pub struct Query {}
+#[GQLObject]
+impl Query {
+    async fn query(&self) -> bool {
+        true
+    }
+}
+
+// Creates an authorization header with a token carrying the given claims
+pub fn get_auth_header_with_claims(claims: &[(&str, &str)]) -> Option<String> {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let claims = claims
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect::<HashMap<_, _>>();
+    let exp = decode_time_str("1m").unwrap(); // The created JWT will be valid for 1 minute
+    let jwt = create_jwt(claims, &secret, exp).unwrap();
+    Some("Bearer ".to_string() + &jwt)
+}
+
+// Creates an authorization header for a client with the given role
+pub fn get_auth_header(role: &str) -> Option<String> {
+    get_auth_header_with_claims(&[("role", role)])
+}
+
+// Creates the authorization header the queries/mutations system publishes with
+pub fn get_publishing_auth_header() -> Option<String> {
+    get_auth_header("graphql_server")
+}
+
+// Gets the authentication state a client with the given claims would have on the subscriptions server
+pub fn get_auth_state(claims: &[(&str, &str)]) -> AuthState {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let header = get_auth_header_with_claims(claims).unwrap();
+    let claims = validate_and_decode_jwt(header.trim_start_matches("Bearer "), &secret).unwrap();
+    AuthState::Authorised(AuthToken(claims))
+}
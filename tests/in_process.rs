@@ -0,0 +1,154 @@
+// These tests check that a queries/mutations system publishes straight to a subscriptions server in the same process when they share options
+
+mod common;
+
+use async_graphql::{Object as GQLObject, Subscription as GQLSubscription};
+use common::{get_auth_header, Context, JWT_SECRET};
+use diana::{
+    errors::GQLResult, graphql_utils::get_stream_for_channel_from_ctx, AuthBlockLevel, DianaHandler, DianaResponse,
+    DianaStreamResponse, Options, Publisher, Stream, StreamExt,
+};
+use std::time::Duration;
+
+#[derive(Clone)]
+struct Query {}
+#[GQLObject]
+impl Query {
+    // Gets everything published on the test channel since the given cursor
+    async fn latest(&self, raw_ctx: &async_graphql::Context<'_>, cursor: String) -> GQLResult<Vec<String>> {
+        let publisher = raw_ctx.data::<Publisher>()?;
+        let res = publisher.poll("test_channel", Some(&cursor), Duration::ZERO).await?;
+        Ok(res.messages.into_iter().map(|message| message.data).collect())
+    }
+    // Gets a cursor for the test channel from now
+    async fn cursor(&self, raw_ctx: &async_graphql::Context<'_>) -> GQLResult<String> {
+        let publisher = raw_ctx.data::<Publisher>()?;
+        let res = publisher.poll("test_channel", None, Duration::ZERO).await?;
+        Ok(res.cursor)
+    }
+}
+#[derive(Clone)]
+struct Mutation {}
+#[GQLObject]
+impl Mutation {
+    // Publishes the given data on the test channel, returning how many subscribers got it
+    async fn notify(&self, raw_ctx: &async_graphql::Context<'_>, data: String) -> GQLResult<usize> {
+        let publisher = raw_ctx.data::<Publisher>()?;
+        let subscribers = publisher.publish("test_channel", data).await?;
+        Ok(subscribers.unwrap_or_default())
+    }
+}
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    async fn messages(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+    ) -> async_graphql::Result<impl Stream<Item = String>> {
+        Ok(get_stream_for_channel_from_ctx("test_channel", raw_ctx)?)
+    }
+}
+
+type Handler = DianaHandler<Context, Query, Mutation, Subscription>;
+
+fn get_opts() -> Options<Context, Query, Mutation, Subscription> {
+    Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::AllowAll)
+        .jwt_secret(JWT_SECRET)
+        .channel_history_size(10)
+        .schema(Query {}, Mutation {}, Subscription {})
+        // Nothing is listening here, so anything that goes over the network will fail
+        .subscriptions_server_hostname("http://127.0.0.1")
+        .subscriptions_server_port("1")
+        .subscriptions_server_endpoint("/graphql")
+        .jwt_to_connect_to_subscriptions_server("notavalidtoken")
+        .publish_retries(0, Duration::from_millis(1))
+        .finish()
+        .unwrap()
+}
+
+// Creates a queries/mutations system and a subscriptions server from the same options, as if they were in the same binary
+fn get_handlers() -> (Handler, Handler) {
+    let opts = get_opts();
+    let graphql_handler = DianaHandler::new(opts.clone()).unwrap();
+    let subscriptions_handler = DianaHandler::new(opts).unwrap();
+    subscriptions_handler.serve_subscriptions_in_process();
+    (graphql_handler, subscriptions_handler)
+}
+
+async fn run(graphql_handler: &Handler, query: &str, variables: serde_json::Value) -> serde_json::Value {
+    let body = serde_json::json!({ "query": query, "variables": variables }).to_string();
+    let res = graphql_handler
+        .run_stateless_without_subscriptions(body, get_auth_header("user"), None)
+        .await;
+    match res {
+        DianaResponse::Success(res) => serde_json::from_str(&res).unwrap(),
+        _ => panic!("request failed"),
+    }
+}
+
+async fn notify(graphql_handler: &Handler, data: &str) -> serde_json::Value {
+    run(
+        graphql_handler,
+        "mutation($data: String!) { notify(data: $data) }",
+        serde_json::json!({ "data": data }),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn publishes_to_subscriptions_server_in_same_process() {
+    let (graphql_handler, subscriptions_handler) = get_handlers();
+    let body = serde_json::json!({ "query": "subscription { messages }" }).to_string();
+    let mut events = match subscriptions_handler.run_sse_for_subscriptions(body, get_auth_header("user"), None, None) {
+        DianaStreamResponse::Success(events) => events,
+        _ => panic!("couldn't subscribe"),
+    };
+    // Polling the stream once subscribes to the channel
+    let _ = tokio::time::timeout(Duration::from_millis(10), events.next()).await;
+
+    let res = notify(&graphql_handler, "hello").await;
+    assert!(res["errors"].is_null(), "publishing failed: {}", res["errors"]);
+    assert_eq!(res["data"]["notify"], 1);
+    let event = tokio::time::timeout(Duration::from_secs(1), events.next())
+        .await
+        .expect("nothing was published")
+        .unwrap();
+    assert!(event.contains("\"messages\":\"hello\""));
+}
+#[tokio::test]
+async fn polls_subscriptions_server_in_same_process() {
+    let (graphql_handler, _subscriptions_handler) = get_handlers();
+    let cursor = run(&graphql_handler, "query { cursor }", serde_json::json!({})).await["data"]["cursor"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    notify(&graphql_handler, "first").await;
+    notify(&graphql_handler, "second").await;
+
+    let res = run(
+        &graphql_handler,
+        "query($cursor: String!) { latest(cursor: $cursor) }",
+        serde_json::json!({ "cursor": cursor }),
+    )
+    .await;
+    assert_eq!(res["data"]["latest"], serde_json::json!(["first", "second"]));
+}
+#[tokio::test]
+async fn uses_network_without_subscriptions_server_in_same_process() {
+    // These are built from the same options, but the subscriptions server isn't being served
+    let opts = get_opts();
+    let graphql_handler = DianaHandler::new(opts.clone()).unwrap();
+    let _subscriptions_handler = DianaHandler::new(opts).unwrap();
+    let res = notify(&graphql_handler, "hello").await;
+    assert!(res["errors"].is_array());
+
+    // Separately built options don't share a subscriptions server
+    let graphql_handler = DianaHandler::new(get_opts()).unwrap();
+    let subscriptions_handler = DianaHandler::new(get_opts()).unwrap();
+    subscriptions_handler.serve_subscriptions_in_process();
+    let res = notify(&graphql_handler, "hello").await;
+    assert!(res["errors"].is_array());
+}
@@ -0,0 +1,93 @@
+// These tests check that the envelope a message is published with reaches its subscribers intact
+
+mod common;
+
+use async_graphql::{EmptyMutation, Request, Response, Subscription as GQLSubscription};
+use common::{get_publishing_auth_header, Context, Query, JWT_SECRET};
+use diana::{
+    graphql_utils::get_event_stream_for_channel_from_ctx, AuthBlockLevel, ChannelEvent, DianaHandler, DianaResponse,
+    MessageEnvelope, Options, Stream, StreamExt,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    // Envelopes are yielded as JSON so we can check them
+    async fn envelopes(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+    ) -> async_graphql::Result<impl Stream<Item = String>> {
+        let stream = get_event_stream_for_channel_from_ctx("test_channel", raw_ctx)?;
+        Ok(stream.filter_map(|event| match event {
+            ChannelEvent::Message(message) => Some(serde_json::to_string(&message.envelope).unwrap()),
+            ChannelEvent::Lagged(_) => None,
+        }))
+    }
+}
+
+fn get_handler() -> DianaHandler<Context, Query, EmptyMutation, Subscription> {
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+async fn publish(
+    diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>,
+    envelope: Option<&MessageEnvelope>,
+) {
+    let body = serde_json::json!({
+        "query": "mutation($envelope: JSON) { publish(channel: \"test_channel\", data: \"test\", envelope: $envelope) }",
+        "variables": { "envelope": envelope }
+    })
+    .to_string();
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_publishing_auth_header(), None)
+        .await;
+    if !matches!(res.clone(), DianaResponse::Success(val) if val.starts_with("{\"data\":{\"publish\":")) {
+        panic!("Couldn't publish message, got {:?}", res)
+    }
+}
+
+async fn next_envelope(subscription: &mut (impl Stream<Item = Response> + Unpin)) -> MessageEnvelope {
+    let res = tokio::time::timeout(Duration::from_secs(1), subscription.next())
+        .await
+        .expect("subscription didn't yield")
+        .expect("subscription ended");
+    let envelope = res.data.into_json().unwrap()["envelopes"].as_str().unwrap().to_string();
+    serde_json::from_str(&envelope).unwrap()
+}
+
+#[tokio::test]
+async fn delivers_envelopes_to_subscribers() {
+    let diana_handler = get_handler();
+    let mut subscription = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { envelopes }"));
+    // Polling the subscription once subscribes to the channel
+    let _ = tokio::time::timeout(Duration::from_millis(10), subscription.next()).await;
+
+    let mut origin = HashMap::new();
+    origin.insert("sub".to_string(), "alice".to_string());
+    let envelope = MessageEnvelope {
+        origin: Some(origin),
+        ..MessageEnvelope::new()
+            .with_content_type("text/plain")
+            .with_request_id("request")
+    };
+    publish(&diana_handler, Some(&envelope)).await;
+    assert_eq!(next_envelope(&mut subscription).await, envelope);
+
+    // Something publishing without an envelope still gets one
+    publish(&diana_handler, None).await;
+    let new_envelope = next_envelope(&mut subscription).await;
+    assert_ne!(new_envelope.id, envelope.id);
+    assert_eq!(new_envelope.origin_sub(), None);
+}
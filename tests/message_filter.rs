@@ -0,0 +1,128 @@
+// These tests check that subscribers only receive the messages their filters accept
+
+mod common;
+
+use async_graphql::{EmptyMutation, Request, Response, Subscription as GQLSubscription};
+use common::{get_publishing_auth_header, Context, Query, JWT_SECRET};
+use diana::{
+    graphql_utils::get_filtered_stream_for_channel_from_ctx, AuthBlockLevel, AuthState, AuthToken, Claims,
+    DianaHandler, DianaResponse, Options, Stream, StreamExt,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    // Only messages starting with the given prefix
+    async fn prefixed(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+        prefix: String,
+    ) -> async_graphql::Result<impl Stream<Item = String>> {
+        Ok(get_filtered_stream_for_channel_from_ctx(
+            "test_channel",
+            move |message, _auth_state| message.data.starts_with(&prefix),
+            raw_ctx,
+        )?)
+    }
+    // Only messages that are the subscriber's `sub` claim
+    async fn mine(&self, raw_ctx: &async_graphql::Context<'_>) -> async_graphql::Result<impl Stream<Item = String>> {
+        Ok(get_filtered_stream_for_channel_from_ctx(
+            "test_channel",
+            |message, auth_state| {
+                auth_state
+                    .get_claims()
+                    .map(|claims| claims.claims.get("sub") == Some(&message.data))
+                    .unwrap_or(false)
+            },
+            raw_ctx,
+        )?)
+    }
+}
+
+fn get_handler() -> DianaHandler<Context, Query, EmptyMutation, Subscription> {
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+async fn publish(diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>, data: &str) {
+    let body = serde_json::json!({
+        "query": "mutation($data: String!) { publish(channel: \"test_channel\", data: $data) }",
+        "variables": { "data": data }
+    })
+    .to_string();
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_publishing_auth_header(), None)
+        .await;
+    if !matches!(res.clone(), DianaResponse::Success(val) if val.starts_with("{\"data\":{\"publish\":")) {
+        panic!("Couldn't publish message, got {:?}", res)
+    }
+}
+
+// Starts the given subscription, polling it once so it's actually subscribed to the channel
+async fn subscribe(
+    diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>,
+    req: Request,
+) -> impl Stream<Item = Response> + Unpin {
+    let mut subscription = diana_handler.schema_for_subscriptions.execute_stream(req);
+    let _ = tokio::time::timeout(Duration::from_millis(10), subscription.next()).await;
+    subscription
+}
+
+// Gets everything the subscription yields until it's been quiet for a little while
+async fn collect(subscription: &mut (impl Stream<Item = Response> + Unpin)) -> Vec<String> {
+    let mut received = Vec::new();
+    while let Ok(Some(res)) = tokio::time::timeout(Duration::from_millis(50), subscription.next()).await {
+        received.push(serde_json::to_string(&res.data).unwrap());
+    }
+    received
+}
+
+#[tokio::test]
+async fn filters_messages_by_subscription_arguments() {
+    let diana_handler = get_handler();
+    let mut orders = subscribe(
+        &diana_handler,
+        Request::new("subscription { prefixed(prefix: \"order\") }"),
+    )
+    .await;
+    let mut users = subscribe(
+        &diana_handler,
+        Request::new("subscription { prefixed(prefix: \"user\") }"),
+    )
+    .await;
+
+    for data in &["order 1", "user 1", "order 2"] {
+        publish(&diana_handler, data).await;
+    }
+
+    assert_eq!(
+        collect(&mut orders).await,
+        vec!["{\"prefixed\":\"order 1\"}", "{\"prefixed\":\"order 2\"}"]
+    );
+    assert_eq!(collect(&mut users).await, vec!["{\"prefixed\":\"user 1\"}"]);
+}
+#[tokio::test]
+async fn filters_messages_by_subscriber_auth_state() {
+    let diana_handler = get_handler();
+    let mut claims = HashMap::new();
+    claims.insert("sub".to_string(), "alice".to_string());
+    let auth_state = AuthState::Authorised(AuthToken(Claims { exp: u64::MAX, claims }));
+    let mut alice = subscribe(&diana_handler, Request::new("subscription { mine }").data(auth_state)).await;
+    let mut anonymous = subscribe(&diana_handler, Request::new("subscription { mine }")).await;
+
+    for data in &["bob", "alice"] {
+        publish(&diana_handler, data).await;
+    }
+
+    assert_eq!(collect(&mut alice).await, vec!["{\"mine\":\"alice\"}"]);
+    assert!(collect(&mut anonymous).await.is_empty());
+}
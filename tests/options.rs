@@ -182,6 +182,25 @@ fn returns_error_on_missing_subscriptions_server_fields() {
     }
 }
 #[test]
+fn returns_valid_options_with_subscriptions_server_socket() {
+    // The hostname and port aren't needed if the subscriptions server is reached over a socket
+    let opts = Options::builder()
+        .ctx(Context {
+            prop: "connection".to_string(),
+        })
+        .subscriptions_server_socket("/tmp/diana.sock")
+        .subscriptions_server_endpoint("/graphql")
+        .jwt_to_connect_to_subscriptions_server("SUBSCRIPTIONS_SERVER_PUBLISH_JWT")
+        .auth_block_state(AuthBlockLevel::AllowAll)
+        .jwt_secret("JWT_SECRET")
+        .schema(Query {}, EmptyMutation {}, EmptySubscription {})
+        .finish()
+        .unwrap();
+
+    let subscriptions_server_data = opts.subscriptions_server_data.unwrap();
+    assert_eq!(subscriptions_server_data.socket.unwrap().to_str(), Some("/tmp/diana.sock"));
+}
+#[test]
 fn returns_error_on_missing_required_fields() {
     if matches!(
         Options::<Context, Query, EmptyMutation, EmptySubscription>::builder()
@@ -236,3 +255,57 @@ fn returns_error_on_missing_required_fields() {
         panic!("Returned valid options instance, should've been invalid.")
     }
 }
+#[test]
+fn returns_error_on_zero_channel_buffer_size() {
+    if matches!(
+        Options::builder()
+            .ctx(Context {
+                prop: "connection".to_string(),
+            })
+            .auth_block_state(AuthBlockLevel::AllowAll)
+            .jwt_secret("JWT_SECRET")
+            .schema(Query {}, EmptyMutation {}, EmptySubscription {})
+            .channel_buffer_size_for("test_channel", 0)
+            .finish(),
+        Ok(Options { .. })
+    ) {
+        panic!("Returned valid options instance, should've been invalid.")
+    }
+}
+#[test]
+fn returns_error_on_zero_max_channels() {
+    if matches!(
+        Options::builder()
+            .ctx(Context {
+                prop: "connection".to_string(),
+            })
+            .auth_block_state(AuthBlockLevel::AllowAll)
+            .jwt_secret("JWT_SECRET")
+            .schema(Query {}, EmptyMutation {}, EmptySubscription {})
+            .max_channels(0)
+            .finish(),
+        Ok(Options { .. })
+    ) {
+        panic!("Returned valid options instance, should've been invalid.")
+    }
+}
+#[test]
+#[cfg(all(feature = "postgres", feature = "redis"))]
+fn returns_error_on_postgres_with_redis() {
+    if matches!(
+        Options::builder()
+            .ctx(Context {
+                prop: "connection".to_string(),
+            })
+            .auth_block_state(AuthBlockLevel::AllowAll)
+            .jwt_secret("JWT_SECRET")
+            .schema(Query {}, EmptyMutation {}, EmptySubscription {})
+            .redis_url("redis://127.0.0.1/")
+            .postgres_url("postgres://localhost/diana")
+            .listen_to_postgres_channel("users_changed", "new_user")
+            .finish(),
+        Ok(Options { .. })
+    ) {
+        panic!("Returned valid options instance, should've been invalid.")
+    }
+}
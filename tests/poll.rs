@@ -0,0 +1,213 @@
+// These tests check that clients can poll channels on the subscriptions server for what's been published since they last did
+
+mod common;
+
+use async_graphql::EmptySubscription;
+use common::{get_auth_header, Context, Query, JWT_SECRET};
+use diana::{AuthBlockLevel, DianaHandler, DianaResponse, Options};
+use std::time::{Duration, Instant};
+
+type Handler = DianaHandler<Context, Query, Query, EmptySubscription>;
+
+fn get_handler(channel_history_size: usize) -> Handler {
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::AllowAll)
+        .jwt_secret(JWT_SECRET)
+        .channel_history_size(channel_history_size)
+        .max_poll_wait(Duration::from_secs(1))
+        .allow_subscribing("public", &[])
+        .allow_subscribing("order.*", &[])
+        .schema(Query {}, Query {}, EmptySubscription {})
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+async fn publish(diana_handler: &Handler, channel: &str, data: &str) {
+    let body = serde_json::json!({
+        "query": "mutation($channel: String!, $data: String!) { publish(channel: $channel, data: $data) }",
+        "variables": { "channel": channel, "data": data }
+    })
+    .to_string();
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_auth_header("graphql_server"), None)
+        .await;
+    assert!(matches!(res, DianaResponse::Success(val) if !val.contains("errors")));
+}
+
+// Polls the given channel as a client with the given role, returning the result of the poll
+async fn poll_as(
+    diana_handler: &Handler,
+    role: &str,
+    channel: &str,
+    cursor: Option<&str>,
+    wait_ms: u64,
+) -> serde_json::Value {
+    let body = serde_json::json!({
+        "query": "query($channel: String!, $cursor: String, $waitMs: Int) {
+            poll(channel: $channel, cursor: $cursor, waitMs: $waitMs) { messages missed cursor }
+        }",
+        "variables": { "channel": channel, "cursor": cursor, "waitMs": wait_ms }
+    })
+    .to_string();
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_auth_header(role), None)
+        .await;
+    match res {
+        DianaResponse::Success(res) => serde_json::from_str::<serde_json::Value>(&res).unwrap(),
+        _ => panic!("couldn't poll"),
+    }
+}
+
+async fn poll(diana_handler: &Handler, channel: &str, cursor: Option<&str>, wait_ms: u64) -> serde_json::Value {
+    let res = poll_as(diana_handler, "user", channel, cursor, wait_ms).await;
+    assert!(res["errors"].is_null(), "poll failed: {}", res["errors"]);
+    res["data"]["poll"].clone()
+}
+
+fn get_data(res: &serde_json::Value) -> Vec<&str> {
+    res["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["data"].as_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn returns_messages_since_cursor() {
+    let diana_handler = get_handler(10);
+    // Polling without waiting is how a client registers its interest
+    let res = poll(&diana_handler, "public", None, 0).await;
+    assert!(get_data(&res).is_empty());
+    let cursor = res["cursor"].as_str().unwrap().to_string();
+    publish(&diana_handler, "public", "first").await;
+    publish(&diana_handler, "public", "second").await;
+
+    let res = poll(&diana_handler, "public", Some(&cursor), 0).await;
+    assert_eq!(get_data(&res), vec!["first", "second"]);
+    assert_eq!(res["missed"], 0);
+    let cursor = res["cursor"].as_str().unwrap().to_string();
+    // Nothing new has been published, so the same cursor comes back
+    let res = poll(&diana_handler, "public", Some(&cursor), 0).await;
+    assert!(get_data(&res).is_empty());
+    assert_eq!(res["cursor"], cursor);
+
+    publish(&diana_handler, "public", "third").await;
+    let res = poll(&diana_handler, "public", Some(&cursor), 0).await;
+    assert_eq!(get_data(&res), vec!["third"]);
+}
+#[tokio::test]
+async fn waits_for_new_messages() {
+    let diana_handler = get_handler(10);
+    let cursor = poll(&diana_handler, "public", None, 0).await["cursor"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let publishing_handler = diana_handler.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        publish(&publishing_handler, "public", "late").await;
+    });
+
+    let started = Instant::now();
+    let res = poll(&diana_handler, "public", Some(&cursor), 5000).await;
+    assert_eq!(get_data(&res), vec!["late"]);
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+#[tokio::test]
+async fn stops_waiting_at_maximum() {
+    let diana_handler = get_handler(10);
+    let started = Instant::now();
+    // This asks for longer than the maximum of 1 second
+    let res = poll(&diana_handler, "public", None, 60000).await;
+    assert!(get_data(&res).is_empty());
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(2));
+}
+#[tokio::test]
+async fn returns_straight_away_without_wait() {
+    let diana_handler = get_handler(10);
+    let body = serde_json::json!({
+        "query": "query { poll(channel: \"public\") { messages cursor } }"
+    })
+    .to_string();
+    let started = Instant::now();
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_auth_header("user"), None)
+        .await;
+    assert!(matches!(res, DianaResponse::Success(val) if !val.contains("errors")));
+    assert!(started.elapsed() < Duration::from_millis(500));
+}
+#[tokio::test]
+async fn polls_channel_patterns() {
+    let diana_handler = get_handler(10);
+    let cursor = poll(&diana_handler, "order.*", None, 0).await["cursor"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    publish(&diana_handler, "order.1", "first").await;
+    publish(&diana_handler, "order.2", "second").await;
+
+    let res = poll(&diana_handler, "order.*", Some(&cursor), 0).await;
+    assert_eq!(get_data(&res), vec!["first", "second"]);
+    let cursor = res["cursor"].as_str().unwrap().to_string();
+    // Patterns carry on by time, but nothing the client has already seen should come back
+    let res = poll(&diana_handler, "order.*", Some(&cursor), 0).await;
+    assert!(get_data(&res).is_empty());
+    publish(&diana_handler, "order.1", "third").await;
+    let res = poll(&diana_handler, "order.*", Some(&cursor), 0).await;
+    assert_eq!(get_data(&res), vec!["third"]);
+}
+#[tokio::test]
+async fn reports_missed_messages() {
+    let diana_handler = get_handler(2);
+    let cursor = poll(&diana_handler, "public", None, 0).await["cursor"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    publish(&diana_handler, "public", "first").await;
+    let res = poll(&diana_handler, "public", Some(&cursor), 0).await;
+    assert_eq!(get_data(&res), vec!["first"]);
+    let cursor = res["cursor"].as_str().unwrap().to_string();
+    // Only two messages are kept, so the client misses one of these
+    publish(&diana_handler, "public", "second").await;
+    publish(&diana_handler, "public", "third").await;
+    publish(&diana_handler, "public", "fourth").await;
+
+    let res = poll(&diana_handler, "public", Some(&cursor), 0).await;
+    assert_eq!(get_data(&res), vec!["third", "fourth"]);
+    assert_eq!(res["missed"], 1);
+}
+#[tokio::test]
+async fn checks_channel_rules() {
+    let diana_handler = get_handler(10);
+    let res = poll_as(&diana_handler, "user", "private", None, 0).await;
+    assert!(res["errors"][0]["message"]
+        .as_str()
+        .unwrap()
+        .contains("not authorised to subscribe"));
+    // Publishers poll on behalf of their own clients, so they aren't held to the rules
+    let res = poll_as(&diana_handler, "graphql_server", "private", None, 0).await;
+    assert!(res["errors"].is_null());
+}
+#[tokio::test]
+async fn keeps_pattern_cursors_small() {
+    let diana_handler = get_handler(10);
+    let cursor = poll(&diana_handler, "order.*", None, 0).await["cursor"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    for idx in 0..50 {
+        publish(&diana_handler, &format!("order.{}", idx), "created").await;
+    }
+
+    let res = poll(&diana_handler, "order.*", Some(&cursor), 0).await;
+    assert_eq!(get_data(&res).len(), 50);
+    // The cursor shouldn't have to remember every channel the pattern has matched
+    let cursor = res["cursor"].as_str().unwrap().to_string();
+    assert!(cursor.len() < 200, "cursor grew to {} characters", cursor.len());
+    let res = poll(&diana_handler, "order.*", Some(&cursor), 0).await;
+    assert!(get_data(&res).is_empty());
+}
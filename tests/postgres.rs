@@ -0,0 +1,65 @@
+// These tests check that Postgres notifications are forwarded into Diana channels
+// They need a Postgres server running locally, specified by the `DATABASE_URL` environment variable, so they're ignored on CI
+
+#![cfg(feature = "postgres")]
+
+mod common;
+
+use async_graphql::{EmptyMutation, Request, Subscription as GQLSubscription};
+use common::{Context, Query, JWT_SECRET};
+use diana::{graphql_utils::get_stream_for_channel_from_ctx, AuthBlockLevel, DianaHandler, Options, Stream, StreamExt};
+use std::env;
+use std::time::Duration;
+use tokio_postgres::NoTls;
+
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    async fn messages(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+    ) -> async_graphql::Result<impl Stream<Item = String>> {
+        Ok(get_stream_for_channel_from_ctx("test_channel", raw_ctx)?)
+    }
+}
+
+#[tokio::test]
+#[ignore] // This test needs a local Postgres server
+async fn forwards_notifications_to_channel() {
+    let database_url = env::var("DATABASE_URL").unwrap();
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::AllowAll)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .postgres_url(&database_url)
+        .listen_to_postgres_channel("diana_test", "test_channel")
+        .finish()
+        .unwrap();
+    let diana_handler = DianaHandler::new(opts).unwrap();
+
+    let mut subscription = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { messages }"));
+    // Polling the subscription once starts it, we give the listener a moment to connect to Postgres after that
+    let first_message = tokio::spawn(async move { subscription.next().await });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let (client, connection) = tokio_postgres::connect(&database_url, NoTls).await.unwrap();
+    tokio::spawn(connection);
+    client
+        .execute("SELECT pg_notify('diana_test', 'test')", &[])
+        .await
+        .unwrap();
+
+    let msg = tokio::time::timeout(Duration::from_secs(5), first_message)
+        .await
+        .expect("notification wasn't forwarded to the channel")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        serde_json::to_string(&msg).unwrap(),
+        "{\"data\":{\"messages\":\"test\"}}"
+    );
+}
@@ -0,0 +1,155 @@
+// These tests check that subscribers are tracked while they're subscribed and that changes are announced on the presence channel
+
+mod common;
+
+use async_graphql::{EmptyMutation, Request, Response, Subscription as GQLSubscription};
+use common::{get_auth_header, get_auth_state, Context, Query, JWT_SECRET};
+use diana::{
+    errors::Result,
+    graphql_utils::{
+        get_presence_for_channel_from_ctx, get_stream_for_channel_from_ctx, get_typed_stream_for_channel_from_ctx,
+    },
+    stream, AuthBlockLevel, DianaHandler, DianaResponse, Options, PresenceChange, PresenceEvent, Stream, StreamExt,
+    PRESENCE_CHANNEL,
+};
+use std::time::Duration;
+
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    // Subscribers are yielded once as a list of their `sub` claims so we can check them
+    async fn viewers(&self, raw_ctx: &async_graphql::Context<'_>) -> Result<impl Stream<Item = Vec<Option<String>>>> {
+        let presence = get_presence_for_channel_from_ctx("test_channel", raw_ctx)?;
+        let subs = presence
+            .subscribers
+            .into_iter()
+            .map(|subscriber| subscriber.sub)
+            .collect();
+        Ok(stream! { yield subs; })
+    }
+    async fn messages(&self, raw_ctx: &async_graphql::Context<'_>) -> Result<impl Stream<Item = String>> {
+        get_stream_for_channel_from_ctx("test_channel", raw_ctx)
+    }
+    async fn public_messages(&self, raw_ctx: &async_graphql::Context<'_>) -> Result<impl Stream<Item = String>> {
+        get_stream_for_channel_from_ctx("public", raw_ctx)
+    }
+    // Events are yielded as JSON so we can check them
+    async fn presence(&self, raw_ctx: &async_graphql::Context<'_>) -> Result<impl Stream<Item = String>> {
+        let stream = get_typed_stream_for_channel_from_ctx(&PRESENCE_CHANNEL, raw_ctx)?;
+        Ok(stream.map(|event| serde_json::to_string(&event.unwrap()).unwrap()))
+    }
+}
+
+fn get_handler() -> DianaHandler<Context, Query, EmptyMutation, Subscription> {
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::AllowAll)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+// Only admins can subscribe to the test channel, but anyone can watch presence
+fn get_handler_with_rules() -> DianaHandler<Context, Query, EmptyMutation, Subscription> {
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::AllowAll)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .allow_subscribing("test_channel", &[("role", "admin")])
+        .allow_subscribing("public", &[])
+        .allow_subscribing(PRESENCE_CHANNEL.name(), &[])
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+async fn get_viewers(diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>) -> String {
+    let mut viewers = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { viewers }"));
+    let res = viewers.next().await.unwrap();
+    serde_json::to_string(&res).unwrap()
+}
+
+async fn next_event(subscription: &mut (impl Stream<Item = Response> + Unpin)) -> PresenceEvent {
+    let res = tokio::time::timeout(Duration::from_secs(1), subscription.next())
+        .await
+        .expect("subscription didn't yield")
+        .expect("subscription ended");
+    let event = res.data.into_json().unwrap()["presence"].as_str().unwrap().to_string();
+    serde_json::from_str(&event).unwrap()
+}
+
+#[tokio::test]
+async fn tracks_subscribers_until_they_leave() {
+    let diana_handler = get_handler();
+    let mut presence = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { presence }"));
+    // Polling the subscription once subscribes to the channel
+    let _ = tokio::time::timeout(Duration::from_millis(10), presence.next()).await;
+    assert_eq!(get_viewers(&diana_handler).await, "{\"data\":{\"viewers\":[]}}");
+
+    let mut subscription = diana_handler.schema_for_subscriptions.execute_stream(
+        Request::new("subscription { messages }").data(get_auth_state(&[("role", "user"), ("sub", "alice")])),
+    );
+    let _ = tokio::time::timeout(Duration::from_millis(10), subscription.next()).await;
+    assert_eq!(
+        get_viewers(&diana_handler).await,
+        "{\"data\":{\"viewers\":[\"alice\"]}}"
+    );
+    let event = next_event(&mut presence).await;
+    assert_eq!(event.channel, "test_channel");
+    assert_eq!(event.change, PresenceChange::Joined);
+    assert_eq!(event.subscriber.sub.as_deref(), Some("alice"));
+    assert_eq!(event.count, 1);
+
+    // Dropping the subscription is how clients leave
+    drop(subscription);
+    assert_eq!(get_viewers(&diana_handler).await, "{\"data\":{\"viewers\":[]}}");
+    let left = next_event(&mut presence).await;
+    assert_eq!(left.change, PresenceChange::Left);
+    assert_eq!(left.subscriber, event.subscriber);
+    assert_eq!(left.count, 0);
+}
+#[tokio::test]
+async fn hides_presence_on_channels_subscriber_cant_see() {
+    let diana_handler = get_handler_with_rules();
+    let mut presence = diana_handler.schema_for_subscriptions.execute_stream(
+        Request::new("subscription { presence }").data(get_auth_state(&[("role", "user"), ("sub", "alice")])),
+    );
+    let _ = tokio::time::timeout(Duration::from_millis(10), presence.next()).await;
+
+    let mut admin_subscription = diana_handler.schema_for_subscriptions.execute_stream(
+        Request::new("subscription { messages }").data(get_auth_state(&[("role", "admin"), ("sub", "alice")])),
+    );
+    let _ = tokio::time::timeout(Duration::from_millis(10), admin_subscription.next()).await;
+    let mut public_subscription = diana_handler.schema_for_subscriptions.execute_stream(
+        Request::new("subscription { publicMessages }").data(get_auth_state(&[("role", "user"), ("sub", "alice")])),
+    );
+    let _ = tokio::time::timeout(Duration::from_millis(10), public_subscription.next()).await;
+
+    // The admin joined first, but the watcher can't subscribe to that channel, so it only hears about the public one
+    let event = next_event(&mut presence).await;
+    assert_eq!(event.channel, "public");
+}
+#[tokio::test]
+async fn rejects_publishing_on_presence_channel() {
+    let diana_handler = get_handler();
+    let body = serde_json::json!({
+        "query": "mutation($channel: String!) { publish(channel: $channel, data: \"test\") }",
+        "variables": { "channel": PRESENCE_CHANNEL.name() }
+    })
+    .to_string();
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_auth_header("graphql_server"), None)
+        .await;
+    match res {
+        DianaResponse::Success(val) => assert!(val.contains("reserved for the subscriptions server's own use")),
+        res => panic!("Expected an error from publishing, got {:?}", res),
+    }
+}
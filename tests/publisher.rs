@@ -0,0 +1,722 @@
+// These tests check how the publisher copes with a subscriptions server that's failing, using a fake one that gives canned responses
+
+mod common;
+
+use async_graphql::{EmptySubscription, Object as GQLObject};
+use common::{Context, Query};
+use diana::{
+    create_jwt, decode_time_str,
+    errors::{GQLResult, PublishError},
+    get_jwt_secret, AuthBlockLevel, BufferedPublisher, DianaHandler, DianaResponse, MessageEnvelope, Options,
+    Publisher, PublisherConfig,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const ACKNOWLEDGED: &str = "{\"data\":{\"publish\":1}}";
+
+// Starts a fake subscriptions server that gives the given responses in order (repeating the last one forever), after waiting for the
+// given delay
+// This returns the port it's listening on and a count of the requests it's received
+async fn start_fake_server(responses: Vec<(u16, &'static str)>, delay: Duration) -> (String, Arc<AtomicUsize>) {
+    let (port, requests, _) = start_recording_fake_server(responses, delay).await;
+    (port, requests)
+}
+
+// The same as `start_fake_server`, but this also returns the bodies of the requests that were acknowledged, in order
+async fn start_recording_fake_server(
+    responses: Vec<(u16, &'static str)>,
+    delay: Duration,
+) -> (String, Arc<AtomicUsize>, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port().to_string();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let requests_clone = Arc::clone(&requests);
+    let acknowledged = Arc::new(Mutex::new(Vec::new()));
+    let acknowledged_clone = Arc::clone(&acknowledged);
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let idx = requests_clone.fetch_add(1, Ordering::SeqCst);
+            let (status, body) = responses[idx.min(responses.len() - 1)];
+            let acknowledged = Arc::clone(&acknowledged_clone);
+            tokio::spawn(async move {
+                let req_body = read_request(&mut socket).await;
+                if status == 200 {
+                    acknowledged.lock().unwrap().push(req_body);
+                }
+                tokio::time::sleep(delay).await;
+                let res = format!(
+                    "HTTP/1.1 {} Whatever\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(res.as_bytes()).await;
+            });
+        }
+    });
+
+    (port, requests, acknowledged)
+}
+
+// Reads an entire HTTP request, so the client doesn't see the connection close early, returning its body
+async fn read_request(socket: &mut (impl AsyncRead + Unpin)) -> String {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        let read = socket.read(&mut chunk).await.unwrap();
+        if read == 0 {
+            return String::new();
+        }
+        buf.extend_from_slice(&chunk[..read]);
+        let req = String::from_utf8_lossy(&buf);
+        if let Some(headers_end) = req.find("\r\n\r\n") {
+            let content_length = req[..headers_end]
+                .lines()
+                .find_map(|line| {
+                    line.to_lowercase()
+                        .strip_prefix("content-length: ")
+                        .map(|len| len.parse::<usize>().unwrap())
+                })
+                .unwrap_or(0);
+            if buf.len() >= headers_end + 4 + content_length {
+                return req[headers_end + 4..].to_string();
+            }
+        }
+    }
+}
+
+// Starts a fake subscriptions server on a Unix socket that acknowledges everything
+// This returns the path of the socket and the bodies of the requests it's received, in order
+#[cfg(unix)]
+async fn start_socket_fake_server(name: &str) -> (String, Arc<Mutex<Vec<String>>>) {
+    let socket_path = get_socket_path(name);
+    let listener = tokio::net::UnixListener::bind(&socket_path).unwrap();
+    let acknowledged = Arc::new(Mutex::new(Vec::new()));
+    let acknowledged_clone = Arc::clone(&acknowledged);
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let acknowledged = Arc::clone(&acknowledged_clone);
+            tokio::spawn(async move {
+                let req_body = read_request(&mut socket).await;
+                acknowledged.lock().unwrap().push(req_body);
+                let res = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    ACKNOWLEDGED.len(),
+                    ACKNOWLEDGED
+                );
+                let _ = socket.write_all(res.as_bytes()).await;
+            });
+        }
+    });
+
+    (socket_path, acknowledged)
+}
+
+#[derive(Clone)]
+struct Mutation {}
+#[GQLObject]
+impl Mutation {
+    // Publishes through the buffered publisher, then fails if asked to
+    async fn publish_then(&self, raw_ctx: &async_graphql::Context<'_>, fail: bool) -> GQLResult<bool> {
+        let publisher = raw_ctx.data::<BufferedPublisher>()?;
+        publisher.publish("test_channel", "buffered".to_string());
+        publisher.publish_now("test_channel", "immediate".to_string()).await?;
+        if fail {
+            return Err("something went wrong".into());
+        }
+        Ok(true)
+    }
+}
+
+fn get_handler(port: String) -> DianaHandler<Context, Query, Mutation, EmptySubscription> {
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::AllowAll)
+        .jwt_secret("thisisaterriblesecretthatshouldberandomlygeneratedseethebook")
+        .schema(Query {}, Mutation {}, EmptySubscription {})
+        .subscriptions_server_hostname("http://127.0.0.1")
+        .subscriptions_server_port(&port)
+        .subscriptions_server_endpoint("/graphql")
+        .jwt_to_connect_to_subscriptions_server("token")
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+fn get_publisher(port: String, config: PublisherConfig) -> Publisher {
+    Publisher::with_config(
+        "http://127.0.0.1".to_string(),
+        port,
+        "/graphql".to_string(),
+        "token".to_string(),
+        config,
+    )
+    .unwrap()
+}
+
+// Gets a publisher with an outbox, waiting for the worker of any previous publisher using the same outbox to notice it's gone
+async fn get_publisher_when_outbox_free(port: String, config: PublisherConfig) -> Publisher {
+    for _ in 0..200 {
+        if let Ok(publisher) = Publisher::with_config(
+            "http://127.0.0.1".to_string(),
+            port.clone(),
+            "/graphql".to_string(),
+            "token".to_string(),
+            config.clone(),
+        ) {
+            return publisher;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("the previous publisher never let go of the outbox")
+}
+
+#[cfg(unix)]
+fn get_socket_path(name: &str) -> String {
+    let socket_path = std::env::temp_dir().join(format!("diana-publisher-test-{}-{}.sock", name, std::process::id()));
+    let _ = std::fs::remove_file(&socket_path);
+    socket_path.to_str().unwrap().to_string()
+}
+
+fn get_config() -> PublisherConfig {
+    PublisherConfig {
+        retry_backoff: Duration::from_millis(1),
+        ..Default::default()
+    }
+}
+
+// Waits for the fake server to have acknowledged the given number of messages, returning them
+async fn wait_for_acknowledged(acknowledged: &Arc<Mutex<Vec<String>>>, count: usize) -> Vec<String> {
+    for _ in 0..100 {
+        let acknowledged = acknowledged.lock().unwrap().clone();
+        if acknowledged.len() >= count {
+            return acknowledged;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("messages weren't delivered from the outbox")
+}
+
+fn get_outbox_dir(name: &str) -> String {
+    let outbox_dir = std::env::temp_dir().join(format!("diana-outbox-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&outbox_dir);
+    outbox_dir.to_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn retries_temporary_failures() {
+    let (port, requests) = start_fake_server(vec![(503, ""), (503, ""), (200, ACKNOWLEDGED)], Duration::ZERO).await;
+    let publisher = get_publisher(port, get_config());
+    publisher.publish("test_channel", "test".to_string()).await.unwrap();
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+}
+#[tokio::test]
+async fn publishes_many_messages_in_one_request() {
+    let (port, requests, acknowledged) =
+        start_recording_fake_server(vec![(200, "{\"data\":{\"publishMany\":[1,1]}}")], Duration::ZERO).await;
+    let publisher = get_publisher(port, get_config());
+    let messages = vec![
+        ("test_channel".to_string(), "first".to_string()),
+        ("other_channel".to_string(), "second".to_string()),
+    ];
+    let subscribers = publisher.publish_many(messages).await.unwrap();
+    assert_eq!(subscribers, Some(vec![1, 1]));
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    let acknowledged = acknowledged.lock().unwrap().clone();
+    assert!(acknowledged[0].contains("publishMany"));
+    let first = acknowledged[0].find("\"data\":\"first\"").unwrap();
+    let second = acknowledged[0].find("\"data\":\"second\"").unwrap();
+    assert!(first < second);
+}
+#[tokio::test]
+async fn returns_subscriber_counts() {
+    let (port, _) = start_fake_server(vec![(200, "{\"data\":{\"publish\":3}}")], Duration::ZERO).await;
+    let publisher = get_publisher(port, get_config());
+    let subscribers = publisher.publish("test_channel", "test".to_string()).await.unwrap();
+    assert_eq!(subscribers, Some(3));
+}
+#[tokio::test]
+async fn returns_status_without_retrying_client_errors() {
+    let (port, requests) = start_fake_server(vec![(400, "bad request")], Duration::ZERO).await;
+    let publisher = get_publisher(port, get_config());
+    let err = publisher.publish("test_channel", "test".to_string()).await.unwrap_err();
+    assert!(matches!(err, PublishError::Status { status: 400, body } if body == "bad request"));
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
+#[tokio::test]
+async fn returns_error_if_not_acknowledged() {
+    let body = "{\"data\":null,\"errors\":[{\"message\":\"unauthorised\"}]}";
+    let (port, _) = start_fake_server(vec![(200, body)], Duration::ZERO).await;
+    let publisher = get_publisher(port, get_config());
+    let err = publisher.publish("test_channel", "test".to_string()).await.unwrap_err();
+    assert!(matches!(err, PublishError::NotAcknowledged { .. }));
+}
+#[tokio::test]
+async fn times_out_slow_responses() {
+    let (port, _) = start_fake_server(vec![(200, ACKNOWLEDGED)], Duration::from_secs(5)).await;
+    let publisher = get_publisher(
+        port,
+        PublisherConfig {
+            timeout: Duration::from_millis(50),
+            retries: 0,
+            ..get_config()
+        },
+    );
+    let err = publisher.publish("test_channel", "test".to_string()).await.unwrap_err();
+    assert!(matches!(err, PublishError::Timeout));
+}
+#[tokio::test]
+async fn opens_circuit_after_repeated_failures() {
+    let (port, requests) = start_fake_server(vec![(500, "")], Duration::ZERO).await;
+    let publisher = get_publisher(
+        port,
+        PublisherConfig {
+            retries: 0,
+            circuit_breaker_threshold: 2,
+            circuit_breaker_cooldown: Duration::from_secs(60),
+            ..get_config()
+        },
+    );
+    for _ in 0..2 {
+        let err = publisher.publish("test_channel", "test".to_string()).await.unwrap_err();
+        assert!(matches!(err, PublishError::Status { status: 500, .. }));
+    }
+    // The subscriptions server shouldn't even be contacted now
+    let err = publisher.publish("test_channel", "test".to_string()).await.unwrap_err();
+    assert!(matches!(err, PublishError::CircuitOpen { .. }));
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+#[tokio::test]
+async fn keeps_circuit_closed_after_permanent_failures() {
+    let body = "{\"data\":null,\"errors\":[{\"message\":\"unauthorised\"}]}";
+    let (port, requests) = start_fake_server(vec![(400, ""), (200, body), (401, "")], Duration::ZERO).await;
+    let publisher = get_publisher(
+        port,
+        PublisherConfig {
+            retries: 0,
+            circuit_breaker_threshold: 2,
+            circuit_breaker_cooldown: Duration::from_secs(60),
+            ..get_config()
+        },
+    );
+    // These are problems with what's being published, not with the subscriptions server, so they shouldn't stop anyone else publishing
+    for _ in 0..4 {
+        let err = publisher.publish("test_channel", "test".to_string()).await.unwrap_err();
+        assert!(!matches!(err, PublishError::CircuitOpen { .. }));
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 4);
+}
+#[tokio::test]
+async fn lets_one_publish_through_after_cooldown() {
+    let (port, requests) = start_fake_server(vec![(500, ""), (200, ACKNOWLEDGED)], Duration::from_millis(100)).await;
+    let publisher = get_publisher(
+        port,
+        PublisherConfig {
+            retries: 0,
+            circuit_breaker_threshold: 1,
+            circuit_breaker_cooldown: Duration::from_millis(50),
+            ..get_config()
+        },
+    );
+    let err = publisher.publish("test_channel", "test".to_string()).await.unwrap_err();
+    assert!(matches!(err, PublishError::Status { status: 500, .. }));
+    tokio::time::sleep(Duration::from_millis(60)).await;
+
+    let probing_publisher = publisher.clone();
+    let probe = tokio::spawn(async move { probing_publisher.publish("test_channel", "test".to_string()).await });
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    // Only the first publish after the cooldown should get through while we wait to see how it goes
+    let err = publisher.publish("test_channel", "test".to_string()).await.unwrap_err();
+    assert!(matches!(err, PublishError::CircuitOpen { retry_after } if retry_after == Duration::ZERO));
+    probe.await.unwrap().unwrap();
+    // The subscriptions server has recovered, so the circuit is closed again
+    publisher.publish("test_channel", "test".to_string()).await.unwrap();
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+}
+#[tokio::test]
+async fn delivers_from_outbox_in_order_when_server_recovers() {
+    let outbox_dir = get_outbox_dir("order");
+    let (port, _, acknowledged) =
+        start_recording_fake_server(vec![(503, ""), (503, ""), (200, ACKNOWLEDGED)], Duration::ZERO).await;
+    let publisher = get_publisher(
+        port,
+        PublisherConfig {
+            outbox_dir: Some(outbox_dir.clone().into()),
+            ..get_config()
+        },
+    );
+    // These return as soon as the messages are recorded, even though the subscriptions server is failing, so nobody has received them yet
+    assert_eq!(
+        publisher.publish("test_channel", "first".to_string()).await.unwrap(),
+        None
+    );
+    publisher.publish("test_channel", "second".to_string()).await.unwrap();
+
+    let acknowledged = wait_for_acknowledged(&acknowledged, 2).await;
+    assert!(acknowledged[0].contains("\"data\":\"first\""));
+    assert!(acknowledged[1].contains("\"data\":\"second\""));
+
+    std::fs::remove_dir_all(outbox_dir).unwrap();
+}
+#[tokio::test]
+async fn delivers_leftover_outbox_messages_after_restart() {
+    let outbox_dir = get_outbox_dir("restart");
+    {
+        // Nothing is listening on this port, so the message will stay in the outbox
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let dead_port = listener.local_addr().unwrap().port().to_string();
+        drop(listener);
+        let publisher = get_publisher(
+            dead_port,
+            PublisherConfig {
+                outbox_dir: Some(outbox_dir.clone().into()),
+                ..get_config()
+            },
+        );
+        publisher.publish("test_channel", "leftover".to_string()).await.unwrap();
+    }
+
+    let (port, _, acknowledged) = start_recording_fake_server(vec![(200, ACKNOWLEDGED)], Duration::ZERO).await;
+    let _publisher = get_publisher_when_outbox_free(
+        port,
+        PublisherConfig {
+            outbox_dir: Some(outbox_dir.clone().into()),
+            ..get_config()
+        },
+    )
+    .await;
+    let acknowledged = wait_for_acknowledged(&acknowledged, 1).await;
+    assert!(acknowledged[0].contains("\"data\":\"leftover\""));
+
+    std::fs::remove_dir_all(outbox_dir).unwrap();
+}
+#[tokio::test]
+async fn refuses_to_share_an_outbox() {
+    let outbox_dir = get_outbox_dir("shared");
+    let (port, _) = start_fake_server(vec![(200, ACKNOWLEDGED)], Duration::ZERO).await;
+    let config = PublisherConfig {
+        outbox_dir: Some(outbox_dir.clone().into()),
+        ..get_config()
+    };
+    let _publisher = get_publisher(port.clone(), config.clone());
+    let err = Publisher::with_config(
+        "http://127.0.0.1".to_string(),
+        port,
+        "/graphql".to_string(),
+        "token".to_string(),
+        config,
+    )
+    .err()
+    .unwrap();
+    assert!(err.to_string().contains("already being used by another publisher"));
+
+    std::fs::remove_dir_all(outbox_dir).unwrap();
+}
+#[tokio::test]
+async fn only_delivers_from_outbox_once_per_options() {
+    let outbox_dir = get_outbox_dir("options");
+    let (port, requests, acknowledged) = start_recording_fake_server(vec![(200, ACKNOWLEDGED)], Duration::ZERO).await;
+    let get_opts = || {
+        Options::builder()
+            .ctx(Context {})
+            .auth_block_state(AuthBlockLevel::AllowAll)
+            .jwt_secret("thisisaterriblesecretthatshouldberandomlygeneratedseethebook")
+            .schema(Query {}, Mutation {}, EmptySubscription {})
+            .subscriptions_server_hostname("http://127.0.0.1")
+            .subscriptions_server_port(&port)
+            .subscriptions_server_endpoint("/graphql")
+            .jwt_to_connect_to_subscriptions_server("token")
+            .publish_outbox_dir(&outbox_dir)
+            .finish()
+            .unwrap()
+    };
+    // The subscriptions server is built from separate options (as if it were in another process), and never touches the outbox
+    let subscriptions_server = DianaHandler::new(get_opts()).unwrap();
+    subscriptions_server.start_subscriptions_server();
+    // Every handler built from the same options (e.g. one per request in a serverless function) shares one outbox
+    let opts = get_opts();
+    let first_handler = DianaHandler::new(opts.clone()).unwrap();
+    first_handler.start_outbox().unwrap();
+    let second_handler = DianaHandler::new(opts).unwrap();
+    second_handler.start_outbox().unwrap();
+    let res = second_handler
+        .run_stateless_without_subscriptions(
+            "{\"query\": \"mutation { publishThen(fail: false) }\"}".to_string(),
+            Option::<String>::None,
+            None,
+        )
+        .await;
+    assert!(matches!(res, DianaResponse::Success(_)));
+
+    let acknowledged = wait_for_acknowledged(&acknowledged, 2).await;
+    assert!(acknowledged[0].contains("\"data\":\"immediate\""));
+    assert!(acknowledged[1].contains("\"data\":\"buffered\""));
+    // Give any other workers the chance to deliver them again
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    std::fs::remove_dir_all(outbox_dir).unwrap();
+}
+#[tokio::test]
+async fn delivers_outbox_messages_recorded_by_older_versions() {
+    let outbox_dir = get_outbox_dir("legacy");
+    // Older versions recorded a single message per line, without an envelope, rather than a batch
+    std::fs::create_dir_all(&outbox_dir).unwrap();
+    std::fs::write(
+        std::path::Path::new(&outbox_dir).join("outbox.jsonl"),
+        "{\"channel\":\"test_channel\",\"data\":\"legacy\"}\n",
+    )
+    .unwrap();
+
+    let (port, _, acknowledged) = start_recording_fake_server(vec![(200, ACKNOWLEDGED)], Duration::ZERO).await;
+    let publisher = get_publisher(
+        port,
+        PublisherConfig {
+            outbox_dir: Some(outbox_dir.clone().into()),
+            ..get_config()
+        },
+    );
+    publisher.publish("test_channel", "current".to_string()).await.unwrap();
+    let acknowledged = wait_for_acknowledged(&acknowledged, 2).await;
+    assert!(acknowledged[0].contains("\"data\":\"legacy\""));
+    assert!(acknowledged[1].contains("\"data\":\"current\""));
+
+    std::fs::remove_dir_all(outbox_dir).unwrap();
+}
+#[tokio::test]
+async fn sets_aside_outbox_messages_the_server_rejects() {
+    let outbox_dir = get_outbox_dir("rejected");
+    // Authentication failures come back as a successful response with no data, and retrying them won't help
+    let (port, requests) = start_fake_server(vec![(200, "{\"data\":null}"), (200, ACKNOWLEDGED)], Duration::ZERO).await;
+    let publisher = get_publisher(
+        port,
+        PublisherConfig {
+            outbox_dir: Some(outbox_dir.clone().into()),
+            ..get_config()
+        },
+    );
+    publisher.publish("test_channel", "rejected".to_string()).await.unwrap();
+    publisher.publish("test_channel", "accepted".to_string()).await.unwrap();
+
+    // The rejected message shouldn't have held up the one behind it
+    for _ in 0..100 {
+        if requests.load(Ordering::SeqCst) >= 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+    let rejected = publisher.take_rejected().await.unwrap();
+    assert_eq!(rejected.len(), 1);
+    assert_eq!(rejected[0].channel, "test_channel");
+    assert_eq!(rejected[0].data, "rejected");
+    assert!(rejected[0].reason.contains("didn't acknowledge"));
+    // Once they've been taken, they're gone
+    assert!(publisher.take_rejected().await.unwrap().is_empty());
+
+    std::fs::remove_dir_all(outbox_dir).unwrap();
+}
+#[tokio::test]
+async fn flushes_buffered_publishes_after_successful_request() {
+    let (port, _, acknowledged) = start_recording_fake_server(vec![(200, ACKNOWLEDGED)], Duration::ZERO).await;
+    let diana_handler = get_handler(port);
+    let res = diana_handler
+        .run_stateless_without_subscriptions(
+            "{\"query\": \"mutation { publishThen(fail: false) }\"}".to_string(),
+            Option::<String>::None,
+            None,
+        )
+        .await;
+    assert!(matches!(res, DianaResponse::Success(val) if val == "{\"data\":{\"publishThen\":true}}"));
+
+    let acknowledged = acknowledged.lock().unwrap().clone();
+    assert_eq!(acknowledged.len(), 2);
+    assert!(acknowledged[0].contains("\"data\":\"immediate\""));
+    assert!(acknowledged[1].contains("\"data\":\"buffered\""));
+}
+#[tokio::test]
+async fn discards_buffered_publishes_after_failed_request() {
+    let (port, _, acknowledged) = start_recording_fake_server(vec![(200, ACKNOWLEDGED)], Duration::ZERO).await;
+    let diana_handler = get_handler(port);
+    let res = diana_handler
+        .run_stateless_without_subscriptions(
+            "{\"query\": \"mutation { publishThen(fail: true) }\"}".to_string(),
+            Option::<String>::None,
+            None,
+        )
+        .await;
+    assert!(matches!(res, DianaResponse::Success(val) if val.contains("something went wrong")));
+
+    // Only the immediate publish should have got through
+    let acknowledged = acknowledged.lock().unwrap().clone();
+    assert_eq!(acknowledged.len(), 1);
+    assert!(acknowledged[0].contains("\"data\":\"immediate\""));
+}
+#[tokio::test]
+async fn sends_request_details_in_envelopes() {
+    let (port, _, acknowledged) = start_recording_fake_server(vec![(200, ACKNOWLEDGED)], Duration::ZERO).await;
+    let diana_handler = get_handler(port);
+    let secret = get_jwt_secret("thisisaterriblesecretthatshouldberandomlygeneratedseethebook".to_string()).unwrap();
+    let mut claims = HashMap::new();
+    claims.insert("sub".to_string(), "alice".to_string());
+    let jwt = create_jwt(claims, &secret, decode_time_str("1m").unwrap()).unwrap();
+    diana_handler
+        .run_stateless_without_subscriptions(
+            "{\"query\": \"mutation { publishThen(fail: false) }\"}".to_string(),
+            Some("Bearer ".to_string() + &jwt),
+            None,
+        )
+        .await;
+
+    let envelopes = acknowledged
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|body| {
+            let body = serde_json::from_str::<serde_json::Value>(body).unwrap();
+            serde_json::from_value::<MessageEnvelope>(body["variables"]["envelope"].clone()).unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(envelopes.len(), 2);
+    assert_eq!(envelopes[0].origin_sub(), Some("alice"));
+    assert_eq!(envelopes[1].origin_sub(), Some("alice"));
+    // Both messages came from the same request, but they're still different messages
+    assert!(envelopes[0].request_id.is_some());
+    assert_eq!(envelopes[0].request_id, envelopes[1].request_id);
+    assert_ne!(envelopes[0].id, envelopes[1].id);
+}
+#[tokio::test]
+async fn schedules_and_cancels_messages() {
+    let (port, requests, acknowledged) = start_recording_fake_server(
+        vec![
+            (200, "{\"data\":{\"schedulePublish\":\"scheduled\"}}"),
+            (200, "{\"data\":{\"cancelScheduled\":true}}"),
+        ],
+        Duration::ZERO,
+    )
+    .await;
+    let publisher = get_publisher(port, get_config());
+    let id = publisher
+        .publish_after("test_channel", "test".to_string(), Duration::from_secs(600))
+        .await
+        .unwrap();
+    assert_eq!(id, "scheduled");
+    let cancelled = publisher.cancel_scheduled(&id).await.unwrap();
+    assert!(cancelled);
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    let acknowledged = acknowledged.lock().unwrap().clone();
+    let body = serde_json::from_str::<serde_json::Value>(&acknowledged[0]).unwrap();
+    assert!(body["query"].as_str().unwrap().contains("schedulePublish"));
+    let at = chrono::DateTime::parse_from_rfc3339(body["variables"]["at"].as_str().unwrap()).unwrap();
+    let delay = at.signed_duration_since(chrono::Utc::now());
+    assert!(delay > chrono::Duration::seconds(590) && delay <= chrono::Duration::seconds(600));
+    assert!(acknowledged[1].contains("cancelScheduled"));
+}
+#[tokio::test]
+async fn refuses_to_schedule_too_far_ahead() {
+    let (port, requests) = start_fake_server(vec![(200, ACKNOWLEDGED)], Duration::ZERO).await;
+    let publisher = get_publisher(port, get_config());
+    let err = publisher
+        .publish_after("test_channel", "test".to_string(), Duration::from_secs(u64::MAX))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, PublishError::ScheduleTooFar));
+    // The subscriptions server couldn't have parsed the time anyway
+    assert_eq!(requests.load(Ordering::SeqCst), 0);
+}
+#[tokio::test]
+async fn polls_channels() {
+    let (port, _, acknowledged) = start_recording_fake_server(
+        vec![(
+            200,
+            "{\"data\":{\"poll\":{\"messages\":[{\"channel\":\"test_channel\",\"seq\":3,\"published_at\":\"2021-06-01T12:00:00Z\",\"data\":\"test\"}],\"missed\":0,\"cursor\":\"next\"}}}",
+        )],
+        Duration::ZERO,
+    )
+    .await;
+    let publisher = get_publisher(port, get_config());
+    let res = publisher
+        .poll("test_channel", Some("cursor"), Duration::from_secs(60))
+        .await
+        .unwrap();
+    assert_eq!(res.messages.len(), 1);
+    assert_eq!(res.messages[0].seq, 3);
+    assert_eq!(res.messages[0].data, "test");
+    assert_eq!(res.missed, 0);
+    assert_eq!(res.cursor, "next");
+
+    let acknowledged = acknowledged.lock().unwrap().clone();
+    let body = serde_json::from_str::<serde_json::Value>(&acknowledged[0]).unwrap();
+    assert_eq!(body["variables"]["cursor"], "cursor");
+    // The wait is cut down so the subscriptions server can respond within the publisher's timeout
+    assert_eq!(body["variables"]["waitMs"], 9000);
+}
+#[cfg(unix)]
+#[tokio::test]
+async fn publishes_over_unix_socket() {
+    let (socket_path, acknowledged) = start_socket_fake_server("publishes").await;
+    let publisher = Publisher::with_socket(
+        socket_path.into(),
+        "/graphql".to_string(),
+        "token".to_string(),
+        get_config(),
+    )
+    .unwrap();
+    let subscribers = publisher.publish("test_channel", "test".to_string()).await.unwrap();
+    assert_eq!(subscribers, Some(1));
+
+    let acknowledged = acknowledged.lock().unwrap().clone();
+    let body = serde_json::from_str::<serde_json::Value>(&acknowledged[0]).unwrap();
+    assert_eq!(body["variables"]["channel"], "test_channel");
+    assert_eq!(body["variables"]["data"], "test");
+}
+#[cfg(unix)]
+#[tokio::test]
+async fn handler_publishes_over_unix_socket() {
+    let (socket_path, acknowledged) = start_socket_fake_server("handler").await;
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::AllowAll)
+        .jwt_secret("thisisaterriblesecretthatshouldberandomlygeneratedseethebook")
+        .schema(Query {}, Mutation {}, EmptySubscription {})
+        .subscriptions_server_socket(&socket_path)
+        .subscriptions_server_endpoint("/graphql")
+        .jwt_to_connect_to_subscriptions_server("token")
+        .finish()
+        .unwrap();
+    let diana_handler = DianaHandler::new(opts).unwrap();
+    let res = diana_handler
+        .run_stateless_without_subscriptions(
+            "{\"query\": \"mutation { publishThen(fail: false) }\"}".to_string(),
+            Option::<String>::None,
+            None,
+        )
+        .await;
+    assert!(matches!(res, DianaResponse::Success(val) if val == "{\"data\":{\"publishThen\":true}}"));
+    assert_eq!(acknowledged.lock().unwrap().len(), 2);
+}
+#[cfg(unix)]
+#[tokio::test]
+async fn returns_error_if_nothing_on_unix_socket() {
+    let publisher = Publisher::with_socket(
+        get_socket_path("nothing").into(),
+        "/graphql".to_string(),
+        "token".to_string(),
+        PublisherConfig {
+            retries: 0,
+            ..get_config()
+        },
+    )
+    .unwrap();
+    let err = publisher.publish("test_channel", "test".to_string()).await.unwrap_err();
+    assert!(matches!(err, PublishError::Socket(_)));
+}
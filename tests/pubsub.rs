@@ -0,0 +1,111 @@
+// These tests check the behaviour of channels on the subscriptions server, by publishing through its inbuilt mutation and subscribing
+// directly through its schema
+
+mod common;
+
+use async_graphql::{EmptyMutation, Request, Response, Subscription as GQLSubscription};
+use common::{get_publishing_auth_header, Context, Query, JWT_SECRET};
+use diana::{
+    graphql_utils::get_event_stream_for_channel_from_ctx, AuthBlockLevel, ChannelEvent, DianaHandler, DianaResponse,
+    LagPolicy, Options, Stream, StreamExt,
+};
+use std::time::Duration;
+
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    // Lag notices are turned into strings so we can see them
+    async fn events(&self, raw_ctx: &async_graphql::Context<'_>) -> async_graphql::Result<impl Stream<Item = String>> {
+        let stream = get_event_stream_for_channel_from_ctx("test_channel", raw_ctx)?;
+        Ok(stream.map(|event| match event {
+            ChannelEvent::Message(message) => message.data,
+            ChannelEvent::Lagged(missed) => format!("lagged {}", missed),
+        }))
+    }
+}
+
+fn get_handler(lag_policy: LagPolicy) -> DianaHandler<Context, Query, EmptyMutation, Subscription> {
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .channel_buffer_size_for("test_channel", 1)
+        .lag_policy(lag_policy)
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+async fn publish(diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>, data: &str) {
+    let body = format!(
+        "{{\"query\": \"mutation {{ publish(channel: \\\"test_channel\\\", data: \\\"{}\\\") }}\"}}",
+        data
+    );
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_publishing_auth_header(), None)
+        .await;
+    if !matches!(res.clone(), DianaResponse::Success(val) if val.starts_with("{\"data\":{\"publish\":")) {
+        panic!("Couldn't publish message, got {:?}", res)
+    }
+}
+
+// Subscribes to the test channel and then publishes three messages, which is two more than the subscriber can buffer
+async fn get_lagged_subscription(
+    diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>,
+) -> impl Stream<Item = Response> {
+    let mut subscription = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { events }"));
+    // Polling the subscription once subscribes to the channel
+    let _ = tokio::time::timeout(Duration::from_millis(10), subscription.next()).await;
+    for data in &["first", "second", "third"] {
+        publish(diana_handler, data).await;
+    }
+    subscription
+}
+
+async fn next_event(subscription: &mut (impl Stream<Item = Response> + Unpin)) -> Option<String> {
+    let res = tokio::time::timeout(Duration::from_secs(1), subscription.next())
+        .await
+        .expect("subscription didn't yield or end")?;
+    Some(serde_json::to_string(&res).unwrap())
+}
+
+#[tokio::test]
+async fn skips_dropped_messages() {
+    let diana_handler = get_handler(LagPolicy::Skip);
+    let subscription = get_lagged_subscription(&diana_handler).await;
+    tokio::pin!(subscription);
+    assert_eq!(
+        next_event(&mut subscription).await,
+        Some("{\"data\":{\"events\":\"third\"}}".to_string())
+    );
+    assert_eq!(diana_handler.pubsub_metrics().messages_dropped, 2);
+}
+#[tokio::test]
+async fn notifies_of_dropped_messages() {
+    let diana_handler = get_handler(LagPolicy::Notify);
+    let subscription = get_lagged_subscription(&diana_handler).await;
+    tokio::pin!(subscription);
+    assert_eq!(
+        next_event(&mut subscription).await,
+        Some("{\"data\":{\"events\":\"lagged 2\"}}".to_string())
+    );
+    assert_eq!(
+        next_event(&mut subscription).await,
+        Some("{\"data\":{\"events\":\"third\"}}".to_string())
+    );
+    assert_eq!(diana_handler.pubsub_metrics().messages_dropped, 2);
+}
+#[tokio::test]
+async fn disconnects_lagging_subscribers() {
+    let diana_handler = get_handler(LagPolicy::Disconnect);
+    let subscription = get_lagged_subscription(&diana_handler).await;
+    tokio::pin!(subscription);
+    assert_eq!(next_event(&mut subscription).await, None);
+    let metrics = diana_handler.pubsub_metrics();
+    assert_eq!(metrics.messages_dropped, 2);
+    assert_eq!(metrics.subscribers_disconnected, 1);
+}
@@ -0,0 +1,156 @@
+// These tests check that messages are relayed between replicas of the subscriptions server through Redis
+// Most of them need a Redis server running locally on the default port (`redis-server` will do), so they're ignored on CI
+
+#![cfg(feature = "redis")]
+
+mod common;
+
+use async_graphql::{EmptyMutation, Request, Subscription as GQLSubscription};
+use common::{get_publishing_auth_header, Context, Query, JWT_SECRET};
+use diana::{
+    graphql_utils::{get_event_stream_for_channel_from_ctx, get_stream_for_channel_from_ctx},
+    AuthBlockLevel, ChannelEvent, DianaHandler, DianaResponse, Options, Stream, StreamExt,
+};
+use std::time::Duration;
+
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    async fn messages(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+    ) -> async_graphql::Result<impl Stream<Item = String>> {
+        Ok(get_stream_for_channel_from_ctx("test_channel", raw_ctx)?)
+    }
+    async fn sequence_numbers(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+    ) -> async_graphql::Result<impl Stream<Item = u64>> {
+        Ok(
+            get_event_stream_for_channel_from_ctx("test_channel", raw_ctx)?.filter_map(|event| match event {
+                ChannelEvent::Message(message) => Some(message.seq),
+                _ => None,
+            }),
+        )
+    }
+}
+
+const REDIS_URL: &str = "redis://127.0.0.1/";
+// Nothing should be listening here
+const UNREACHABLE_REDIS_URL: &str = "redis://127.0.0.1:1/";
+const PUBLISH_MUTATION: &str =
+    "{\"query\": \"mutation { publish(channel: \\\"test_channel\\\", data: \\\"test\\\") }\"}";
+
+fn get_replica(redis_url: &str) -> DianaHandler<Context, Query, EmptyMutation, Subscription> {
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .redis_url(redis_url)
+        .finish()
+        .unwrap();
+    let diana_handler = DianaHandler::new(opts).unwrap();
+    diana_handler.start_subscriptions_server();
+    diana_handler
+}
+
+#[tokio::test]
+#[ignore] // This test needs a local Redis server
+async fn relays_messages_between_replicas() {
+    let publishing_replica = get_replica(REDIS_URL);
+    let subscribing_replica = get_replica(REDIS_URL);
+
+    let mut subscription = subscribing_replica
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { messages }"));
+    // Polling the subscription once starts it, and we give the relays a moment to connect to Redis
+    let first_message = tokio::spawn(async move { subscription.next().await });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let res = publishing_replica
+        .run_stateless_for_subscriptions(PUBLISH_MUTATION.to_string(), get_publishing_auth_header(), None)
+        .await;
+    if !matches!(res.clone(), DianaResponse::Success(val) if val.starts_with("{\"data\":{\"publish\":")) {
+        panic!("Couldn't publish message to first replica, got {:?}", res)
+    }
+
+    let msg = tokio::time::timeout(Duration::from_secs(5), first_message)
+        .await
+        .expect("message wasn't relayed to the second replica")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        serde_json::to_string(&msg).unwrap(),
+        "{\"data\":{\"messages\":\"test\"}}"
+    );
+}
+#[tokio::test]
+#[ignore] // This test needs a local Redis server
+async fn relays_messages_in_sequence_from_concurrent_replicas() {
+    const MESSAGES_PER_REPLICA: usize = 50;
+    let subscribing_replica = get_replica(REDIS_URL);
+    let mut subscription = subscribing_replica
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { sequenceNumbers }"));
+    let received = tokio::spawn(async move {
+        let mut seqs = Vec::new();
+        while seqs.len() < MESSAGES_PER_REPLICA * 2 {
+            let res = subscription.next().await.unwrap();
+            seqs.push(res.data.into_json().unwrap()["sequenceNumbers"].as_u64().unwrap());
+        }
+        seqs
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // Both replicas publish to the same channel at once, so their sequence numbers are interleaved
+    let publishers = (0..2)
+        .map(|_| {
+            let publishing_replica = get_replica(REDIS_URL);
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                for _ in 0..MESSAGES_PER_REPLICA {
+                    let res = publishing_replica
+                        .run_stateless_for_subscriptions(
+                            PUBLISH_MUTATION.to_string(),
+                            get_publishing_auth_header(),
+                            None,
+                        )
+                        .await;
+                    assert!(matches!(res, DianaResponse::Success(val) if val.starts_with("{\"data\":{\"publish\":")));
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for publisher in publishers {
+        publisher.await.unwrap();
+    }
+
+    // Every message must arrive in sequence, otherwise a subscriber resuming from a later one would never get an earlier one
+    let seqs = tokio::time::timeout(Duration::from_secs(5), received)
+        .await
+        .expect("messages weren't all relayed to the subscribing replica")
+        .unwrap();
+    for (prev, next) in seqs.iter().zip(seqs.iter().skip(1)) {
+        assert_eq!(*next, prev + 1, "messages arrived out of sequence: {:?}", seqs);
+    }
+}
+#[tokio::test]
+async fn fails_to_publish_while_redis_is_unavailable() {
+    let replica = get_replica(UNREACHABLE_REDIS_URL);
+    // The relay was started with the replica, so it'll have failed to connect by now
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let res = replica
+        .run_stateless_for_subscriptions(PUBLISH_MUTATION.to_string(), get_publishing_auth_header(), None)
+        .await;
+    match res {
+        DianaResponse::Success(val) => assert!(
+            val.contains("couldn't relay message to other subscriptions servers: not connected to redis"),
+            "message was published without redis: {}",
+            val
+        ),
+        _ => panic!("Couldn't run publish mutation, got {:?}", res),
+    }
+}
@@ -0,0 +1,174 @@
+// These tests check that scheduled messages are published when they're due, can be cancelled, and survive restarts
+
+mod common;
+
+use async_graphql::{EmptyMutation, Request, Response, Subscription as GQLSubscription};
+use chrono::{DateTime, Utc};
+use common::{get_publishing_auth_header, Context, Query, JWT_SECRET};
+use diana::{
+    graphql_utils::get_stream_for_channel_from_ctx, AuthBlockLevel, DianaHandler, DianaResponse, Options, Stream,
+    StreamExt,
+};
+use std::time::Duration;
+
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    async fn messages(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+    ) -> async_graphql::Result<impl Stream<Item = String>> {
+        Ok(get_stream_for_channel_from_ctx("test_channel", raw_ctx)?)
+    }
+}
+
+fn get_handler(schedule_dir: Option<&str>) -> DianaHandler<Context, Query, EmptyMutation, Subscription> {
+    let mut opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, Subscription {});
+    if let Some(schedule_dir) = schedule_dir {
+        opts = opts.schedule_dir(schedule_dir);
+    }
+    let diana_handler = DianaHandler::new(opts.finish().unwrap()).unwrap();
+    diana_handler.start_subscriptions_server();
+    diana_handler
+}
+
+// Runs the given mutation on the subscriptions server as the publisher would, returning the data in the response
+async fn run_mutation(
+    diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>,
+    query: &str,
+    variables: serde_json::Value,
+) -> serde_json::Value {
+    let body = serde_json::json!({ "query": query, "variables": variables }).to_string();
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_publishing_auth_header(), None)
+        .await;
+    match res {
+        DianaResponse::Success(val) => serde_json::from_str::<serde_json::Value>(&val).unwrap()["data"].clone(),
+        res => panic!("Couldn't run mutation, got {:?}", res),
+    }
+}
+
+async fn schedule(
+    diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>,
+    data: &str,
+    at: DateTime<Utc>,
+) -> String {
+    let data = run_mutation(
+        diana_handler,
+        "mutation($data: String!, $at: String!) { schedulePublish(channel: \"test_channel\", data: $data, at: $at) }",
+        serde_json::json!({ "data": data, "at": at.to_rfc3339() }),
+    )
+    .await;
+    data["schedulePublish"]
+        .as_str()
+        .expect("message wasn't scheduled")
+        .to_string()
+}
+
+async fn subscribe(
+    diana_handler: &DianaHandler<Context, Query, EmptyMutation, Subscription>,
+) -> impl Stream<Item = Response> + Unpin + '_ {
+    let mut subscription = diana_handler
+        .schema_for_subscriptions
+        .execute_stream(Request::new("subscription { messages }"));
+    // Polling the subscription once subscribes to the channel
+    let _ = tokio::time::timeout(Duration::from_millis(10), subscription.next()).await;
+    subscription
+}
+
+fn in_millis(millis: i64) -> DateTime<Utc> {
+    Utc::now() + chrono::Duration::milliseconds(millis)
+}
+
+#[tokio::test]
+async fn publishes_scheduled_messages_when_due() {
+    let diana_handler = get_handler(None);
+    let mut subscription = subscribe(&diana_handler).await;
+    // These are scheduled out of order, but should be published in the order they're due
+    schedule(&diana_handler, "second", in_millis(400)).await;
+    schedule(&diana_handler, "first", in_millis(200)).await;
+
+    // Nothing should arrive before the first message is due
+    let res = tokio::time::timeout(Duration::from_millis(100), subscription.next()).await;
+    assert!(res.is_err());
+    for data in &["first", "second"] {
+        let res = tokio::time::timeout(Duration::from_secs(2), subscription.next())
+            .await
+            .expect("scheduled message wasn't published")
+            .unwrap();
+        assert_eq!(
+            serde_json::to_string(&res).unwrap(),
+            format!("{{\"data\":{{\"messages\":\"{}\"}}}}", data)
+        );
+    }
+}
+#[tokio::test]
+async fn cancels_scheduled_messages() {
+    let diana_handler = get_handler(None);
+    let mut subscription = subscribe(&diana_handler).await;
+    let id = schedule(&diana_handler, "cancelled", in_millis(200)).await;
+
+    let cancel_query = "mutation($id: String!) { cancelScheduled(id: $id) }";
+    let data = run_mutation(&diana_handler, cancel_query, serde_json::json!({ "id": id })).await;
+    assert_eq!(data["cancelScheduled"], true);
+    let res = tokio::time::timeout(Duration::from_millis(600), subscription.next()).await;
+    assert!(res.is_err());
+    // There's nothing left to cancel now
+    let data = run_mutation(&diana_handler, cancel_query, serde_json::json!({ "id": id })).await;
+    assert_eq!(data["cancelScheduled"], false);
+}
+#[tokio::test]
+async fn rejects_invalid_schedule_times() {
+    let diana_handler = get_handler(None);
+    let body = serde_json::json!({
+        "query": "mutation { schedulePublish(channel: \"test_channel\", data: \"test\", at: \"tomorrow\") }"
+    })
+    .to_string();
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_publishing_auth_header(), None)
+        .await;
+    assert!(
+        matches!(res, DianaResponse::Success(val) if val.contains("invalid time 'tomorrow' for scheduled message"))
+    );
+}
+#[tokio::test]
+async fn publishes_persisted_messages_after_restart() {
+    let schedule_dir = std::env::temp_dir().join(format!("diana-schedule-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&schedule_dir);
+    let schedule_dir = schedule_dir.to_str().unwrap();
+
+    let diana_handler = get_handler(Some(schedule_dir));
+    schedule(&diana_handler, "persisted", in_millis(300)).await;
+    drop(diana_handler);
+
+    let diana_handler = get_handler(Some(schedule_dir));
+    let mut subscription = subscribe(&diana_handler).await;
+    let res = tokio::time::timeout(Duration::from_secs(2), subscription.next())
+        .await
+        .expect("persisted message wasn't published")
+        .unwrap();
+    assert_eq!(
+        serde_json::to_string(&res).unwrap(),
+        "{\"data\":{\"messages\":\"persisted\"}}"
+    );
+}
+#[tokio::test]
+async fn publishes_persisted_messages_without_any_requests() {
+    let schedule_dir = std::env::temp_dir().join(format!("diana-schedule-startup-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&schedule_dir);
+    let schedule_dir = schedule_dir.to_str().unwrap();
+
+    let diana_handler = get_handler(Some(schedule_dir));
+    schedule(&diana_handler, "persisted", in_millis(100)).await;
+    drop(diana_handler);
+
+    // The new server's scheduler is started along with it, so nothing has to use it for the message to be published
+    let diana_handler = get_handler(Some(schedule_dir));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(diana_handler.pubsub_metrics().messages_published, 1);
+}
@@ -0,0 +1,184 @@
+// These tests check that subscriptions can be run over Server-Sent Events, and that reconnecting clients carry on from where they were
+
+mod common;
+
+use async_graphql::{EmptyMutation, Subscription as GQLSubscription};
+use common::{get_auth_header, Context, Query, JWT_SECRET};
+use diana::{
+    graphql_utils::get_stream_for_channel_from_ctx, AuthBlockLevel, DianaHandler, DianaResponse, DianaStreamResponse,
+    Options, Stream, StreamExt,
+};
+use std::pin::Pin;
+use std::time::Duration;
+
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    async fn messages(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+        channel: String,
+    ) -> async_graphql::Result<impl Stream<Item = String>> {
+        Ok(get_stream_for_channel_from_ctx(&channel, raw_ctx)?)
+    }
+}
+
+type Handler = DianaHandler<Context, Query, EmptyMutation, Subscription>;
+type Events = Pin<Box<dyn Stream<Item = String> + Send>>;
+
+fn get_handler() -> Handler {
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .channel_history_size(10)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+async fn publish(diana_handler: &Handler, channel: &str, data: &str) {
+    let body = serde_json::json!({
+        "query": "mutation($channel: String!, $data: String!) { publish(channel: $channel, data: $data) }",
+        "variables": { "channel": channel, "data": data }
+    })
+    .to_string();
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_auth_header("graphql_server"), None)
+        .await;
+    assert!(matches!(res, DianaResponse::Success(val) if !val.contains("errors")));
+}
+
+// Subscribes to the given channel over SSE, carrying on from the given event ID if there is one
+async fn subscribe(diana_handler: &Handler, channel: &str, last_event_id: Option<&str>) -> Events {
+    let body = serde_json::json!({
+        "query": "subscription($channel: String!) { messages(channel: $channel) }",
+        "variables": { "channel": channel }
+    })
+    .to_string();
+    let res = diana_handler.run_sse_for_subscriptions(body, get_auth_header("user"), None, last_event_id);
+    let mut events = match res {
+        DianaStreamResponse::Success(events) => events,
+        _ => panic!("couldn't subscribe over SSE"),
+    };
+    // Polling the stream once subscribes to the channel, but that would swallow the first replayed message if we're carrying on
+    if last_event_id.is_none() {
+        let _ = tokio::time::timeout(Duration::from_millis(10), events.next()).await;
+    }
+    events
+}
+
+// Gets the next event from the given stream, returning its ID and data
+async fn next_event(events: &mut Events) -> (String, String) {
+    let event = tokio::time::timeout(Duration::from_secs(1), events.next())
+        .await
+        .expect("no event was sent")
+        .unwrap();
+    let mut id = None;
+    let mut data = None;
+    for line in event.strip_suffix("\n\n").expect("event wasn't terminated").lines() {
+        match line.split_once(": ") {
+            Some(("id", value)) => id = Some(value.to_string()),
+            Some(("event", value)) => assert_eq!(value, "next"),
+            Some(("data", value)) => data = Some(value.to_string()),
+            _ => panic!("unexpected line in event: {}", line),
+        }
+    }
+    let data = serde_json::from_str::<serde_json::Value>(&data.expect("event had no data")).unwrap();
+    (
+        id.expect("event had no ID"),
+        data["data"]["messages"].as_str().unwrap().to_string(),
+    )
+}
+
+async fn assert_no_events(events: &mut Events) {
+    let res = tokio::time::timeout(Duration::from_millis(100), events.next()).await;
+    assert!(res.is_err(), "unexpected event: {:?}", res);
+}
+
+#[tokio::test]
+async fn streams_subscription_results_as_events() {
+    let diana_handler = get_handler();
+    let mut events = subscribe(&diana_handler, "test_channel", None).await;
+    publish(&diana_handler, "test_channel", "first").await;
+    publish(&diana_handler, "test_channel", "second").await;
+
+    let (first_id, data) = next_event(&mut events).await;
+    assert_eq!(data, "first");
+    let (second_id, data) = next_event(&mut events).await;
+    assert_eq!(data, "second");
+    assert_ne!(first_id, second_id);
+}
+#[tokio::test]
+async fn resumes_from_last_event_id() {
+    let diana_handler = get_handler();
+    let mut events = subscribe(&diana_handler, "test_channel", None).await;
+    publish(&diana_handler, "test_channel", "first").await;
+    let (first_id, _) = next_event(&mut events).await;
+    drop(events);
+    // These are published while the client is disconnected
+    publish(&diana_handler, "test_channel", "second").await;
+    publish(&diana_handler, "test_channel", "third").await;
+
+    let mut events = subscribe(&diana_handler, "test_channel", Some(&first_id)).await;
+    assert_eq!(next_event(&mut events).await.1, "second");
+    let (third_id, data) = next_event(&mut events).await;
+    assert_eq!(data, "third");
+    assert_no_events(&mut events).await;
+    publish(&diana_handler, "test_channel", "fourth").await;
+    assert_eq!(next_event(&mut events).await.1, "fourth");
+    drop(events);
+
+    // Carrying on from an earlier event shouldn't skip anything, and shouldn't replay what came before it
+    let mut events = subscribe(&diana_handler, "test_channel", Some(&third_id)).await;
+    assert_eq!(next_event(&mut events).await.1, "fourth");
+    assert_no_events(&mut events).await;
+}
+#[tokio::test]
+async fn resumes_channel_patterns_from_last_event_id() {
+    let diana_handler = get_handler();
+    let mut events = subscribe(&diana_handler, "order.*", None).await;
+    publish(&diana_handler, "order.1", "first").await;
+    let (first_id, _) = next_event(&mut events).await;
+    drop(events);
+    // The client has never seen anything from `order.2`, but it should still get what it missed there
+    publish(&diana_handler, "order.2", "second").await;
+    publish(&diana_handler, "order.1", "third").await;
+
+    let mut events = subscribe(&diana_handler, "order.*", Some(&first_id)).await;
+    assert_eq!(next_event(&mut events).await.1, "second");
+    assert_eq!(next_event(&mut events).await.1, "third");
+    assert_no_events(&mut events).await;
+}
+#[tokio::test]
+async fn ignores_invalid_last_event_ids() {
+    let diana_handler = get_handler();
+    publish(&diana_handler, "test_channel", "before").await;
+    let mut events = subscribe(&diana_handler, "test_channel", Some("notavalidid")).await;
+    assert_no_events(&mut events).await;
+    publish(&diana_handler, "test_channel", "after").await;
+    assert_eq!(next_event(&mut events).await.1, "after");
+}
+#[tokio::test]
+async fn completes_when_subscription_ends() {
+    let diana_handler = get_handler();
+    // This isn't a valid subscription, so the only result will be an error
+    let body = serde_json::json!({ "query": "subscription { nothing }" }).to_string();
+    let res = diana_handler.run_sse_for_subscriptions(body, get_auth_header("user"), None, None);
+    let events = match res {
+        DianaStreamResponse::Success(events) => events.collect::<Vec<_>>().await,
+        _ => panic!("couldn't subscribe over SSE"),
+    };
+    assert_eq!(events.len(), 2);
+    assert!(events[0].starts_with("event: next\ndata: {\"data\":null,\"errors\":"));
+    assert_eq!(events[1], "event: complete\ndata:\n\n");
+}
+#[tokio::test]
+async fn blocks_unauthenticated_clients() {
+    let diana_handler = get_handler();
+    let body = serde_json::json!({ "query": "subscription { messages(channel: \"test_channel\") }" }).to_string();
+    let res = diana_handler.run_sse_for_subscriptions(body, Option::<String>::None, None, None);
+    assert!(matches!(res, DianaStreamResponse::Blocked));
+}
@@ -0,0 +1,319 @@
+// These tests check that messages are POSTed to webhooks with valid signatures and retried when they fail, using a local server to
+// receive them
+
+mod common;
+
+use async_graphql::{EmptyMutation, EmptySubscription};
+use common::{get_publishing_auth_header, Context, Query, JWT_SECRET};
+use diana::{
+    verify_webhook_signature, AuthBlockLevel, DianaHandler, DianaResponse, FileWebhookRegistry, MemoryWebhookRegistry,
+    Options, Webhook, WebhookRegistry, WEBHOOK_ID_HEADER, WEBHOOK_SIGNATURE_HEADER, WEBHOOK_TIMESTAMP_HEADER,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+// A request a webhook received, with its headers (names lowercased) and body
+struct ReceivedRequest {
+    headers: HashMap<String, String>,
+    body: String,
+}
+
+// Starts a webhook receiver that gives the given statuses in order (repeating the last one forever)
+// This returns its URL, a count of the requests it's received, and the requests it accepted, in order
+async fn start_receiver(statuses: Vec<u16>) -> (String, Arc<AtomicUsize>, Arc<Mutex<Vec<ReceivedRequest>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://127.0.0.1:{}/hook", listener.local_addr().unwrap().port());
+    let requests = Arc::new(AtomicUsize::new(0));
+    let requests_clone = Arc::clone(&requests);
+    let accepted = Arc::new(Mutex::new(Vec::new()));
+    let accepted_clone = Arc::clone(&accepted);
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let idx = requests_clone.fetch_add(1, Ordering::SeqCst);
+            let status = statuses[idx.min(statuses.len() - 1)];
+            let accepted = Arc::clone(&accepted_clone);
+            tokio::spawn(async move {
+                let req = read_request(&mut socket).await;
+                if status == 200 {
+                    accepted.lock().unwrap().push(req);
+                }
+                let res = format!(
+                    "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                let _ = socket.write_all(res.as_bytes()).await;
+            });
+        }
+    });
+
+    (url, requests, accepted)
+}
+
+// Reads an entire HTTP request, so the client doesn't see the connection close early
+async fn read_request(socket: &mut tokio::net::TcpStream) -> ReceivedRequest {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        let read = socket.read(&mut chunk).await.unwrap();
+        buf.extend_from_slice(&chunk[..read]);
+        let req = String::from_utf8_lossy(&buf);
+        if let Some(headers_end) = req.find("\r\n\r\n") {
+            let headers = req[..headers_end]
+                .lines()
+                .skip(1)
+                .filter_map(|line| line.split_once(": "))
+                .map(|(name, value)| (name.to_lowercase(), value.to_string()))
+                .collect::<HashMap<_, _>>();
+            let content_length = headers
+                .get("content-length")
+                .map(|len| len.parse::<usize>().unwrap())
+                .unwrap_or(0);
+            if buf.len() >= headers_end + 4 + content_length || read == 0 {
+                return ReceivedRequest {
+                    headers,
+                    body: req[headers_end + 4..].to_string(),
+                };
+            }
+        }
+    }
+}
+
+type Handler = DianaHandler<Context, Query, EmptyMutation, EmptySubscription>;
+
+const WEBHOOK_SECRET: &str = "thisisthewebhooksecret";
+
+fn get_handler(registry: Option<MemoryWebhookRegistry>) -> Handler {
+    let mut opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .webhook_retries(2, Duration::from_millis(10))
+        .schema(Query {}, EmptyMutation {}, EmptySubscription {});
+    if let Some(registry) = registry {
+        opts = opts.webhook_registry(registry);
+    }
+    DianaHandler::new(opts.finish().unwrap()).unwrap()
+}
+
+// Runs the given mutation on the subscriptions server as the publisher would, returning the whole response
+async fn run_mutation(diana_handler: &Handler, query: &str, variables: serde_json::Value) -> serde_json::Value {
+    let body = serde_json::json!({ "query": query, "variables": variables }).to_string();
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_publishing_auth_header(), None)
+        .await;
+    match res {
+        DianaResponse::Success(val) => serde_json::from_str::<serde_json::Value>(&val).unwrap(),
+        res => panic!("Couldn't run mutation, got {:?}", res),
+    }
+}
+
+async fn publish(diana_handler: &Handler, channel: &str, data: &str) {
+    let res = run_mutation(
+        diana_handler,
+        "mutation($channel: String!, $data: String!) { publish(channel: $channel, data: $data) }",
+        serde_json::json!({ "channel": channel, "data": data }),
+    )
+    .await;
+    assert!(res.get("errors").is_none(), "couldn't publish message: {}", res);
+}
+
+// Waits until the given condition holds, failing the test if it doesn't within a couple of seconds
+async fn wait_for(condition: impl Fn() -> bool) {
+    for _ in 0..200 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("timed out waiting for webhook");
+}
+
+#[tokio::test]
+async fn posts_signed_messages_to_matching_webhooks() {
+    let (url, requests, accepted) = start_receiver(vec![200]).await;
+    let webhook = Webhook::new(&url, "order.*", WEBHOOK_SECRET);
+    let diana_handler = get_handler(Some(MemoryWebhookRegistry::new(vec![webhook.clone()])));
+    publish(&diana_handler, "user.1", "ignored").await;
+    publish(&diana_handler, "order.1", "first").await;
+    publish(&diana_handler, "order.2", "second").await;
+
+    wait_for(|| accepted.lock().unwrap().len() == 2).await;
+    // Messages on other channels should never have been sent
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+    let accepted = accepted.lock().unwrap();
+    for (req, (channel, data)) in accepted.iter().zip(&[("order.1", "first"), ("order.2", "second")]) {
+        let message = serde_json::from_str::<serde_json::Value>(&req.body).unwrap();
+        assert_eq!(message["channel"], *channel);
+        assert_eq!(message["data"], *data);
+        assert_eq!(req.headers[&WEBHOOK_ID_HEADER.to_lowercase()], webhook.id);
+        let signature = &req.headers[&WEBHOOK_SIGNATURE_HEADER.to_lowercase()];
+        let timestamp = &req.headers[&WEBHOOK_TIMESTAMP_HEADER.to_lowercase()];
+        assert!(verify_webhook_signature(
+            WEBHOOK_SECRET,
+            timestamp,
+            req.body.as_bytes(),
+            signature
+        ));
+        assert!(!verify_webhook_signature(
+            "notthesecret",
+            timestamp,
+            req.body.as_bytes(),
+            signature
+        ));
+        assert!(!verify_webhook_signature(
+            WEBHOOK_SECRET,
+            timestamp,
+            b"tampered",
+            signature
+        ));
+        // The timestamp is signed too, so it can't be changed to make an old request look new
+        let later = (timestamp.parse::<u64>().unwrap() + 1).to_string();
+        assert!(!verify_webhook_signature(
+            WEBHOOK_SECRET,
+            &later,
+            req.body.as_bytes(),
+            signature
+        ));
+    }
+}
+#[tokio::test]
+async fn retries_failed_deliveries() {
+    let (url, requests, accepted) = start_receiver(vec![500, 429, 200]).await;
+    let webhook = Webhook::new(&url, "test_channel", WEBHOOK_SECRET);
+    let diana_handler = get_handler(Some(MemoryWebhookRegistry::new(vec![webhook])));
+    publish(&diana_handler, "test_channel", "test").await;
+
+    wait_for(|| accepted.lock().unwrap().len() == 1).await;
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+    assert_eq!(diana_handler.pubsub_metrics().webhook_deliveries_failed, 0);
+}
+#[tokio::test]
+async fn gives_up_on_failing_webhooks() {
+    // Client errors aren't retried, but server errors are until the retries run out
+    for (statuses, expected_requests) in [(vec![400], 1), (vec![500], 3)] {
+        let (url, requests, _) = start_receiver(statuses).await;
+        let webhook = Webhook::new(&url, "test_channel", WEBHOOK_SECRET);
+        let diana_handler = get_handler(Some(MemoryWebhookRegistry::new(vec![webhook])));
+        publish(&diana_handler, "test_channel", "test").await;
+
+        wait_for(|| diana_handler.pubsub_metrics().webhook_deliveries_failed == 1).await;
+        assert_eq!(requests.load(Ordering::SeqCst), expected_requests);
+    }
+}
+#[tokio::test]
+async fn drops_messages_for_webhooks_that_fall_behind() {
+    // This webhook accepts connections but never responds, so the first message is stuck there and the rest pile up behind it
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://127.0.0.1:{}/hook", listener.local_addr().unwrap().port());
+    tokio::spawn(async move {
+        let mut sockets = Vec::new();
+        while let Ok((socket, _)) = listener.accept().await {
+            sockets.push(socket);
+        }
+    });
+    let webhook = Webhook::new(&url, "test_channel", WEBHOOK_SECRET);
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .webhook_registry(MemoryWebhookRegistry::new(vec![webhook]))
+        .webhook_queue_size(1)
+        .schema(Query {}, EmptyMutation {}, EmptySubscription {})
+        .finish()
+        .unwrap();
+    let diana_handler = DianaHandler::new(opts).unwrap();
+    for _ in 0..10 {
+        publish(&diana_handler, "test_channel", "test").await;
+    }
+
+    wait_for(|| diana_handler.pubsub_metrics().webhook_messages_dropped > 0).await;
+}
+#[tokio::test]
+async fn registers_and_unregisters_webhooks() {
+    let (url, requests, accepted) = start_receiver(vec![200]).await;
+    let diana_handler = get_handler(Some(MemoryWebhookRegistry::default()));
+    let res = run_mutation(
+        &diana_handler,
+        "mutation($url: String!, $secret: String!) { registerWebhook(url: $url, channel: \"test_channel\", secret: $secret) }",
+        serde_json::json!({ "url": url, "secret": WEBHOOK_SECRET }),
+    )
+    .await;
+    let id = res["data"]["registerWebhook"]
+        .as_str()
+        .expect("webhook wasn't registered")
+        .to_string();
+    publish(&diana_handler, "test_channel", "registered").await;
+    wait_for(|| accepted.lock().unwrap().len() == 1).await;
+
+    let unregister_query = "mutation($id: String!) { unregisterWebhook(id: $id) }";
+    let res = run_mutation(&diana_handler, unregister_query, serde_json::json!({ "id": id })).await;
+    assert_eq!(res["data"]["unregisterWebhook"], true);
+    publish(&diana_handler, "test_channel", "unregistered").await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    // There's nothing left to unregister now
+    let res = run_mutation(&diana_handler, unregister_query, serde_json::json!({ "id": id })).await;
+    assert_eq!(res["data"]["unregisterWebhook"], false);
+}
+#[tokio::test]
+async fn rejects_webhooks_when_not_enabled() {
+    let diana_handler = get_handler(None);
+    let res = run_mutation(
+        &diana_handler,
+        "mutation { registerWebhook(url: \"http://localhost/hook\", channel: \"test_channel\", secret: \"secret\") }",
+        serde_json::json!({}),
+    )
+    .await;
+    assert_eq!(
+        res["errors"][0]["message"],
+        "webhooks aren't enabled on this subscriptions server"
+    );
+}
+#[tokio::test]
+async fn rejects_invalid_webhook_urls() {
+    let diana_handler = get_handler(Some(MemoryWebhookRegistry::default()));
+    let res = run_mutation(
+        &diana_handler,
+        "mutation { registerWebhook(url: \"ftp://localhost/hook\", channel: \"test_channel\", secret: \"secret\") }",
+        serde_json::json!({}),
+    )
+    .await;
+    assert_eq!(
+        res["errors"][0]["message"],
+        "invalid webhook url 'ftp://localhost/hook': only HTTP(S) URLs are supported"
+    );
+}
+#[test]
+fn persists_webhooks_in_file_registry() {
+    let path = std::env::temp_dir()
+        .join(format!("diana-webhooks-test-{}", std::process::id()))
+        .join("webhooks.json");
+    let _ = std::fs::remove_file(&path);
+    let path = path.to_str().unwrap();
+
+    let registry = FileWebhookRegistry::new(path);
+    assert!(registry.list().unwrap().is_empty());
+    let webhook = Webhook::new("http://localhost/hook", "test_channel", WEBHOOK_SECRET);
+    registry.register(webhook.clone()).unwrap();
+    registry
+        .register(Webhook::new("http://localhost/other", "other_channel", WEBHOOK_SECRET))
+        .unwrap();
+    // Registering the same ID again replaces the webhook
+    let mut updated = webhook.clone();
+    updated.channel = "order.*".to_string();
+    registry.register(updated.clone()).unwrap();
+
+    let registry = FileWebhookRegistry::new(path);
+    let webhooks = registry.list().unwrap();
+    assert_eq!(webhooks.len(), 2);
+    assert!(webhooks.contains(&updated));
+    assert!(registry.unregister(&webhook.id).unwrap());
+    assert!(!registry.unregister(&webhook.id).unwrap());
+    assert_eq!(FileWebhookRegistry::new(path).list().unwrap().len(), 1);
+}
@@ -0,0 +1,248 @@
+// These tests check that subscriptions can be run over WebSockets with both the graphql-transport-ws and legacy protocols
+
+mod common;
+
+use async_graphql::{EmptyMutation, Subscription as GQLSubscription};
+use common::{get_auth_header, Context, Query, JWT_SECRET};
+use diana::{
+    graphql_utils::get_stream_for_channel_from_ctx, stream, AuthBlockLevel, DianaHandler, DianaResponse,
+    DianaWsMessage, Options, Stream, StreamExt, WsProtocol,
+};
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    async fn messages(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+        channel: String,
+    ) -> async_graphql::Result<impl Stream<Item = String>> {
+        Ok(get_stream_for_channel_from_ctx(&channel, raw_ctx)?)
+    }
+}
+
+type Handler = DianaHandler<Context, Query, EmptyMutation, Subscription>;
+type Outgoing = Pin<Box<dyn Stream<Item = DianaWsMessage> + Send>>;
+
+fn get_handler() -> Handler {
+    get_handler_with_keep_alive(Duration::from_secs(15))
+}
+
+fn get_handler_with_keep_alive(keep_alive_interval: Duration) -> Handler {
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::AllowAll)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .ws_keep_alive_interval(keep_alive_interval)
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+async fn publish(diana_handler: &Handler, channel: &str, data: &str) {
+    let body = serde_json::json!({
+        "query": "mutation($channel: String!, $data: String!) { publish(channel: $channel, data: $data) }",
+        "variables": { "channel": channel, "data": data }
+    })
+    .to_string();
+    let res = diana_handler
+        .run_stateless_for_subscriptions(body, get_auth_header("graphql_server"), None)
+        .await;
+    assert!(matches!(res, DianaResponse::Success(val) if !val.contains("errors")));
+}
+
+// A client connected to the subscriptions server over a WebSocket
+struct Client {
+    incoming_tx: UnboundedSender<String>,
+    outgoing: Outgoing,
+}
+impl Client {
+    fn connect(diana_handler: &Handler, protocol: WsProtocol) -> Self {
+        let (incoming_tx, mut incoming_rx) = unbounded_channel::<String>();
+        let incoming = Box::pin(stream! {
+            while let Some(message) = incoming_rx.recv().await {
+                yield message;
+            }
+        });
+        let outgoing = diana_handler.run_ws_for_subscriptions(protocol, Option::<String>::None, incoming);
+        Self {
+            incoming_tx,
+            outgoing: Box::pin(outgoing),
+        }
+    }
+    fn send(&self, message: serde_json::Value) {
+        self.incoming_tx.send(message.to_string()).unwrap();
+    }
+    async fn next(&mut self) -> DianaWsMessage {
+        tokio::time::timeout(Duration::from_secs(1), self.outgoing.next())
+            .await
+            .expect("nothing was sent")
+            .expect("the connection ended without being closed")
+    }
+    async fn next_json(&mut self) -> serde_json::Value {
+        match self.next().await {
+            DianaWsMessage::Text(message) => serde_json::from_str(&message).unwrap(),
+            DianaWsMessage::Close(code, reason) => panic!("connection was closed with {}: {}", code, reason),
+        }
+    }
+    async fn next_close_code(&mut self) -> u16 {
+        match self.next().await {
+            DianaWsMessage::Close(code, _) => code,
+            DianaWsMessage::Text(message) => panic!("expected the connection to be closed, got {}", message),
+        }
+    }
+    async fn init(&mut self) {
+        self.send(
+            serde_json::json!({ "type": "connection_init", "payload": { "Authorization": get_auth_header("user") } }),
+        );
+        assert_eq!(self.next_json().await["type"], "connection_ack");
+    }
+    fn subscribe(&self, message_type: &str, id: &str, channel: &str) {
+        self.send(serde_json::json!({
+            "type": message_type,
+            "id": id,
+            "payload": {
+                "query": "subscription($channel: String!) { messages(channel: $channel) }",
+                "variables": { "channel": channel }
+            }
+        }));
+    }
+}
+
+#[test]
+fn negotiates_protocols() {
+    assert_eq!(WsProtocol::negotiate(None), Some(WsProtocol::SubscriptionsTransportWS));
+    assert_eq!(
+        WsProtocol::negotiate(Some("graphql-ws")),
+        Some(WsProtocol::SubscriptionsTransportWS)
+    );
+    assert_eq!(
+        WsProtocol::negotiate(Some("graphql-transport-ws, graphql-ws")),
+        Some(WsProtocol::GraphQLTransportWS)
+    );
+    assert_eq!(
+        WsProtocol::negotiate(Some("mqtt, graphql-transport-ws")),
+        Some(WsProtocol::GraphQLTransportWS)
+    );
+    assert_eq!(WsProtocol::negotiate(Some("mqtt")), None);
+}
+#[tokio::test]
+async fn runs_subscriptions_with_graphql_transport_ws() {
+    let diana_handler = get_handler();
+    let mut client = Client::connect(&diana_handler, WsProtocol::GraphQLTransportWS);
+    client.init().await;
+    client.subscribe("subscribe", "1", "test_channel");
+    // Give the subscription a chance to start before publishing
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    publish(&diana_handler, "test_channel", "hello").await;
+
+    let message = client.next_json().await;
+    assert_eq!(message["type"], "next");
+    assert_eq!(message["id"], "1");
+    assert_eq!(message["payload"]["data"]["messages"], "hello");
+}
+#[tokio::test]
+async fn runs_subscriptions_with_legacy_protocol() {
+    let diana_handler = get_handler();
+    let mut client = Client::connect(&diana_handler, WsProtocol::SubscriptionsTransportWS);
+    client.init().await;
+    client.subscribe("start", "1", "test_channel");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    publish(&diana_handler, "test_channel", "hello").await;
+
+    let message = client.next_json().await;
+    assert_eq!(message["type"], "data");
+    assert_eq!(message["id"], "1");
+    assert_eq!(message["payload"]["data"]["messages"], "hello");
+
+    client.send(serde_json::json!({ "type": "stop", "id": "1" }));
+    assert_eq!(
+        client.next_json().await,
+        serde_json::json!({ "type": "complete", "id": "1" })
+    );
+}
+#[tokio::test]
+async fn sends_errors_for_failed_subscriptions() {
+    let diana_handler = get_handler();
+    let mut client = Client::connect(&diana_handler, WsProtocol::GraphQLTransportWS);
+    client.init().await;
+    // This isn't a valid subscription, so the only result will be an error
+    client.send(
+        serde_json::json!({ "type": "subscribe", "id": "1", "payload": { "query": "subscription { nothing }" } }),
+    );
+
+    let message = client.next_json().await;
+    assert_eq!(message["type"], "error");
+    assert_eq!(message["id"], "1");
+    assert!(message["payload"][0]["message"].is_string());
+    // An error ends the subscription, so it shouldn't be completed as well
+    let res = tokio::time::timeout(Duration::from_millis(100), client.outgoing.next()).await;
+    assert!(res.is_err());
+}
+#[tokio::test]
+async fn answers_pings() {
+    let diana_handler = get_handler();
+    let mut client = Client::connect(&diana_handler, WsProtocol::GraphQLTransportWS);
+    client.init().await;
+    client.send(serde_json::json!({ "type": "ping", "payload": { "sent": "now" } }));
+    assert_eq!(client.next_json().await, serde_json::json!({ "type": "pong" }));
+}
+#[tokio::test]
+async fn closes_when_pings_go_unanswered() {
+    let diana_handler = get_handler_with_keep_alive(Duration::from_millis(100));
+    let mut client = Client::connect(&diana_handler, WsProtocol::GraphQLTransportWS);
+    client.init().await;
+    // A client that answers is kept
+    assert_eq!(client.next_json().await, serde_json::json!({ "type": "ping" }));
+    client.send(serde_json::json!({ "type": "pong" }));
+    assert_eq!(client.next_json().await, serde_json::json!({ "type": "ping" }));
+    // But one that doesn't is assumed to have gone
+    assert_eq!(client.next_close_code().await, 1001);
+}
+#[tokio::test]
+async fn closes_on_subscribe_before_init() {
+    let diana_handler = get_handler();
+    let mut client = Client::connect(&diana_handler, WsProtocol::GraphQLTransportWS);
+    client.subscribe("subscribe", "1", "test_channel");
+    assert_eq!(client.next_close_code().await, 4401);
+}
+#[tokio::test]
+async fn closes_on_duplicate_subscription_ids() {
+    let diana_handler = get_handler();
+    let mut client = Client::connect(&diana_handler, WsProtocol::GraphQLTransportWS);
+    client.init().await;
+    client.subscribe("subscribe", "1", "test_channel");
+    client.subscribe("subscribe", "1", "test_channel");
+    assert_eq!(client.next_close_code().await, 4409);
+}
+#[tokio::test]
+async fn closes_on_repeated_init() {
+    let diana_handler = get_handler();
+    let mut client = Client::connect(&diana_handler, WsProtocol::GraphQLTransportWS);
+    client.init().await;
+    client.send(serde_json::json!({ "type": "connection_init" }));
+    assert_eq!(client.next_close_code().await, 4429);
+}
+#[tokio::test]
+async fn closes_on_invalid_messages() {
+    let diana_handler = get_handler();
+    let mut client = Client::connect(&diana_handler, WsProtocol::GraphQLTransportWS);
+    client.send(serde_json::json!({ "type": "nonsense" }));
+    assert_eq!(client.next_close_code().await, 4400);
+
+    let mut client = Client::connect(&diana_handler, WsProtocol::SubscriptionsTransportWS);
+    client.send(serde_json::json!({ "type": "nonsense" }));
+    assert_eq!(client.next_close_code().await, 1002);
+}
+#[tokio::test]
+async fn closes_on_init_timeout() {
+    let diana_handler = get_handler();
+    let mut client = Client::connect(&diana_handler, WsProtocol::GraphQLTransportWS);
+    let res = tokio::time::timeout(Duration::from_secs(5), client.outgoing.next()).await;
+    assert!(matches!(res, Ok(Some(DianaWsMessage::Close(4408, _)))));
+}